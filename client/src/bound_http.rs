@@ -0,0 +1,213 @@
+//! A minimal HTTP client for talking to the server from a specific source interface or address,
+//! which ureq has no way of configuring.
+//!
+//! On multi-homed hosts, this ensures requests to the server's internal endpoint go through the
+//! innernet tunnel instead of whichever route the OS would otherwise pick.
+
+use crate::{
+    util::{HTTP_CONNECT_TIMEOUT, HTTP_IO_TIMEOUT},
+    ClientError, Error,
+};
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    os::unix::io::FromRawFd,
+    str::FromStr,
+};
+
+#[derive(Clone, Debug)]
+pub enum HttpBind {
+    /// Bind to a network interface by name (`SO_BINDTODEVICE`).
+    Interface(String),
+
+    /// Bind to a local IP address.
+    Address(IpAddr),
+}
+
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            std::mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn check(result: libc::c_int) -> Result<(), io::Error> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(fd: libc::c_int, interface: &str) -> Result<(), io::Error> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_fd: libc::c_int, _interface: &str) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding to an interface is only supported on Linux; use --bind-address instead",
+    ))
+}
+
+fn connect(bind: &HttpBind, server: SocketAddr) -> Result<TcpStream, io::Error> {
+    let family = if server.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    check(fd)?;
+    // Owning the socket right away closes it if any of the steps below fail.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    match bind {
+        HttpBind::Interface(interface) => bind_to_device(fd, interface)?,
+        HttpBind::Address(address) => {
+            let (addr, len) = sockaddr(SocketAddr::new(*address, 0));
+            check(unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) })?;
+        },
+    }
+
+    // Linux also applies the send timeout to connecting.
+    stream.set_write_timeout(Some(HTTP_CONNECT_TIMEOUT))?;
+    let (addr, len) = sockaddr(server);
+    check(unsafe { libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len) }).map_err(
+        |e| match e.raw_os_error() {
+            Some(libc::EINPROGRESS) => {
+                io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+            },
+            _ => e,
+        },
+    )?;
+    stream.set_read_timeout(Some(HTTP_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_IO_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Send a request to the server with an optional extra request header, returning the response
+/// body and the value of the `header` response header, if the server sent it.
+///
+/// HTTP/1.0 is used so that the server closes the connection after its response, which is then
+/// simply read to the end, without needing to handle chunked encoding or keep-alive.
+pub fn request(
+    bind: &HttpBind,
+    method: &str,
+    server: &SocketAddr,
+    path: &str,
+    body: Option<&str>,
+    request_header: Option<(&str, &str)>,
+    header: Option<&str>,
+) -> Result<(String, Option<String>), Error> {
+    let mut stream = connect(bind, *server)?;
+
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, server);
+    if let Some((name, value)) = request_header {
+        request += &format!("{}: {}\r\n", name, value);
+    }
+    if let Some(body) = body {
+        request += &format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+    } else {
+        request += "\r\n";
+    }
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let mut parts = response.splitn(2, "\r\n\r\n");
+    let head = parts.next().unwrap_or_default();
+    let body = parts.next().unwrap_or_default().to_string();
+
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| u16::from_str(status).ok())
+        .ok_or_else(|| ClientError(format!("invalid HTTP response: {}", status_line)))?;
+    if status >= 400 {
+        return Err(ClientError(format!("{}: status code {}", server, status)).into());
+    }
+
+    let header = header.and_then(|header| {
+        lines.find_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case(header) => {
+                    Some(value.trim().to_string())
+                },
+                _ => None,
+            }
+        })
+    });
+
+    Ok((body, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_bound_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\nX-Test: hello\r\n\r\n{\"ok\":true}")
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        let (body, header) = request(
+            &HttpBind::Address("127.0.0.1".parse().unwrap()),
+            "PUT",
+            &server,
+            "/v1/test",
+            Some("{}"),
+            Some(("X-Request", "hi")),
+            Some("x-test"),
+        )
+        .unwrap();
+        assert_eq!(body, "{\"ok\":true}");
+        assert_eq!(header.as_deref(), Some("hello"));
+
+        let request = handle.join().unwrap();
+        assert!(request.starts_with("PUT /v1/test HTTP/1.0\r\n"));
+        assert!(request.contains("\r\nX-Request: hi\r\n"));
+        assert!(request.ends_with("Content-Length: 2\r\n\r\n{}"));
+    }
+}
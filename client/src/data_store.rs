@@ -1,10 +1,12 @@
 use crate::Error;
 use serde::{Deserialize, Serialize};
-use shared::{ensure_dirs_exist, Cidr, IoErrorContext, Peer, CLIENT_DATA_PATH};
+use shared::{client_data_path, ensure_dirs_exist, Cidr, IoErrorContext, Peer};
 use std::{
     fs::{File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
-    path::Path,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug)]
@@ -13,11 +15,44 @@ pub struct DataStore {
     contents: Contents,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "version")]
 pub enum Contents {
     #[serde(rename = "1")]
-    V1 { peers: Vec<Peer>, cidrs: Vec<Cidr> },
+    V1 {
+        peers: Vec<Peer>,
+        cidrs: Vec<Cidr>,
+
+        /// The oldest client version the server supported as of the last fetch.
+        #[serde(default)]
+        min_client_version: Option<String>,
+
+        /// The network's message of the day as of the last fetch.
+        #[serde(default)]
+        motd: Option<String>,
+
+        /// The public keys of peers this host was told are intentionally offline, i.e.
+        /// decommissioned but not yet removed. Purely local, the server never sees it.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        expected_offline: Vec<String>,
+
+        /// IPs that peers have moved away from, still pinned to the peer that had them until
+        /// the move is confirmed locally.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        moved_from: Vec<PinnedIp>,
+
+        /// When the peers and CIDRs were last fetched from the server, in seconds since the
+        /// UNIX epoch.
+        #[serde(default)]
+        fetched_at: Option<u64>,
+    },
+}
+
+/// An IP pinned to a peer's public key after the peer itself moved on to another IP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PinnedIp {
+    pub ip: IpAddr,
+    pub public_key: String,
 }
 
 impl DataStore {
@@ -33,17 +68,32 @@ impl DataStore {
         let contents = serde_json::from_str(&json).unwrap_or_else(|_| Contents::V1 {
             peers: vec![],
             cidrs: vec![],
+            min_client_version: None,
+            motd: None,
+            expected_offline: vec![],
+            moved_from: vec![],
+            fetched_at: None,
         });
 
         Ok(Self { file, contents })
     }
 
+    fn path(interface: &str) -> PathBuf {
+        client_data_path().join(interface).with_extension("json")
+    }
+
     fn _open(interface: &str, create: bool) -> Result<Self, Error> {
-        ensure_dirs_exist(&[*CLIENT_DATA_PATH])?;
-        Self::open_with_path(
-            CLIENT_DATA_PATH.join(interface).with_extension("json"),
-            create,
-        )
+        ensure_dirs_exist(&[&client_data_path()])?;
+        Self::open_with_path(Self::path(interface), create)
+    }
+
+    /// Delete an interface's cached state, if there is any.
+    pub fn remove(interface: &str) -> Result<(), Error> {
+        let path = Self::path(interface);
+        if path.exists() {
+            std::fs::remove_file(&path).with_path(&path)?;
+        }
+        Ok(())
     }
 
     pub fn open(interface: &str) -> Result<Self, Error> {
@@ -68,20 +118,66 @@ impl DataStore {
     ///
     /// Note, however, that this does not prevent a compromised server from adding a new
     /// peer under its control, of course.
+    ///
+    /// A known public key showing up at a new, unpinned IP is a peer an admin moved, so its
+    /// pin moves along with it. Its old IP stays pinned to it, though, until the move is
+    /// confirmed with `release_ip`, since a compromised server could otherwise move a peer
+    /// away only to hand its IP to a key under its control. For the same reason, the IP of a
+    /// peer that was deleted stays pinned until `remove_peer` or `release_ip` drops it.
     pub fn add_peers(&mut self, new_peers: Vec<Peer>) -> Result<(), Error> {
-        let peers = match &mut self.contents {
-            Contents::V1 { ref mut peers, .. } => peers,
+        Self::add_peers_to(&mut self.contents, new_peers)
+    }
+
+    /// Check that `add_peers` would accept the peers, without adding them, so that a pinning
+    /// error is caught before the peers are applied anywhere else.
+    pub fn check_peers(&self, new_peers: &[Peer]) -> Result<(), Error> {
+        Self::add_peers_to(&mut self.contents.clone(), new_peers.to_vec())
+    }
+
+    fn add_peers_to(contents: &mut Contents, new_peers: Vec<Peer>) -> Result<(), Error> {
+        let (peers, moved_from) = match contents {
+            Contents::V1 {
+                ref mut peers,
+                ref mut moved_from,
+                ..
+            } => (peers, moved_from),
         };
 
         for new_peer in new_peers {
+            if moved_from
+                .iter()
+                .any(|pin| pin.ip == new_peer.ip && pin.public_key != new_peer.public_key)
+            {
+                return Err(format!(
+                    "PINNING ERROR: New peer has the IP {} that another peer moved away from, \
+                     which hasn't been released with \"innernet release-ip\".",
+                    new_peer.ip
+                )
+                .into());
+            }
+            // A peer moving back to its old IP no longer needs it pinned separately.
+            moved_from.retain(|pin| pin.ip != new_peer.ip);
+
             if let Some(existing_peer) = peers.iter_mut().find(|p| p.ip == new_peer.ip) {
                 if existing_peer.public_key != new_peer.public_key {
-                    return Err(
-                        "PINNING ERROR: New peer has same IP but different public key.".into(),
-                    );
+                    return Err(format!(
+                        "PINNING ERROR: New peer has same IP but different public key. If {} \
+                         at {} was deleted, release the IP with \"innernet release-ip\".",
+                        existing_peer.name, new_peer.ip
+                    )
+                    .into());
                 } else {
                     *existing_peer = new_peer;
                 }
+            } else if let Some(moved_peer) = peers
+                .iter_mut()
+                .find(|p| p.public_key == new_peer.public_key)
+            {
+                moved_from.push(PinnedIp {
+                    ip: moved_peer.ip,
+                    public_key: moved_peer.public_key.clone(),
+                });
+                *moved_peer = new_peer;
             } else {
                 peers.push(new_peer);
             }
@@ -90,6 +186,52 @@ impl DataStore {
         Ok(())
     }
 
+    /// The IPs that peers moved away from, which are still pinned to them.
+    pub fn moved_from(&self) -> &[PinnedIp] {
+        match &self.contents {
+            Contents::V1 { moved_from, .. } => moved_from,
+        }
+    }
+
+    /// The cached peer at an IP, which stays pinned to it even if the server stops listing it.
+    pub fn peer_at(&self, ip: IpAddr) -> Option<&Peer> {
+        self.peers().iter().find(|peer| peer.ip == ip)
+    }
+
+    /// Let another peer have an IP that a peer moved away from or was deleted from, returning
+    /// whether it was pinned.
+    pub fn release_ip(&mut self, ip: IpAddr) -> bool {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut peers,
+                ref mut moved_from,
+                ..
+            } => {
+                let pinned = moved_from.len() + peers.len();
+                moved_from.retain(|pin| pin.ip != ip);
+                peers.retain(|peer| peer.ip != ip);
+                moved_from.len() + peers.len() != pinned
+            },
+        }
+    }
+
+    /// Forget a peer that was deleted, including the IPs it moved away from, so that they can
+    /// be given to other peers. Returns whether the peer was known.
+    pub fn remove_peer(&mut self, public_key: &str) -> bool {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut peers,
+                ref mut moved_from,
+                ..
+            } => {
+                moved_from.retain(|pin| pin.public_key != public_key);
+                let known = peers.len();
+                peers.retain(|peer| peer.public_key != public_key);
+                peers.len() != known
+            },
+        }
+    }
+
     pub fn cidrs(&self) -> &[Cidr] {
         match &self.contents {
             Contents::V1 { cidrs, .. } => cidrs,
@@ -102,6 +244,80 @@ impl DataStore {
         }
     }
 
+    pub fn min_client_version(&self) -> Option<&str> {
+        match &self.contents {
+            Contents::V1 {
+                min_client_version, ..
+            } => min_client_version.as_deref(),
+        }
+    }
+
+    pub fn set_min_client_version(&mut self, version: Option<String>) {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut min_client_version,
+                ..
+            } => *min_client_version = version,
+        }
+    }
+
+    pub fn motd(&self) -> Option<&str> {
+        match &self.contents {
+            Contents::V1 { motd, .. } => motd.as_deref(),
+        }
+    }
+
+    pub fn set_motd(&mut self, new_motd: Option<String>) {
+        match &mut self.contents {
+            Contents::V1 { ref mut motd, .. } => *motd = new_motd,
+        }
+    }
+
+    /// Whether the peer with this public key was marked as expected to be offline.
+    pub fn is_expected_offline(&self, public_key: &str) -> bool {
+        match &self.contents {
+            Contents::V1 {
+                expected_offline, ..
+            } => expected_offline.iter().any(|key| key == public_key),
+        }
+    }
+
+    pub fn set_expected_offline(&mut self, public_key: &str, offline: bool) {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut expected_offline,
+                ..
+            } => {
+                expected_offline.retain(|key| key != public_key);
+                if offline {
+                    expected_offline.push(public_key.to_string());
+                }
+            },
+        }
+    }
+
+    /// When the cached peers were last fetched from the server, if they ever were.
+    pub fn fetched_at(&self) -> Option<SystemTime> {
+        match &self.contents {
+            Contents::V1 { fetched_at, .. } => {
+                fetched_at.map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            },
+        }
+    }
+
+    pub fn set_fetched_at(&mut self, time: SystemTime) {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut fetched_at, ..
+            } => {
+                *fetched_at = time
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|duration| duration.as_secs())
+            },
+        }
+    }
+
     pub fn write(&mut self) -> Result<(), Error> {
         self.file.seek(SeekFrom::Start(0))?;
         self.file.set_len(0)?;
@@ -115,28 +331,16 @@ impl DataStore {
 mod tests {
     use super::*;
     use lazy_static::lazy_static;
-    use shared::{Cidr, CidrContents, Peer, PeerContents};
+    use shared::{Cidr, CidrContents, Peer};
     lazy_static! {
-        static ref BASE_PEERS: Vec<Peer> = vec![Peer {
-            id: 0,
-            contents: PeerContents {
-                name: "blah".to_string(),
-                ip: "10.0.0.1".parse().unwrap(),
-                cidr_id: 1,
-                public_key: "abc".to_string(),
-                endpoint: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                persistent_keepalive_interval: None,
-            }
-        }];
+        static ref BASE_PEERS: Vec<Peer> = vec![Peer::for_tests(0, "blah", "10.0.0.1", "abc")];
         static ref BASE_CIDRS: Vec<Cidr> = vec![Cidr {
             id: 1,
             contents: CidrContents {
                 name: "cidr".to_string(),
                 cidr: "10.0.0.0/24".parse().unwrap(),
-                parent: None
+                parent: None,
+                is_infra: false
             }
         }];
     }
@@ -161,6 +365,21 @@ mod tests {
         assert_eq!(store.cidrs(), &*BASE_CIDRS);
     }
 
+    #[test]
+    fn test_fetched_at() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let path = dir.path().join("peer_store.json");
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.fetched_at(), None);
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        store.set_fetched_at(time);
+        store.write().unwrap();
+        let store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.fetched_at(), Some(time));
+    }
+
     #[test]
     fn test_pinning() {
         let dir = tempfile::tempdir().unwrap();
@@ -189,4 +408,101 @@ mod tests {
         store.add_peers(vec![]).unwrap();
         assert_eq!(store.peers(), &*BASE_PEERS);
     }
+
+    #[test]
+    fn test_moved_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let mut store =
+            DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
+
+        let mut moved = BASE_PEERS.clone();
+        moved[0].contents.ip = "10.0.0.9".parse().unwrap();
+        store.add_peers(moved.clone()).unwrap();
+        assert_eq!(store.peers(), &moved[..]);
+        store.write().unwrap();
+
+        // The old IP stays pinned to the peer until it's released.
+        let mut store =
+            DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
+        let mut other = BASE_PEERS.clone();
+        other[0].contents.public_key = "def".to_string();
+        assert!(store.add_peers(other.clone()).is_err());
+
+        assert!(store.release_ip(BASE_PEERS[0].ip));
+        assert!(!store.release_ip(BASE_PEERS[0].ip));
+        store.add_peers(other).unwrap();
+        assert_eq!(store.peers().len(), 2);
+    }
+
+    #[test]
+    fn test_peer_moved_back() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let mut store =
+            DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
+
+        let mut moved = BASE_PEERS.clone();
+        moved[0].contents.ip = "10.0.0.9".parse().unwrap();
+        store.add_peers(moved).unwrap();
+        store.add_peers(BASE_PEERS.clone()).unwrap();
+        assert_eq!(store.peers(), &*BASE_PEERS);
+        assert_eq!(
+            store.moved_from(),
+            &[PinnedIp {
+                ip: "10.0.0.9".parse().unwrap(),
+                public_key: "abc".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_deleted_peer_ip_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let path = dir.path().join("peer_store.json");
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+
+        // The server no longer lists the deleted peer, and gives its IP to a new one.
+        let mut reused = BASE_PEERS.clone();
+        reused[0].contents.public_key = "def".to_string();
+        assert!(store.check_peers(&reused).is_err());
+        assert_eq!(store.peers(), &*BASE_PEERS);
+        assert!(store.add_peers(reused.clone()).is_err());
+
+        // Either deleting it here or releasing its IP lets the new peer have it.
+        assert!(store.remove_peer("abc"));
+        assert!(!store.remove_peer("abc"));
+        store.check_peers(&reused).unwrap();
+        store.add_peers(reused.clone()).unwrap();
+        assert_eq!(store.peers(), &reused[..]);
+
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.peer_at(BASE_PEERS[0].ip), Some(&BASE_PEERS[0]));
+        assert!(store.release_ip(BASE_PEERS[0].ip));
+        assert!(store.peer_at(BASE_PEERS[0].ip).is_none());
+        store.add_peers(reused).unwrap();
+    }
+
+    #[test]
+    fn test_expected_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let path = dir.path().join("peer_store.json");
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert!(!store.is_expected_offline("abc"));
+
+        store.set_expected_offline("abc", true);
+        store.set_expected_offline("abc", true);
+        store.write().unwrap();
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert!(store.is_expected_offline("abc"));
+
+        // Fetching new peers keeps the annotation.
+        store.add_peers(BASE_PEERS.clone()).unwrap();
+        assert!(store.is_expected_offline("abc"));
+
+        store.set_expected_offline("abc", false);
+        assert!(!store.is_expected_offline("abc"));
+    }
 }
@@ -0,0 +1,211 @@
+//! A persisted log of peers connecting and disconnecting, recorded by `innernet up --daemon`
+//! after every fetch and shown with `innernet events`.
+//!
+//! A peer counts as connected while its last handshake is recent. Each peer's previous state is
+//! its last event in the log, so transitions are detected across restarts of the daemon too.
+//! The daemon reads the log once and then keeps the states in memory, and the log is rotated
+//! once it gets big.
+
+use crate::{health::MAX_HANDSHAKE_AGE, util, Error};
+use shared::{client_data_path, ensure_dirs_exist, IoErrorContext, Peer};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use wgctrl::DeviceInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    Connected,
+    Disconnected,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connected => write!(f, "connected"),
+            Self::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub peer: String,
+    pub transition: Transition,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}\t{}", self.timestamp, self.peer, self.transition)
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split('\t').collect::<Vec<_>>();
+        match fields[..] {
+            [timestamp, peer, transition] => Ok(Self {
+                timestamp: timestamp
+                    .parse()
+                    .map_err(|_| format!("invalid timestamp \"{}\"", timestamp))?,
+                peer: peer.to_string(),
+                transition: match transition {
+                    "connected" => Transition::Connected,
+                    "disconnected" => Transition::Disconnected,
+                    _ => return Err(format!("invalid transition \"{}\"", transition)),
+                },
+            }),
+            _ => Err(format!("invalid event \"{}\"", s)),
+        }
+    }
+}
+
+/// The size at which the log is rotated.
+const MAX_EVENTS_BYTES: u64 = 4 * 1024 * 1024;
+
+pub struct EventLog {
+    path: PathBuf,
+
+    /// Each peer's last logged state, read from the log on the first `record`.
+    last_states: Option<HashMap<String, Transition>>,
+}
+
+impl EventLog {
+    pub fn open(interface: &str) -> Result<Self, Error> {
+        ensure_dirs_exist(&[&client_data_path()])?;
+        Ok(Self {
+            path: client_data_path().join(interface).with_extension("events"),
+            last_states: None,
+        })
+    }
+
+    /// All events, oldest first, including the rotated ones. Lines that can't be parsed (e.g. a
+    /// partially written last line) are skipped.
+    pub fn events(&self) -> Result<Vec<Event>, Error> {
+        let mut events = vec![];
+        for path in &[util::rotated_path(&self.path), self.path.clone()] {
+            if path.exists() {
+                let contents = fs::read_to_string(path).with_path(path)?;
+                events.extend(contents.lines().filter_map(|l| l.parse::<Event>().ok()));
+            }
+        }
+        Ok(events)
+    }
+
+    /// Append an event for every peer whose connection state changed since its last event.
+    pub fn record(&mut self, device_info: &DeviceInfo, peers: &[Peer]) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let connected = peers.iter().filter(|peer| !peer.is_disabled).map(|peer| {
+            let handshake_age = device_info
+                .peers
+                .iter()
+                .find(|info| info.config.public_key.to_base64() == peer.public_key)
+                .and_then(|info| info.stats.last_handshake_time)
+                .and_then(|time| now.duration_since(time).ok());
+            let is_connected = matches!(handshake_age, Some(age) if age <= MAX_HANDSHAKE_AGE);
+            (&peer.name[..], is_connected)
+        });
+        let last_states = match &mut self.last_states {
+            Some(last_states) => last_states,
+            None => {
+                let last_states = self
+                    .events()?
+                    .into_iter()
+                    .map(|event| (event.peer, event.transition))
+                    .collect();
+                self.last_states.get_or_insert(last_states)
+            },
+        };
+        let events = transitions(last_states, connected, timestamp);
+        if events.is_empty() {
+            return Ok(());
+        }
+        for event in &events {
+            last_states.insert(event.peer.clone(), event.transition);
+        }
+
+        let lines = events
+            .iter()
+            .map(|event| format!("{}\n", event))
+            .collect::<String>();
+        util::append_rotating(&self.path, MAX_EVENTS_BYTES, None, &lines)
+    }
+}
+
+/// The events for peers whose current state differs from their last logged one. Peers without
+/// any events yet are considered disconnected, so only connecting is logged for them.
+fn transitions<'a>(
+    last_states: &HashMap<String, Transition>,
+    current: impl Iterator<Item = (&'a str, bool)>,
+    timestamp: u64,
+) -> Vec<Event> {
+    current
+        .filter_map(|(peer, is_connected)| {
+            let was_connected = last_states.get(peer) == Some(&Transition::Connected);
+            let transition = match (was_connected, is_connected) {
+                (false, true) => Transition::Connected,
+                (true, false) => Transition::Disconnected,
+                _ => return None,
+            };
+            Some(Event {
+                timestamp,
+                peer: peer.to_string(),
+                transition,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: u64, peer: &str, transition: Transition) -> Event {
+        Event {
+            timestamp,
+            peer: peer.to_string(),
+            transition,
+        }
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let e = event(1_600_000_000, "laptop", Transition::Disconnected);
+        assert_eq!(e.to_string().parse(), Ok(e));
+        assert!("1600000000\tlaptop".parse::<Event>().is_err());
+        assert!("1600000000\tlaptop\tgone".parse::<Event>().is_err());
+    }
+
+    #[test]
+    fn test_transitions() {
+        let last_states = vec![
+            ("laptop".to_string(), Transition::Connected),
+            ("phone".to_string(), Transition::Disconnected),
+        ]
+        .into_iter()
+        .collect();
+        let current = vec![
+            ("laptop", false),
+            ("phone", true),
+            ("desktop", true),
+            ("server", false),
+        ];
+        assert_eq!(
+            transitions(&last_states, current.into_iter(), 3),
+            vec![
+                event(3, "laptop", Transition::Disconnected),
+                event(3, "phone", Transition::Connected),
+                event(3, "desktop", Transition::Connected),
+            ]
+        );
+    }
+}
@@ -0,0 +1,414 @@
+//! What each command does to the host and the network, printed by the global `--explain` flag
+//! before a command runs. The command's own description comes from its doc comment, so this
+//! only lists the files it touches and the requests it makes.
+
+use crate::{help, Command};
+use colored::*;
+use shared::{client_config_path, client_data_path, interface_config::InterfaceConfig};
+use std::path::Path;
+
+const READ_ONLY: &str = "nothing is changed and the server isn't contacted";
+
+/// The base URL of the server's API for an interface, or a generic description if the
+/// interface's config can't be read.
+fn api_base(config: Result<InterfaceConfig, shared::Error>) -> String {
+    match config {
+        Ok(config) => format!("http://{}/v1", config.server.internal_endpoint),
+        Err(_) => "the innernet server".to_string(),
+    }
+}
+
+fn interface_api_base(interface: &str) -> String {
+    api_base(InterfaceConfig::from_interface(interface))
+}
+
+fn config_path(interface: &str) -> String {
+    client_config_path()
+        .join(interface)
+        .with_extension("conf")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn data_path(interface: &str) -> String {
+    client_data_path()
+        .join(interface)
+        .with_extension("json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn fetch_steps(interface: &str, api: &str, update_hosts: bool) -> Vec<String> {
+    let mut steps = vec![
+        format!("GET {}/user/state", api),
+        format!(
+            "add, update, and remove peers on the WireGuard interface {} to match",
+            interface
+        ),
+    ];
+    if update_hosts {
+        steps.push("rewrite the innernet section of /etc/hosts".to_string());
+    }
+    steps.push(format!(
+        "cache the fetched state in {}",
+        data_path(interface)
+    ));
+    steps
+}
+
+fn trusted_keys_step(path: &Path) -> String {
+    format!(
+        "ignore any fetched peer (besides the server) whose public key isn't listed in {}",
+        path.display()
+    )
+}
+
+fn hosts_suffix_step(interface: &str) -> String {
+    format!("save the hosts suffix in {}", config_path(interface))
+}
+
+/// The side effects of a command, including any requests it will make to the server.
+pub fn explain(command: &Command) -> Vec<String> {
+    match command {
+        Command::Install {
+            config,
+            reinstall,
+            no_interface,
+        } => {
+            let api = api_base(InterfaceConfig::from_file(config));
+            let mut steps = vec![];
+            if *reinstall {
+                steps.push(format!(
+                    "tear down an existing interface of the chosen name and delete {} and {}",
+                    config_path("<interface>"),
+                    data_path("<interface>")
+                ));
+            }
+            if !*no_interface {
+                steps.push("bring up a WireGuard interface with the invitation's key".to_string());
+            }
+            steps.push(format!(
+                "POST a new public key to {}/user/redeem, or if the server can't be reached and \
+                 the invitation has a snapshot of the network, leave that to the next fetch",
+                api
+            ));
+            steps.push(format!("write {}", config_path("<interface>")));
+            steps.extend(fetch_steps("<interface>", &api, true));
+            steps
+        },
+        Command::InstallAll { .. } => {
+            let mut steps = vec![
+                "for each invitation, bring up a WireGuard interface with its key".to_string(),
+                "POST a new public key to its server's /user/redeem".to_string(),
+                format!("write {}", config_path("<interface>")),
+            ];
+            steps.extend(fetch_steps("<interface>", "<server>", true));
+            steps
+        },
+        Command::Validate { .. }
+        | Command::Show { .. }
+        | Command::Diff { .. }
+        | Command::Inventory { .. }
+        | Command::Events { .. }
+        | Command::Routes { .. }
+        | Command::PublicKey { .. }
+        | Command::Status { .. }
+        | Command::Man
+        | Command::Completions { .. } => vec![READ_ONLY.to_string()],
+        Command::Up {
+            interface,
+            daemon,
+            watch_network,
+            health_listen,
+            stats_csv,
+            metrics_listen,
+            metrics_port,
+            nice,
+            ionice,
+            down_on_exit,
+            fetch_opts,
+            ..
+        } => {
+            let mut steps = vec![];
+            if nice.is_some() || *ionice {
+                steps.push("lower the process's CPU or I/O priority".to_string());
+            }
+            steps.push(format!(
+                "bring up the WireGuard interface {} from {} if it isn't already up",
+                &**interface,
+                config_path(interface)
+            ));
+            let mut fetch = fetch_steps(
+                interface,
+                &interface_api_base(interface),
+                !fetch_opts.no_host_updates,
+            );
+            if let Some(path) = &fetch_opts.trusted_keys {
+                fetch.insert(1, trusted_keys_step(path));
+            }
+            if fetch_opts.hosts_suffix.is_some() {
+                fetch.push(hosts_suffix_step(interface));
+            }
+            steps.extend(fetch);
+            if *daemon {
+                steps.push("keep repeating the fetch at the interval".to_string());
+                steps.push(format!(
+                    "log peer events to {}",
+                    client_data_path()
+                        .join(&**interface)
+                        .with_extension("events")
+                        .to_string_lossy()
+                ));
+                if *down_on_exit {
+                    steps.push(format!("take down {} on SIGTERM or SIGINT", &**interface));
+                }
+            }
+            if *watch_network {
+                steps.push("watch the system's network links and routes".to_string());
+            }
+            if let Some(addr) = health_listen {
+                steps.push(format!("serve a health check on http://{}", addr));
+            }
+            if let Some(path) = stats_csv {
+                steps.push(format!("append peer stats to {}", path.display()));
+            }
+            if let Some(addr) = metrics_listen {
+                steps.push(format!("serve peer metrics on http://{}/metrics", addr));
+            }
+            if let Some(port) = metrics_port {
+                steps.push(format!(
+                    "serve peer metrics on http://0.0.0.0:{}/metrics",
+                    port
+                ));
+            }
+            steps
+        },
+        Command::Fetch {
+            interface,
+            dry_run: true,
+            ..
+        } => vec![
+            format!("GET {}/user/state", interface_api_base(interface)),
+            "nothing is changed".to_string(),
+        ],
+        Command::Fetch {
+            interface,
+            repair,
+            fetch_opts,
+            ..
+        } => {
+            let mut steps = fetch_steps(
+                interface,
+                &interface_api_base(interface),
+                !fetch_opts.no_host_updates,
+            );
+            if *repair {
+                steps[1] = format!(
+                    "replace every peer on the WireGuard interface {} with the fetched peers",
+                    &**interface
+                );
+            }
+            if let Some(path) = &fetch_opts.trusted_keys {
+                steps.insert(1, trusted_keys_step(path));
+            }
+            if fetch_opts.hosts_suffix.is_some() {
+                steps.push(hosts_suffix_step(interface));
+            }
+            steps
+        },
+        Command::UpdateHosts { interface, tag } => {
+            let mut steps = vec!["rewrite the innernet section of /etc/hosts".to_string()];
+            if tag.is_some() {
+                steps.push(format!("save the tag in {}", config_path(interface)));
+            }
+            steps
+        },
+        Command::ExpectOffline { interface, .. } => {
+            vec![format!(
+                "update the peer's mark in {}",
+                data_path(interface)
+            )]
+        },
+        Command::ReleaseIp { interface, ip, .. } => vec![
+            match ip {
+                Some(_) => format!(
+                    "GET {}/user/state if a cached peer has the IP, to check it's gone",
+                    interface_api_base(interface)
+                ),
+                None => format!("GET {}/user/state", interface_api_base(interface)),
+            },
+            format!("unpin the IPs in {}", data_path(interface)),
+        ],
+        Command::ExportWgQuick { output, .. } => vec![match output {
+            Some(path) => format!("write {}, only readable by you", path.display()),
+            None => READ_ONLY.to_string(),
+        }],
+        Command::Ping { interface, .. } => vec![
+            format!("GET {}/user/state", interface_api_base(interface)),
+            "nothing is changed".to_string(),
+        ],
+        Command::Down { interface } => {
+            vec![format!("delete the WireGuard interface {}", &**interface)]
+        },
+        Command::AddPeer { interface, opts } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {api}/admin/cidrs and {api}/admin/peers", api = api),
+                format!("POST the new peer to {}/admin/peers", api),
+                match &opts.save_config {
+                    Some(path) => format!("write an invitation to {}", path.to_string_lossy()),
+                    None => "write an invitation to a path you choose".to_string(),
+                },
+            ]
+        },
+        Command::AddCidr { interface, .. } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {}/admin/cidrs", api),
+                format!("POST the new CIDR to {}/admin/cidrs", api),
+            ]
+        },
+        Command::DeleteCidr { interface } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!(
+                    "GET {api}/admin/cidrs, {api}/admin/peers, and {api}/admin/associations",
+                    api = api
+                ),
+                format!("DELETE {}/admin/cidrs/<id>", api),
+            ]
+        },
+        Command::DisablePeer { interface }
+        | Command::EnablePeer { interface }
+        | Command::RenamePeer { interface }
+        | Command::AllowEndpointOverride { interface, .. } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {}/admin/peers", api),
+                format!("PUT the updated peer to {}/admin/peers/<id>", api),
+            ]
+        },
+        Command::DisableCidr { interface } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {api}/admin/cidrs and {api}/admin/peers", api = api),
+                format!("PUT each disabled peer to {}/admin/peers/<id>", api),
+            ]
+        },
+        Command::DeletePeer { interface } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {}/admin/peers", api),
+                format!("DELETE {}/admin/peers/<id>?permanent=true", api),
+                format!("unpin the peer's IP in {}", data_path(interface)),
+            ]
+        },
+        Command::ReassignPeerIp { interface } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {api}/admin/cidrs and {api}/admin/peers", api = api),
+                format!("PUT the peer with its new IP to {}/admin/peers/<id>", api),
+                format!("move the peer to its new IP in {}", data_path(interface)),
+            ]
+        },
+        Command::ReapExpired { interface } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {}/admin/peers", api),
+                format!(
+                    "DELETE {}/admin/peers/<id>?permanent=true for each expired peer",
+                    api
+                ),
+                format!("unpin the deleted peers' IPs in {}", data_path(interface)),
+            ]
+        },
+        Command::AuditRedemptions { interface, .. } => vec![
+            format!("GET {}/admin/peers", interface_api_base(interface)),
+            "nothing is changed".to_string(),
+        ],
+        Command::SetInfraCidr { interface, .. } => {
+            let api = interface_api_base(interface);
+            vec![
+                format!("GET {api}/admin/cidrs and {api}/admin/peers", api = api),
+                format!("PUT the CIDR's new infra flag to {}/admin/cidrs/<id>", api),
+            ]
+        },
+        Command::AddAssociation { interface, preview }
+        | Command::DeleteAssociation { interface, preview } => {
+            let api = interface_api_base(interface);
+            let mut steps = vec![format!(
+                "GET {api}/admin/cidrs, {api}/admin/associations, and {api}/admin/peers",
+                api = api
+            )];
+            steps.push(match command {
+                _ if *preview => "nothing is changed".to_string(),
+                Command::AddAssociation { .. } => {
+                    format!("POST the association to {}/admin/associations", api)
+                },
+                _ => format!("DELETE {}/admin/associations/<id>", api),
+            });
+            steps
+        },
+        Command::ListCidrs { interface, .. }
+        | Command::ListAssociations { interface }
+        | Command::ReachabilityMatrix { interface } => vec![
+            format!("GET {}/admin/...", interface_api_base(interface)),
+            "nothing is changed".to_string(),
+        ],
+        Command::MigratePeers {
+            from_interface,
+            to_interface,
+        } => vec![
+            format!("GET {}/admin/...", interface_api_base(from_interface)),
+            format!(
+                "POST the missing CIDRs, peers, and associations to {}/admin/...",
+                interface_api_base(to_interface)
+            ),
+        ],
+        Command::Verify { interface } => vec![
+            format!(
+                "GET {}/admin/associations, for admins only",
+                interface_api_base(interface)
+            ),
+            "nothing is changed".to_string(),
+        ],
+        Command::SetListenPort { interface, .. } => vec![
+            format!(
+                "set the listen port of the WireGuard interface {}",
+                &**interface
+            ),
+            format!("save the new port in {}", config_path(interface)),
+        ],
+        Command::OverrideEndpoint {
+            interface,
+            unset,
+            endpoint,
+            ..
+        } => {
+            let mut steps = vec![];
+            if endpoint.is_none() && !*unset {
+                steps
+                    .push("contact http://4.icanhazip.com to suggest your external IP".to_string());
+            }
+            steps.push(format!(
+                "PUT your endpoint to {}/user/endpoint",
+                interface_api_base(interface)
+            ));
+            steps
+        },
+    }
+}
+
+/// Print the explanation for a command: its description, and then what it will do.
+pub fn print_explanation(name: &str, command: &Command) {
+    println!(
+        "{} {}",
+        "[*]".dimmed(),
+        help::about(&help::help(Some(name))).bold()
+    );
+    println!("    This will:");
+    for step in explain(command) {
+        println!("      - {}", step);
+    }
+    println!();
+}
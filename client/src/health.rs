@@ -0,0 +1,111 @@
+//! A minimal HTTP health endpoint for `innernet up --daemon --health-listen`, so that
+//! orchestrators (e.g. Kubernetes liveness probes) can monitor the daemon without exec'ing it.
+//!
+//! Every request, regardless of method or path, gets a `200 OK` if the last fetch succeeded
+//! recently and the server peer has handshaked recently, or a `503 Service Unavailable`
+//! saying which of the two isn't the case.
+
+use crate::{http_server, Error};
+use shared::interface_config::InterfaceConfig;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use wgctrl::DeviceInfo;
+
+/// WireGuard re-handshakes at least every two minutes while there's traffic (and there is,
+/// with a persistent keepalive), and gives up on a session after three.
+pub const MAX_HANDSHAKE_AGE: Duration = Duration::from_secs(180);
+
+pub struct HealthServer {
+    last_fetch: Arc<Mutex<Option<Instant>>>,
+    max_fetch_age: Arc<Mutex<Duration>>,
+}
+
+impl HealthServer {
+    /// Start serving in the background. A fetch older than `max_fetch_age` counts as stale.
+    pub fn start(
+        addr: SocketAddr,
+        interface: &str,
+        max_fetch_age: Duration,
+    ) -> Result<Self, Error> {
+        let server_public_key = InterfaceConfig::from_interface(interface)?
+            .server
+            .public_key;
+        let last_fetch = Arc::new(Mutex::new(None));
+        let max_fetch_age = Arc::new(Mutex::new(max_fetch_age));
+
+        let (interface, state, max_age) = (
+            interface.to_string(),
+            last_fetch.clone(),
+            max_fetch_age.clone(),
+        );
+        http_server::serve(addr, "health checks", "text/plain", move |_| {
+            let last_fetch = *state.lock().unwrap();
+            let max_fetch_age = *max_age.lock().unwrap();
+            let last_handshake = DeviceInfo::get_by_name(&interface)
+                .ok()
+                .and_then(|device_info| {
+                    device_info
+                        .peers
+                        .into_iter()
+                        .find(|peer| peer.config.public_key.to_base64() == server_public_key)
+                })
+                .and_then(|peer| peer.stats.last_handshake_time);
+            match check(last_fetch, last_handshake, max_fetch_age) {
+                Ok(()) => ("200 OK", "ok\n".to_string()),
+                Err(reason) => ("503 Service Unavailable", format!("{}\n", reason)),
+            }
+        })?;
+
+        Ok(Self {
+            last_fetch,
+            max_fetch_age,
+        })
+    }
+
+    pub fn fetch_succeeded(&self) {
+        *self.last_fetch.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Change how old a fetch can get, e.g. when the fetch interval changes.
+    pub fn set_max_fetch_age(&self, max_fetch_age: Duration) {
+        *self.max_fetch_age.lock().unwrap() = max_fetch_age;
+    }
+}
+
+fn check(
+    last_fetch: Option<Instant>,
+    last_handshake: Option<SystemTime>,
+    max_fetch_age: Duration,
+) -> Result<(), &'static str> {
+    match last_fetch {
+        Some(fetched) if fetched.elapsed() <= max_fetch_age => {},
+        _ => return Err("no recent successful fetch"),
+    }
+    match last_handshake.and_then(|handshake| handshake.elapsed().ok()) {
+        Some(age) if age <= MAX_HANDSHAKE_AGE => Ok(()),
+        _ => Err("no recent handshake with the server"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let max_fetch_age = Duration::from_secs(120);
+        let now = SystemTime::now();
+        let long_ago = now - Duration::from_secs(600);
+
+        assert_eq!(
+            check(Some(Instant::now()), Some(now), max_fetch_age),
+            Ok(())
+        );
+        assert!(check(None, Some(now), max_fetch_age).is_err());
+        assert!(check(Some(Instant::now()), None, max_fetch_age).is_err());
+        assert!(check(Some(Instant::now()), Some(long_ago), max_fetch_age).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! The CLI's own help output, read through clap's public interface, so that the man page and
+//! `--explain` describe commands in the words of their doc comments.
+
+use crate::Opt;
+use structopt::{clap::ErrorKind, StructOpt};
+
+/// The help printed by `innernet [subcommand] --help`.
+pub fn help(subcommand: Option<&str>) -> String {
+    let mut args = vec!["innernet"];
+    args.extend(subcommand);
+    args.push("--help");
+    match Opt::clap().get_matches_from_safe(args) {
+        Err(e) if e.kind == ErrorKind::HelpDisplayed => e.message,
+        _ => String::new(),
+    }
+}
+
+/// The first paragraph of the description under a help's title line.
+pub fn about(help: &str) -> String {
+    help.lines()
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_about() {
+        assert_eq!(
+            about(&help(Some("down"))),
+            "Bring down the interface (equivalent to \"wg-quick down [interface]\")"
+        );
+        assert!(about(&help(None)).starts_with("A client to manage innernet"));
+    }
+}
@@ -0,0 +1,90 @@
+//! A minimal HTTP server for the daemon's plain-text endpoints (`--health-listen` and
+//! `--metrics-listen`), which only ever answer small GET requests, so that they don't need a
+//! web framework.
+
+use crate::Error;
+use colored::*;
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+/// A response's status (e.g. "200 OK") and body.
+pub type Response = (&'static str, String);
+
+/// Start answering connections to `addr` in the background, each with the response `respond`
+/// gives for its request line (e.g. "GET /metrics HTTP/1.1"). `what` names the requests, for
+/// error messages (e.g. "health checks").
+pub fn serve<F>(
+    addr: SocketAddr,
+    what: &'static str,
+    content_type: &'static str,
+    respond: F,
+) -> Result<(), Error>
+where
+    F: Fn(&str) -> Response + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| format!("failed to listen for {} on {}: {}", what, addr, e))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = answer(stream, content_type, &respond) {
+                println!("{} failed to answer {}: {}", "[!]".yellow(), what, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn answer(
+    mut stream: TcpStream,
+    content_type: &str,
+    respond: impl Fn(&str) -> Response,
+) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // Only the request line matters, but reading (some of) the rest keeps clients that are
+    // still sending from seeing a connection reset.
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request).unwrap_or(0);
+    let request = String::from_utf8_lossy(&request[..len]);
+
+    let (status, body) = respond(request.lines().next().unwrap_or_default());
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serve() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        serve(addr, "tests", "text/plain", |request| {
+            ("200 OK", format!("{}\n", request))
+        })
+        .unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /test HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 19\r\n"));
+        assert!(response.ends_with("\r\n\r\nGET /test HTTP/1.1\n"));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::Error;
+use shared::{client_data_path, ensure_dirs_exist, IoErrorContext};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    num::NonZeroUsize,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+};
+
+/// Lock a file, returning `false` instead of waiting if `block` is unset and it's already
+/// locked by another process.
+fn flock(file: &File, block: bool) -> io::Result<bool> {
+    let operation = if block {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    if error.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(error)
+    }
+}
+
+/// An advisory lock on an interface, held while a process manages it (e.g. `innernet up` or
+/// `innernet fetch`) so that two processes don't race each other configuring the same device.
+///
+/// The lock is released when this is dropped, or when the process exits for any reason.
+#[derive(Debug)]
+pub struct InterfaceLock {
+    _file: File,
+}
+
+impl InterfaceLock {
+    fn path(interface: &str) -> PathBuf {
+        client_data_path().join(interface).with_extension("lock")
+    }
+
+    /// Try to take the lock for an interface, returning `None` if another process holds it.
+    pub fn try_acquire(interface: &str) -> Result<Option<Self>, Error> {
+        ensure_dirs_exist(&[&client_data_path()])?;
+        let path = Self::path(interface);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The previous holder's PID is only cleared once the lock is ours.
+            .truncate(false)
+            .open(&path)
+            .with_path(&path)?;
+
+        if !flock(&file, false).with_path(&path)? {
+            return Ok(None);
+        }
+
+        file.set_len(0).with_path(&path)?;
+        file.seek(SeekFrom::Start(0)).with_path(&path)?;
+        write!(file, "{}", std::process::id()).with_path(&path)?;
+
+        Ok(Some(Self { _file: file }))
+    }
+
+    /// The process ID of the current holder of an interface's lock, if known.
+    pub fn holder(interface: &str) -> Option<u32> {
+        let mut contents = String::new();
+        File::open(Self::path(interface))
+            .ok()?
+            .read_to_string(&mut contents)
+            .ok()?;
+        contents.trim().parse().ok()
+    }
+}
+
+/// One of a fixed number of host-wide slots for fetching, so that the daemons of a host joined
+/// to many networks don't all hit their servers at once (e.g. when they start together at
+/// boot). Like `InterfaceLock`, it's released when dropped or when the process exits.
+#[derive(Debug)]
+pub struct FetchSlot {
+    _file: File,
+}
+
+impl FetchSlot {
+    fn path(slot: usize) -> PathBuf {
+        client_data_path().join(format!("fetch-slot-{}.lock", slot))
+    }
+
+    /// Take the first free of `slots` slots, waiting for one if they're all taken. Processes
+    /// only exclude each other as intended if they all use the same number of slots.
+    pub fn acquire(slots: NonZeroUsize) -> Result<Self, Error> {
+        ensure_dirs_exist(&[&client_data_path()])?;
+        let slots = slots.get();
+        let open = |slot| {
+            let path = Self::path(slot);
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .with_path(&path)
+        };
+
+        for slot in 0..slots {
+            let file = open(slot)?;
+            if flock(&file, false).with_path(Self::path(slot))? {
+                return Ok(Self { _file: file });
+            }
+        }
+        // Spread the waiting processes over the slots instead of queueing them all on one.
+        let slot = std::process::id() as usize % slots;
+        let file = open(slot)?;
+        flock(&file, true).with_path(Self::path(slot))?;
+        Ok(Self { _file: file })
+    }
+}
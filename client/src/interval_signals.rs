@@ -0,0 +1,117 @@
+//! Live control over the fetch interval of `innernet up --daemon`: SIGUSR1 halves it and SIGUSR2
+//! doubles it, e.g. to sync rapidly during an incident without restarting the daemon.
+//!
+//! The handlers only count the requested changes, which the daemon applies between fetches. A
+//! signal also cuts the current wait short, so that the new interval takes effect right away, as
+//! does a request to stop.
+
+use crate::{shutdown, util};
+use std::{
+    convert::Infallible,
+    io,
+    sync::atomic::{AtomicI32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The bounds that signals can move the interval within. An interval that's already outside of
+/// them is never moved further out.
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often a wait checks for signals.
+const WAIT_SLICE: Duration = Duration::from_millis(500);
+
+/// Doublings (positive) and halvings (negative) requested since they were last applied.
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn handle(signal: libc::c_int) {
+    let change = if signal == libc::SIGUSR1 { -1 } else { 1 };
+    PENDING.fetch_add(change, Ordering::SeqCst);
+}
+
+pub fn install() -> Result<(), io::Error> {
+    util::handle_signals(&[libc::SIGUSR1, libc::SIGUSR2], handle)
+}
+
+/// The interval with the changes requested since the last call applied.
+pub fn adjust(interval: Duration) -> Duration {
+    apply(interval, PENDING.swap(0, Ordering::SeqCst))
+}
+
+fn apply(mut interval: Duration, changes: i32) -> Duration {
+    for _ in 0..changes.abs() {
+        interval = if changes < 0 {
+            (interval / 2).max(MIN_INTERVAL.min(interval))
+        } else {
+            interval.saturating_mul(2).min(MAX_INTERVAL.max(interval))
+        };
+    }
+    interval
+}
+
+/// Sleep for the duration, or until a signal asks to change the interval or to stop.
+pub fn sleep(duration: Duration) {
+    let _ = wait(duration, |slice| {
+        thread::sleep(slice);
+        Ok::<_, Infallible>(false)
+    });
+}
+
+/// Wait for the duration with `wait_slice` (e.g. for network changes), a slice at a time so that
+/// signals are noticed, until a slice returns true or a signal asks to change the interval or to
+/// stop. Returns whether a slice returned true.
+pub fn wait<E>(
+    duration: Duration,
+    mut wait_slice: impl FnMut(Duration) -> Result<bool, E>,
+) -> Result<bool, E> {
+    // A duration too long to represent is as good as waiting forever.
+    let deadline = Instant::now().checked_add(duration);
+    while PENDING.load(Ordering::SeqCst) == 0 && !shutdown::requested() {
+        let now = Instant::now();
+        let remaining = match deadline {
+            Some(deadline) if now >= deadline => break,
+            Some(deadline) => deadline - now,
+            None => WAIT_SLICE,
+        };
+        if wait_slice(remaining.min(WAIT_SLICE))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(apply(minute, 0), minute);
+        assert_eq!(apply(minute, -1), Duration::from_secs(30));
+        assert_eq!(apply(minute, 2), Duration::from_secs(240));
+        assert_eq!(apply(minute, -10), MIN_INTERVAL);
+        assert_eq!(apply(minute, 10), MAX_INTERVAL);
+        // Intervals configured outside of the bounds aren't pushed further out.
+        assert_eq!(apply(Duration::from_secs(2), -1), Duration::from_secs(2));
+        assert_eq!(apply(Duration::from_secs(2), 1), Duration::from_secs(4));
+        assert_eq!(apply(MAX_INTERVAL * 2, 1), MAX_INTERVAL * 2);
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut slices = vec![];
+        let changed = wait(Duration::from_millis(1200), |slice| {
+            slices.push(slice);
+            thread::sleep(slice);
+            Ok::<_, Infallible>(false)
+        });
+        assert!(!changed.unwrap());
+        assert!(slices.len() >= 3);
+        assert!(slices.iter().all(|slice| *slice <= WAIT_SLICE));
+
+        let changed = wait(Duration::from_secs(60), |_| Ok::<_, Infallible>(true));
+        assert!(changed.unwrap());
+    }
+}
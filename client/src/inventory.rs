@@ -0,0 +1,211 @@
+//! A normalized inventory of an interface's peers, for `innernet inventory`, meant for syncing
+//! into a CMDB or asset database.
+//!
+//! Unlike `show --json`, which mirrors innernet's internal types, this schema is kept stable:
+//! fields may be added, but are never renamed, removed, or change meaning without bumping
+//! `schema_version`. Timestamps are seconds since the UNIX epoch, and absent values are null
+//! (or left out, in TOML). The top level has the `schema_version`, the `interface`, when it was
+//! `generated_at`, and the `peers`, where every peer in the last fetched peer list gets a record:
+//!
+//! * `name`, `ip`, `public_key`: the peer's identity.
+//! * `cidr`: the name of the CIDR the peer is in.
+//! * `enabled`, `admin`, `redeemed`: whether the peer is enabled, an admin, and has redeemed
+//!   its invitation.
+//! * `created_at`, `expires_at`: when the server created the peer, and when it expires.
+//! * `last_handshake`: the last handshake with the peer on this host.
+//! * `endpoint`: the endpoint the peer is currently reached at, or otherwise the one it
+//!   advertises.
+
+use serde::Serialize;
+use shared::{Cidr, Peer};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use wgctrl::DeviceInfo;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct Inventory {
+    pub schema_version: u32,
+    pub interface: String,
+    pub generated_at: u64,
+    pub peers: Vec<InventoryRecord>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct InventoryRecord {
+    pub name: String,
+    pub ip: IpAddr,
+    pub cidr: Option<String>,
+    pub public_key: String,
+    pub enabled: bool,
+    pub admin: bool,
+    pub redeemed: bool,
+    pub created_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub last_handshake: Option<u64>,
+    pub endpoint: Option<SocketAddr>,
+}
+
+/// What WireGuard currently knows about a peer, by public key.
+#[derive(Debug)]
+struct LiveState {
+    last_handshake: Option<u64>,
+    endpoint: Option<SocketAddr>,
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs())
+}
+
+impl Inventory {
+    /// Combine the cached peer list with the interface's live state, if it's up.
+    pub fn new(
+        interface: &str,
+        peers: &[Peer],
+        cidrs: &[Cidr],
+        device_info: Option<&DeviceInfo>,
+    ) -> Self {
+        let live = device_info
+            .map(|device_info| {
+                device_info
+                    .peers
+                    .iter()
+                    .map(|info| {
+                        (
+                            info.config.public_key.to_base64(),
+                            LiveState {
+                                last_handshake: info.stats.last_handshake_time.and_then(unix_secs),
+                                endpoint: info.config.endpoint,
+                            },
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self::from_live(interface, peers, cidrs, &live, SystemTime::now())
+    }
+
+    fn from_live(
+        interface: &str,
+        peers: &[Peer],
+        cidrs: &[Cidr],
+        live: &HashMap<String, LiveState>,
+        now: SystemTime,
+    ) -> Self {
+        let mut peers = peers
+            .iter()
+            .map(|peer| {
+                let live = live.get(&peer.public_key);
+                InventoryRecord {
+                    name: peer.name.clone(),
+                    ip: peer.ip,
+                    cidr: cidrs
+                        .iter()
+                        .find(|cidr| cidr.id == peer.cidr_id)
+                        .map(|cidr| cidr.name.clone()),
+                    public_key: peer.public_key.clone(),
+                    enabled: !peer.is_disabled,
+                    admin: peer.is_admin,
+                    redeemed: peer.is_redeemed,
+                    created_at: peer.created_at.and_then(unix_secs),
+                    expires_at: peer.expires_at.and_then(unix_secs),
+                    last_handshake: live.and_then(|live| live.last_handshake),
+                    endpoint: live.and_then(|live| live.endpoint).or(peer.endpoint),
+                }
+            })
+            .collect::<Vec<_>>();
+        // Sorted, so that syncs only see changes when something actually changed.
+        peers.sort_by_key(|record| record.ip);
+
+        Self {
+            schema_version: SCHEMA_VERSION,
+            interface: interface.to_string(),
+            generated_at: unix_secs(now).unwrap_or_default(),
+            peers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::CidrContents;
+    use std::time::Duration;
+
+    fn peer(id: i64, name: &str, ip: &str, public_key: &str) -> Peer {
+        let mut peer = Peer::for_tests(id, name, ip, public_key);
+        peer.contents.cidr_id = 2;
+        peer.contents.endpoint = Some("203.0.113.1:51820".parse().unwrap());
+        peer.contents.created_at = Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        peer
+    }
+
+    #[test]
+    fn test_from_live() {
+        let cidrs = vec![Cidr {
+            id: 2,
+            contents: CidrContents {
+                name: "laptops".to_string(),
+                cidr: "10.0.0.0/24".parse().unwrap(),
+                parent: None,
+                is_infra: false,
+            },
+        }];
+        let mut peers = vec![
+            peer(3, "phone", "10.0.0.3", "cGhvbmU="),
+            peer(2, "laptop", "10.0.0.2", "bGFwdG9w"),
+        ];
+        peers[0].contents.is_disabled = true;
+        let live = vec![(
+            "bGFwdG9w".to_string(),
+            LiveState {
+                last_handshake: Some(1_600_000_100),
+                endpoint: Some("198.51.100.7:40000".parse().unwrap()),
+            },
+        )]
+        .into_iter()
+        .collect();
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_600_000_200);
+        let inventory = Inventory::from_live("evilcorp", &peers, &cidrs, &live, now);
+        assert_eq!(inventory.schema_version, SCHEMA_VERSION);
+        assert_eq!(inventory.generated_at, 1_600_000_200);
+        assert_eq!(
+            inventory.peers,
+            vec![
+                InventoryRecord {
+                    name: "laptop".to_string(),
+                    ip: "10.0.0.2".parse().unwrap(),
+                    cidr: Some("laptops".to_string()),
+                    public_key: "bGFwdG9w".to_string(),
+                    enabled: true,
+                    admin: false,
+                    redeemed: true,
+                    created_at: Some(1_600_000_000),
+                    expires_at: None,
+                    last_handshake: Some(1_600_000_100),
+                    endpoint: Some("198.51.100.7:40000".parse().unwrap()),
+                },
+                InventoryRecord {
+                    name: "phone".to_string(),
+                    ip: "10.0.0.3".parse().unwrap(),
+                    cidr: Some("laptops".to_string()),
+                    public_key: "cGhvbmU=".to_string(),
+                    enabled: false,
+                    admin: false,
+                    redeemed: true,
+                    created_at: Some(1_600_000_000),
+                    expires_at: None,
+                    last_handshake: None,
+                    endpoint: Some("203.0.113.1:51820".parse().unwrap()),
+                },
+            ]
+        );
+    }
+}
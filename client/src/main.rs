@@ -2,30 +2,132 @@ use colored::*;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
 use hostsfile::HostsBuilder;
 use indoc::printdoc;
+use ipnetwork::IpNetwork;
 use shared::{
-    interface_config::InterfaceConfig, prompts, Association, AssociationContents, Cidr, CidrTree,
-    EndpointContents, Interface, IoErrorContext, Peer, RedeemContents, State, CLIENT_CONFIG_PATH,
-    REDEEM_TRANSITION_WAIT,
+    client_config_path, compare_versions,
+    interface_config::{InterfaceConfig, WgQuickPeer},
+    prompts, Association, AssociationContents, Cidr, CidrTree, EndpointContents, Interface,
+    IoErrorContext, Peer, PeerNamePattern, PortRange, RedeemContents, State,
+    MIN_CLIENT_VERSION_HEADER, PEER_NAME_PATTERN_HEADER, REDEEM_TRANSITION_WAIT,
 };
 use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
     fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    num::NonZeroUsize,
+    os::unix::fs::OpenOptionsExt,
     path::{Path, PathBuf},
+    str::FromStr,
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use structopt::{clap::Shell, StructOpt};
+use wgctrl::{
+    AllowedIp, DeviceConfigBuilder, DeviceInfo, Key, KeyPair, PeerConfig, PeerConfigBuilder,
+    PeerInfo,
 };
-use structopt::StructOpt;
-use wgctrl::{DeviceConfigBuilder, DeviceInfo, PeerConfigBuilder, PeerInfo};
 
+mod bound_http;
 mod data_store;
+mod events;
+mod explain;
+mod health;
+mod help;
+mod http_server;
+mod interface_lock;
+mod interval_signals;
+mod inventory;
+mod man;
+mod metrics;
+mod migrate;
+mod network_monitor;
+mod output;
+mod peer_diff;
+mod probe;
+mod reachability;
+mod routes;
+mod shutdown;
+mod srv;
+mod stats_csv;
+mod trusted_keys;
 mod util;
+mod validate;
 
+use bound_http::HttpBind;
 use data_store::DataStore;
+use events::{EventLog, Transition};
+use health::HealthServer;
+use interface_lock::{FetchSlot, InterfaceLock};
+use inventory::Inventory;
+use network_monitor::NetworkMonitor;
+use output::{InterfaceState, OutputFormat, ShowOutput};
+use probe::Probe;
+use reachability::Reachability;
 use shared::{wg, Error};
-use util::{http_delete, http_get, http_post, http_put, human_duration, human_size};
+use stats_csv::StatsCsv;
+use trusted_keys::TrustedKeys;
+use util::{
+    abbreviate_key, choose_endpoint, http_delete, http_get, http_get_with_header, http_post,
+    http_post_idempotent, http_put, human_duration, human_size, PhaseTimer,
+};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How many times creating a peer is attempted when the connection to the server fails.
+const ADD_PEER_ATTEMPTS: u64 = 3;
+
+/// The longest the daemon waits to retry after repeatedly failing to reach the server.
+const MAX_FETCH_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// How long after its last handshake "show" stops flagging a peer as only recently offline.
+const STALE_HANDSHAKE_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait for a handshake with the server using a newly redeemed key before fetching
+/// anyway. The server only switches to the new key `REDEEM_TRANSITION_WAIT` after redeeming it,
+/// so this has to be well beyond that.
+const REDEEM_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "innernet", about)]
 struct Opt {
+    /// Describe what the command will do, including which server endpoints it will
+    /// contact, and ask for confirmation before running it.
+    #[structopt(long, global = true)]
+    explain: bool,
+
+    /// Send requests to the server from this network interface, e.g. the innernet interface
+    /// itself, so that they go through the tunnel on hosts with several routes to it.
+    #[structopt(long, global = true, conflicts_with = "bind-address")]
+    bind_interface: Option<String>,
+
+    /// Send requests to the server from this local IP address.
+    #[structopt(long, global = true)]
+    bind_address: Option<IpAddr>,
+
+    /// When to color the output: "auto" only colors it when printing to a terminal,
+    /// "always" also colors it when piped (e.g. into "less -R").
+    #[structopt(
+        long,
+        global = true,
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"]
+    )]
+    color: ColorChoice,
+
+    /// After an admin command changes the network (e.g. add-peer or add-association), fetch
+    /// the interface's latest state, so that this host reflects the change right away.
+    #[structopt(long, global = true)]
+    auto_fetch: bool,
+
+    /// Read and write interface configs, cached peers, and other state in this directory
+    /// instead of /etc/innernet and /var/lib/innernet, e.g. to run a separate profile. Root
+    /// isn't required then, as long as the user can manage WireGuard interfaces.
+    #[structopt(long, global = true)]
+    config_dir: Option<PathBuf>,
+
     #[structopt(subcommand)]
     command: Option<Command>,
 }
@@ -34,16 +136,41 @@ struct Opt {
 enum Command {
     /// Install a new innernet config.
     #[structopt(alias = "redeem")]
-    Install { config: PathBuf },
+    Install {
+        config: PathBuf,
+
+        /// If an interface with the chosen name is already installed, tear it down and remove
+        /// its config and cached peers first, e.g. to recover from a broken install.
+        #[structopt(long)]
+        reinstall: bool,
+
+        /// Only redeem the invitation and write the config, leaving the interface to be
+        /// managed with other tools (e.g. wg-quick). It has to be brought up with the
+        /// invitation's key first, and its wg-quick config is printed at the end.
+        #[structopt(long, alias = "peer-config-only")]
+        no_interface: bool,
+    },
+
+    /// Install several invitations in one go, each as an interface named after its network.
+    ///
+    /// A name that's already installed (or taken by an earlier invitation) gets a numbered
+    /// suffix, e.g. "evilcorp-2". One invitation failing doesn't stop the rest from being
+    /// installed, and a summary of each is printed at the end.
+    InstallAll {
+        #[structopt(required = true)]
+        configs: Vec<PathBuf>,
+    },
+
+    /// Check an interface config or invitation file for problems, e.g. invalid keys or an
+    /// internal endpoint outside of the network, without changing anything or contacting the
+    /// server. Fails if any check does.
+    Validate { config: PathBuf },
 
     /// Enumerate all innernet connections.
     #[structopt(alias = "list")]
     Show {
-        #[structopt(short, long)]
-        short: bool,
-
-        #[structopt(short, long)]
-        tree: bool,
+        #[structopt(flatten)]
+        opts: ShowOpts,
 
         interface: Option<Interface>,
     },
@@ -55,25 +182,231 @@ enum Command {
         #[structopt(short, long)]
         daemon: bool,
 
-        /// Keep fetching the latest peer list at the specified interval in
-        /// seconds. Valid only in daemon mode.
-        #[structopt(long, default_value = "60")]
-        interval: u64,
+        /// Keep fetching the latest peer list at the specified interval, e.g. "30s" or "5m"
+        /// (a bare number is in seconds). Valid only in daemon mode. While running, SIGUSR1
+        /// halves the interval and SIGUSR2 doubles it (within 5 seconds and an hour).
+        #[structopt(long, default_value = "60", parse(try_from_str = util::parse_interval))]
+        interval: Duration,
+
+        /// Fetch immediately, and re-assert the server's endpoint, whenever the system's
+        /// network links or routes change (e.g. when roaming between networks). Valid only
+        /// in daemon mode, and currently only supported on Linux.
+        #[structopt(long, requires = "daemon")]
+        watch_network: bool,
+
+        /// Serve a health check on this address (e.g. "127.0.0.1:8099") for liveness probes,
+        /// answering 200 if the last fetch succeeded recently and the server peer is
+        /// handshaking, and 503 otherwise. Valid only in daemon mode.
+        #[structopt(long, requires = "daemon")]
+        health_listen: Option<SocketAddr>,
+
+        /// Append every peer's traffic and handshake age to this CSV file after each fetch.
+        /// Once the file passes 16 MiB it's moved to "<PATH>.1" and a new one is started.
+        /// Valid only in daemon mode.
+        #[structopt(long, requires = "daemon")]
+        stats_csv: Option<PathBuf>,
+
+        /// Serve every peer's traffic and handshake age as Prometheus gauges on this address
+        /// (e.g. "127.0.0.1:9586"), at "/metrics". Valid only in daemon mode.
+        #[structopt(long, requires = "daemon")]
+        metrics_listen: Option<SocketAddr>,
+
+        /// Like --metrics-listen, but on this port of every IPv4 address.
+        #[structopt(
+            long,
+            value_name = "PORT",
+            requires = "daemon",
+            conflicts_with = "metrics-listen"
+        )]
+        metrics_port: Option<u16>,
+
+        /// How many daemons on this host (e.g. one per joined network) may fetch at once, so
+        /// that they don't overwhelm a shared server when their fetches line up. Every daemon
+        /// on the host has to be given the same number, since each only takes turns with those
+        /// counting the same slots. Valid only in daemon mode.
+        #[structopt(long, default_value = "4")]
+        max_concurrent_fetches: NonZeroUsize,
+
+        /// Run with this niceness, from -20 (highest priority) to 19 (lowest), so that
+        /// fetches on large networks don't contend with other workloads. Valid only in
+        /// daemon mode.
+        #[structopt(long, requires = "daemon", allow_hyphen_values = true)]
+        nice: Option<i32>,
+
+        /// Only use the disk when nothing else needs it (the idle I/O scheduling class).
+        /// Valid only in daemon mode, and currently only supported on Linux.
+        #[structopt(long, requires = "daemon")]
+        ionice: bool,
+
+        /// Take the interface down when stopped by SIGTERM or SIGINT (e.g. by systemd), rather
+        /// than leaving it up. Valid only in daemon mode.
+        #[structopt(long, requires = "daemon")]
+        down_on_exit: bool,
+
+        #[structopt(flatten)]
+        fetch_opts: FetchOpts,
 
         interface: Interface,
     },
 
     /// Fetch and update your local interface with the latest peer list.
-    Fetch { interface: Interface },
+    Fetch {
+        interface: Interface,
+
+        /// Rebuild the interface's entire peer list from the server's state instead of
+        /// only applying changes, in case the device has drifted out of sync.
+        #[structopt(long)]
+        repair: bool,
+
+        /// Show the peer changes and ask for confirmation before applying them, leaving both
+        /// the interface and the cached peers untouched if declined.
+        #[structopt(short, long)]
+        interactive: bool,
+
+        /// Only show the peer changes the server would push, without applying them to the
+        /// interface, /etc/hosts, or the cached peers. The server's endpoint isn't updated
+        /// either.
+        #[structopt(long, conflicts_with = "interactive")]
+        dry_run: bool,
+
+        #[structopt(flatten)]
+        fetch_opts: FetchOpts,
+    },
+
+    /// Rewrite the interface's /etc/hosts entries from the last fetched peer list, without
+    /// contacting the server or touching the WireGuard device.
+    UpdateHosts {
+        interface: Interface,
+
+        /// Move the entries to a section tagged with this instead (e.g. a shorter marker for
+        /// strict hosts file validators), which is saved for later updates.
+        #[structopt(long)]
+        tag: Option<String>,
+    },
+
+    /// Compare the cached peer lists of two interfaces, e.g. to verify a migration between
+    /// servers, listing the peers (by name) only in one, only in the other, and in both.
+    Diff {
+        interface_a: Interface,
+        interface_b: Interface,
+    },
+
+    /// List when peers connected and disconnected, as recorded by "innernet up --daemon".
+    Events { interface: Interface },
+
+    /// Print a record of every peer for syncing into a CMDB or asset database: its name, ip,
+    /// cidr, public_key, enabled, admin, redeemed, created_at, expires_at, last_handshake,
+    /// and endpoint. Unlike "show --json", the schema is stable, with a schema_version that's
+    /// bumped if a field ever has to change.
+    Inventory {
+        interface: Interface,
+
+        /// json, yaml, or toml.
+        #[structopt(long, default_value = "json")]
+        format: OutputFormat,
+    },
+
+    /// List the routes the kernel sends through the interface, and which CIDRs they're for,
+    /// flagging duplicate routes and peers' allowed IPs that aren't routed.
+    Routes { interface: Interface },
+
+    /// Mark a peer as intentionally offline (e.g. decommissioned but not yet removed), so that
+    /// "show" lists it separately instead of among the peers that should be connected. The mark
+    /// is only kept on this host, in the cached peers, so it can't be changed while another
+    /// process (e.g. "innernet up --daemon") is managing the interface.
+    ExpectOffline {
+        interface: Interface,
+
+        /// The peer's name.
+        peer: String,
+
+        /// Remove the mark instead.
+        #[structopt(long)]
+        unset: bool,
+    },
+
+    /// Let another peer have an IP that a peer moved away from (e.g. with "innernet
+    /// reassign-peer-ip") or that belonged to a peer the server no longer lists (e.g. after
+    /// "innernet delete-peer"), once you've confirmed that was intended. Until then, fetching
+    /// refuses any other peer at that IP, so that a compromised server can't take over a peer's
+    /// IP by moving or deleting the peer first.
+    ReleaseIp {
+        interface: Interface,
+
+        /// The IP the peer moved away from or was deleted from.
+        #[structopt(required_unless = "all-deleted")]
+        ip: Option<IpAddr>,
+
+        /// Release the IPs of every cached peer the server no longer lists instead, e.g. after
+        /// "innernet reap-expired" deleted a batch of guests.
+        #[structopt(long, conflicts_with = "ip")]
+        all_deleted: bool,
+    },
+
+    /// Print the interface's WireGuard public key, e.g. for firewall allowlists or scripts.
+    PublicKey { interface: Interface },
+
+    /// Print a short summary of this peer on the interface: its IP, CIDR, public key, listen
+    /// port, and the server it's connected to.
+    #[structopt(alias = "whoami")]
+    Status { interface: Interface },
+
+    /// Print a standalone wg-quick config for this peer, with the server as its only peer, e.g.
+    /// to take this membership to a device that can only run wg-quick. Includes the private key.
+    ExportWgQuick {
+        interface: Interface,
+
+        /// Include every peer the interface currently has instead of only the server, e.g. for
+        /// a device that can't fetch. The peers aren't kept up to date afterwards.
+        #[structopt(long)]
+        all_peers: bool,
+
+        /// Write the config to this file (only readable by its owner) instead.
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Measure the round-trip time of requests to the server's API through the tunnel, e.g. to
+    /// tell a slow server apart from a slow tunnel when fetching takes long.
+    Ping {
+        interface: Interface,
+
+        /// How many requests to send, one per second.
+        #[structopt(short, long, default_value = "5")]
+        count: u32,
+    },
 
     /// Bring down the interface (equivalent to "wg-quick down [interface]")
     Down { interface: Interface },
 
     /// Add a new peer.
-    AddPeer { interface: Interface },
+    AddPeer {
+        interface: Interface,
+
+        #[structopt(flatten)]
+        opts: AddPeerOpts,
+    },
+
+    /// Add a new CIDR. Its name, range, and parent are asked for unless given as options, and
+    /// it's created without asking for confirmation when all three are.
+    AddCidr {
+        interface: Interface,
 
-    /// Add a new CIDR.
-    AddCidr { interface: Interface },
+        /// The new CIDR's name.
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// The new CIDR's range, e.g. "10.42.16.0/20".
+        #[structopt(long)]
+        cidr: Option<IpNetwork>,
+
+        /// The name of the CIDR to create it in.
+        #[structopt(long)]
+        parent: Option<String>,
+    },
+
+    /// Delete a CIDR without any peers, child CIDRs, or associations.
+    DeleteCidr { interface: Interface },
 
     /// Disable an enabled peer.
     DisablePeer { interface: Interface },
@@ -81,15 +414,105 @@ enum Command {
     /// Enable a disabled peer.
     EnablePeer { interface: Interface },
 
+    /// Disable every enabled peer in a CIDR at once, e.g. when decommissioning a site. Peers
+    /// in its child CIDRs, the server, and this peer are left alone.
+    DisableCidr { interface: Interface },
+
+    /// Delete a peer for good, unlike disabling it, so that its IP can be reused.
+    DeletePeer { interface: Interface },
+
+    /// Move a peer to another IP in its CIDR, e.g. when its IP clashes with other
+    /// infrastructure, without inviting it again.
+    ReassignPeerIp { interface: Interface },
+
+    /// Rename a peer, keeping its IP and keys.
+    RenamePeer { interface: Interface },
+
+    /// Delete every peer whose expiry has passed for good, e.g. guests or CI runners added with
+    /// an expiry, freeing up their IPs (on other hosts, once they run "innernet release-ip
+    /// --all-deleted"). Doesn't ask for confirmation, so it can be run from a timer.
+    ReapExpired { interface: Interface },
+
+    /// List which peers have redeemed their invitations, and which are still pending.
+    AuditRedemptions {
+        interface: Interface,
+
+        /// Flag pending invitations older than this many days as stale.
+        #[structopt(long, default_value = "7")]
+        stale_after_days: u64,
+    },
+
+    /// Flag a CIDR as infra, making its peers visible to every other peer regardless of
+    /// associations (e.g. for the innernet server or other shared infrastructure).
+    SetInfraCidr {
+        interface: Interface,
+
+        /// Unflag an infra CIDR instead.
+        #[structopt(long)]
+        unset: bool,
+    },
+
+    /// Allow a peer to override the endpoint other peers reach it at with "override-endpoint",
+    /// e.g. for peers behind a port forward, instead of using the one the server sees.
+    AllowEndpointOverride {
+        interface: Interface,
+
+        /// Stop the peer from overriding its endpoint instead.
+        #[structopt(long)]
+        disallow: bool,
+    },
+
     /// Add an association between CIDRs.
-    AddAssociation { interface: Interface },
+    AddAssociation {
+        interface: Interface,
+
+        /// Only print which peers would newly be able to reach each other, without adding
+        /// the association.
+        #[structopt(long)]
+        preview: bool,
+    },
 
     /// Delete an association between CIDRs.
-    DeleteAssociation { interface: Interface },
+    DeleteAssociation {
+        interface: Interface,
+
+        /// Only print which peers would no longer be able to reach each other, without
+        /// deleting the association.
+        #[structopt(long)]
+        preview: bool,
+    },
+
+    /// List the network's CIDRs (admin only), without the peers "show --tree" mixes in.
+    ListCidrs {
+        interface: Interface,
+
+        /// Print the CIDRs as a tree of their hierarchy.
+        #[structopt(short, long)]
+        tree: bool,
+
+        /// Print the CIDRs as JSON instead, e.g. for scripts.
+        #[structopt(long, conflicts_with = "tree")]
+        json: bool,
+    },
 
     /// List existing assocations between CIDRs.
     ListAssociations { interface: Interface },
 
+    /// Recreate a network's CIDRs, peers, and associations on another server (admin only on
+    /// both), e.g. to migrate to a new server. Peers keep their IPs and keys.
+    MigratePeers {
+        from_interface: Interface,
+        to_interface: Interface,
+    },
+
+    /// Print which CIDRs' peers can reach which others, given the CIDRs' associations.
+    ReachabilityMatrix { interface: Interface },
+
+    /// Check that every peer from the last fetch is configured on the interface with its IP,
+    /// and, for admins, that each of them is also told about this peer, so that connections
+    /// work both ways.
+    Verify { interface: Interface },
+
     /// Set the local listen port.
     SetListenPort {
         interface: Interface,
@@ -97,6 +520,11 @@ enum Command {
         /// Unset the local listen port to use a randomized port.
         #[structopt(short, long)]
         unset: bool,
+
+        /// Pick a free port from an inclusive range (e.g. "51820-51830"), such as a band of
+        /// ports opened in a firewall.
+        #[structopt(long, conflicts_with = "unset")]
+        range: Option<PortRange>,
     },
 
     /// Override your external endpoint that the server sends to other peers.
@@ -106,352 +534,2506 @@ enum Command {
         /// Unset an existing override to use the automatic endpoint discovery.
         #[structopt(short, long)]
         unset: bool,
+
+        /// Set the endpoint to this address without any prompts, e.g. on a headless server
+        /// with a known static IP. The interface needs a listen port already.
+        #[structopt(long, conflicts_with = "unset")]
+        endpoint: Option<SocketAddr>,
+
+        /// Additional endpoint to advertise for peers that can't reach the main one,
+        /// e.g. a VPN-internal address. Can be given multiple times, in order of preference.
+        #[structopt(long = "fallback", conflicts_with = "unset")]
+        fallbacks: Vec<SocketAddr>,
     },
-}
 
-/// Application-level error.
-#[derive(Debug, Clone)]
-pub(crate) struct ClientError(String);
+    /// Print a man page describing all commands and options, for packaging.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Man,
 
-impl fmt::Display for ClientError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+    /// Print a shell completion script, e.g. "innernet completions zsh >
+    /// /usr/share/zsh/site-functions/_innernet". Interface names aren't completed.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Completions {
+        #[structopt(possible_values = &["bash", "zsh", "fish", "powershell"])]
+        shell: Shell,
+    },
 }
 
-impl std::error::Error for ClientError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+impl Command {
+    /// The interface through which an admin command changes the network, if it does.
+    fn admin_mutation_interface(&self) -> Option<&Interface> {
+        match self {
+            Command::AddPeer { interface, .. }
+            | Command::AddCidr { interface, .. }
+            | Command::DeleteCidr { interface }
+            | Command::DisablePeer { interface }
+            | Command::EnablePeer { interface }
+            | Command::DisableCidr { interface }
+            | Command::RenamePeer { interface }
+            | Command::DeletePeer { interface }
+            | Command::ReassignPeerIp { interface }
+            | Command::ReapExpired { interface }
+            | Command::AddAssociation {
+                interface,
+                preview: false,
+            }
+            | Command::DeleteAssociation {
+                interface,
+                preview: false,
+            }
+            | Command::SetInfraCidr { interface, .. }
+            | Command::AllowEndpointOverride { interface, .. }
+            | Command::MigratePeers {
+                to_interface: interface,
+                ..
+            } => Some(interface),
+            _ => None,
+        }
     }
 }
 
-fn update_hosts_file(interface: &str, peers: &Vec<Peer>) -> Result<(), Error> {
-    println!(
-        "{} updating {} with the latest peers.",
-        "[*]".dimmed(),
-        "/etc/hosts".yellow()
-    );
+// Options for displaying interfaces and their peers.
+#[derive(Debug, Default, StructOpt)]
+struct ShowOpts {
+    #[structopt(short, long)]
+    short: bool,
 
-    let mut hosts_builder = HostsBuilder::new(format!("innernet {}", interface));
-    for peer in peers {
-        hosts_builder.add_hostname(
-            peer.contents.ip,
-            &format!("{}.{}.wg", peer.contents.name, interface),
-        );
-    }
-    hosts_builder.write()?;
+    #[structopt(short, long)]
+    tree: bool,
 
-    Ok(())
-}
+    /// Only show peers in the CIDRs flagged as infra, including the innernet server.
+    #[structopt(long)]
+    infra_only: bool,
 
-fn install(invite: &Path) -> Result<(), Error> {
-    let theme = ColorfulTheme::default();
-    shared::ensure_dirs_exist(&[*CLIENT_CONFIG_PATH])?;
-    let mut config = InterfaceConfig::from_file(invite)?;
+    /// Print the interfaces, peers, and CIDRs in a machine-readable format
+    /// instead (json, yaml, or toml).
+    #[structopt(long)]
+    format: Option<OutputFormat>,
 
-    let iface = Input::with_theme(&theme)
-        .with_prompt("Interface name")
-        .default(config.interface.network_name.clone())
-        .interact()?;
+    /// Shorthand for "--format json".
+    #[structopt(long, conflicts_with = "format")]
+    json: bool,
 
-    let target_conf = CLIENT_CONFIG_PATH.join(&iface).with_extension("conf");
-    if target_conf.exists() {
-        return Err("An interface with this name already exists in innernet.".into());
-    }
-
-    println!("{} bringing up the interface.", "[*]".dimmed());
-    wg::up(
-        &iface,
-        &config.interface.private_key,
-        config.interface.address,
-        None,
-        Some((
-            &config.server.public_key,
-            config.server.internal_endpoint.ip(),
-            config.server.external_endpoint,
-        )),
-    )?;
+    /// Only list the first N peers of each interface, which are the most recently
+    /// active ones unless --sort says otherwise. 0 lists all of them.
+    #[structopt(long, default_value = "0")]
+    limit: usize,
 
-    println!("{} Generating new keypair.", "[*]".dimmed());
-    let keypair = wgctrl::KeyPair::generate();
+    /// Order the listed peers by "name", "ip", "handshake" (most recent first), or
+    /// "transfer" (most bytes sent and received first). Ties are ordered by IP.
+    #[structopt(long, default_value = "handshake")]
+    sort: PeerSort,
 
-    println!(
-        "{} Registering keypair with server (at {}).",
-        "[*]".dimmed(),
-        &config.server.internal_endpoint
-    );
-    http_post(
-        &config.server.internal_endpoint,
-        "/user/redeem",
-        RedeemContents {
-            public_key: keypair.public.to_base64(),
-        },
-    )?;
+    /// List the peers in the opposite order of --sort.
+    #[structopt(long)]
+    reverse: bool,
 
-    config.interface.private_key = keypair.private.to_base64();
-    config.write_to_path(&target_conf, false, Some(0o600))?;
-    println!(
-        "{} New keypair registered. Copied config to {}.\n",
-        "[*]".dimmed(),
-        target_conf.to_string_lossy().yellow()
-    );
-    println!(
-        "{} Waiting for server's WireGuard interface to transition to new key.",
-        "[*]".dimmed(),
-    );
-    thread::sleep(*REDEEM_TRANSITION_WAIT);
+    /// Check whether each listed peer is reachable through the tunnel right now, with an
+    /// ICMP ping ("icmp") or a TCP connection to a port (e.g. "tcp:22").
+    #[structopt(long, conflicts_with_all = &["tree", "format", "json"])]
+    probe: Option<Probe>,
 
-    DeviceConfigBuilder::new()
-        .set_private_key(keypair.private)
-        .apply(&iface)?;
+    /// Print exact byte counts and handshake ages in seconds instead of rounding them, e.g.
+    /// to compare transfer between runs.
+    #[structopt(long)]
+    raw_bytes: bool,
+}
 
-    fetch(&iface, false)?;
+/// The order `innernet show` lists peers in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum PeerSort {
+    Name,
+    Ip,
+    #[default]
+    Handshake,
+    Transfer,
+}
 
-    if Confirm::with_theme(&theme)
-        .with_prompt(&format!(
-            "Delete invitation file \"{}\" now? (It's no longer needed)",
-            invite.to_string_lossy().yellow()
-        ))
-        .default(true)
-        .interact()?
-    {
-        std::fs::remove_file(invite).with_path(invite)?;
+impl FromStr for PeerSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "ip" => Ok(Self::Ip),
+            "handshake" => Ok(Self::Handshake),
+            "transfer" => Ok(Self::Transfer),
+            _ => Err(format!(
+                "unknown sort field \"{}\" (expected name, ip, handshake, or transfer)",
+                s
+            )),
+        }
+    }
+}
+
+impl PeerSort {
+    fn compare(self, (a, a_info): (&Peer, &PeerInfo), (b, b_info): (&Peer, &PeerInfo)) -> Ordering {
+        let transfer = |info: &PeerInfo| info.stats.rx_bytes + info.stats.tx_bytes;
+        match self {
+            Self::Name => a.name.cmp(&b.name),
+            Self::Ip => Ordering::Equal,
+            Self::Handshake => b_info
+                .stats
+                .last_handshake_time
+                .cmp(&a_info.stats.last_handshake_time),
+            Self::Transfer => transfer(b_info).cmp(&transfer(a_info)),
+        }
+        .then(a.ip.cmp(&b.ip))
     }
+}
 
-    printdoc!(
-        "
-        {star} Done!
+// Options for add-peer, which creates the peer without any prompts when given a name. (Not a
+// doc comment, since structopt would use it as the about text of add-peer.)
+#[derive(Debug, StructOpt)]
+struct AddPeerOpts {
+    /// Bundle the peers the new peer can reach into its invitation, so that it has
+    /// connectivity even if the server can't be reached right after installing.
+    #[structopt(long)]
+    offline_bundle: bool,
 
-            {interface} has been {installed}.
+    /// Create the peer with this name without any prompts, e.g. from a provisioning script.
+    /// Needs --cidr, --save-config, and either --ip or --auto-ip, and prints the invitation's
+    /// path last.
+    #[structopt(long, requires_all = &["cidr", "save-config"])]
+    name: Option<String>,
 
-            It's recommended to now keep the interface automatically refreshing via systemd:
+    /// The name of the CIDR to create the peer in.
+    #[structopt(long, requires = "name")]
+    cidr: Option<String>,
 
-                {systemctl_enable}{interface}
+    /// The new peer's IP.
+    #[structopt(long, requires = "name", conflicts_with = "auto-ip")]
+    ip: Option<IpAddr>,
 
-            See the documentation for more detailed instruction on managing your interface
-            and your network.
+    /// Give the new peer the first free IP in its CIDR.
+    #[structopt(long, requires = "name")]
+    auto_ip: bool,
 
-    ",
-        star = "[*]".dimmed(),
-        interface = iface.yellow(),
-        installed = "installed".green(),
-        systemctl_enable = "systemctl enable --now innernet@".yellow(),
-    );
+    /// Write the invitation to this path instead of asking where to.
+    #[structopt(long)]
+    save_config: Option<PathBuf>,
 
-    Ok(())
+    /// Refuse names that don't entirely match this regex, e.g. "[a-z]+-[a-z]+-[a-z0-9-]+".
+    /// Overrides the interface config's `peer-name-pattern`. Both only apply if the server
+    /// doesn't advertise a naming convention of its own, which always takes precedence.
+    #[structopt(long, value_name = "REGEX")]
+    peer_name_regex: Option<PeerNamePattern>,
 }
 
-fn up(interface: &str, loop_interval: Option<Duration>) -> Result<(), Error> {
-    loop {
-        fetch(interface, true)?;
-        match loop_interval {
-            Some(interval) => thread::sleep(interval),
-            None => break,
-        }
-    }
+// Options for the commands that fetch the latest state from the server. (Not a doc comment,
+// since structopt would use it as the about text of every command it's flattened into.)
+#[derive(Debug, Default, StructOpt)]
+struct FetchOpts {
+    /// Refuse to update the interface if this client is older than the minimum version
+    /// the server supports, instead of only warning about it.
+    #[structopt(long)]
+    enforce_version: bool,
 
-    Ok(())
-}
+    /// Don't log peers as modified when only their endpoint changed, as happens constantly
+    /// with NAT roaming. The new endpoints are still applied.
+    #[structopt(long)]
+    ignore_endpoint_changes: bool,
 
-fn fetch(interface: &str, bring_up_interface: bool) -> Result<(), Error> {
-    let config = InterfaceConfig::from_interface(interface)?;
-    let interface_up = if let Ok(interfaces) = DeviceInfo::enumerate() {
-        interfaces.iter().any(|name| name == interface)
-    } else {
-        false
-    };
+    /// Print how long each phase of the fetch took, to find out what's making it slow.
+    #[structopt(long)]
+    timing: bool,
 
-    if !interface_up {
-        if !bring_up_interface {
-            return Err(format!(
-                "Interface is not up. Use 'innernet up {}' instead",
-                interface
-            )
-            .into());
-        }
+    /// Fail if /etc/hosts can't be updated, for hosts that rely on the peers' hostnames,
+    /// instead of only warning about it.
+    #[structopt(long)]
+    strict_hosts: bool,
 
-        println!("{} bringing up the interface.", "[*]".dimmed());
-        wg::up(
-            interface,
-            &config.interface.private_key,
-            config.interface.address,
-            config.interface.listen_port,
-            Some((
-                &config.server.public_key,
-                config.server.internal_endpoint.ip(),
-                config.server.external_endpoint,
-            )),
-        )?
-    }
+    /// Don't write the peers' hostnames to /etc/hosts, e.g. when it's managed by another
+    /// tool. Can also be set for good with `no-host-updates = true` in the interface config.
+    #[structopt(long, conflicts_with = "strict-hosts")]
+    no_host_updates: bool,
 
-    println!("{} fetching state from server.", "[*]".dimmed());
-    let mut store = DataStore::open_or_create(&interface)?;
-    let State { peers, cidrs } = http_get(&config.server.internal_endpoint, "/user/state")?;
+    /// End the peers' hostnames in this domain instead of "wg" (e.g. "laptop.evilcorp.internal"),
+    /// saving it as `hosts-suffix` in the interface config so that later updates (e.g.
+    /// `update-hosts`) keep it. Unused if the config has a `hosts-template`.
+    #[structopt(long, value_name = "DOMAIN", parse(try_from_str = util::parse_hosts_suffix))]
+    hosts_suffix: Option<String>,
 
-    let device_info = DeviceInfo::get_by_name(&interface)?;
-    let interface_public_key = device_info
-        .public_key
-        .as_ref()
-        .map(|k| k.to_base64())
-        .unwrap_or_default();
-    let existing_peers = &device_info.peers;
+    /// Switch to a new endpoint advertised by the server without asking, e.g. when running
+    /// as a daemon. Otherwise, the new endpoint is only switched to after confirming it.
+    #[structopt(long)]
+    accept_server_endpoint: bool,
 
-    let peer_configs_diff = peers
-        .iter()
-        .filter(|peer| !peer.is_disabled && peer.public_key != interface_public_key)
-        .filter_map(|peer| {
-            let existing_peer = existing_peers
-                .iter()
-                .find(|p| p.config.public_key.to_base64() == peer.public_key);
+    /// Refuse to add any peer whose public key isn't listed in this file (one base64 key per
+    /// line, optionally followed by a description), as a defense against a compromised server
+    /// adding rogue peers. The server itself is always trusted.
+    #[structopt(long, value_name = "FILE")]
+    trusted_keys: Option<PathBuf>,
 
-            let change = match existing_peer {
-                Some(existing_peer) => peer
-                    .diff(&existing_peer.config)
-                    .map(|diff| (PeerConfigBuilder::from(&diff), peer, "modified".normal())),
-                None => Some((PeerConfigBuilder::from(peer), peer, "added".green())),
-            };
+    /// Only show the changes the server would push, without applying them. Set by
+    /// `fetch --dry-run` rather than a flag of its own, since `up` always applies them.
+    #[structopt(skip)]
+    dry_run: bool,
 
-            change.map(|(builder, peer, text)| {
-                println!(
-                    "    peer {} ({}...) was {}.",
-                    peer.name.yellow(),
-                    &peer.public_key[..10].dimmed(),
-                    text
-                );
-                builder
-            })
-        })
-        .collect::<Vec<PeerConfigBuilder>>();
+    /// Rebuild the entire peer list rather than applying changes. Set by `fetch --repair`.
+    #[structopt(skip)]
+    repair: bool,
 
-    let mut device_config_builder = DeviceConfigBuilder::new();
+    /// Ask for confirmation before applying the changes. Set by `fetch --interactive`.
+    #[structopt(skip)]
+    interactive: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("unknown color choice \"{}\"", s)),
+        }
+    }
+}
+
+impl ColorChoice {
+    fn apply(self) {
+        match self {
+            // colored already checks whether stdout is a terminal (and NO_COLOR and friends).
+            Self::Auto => colored::control::unset_override(),
+            Self::Always => colored::control::set_override(true),
+            Self::Never => colored::control::set_override(false),
+        }
+    }
+}
+
+/// Application-level error.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientError(String);
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+/// The hostname peers get in /etc/hosts unless the interface config sets a template.
+const DEFAULT_HOSTS_SUFFIX: &str = "wg";
+
+/// The template for the interface's peers' hostnames, ending in `suffix` (or the configured
+/// suffix) unless the interface config has a template of its own.
+fn hosts_template(config: &InterfaceConfig) -> String {
+    config.interface.hosts_template.clone().unwrap_or_else(|| {
+        let suffix = config
+            .interface
+            .hosts_suffix
+            .as_deref()
+            .unwrap_or(DEFAULT_HOSTS_SUFFIX);
+        format!("{{name}}.{{interface}}.{}", suffix)
+    })
+}
+
+/// Render a peer's /etc/hosts name from a template. IP addresses have their dots and colons
+/// replaced by dashes so that they fit into a single label.
+fn render_hostname(template: &str, interface: &str, peer: &Peer, cidrs: &[Cidr]) -> String {
+    let cidr = cidrs
+        .iter()
+        .find(|c| c.id == peer.cidr_id)
+        .map_or("", |c| &c.name[..]);
+    template
+        .replace("{name}", &peer.name)
+        .replace("{interface}", interface)
+        .replace("{cidr}", cidr)
+        .replace("{ip}", &peer.ip.to_string().replace(['.', ':'], "-"))
+}
+
+fn is_valid_fqdn(hostname: &str) -> bool {
+    hostname.len() <= 253 && hostname.split('.').all(prompts::is_valid_hostname)
+}
+
+/// The tag of the interface's section in /etc/hosts, unless the interface config sets one.
+fn default_hosts_tag(interface: &str) -> String {
+    format!("innernet {}", interface)
+}
+
+fn update_hosts_file(
+    interface: &str,
+    template: &str,
+    hosts_tag: Option<&str>,
+    peers: &[Peer],
+    cidrs: &[Cidr],
+) -> Result<(), Error> {
+    println!(
+        "{} updating {} with the latest peers.",
+        "[*]".dimmed(),
+        "/etc/hosts".yellow()
+    );
+
+    let hostnames = peers
+        .iter()
+        .filter_map(|peer| {
+            let hostname = render_hostname(template, interface, peer, cidrs);
+            if !is_valid_fqdn(&hostname) {
+                println!(
+                    "{}: skipping {}, since \"{}\" isn't a valid hostname.",
+                    "warning".bold().yellow(),
+                    peer.name.yellow(),
+                    hostname
+                );
+                return None;
+            }
+            Some((hostname, peer))
+        })
+        .collect::<Vec<_>>();
+    for (hostname, conflicting) in util::hostname_conflicts(&hostnames) {
+        println!(
+            "{}: {} all have the hostname {}, so only {} gets it.",
+            "warning".bold().yellow(),
+            conflicting
+                .iter()
+                .map(|peer| format!("{} ({})", peer.name.yellow(), peer.ip))
+                .collect::<Vec<_>>()
+                .join(", "),
+            hostname,
+            conflicting[0].name.yellow()
+        );
+    }
+
+    // On dual-stack networks, a hostname can be shared by an IPv4 and an IPv6 peer (e.g. a
+    // host's two peers, with a template that renders the same name for both), so that it
+    // resolves to either family. Within a family, the first peer keeps it.
+    let mut hosts_builder = HostsBuilder::new(
+        hosts_tag
+            .map(str::to_string)
+            .unwrap_or_else(|| default_hosts_tag(interface)),
+    );
+    for (hostname, peer) in &hostnames {
+        hosts_builder.add_hostname_per_family(peer.ip, hostname);
+    }
+    hosts_builder.write()?;
+
+    Ok(())
+}
+
+fn update_hosts(interface: &str, tag: Option<String>) -> Result<(), Error> {
+    let store = DataStore::open(interface).map_err(|_| {
+        format!(
+            "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+            interface, interface
+        )
+    })?;
+    let mut config = InterfaceConfig::from_interface(interface)?;
+    let peers = store
+        .peers()
+        .iter()
+        .filter(|peer| !peer.is_disabled)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if let Some(tag) = tag {
+        if tag.trim().is_empty() || tag.contains('\n') {
+            return Err(format!("invalid hosts tag \"{}\"", tag).into());
+        }
+        let old_tag = config
+            .interface
+            .hosts_tag
+            .clone()
+            .unwrap_or_else(|| default_hosts_tag(interface));
+        if tag != old_tag {
+            // Writing a section without any hosts removes it.
+            HostsBuilder::new(old_tag).write()?;
+            config.interface.hosts_tag = Some(tag);
+            config.write_to_interface(interface)?;
+        }
+    }
+
+    update_hosts_file(
+        interface,
+        &hosts_template(&config),
+        config.interface.hosts_tag.as_deref(),
+        &peers,
+        store.cidrs(),
+    )
+}
+
+fn print_inventory(interface: &str, format: OutputFormat) -> Result<(), Error> {
+    let store = DataStore::open(interface).map_err(|_| {
+        format!(
+            "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+            interface, interface
+        )
+    })?;
+    // The roster is still worth syncing while the interface is down, just without live state.
+    let device_info = DeviceInfo::get_by_name(interface).ok();
+    let inventory = Inventory::new(
+        interface,
+        store.peers(),
+        store.cidrs(),
+        device_info.as_ref(),
+    );
+    println!("{}", format.render(&inventory)?);
+    Ok(())
+}
+
+fn show_events(interface: &str) -> Result<(), Error> {
+    let events = EventLog::open(interface)?.events()?;
+    if events.is_empty() {
+        println!(
+            "{} no events recorded for {} yet; they're recorded by \"innernet up --daemon\".",
+            "[*]".dimmed(),
+            interface.yellow()
+        );
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+    for event in events {
+        let ago = now
+            .duration_since(UNIX_EPOCH + Duration::from_secs(event.timestamp))
+            .map(human_duration)
+            .unwrap_or_else(|_| "0 seconds".to_string());
+        let transition = match event.transition {
+            Transition::Connected => event.transition.to_string().green(),
+            Transition::Disconnected => event.transition.to_string().red(),
+        };
+        println!(
+            "{} {} {}",
+            format!("{:>16}", format!("{} ago", ago)).dimmed(),
+            event.peer.yellow(),
+            transition
+        );
+    }
+    Ok(())
+}
+
+fn interface_public_key(interface: &str, device_info: Option<&DeviceInfo>) -> Result<Key, Error> {
+    match device_info.and_then(|device_info| device_info.public_key.clone()) {
+        Some(public_key) => Ok(public_key),
+        // The interface isn't up, so derive it from the configured private key instead.
+        None => {
+            let config = InterfaceConfig::from_interface(interface)?;
+            let private_key = Key::from_base64(&config.interface.private_key)
+                .map_err(|_| "interface config has an invalid private key.")?;
+            Ok(KeyPair::from_private(private_key).public)
+        },
+    }
+}
+
+fn print_public_key(interface: &str) -> Result<(), Error> {
+    let device_info = DeviceInfo::get_by_name(interface).ok();
+    let public_key = interface_public_key(interface, device_info.as_ref())?;
+    println!("{}", public_key.to_base64());
+    Ok(())
+}
+
+fn print_status(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let device_info = DeviceInfo::get_by_name(interface).ok();
+    let public_key = interface_public_key(interface, device_info.as_ref())?.to_base64();
+    let store = DataStore::open(interface)?;
+    let me = store
+        .peers()
+        .iter()
+        .find(|peer| peer.public_key == public_key)
+        .ok_or("missing peer info, run \"innernet fetch\" first.")?;
+    let cidr = store
+        .cidrs()
+        .iter()
+        .find(|cidr| cidr.id == me.cidr_id)
+        .map_or_else(|| "unknown".to_string(), |cidr| cidr.name.clone());
+
+    println!(
+        "{}: {} ({})",
+        "interface".green().bold(),
+        interface.green(),
+        if device_info.is_some() {
+            "up".green()
+        } else {
+            "down".red()
+        }
+    );
+    println!(
+        "  {}: {}",
+        "you".bold(),
+        format!("{}: {}", me.ip, me.name).yellow()
+    );
+    println!("  {}: {}", "cidr".bold(), cidr);
+    println!("  {}: {}", "public key".bold(), public_key);
+    if let Some(listen_port) = device_info
+        .and_then(|device_info| device_info.listen_port)
+        .or(config.interface.listen_port)
+    {
+        println!("  {}: {}", "listening_port".bold(), listen_port);
+    }
+    println!(
+        "  {}: {} (api at {})",
+        "server".bold(),
+        config.server.external_endpoint,
+        config.server.internal_endpoint
+    );
+    Ok(())
+}
+
+/// Print the config, or write it to a file only readable by its owner, since it includes the
+/// private key.
+fn export_wg_quick(interface: &str, all_peers: bool, output: Option<&Path>) -> Result<(), Error> {
+    let mut config = InterfaceConfig::from_interface(interface)?;
+    let peers = if all_peers {
+        let device_info = DeviceInfo::get_by_name(interface)?;
+        config.interface.listen_port = config.interface.listen_port.or(device_info.listen_port);
+        let known_peers = DataStore::open(interface)
+            .map(|store| store.peers().to_vec())
+            .unwrap_or_default();
+        Some(
+            device_info
+                .peers
+                .iter()
+                .map(|info| wg_quick_peer(&info.config, &known_peers))
+                .collect::<Vec<_>>(),
+        )
+    } else {
+        None
+    };
+    let wg_quick = format!(
+        "# innernet interface {}, exported for wg-quick.\n{}",
+        interface,
+        config.to_wg_quick(peers.as_deref())
+    );
+
+    match output {
+        Some(path) => {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(wg_quick.as_bytes()))
+                .with_path(path)?;
+            println!(
+                "{} exported {} to {}.",
+                "[*]".dimmed(),
+                interface.yellow(),
+                path.to_string_lossy().yellow()
+            );
+        },
+        None => print!("{}", wg_quick),
+    }
+    Ok(())
+}
+
+/// A peer of the WireGuard interface, named after the peer with its key from the last fetch.
+fn wg_quick_peer(config: &PeerConfig, known_peers: &[Peer]) -> WgQuickPeer {
+    let public_key = config.public_key.to_base64();
+    WgQuickPeer {
+        name: known_peers
+            .iter()
+            .find(|peer| peer.public_key == public_key)
+            .map(|peer| peer.name.to_string()),
+        public_key,
+        preshared_key: config.preshared_key.as_ref().map(Key::to_base64),
+        endpoint: config.endpoint,
+        allowed_ips: config
+            .allowed_ips
+            .iter()
+            .filter_map(|ip| IpNetwork::new(ip.address, ip.cidr).ok())
+            .collect(),
+        persistent_keepalive: config.persistent_keepalive_interval,
+    }
+}
+
+fn ping_server(interface: &str, count: u32) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!(
+        "{} timing {} requests to {}.",
+        "[*]".dimmed(),
+        count,
+        server.internal_endpoint
+    );
+
+    let mut samples = vec![];
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        let start = Instant::now();
+        match http_get::<State>(&server.internal_endpoint, "/user/state") {
+            Ok(_) => {
+                let elapsed = start.elapsed();
+                println!("    reply in {:.1} ms", elapsed.as_secs_f64() * 1000.0);
+                samples.push(elapsed);
+            },
+            Err(e) => println!("    {} {}", "request failed:".red(), e),
+        }
+    }
+
+    let (min, max) = match (samples.iter().min(), samples.iter().max()) {
+        (Some(min), Some(max)) => (min, max),
+        _ => return Err(format!("none of the {} requests succeeded", count).into()),
+    };
+    let avg = samples.iter().sum::<Duration>() / samples.len() as u32;
+    println!(
+        "\n{} {}/{} requests succeeded, round-trip min/avg/max = {:.1}/{:.1}/{:.1} ms",
+        "[*]".dimmed(),
+        samples.len(),
+        count,
+        min.as_secs_f64() * 1000.0,
+        avg.as_secs_f64() * 1000.0,
+        max.as_secs_f64() * 1000.0
+    );
+    Ok(())
+}
+
+fn diff_interfaces(interface_a: &str, interface_b: &str) -> Result<(), Error> {
+    let open = |interface: &str| -> Result<Vec<Peer>, Error> {
+        let store = DataStore::open(interface).map_err(|_| {
+            format!(
+                "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+                interface, interface
+            )
+        })?;
+        let mut peers = store
+            .peers()
+            .iter()
+            .filter(|peer| !peer.is_disabled)
+            .cloned()
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(peers)
+    };
+    let peers_a = open(interface_a)?;
+    let peers_b = open(interface_b)?;
+    let find = |peers: &[Peer], name: &str| peers.iter().find(|p| p.name == name).cloned();
+
+    let print_section = |title: String, peers: Vec<&Peer>| {
+        println!("{} ({}):", title.bold(), peers.len());
+        for peer in peers {
+            println!(
+                "  {} ({})",
+                peer.name.yellow(),
+                abbreviate_key(&peer.public_key).dimmed()
+            );
+        }
+    };
+    print_section(
+        format!("only in {}", interface_a),
+        peers_a
+            .iter()
+            .filter(|p| find(&peers_b, &p.name).is_none())
+            .collect(),
+    );
+    print_section(
+        format!("only in {}", interface_b),
+        peers_b
+            .iter()
+            .filter(|p| find(&peers_a, &p.name).is_none())
+            .collect(),
+    );
+
+    let both = peers_a
+        .iter()
+        .filter_map(|a| find(&peers_b, &a.name).map(|b| (a, b)))
+        .collect::<Vec<_>>();
+    println!("{} ({}):", "in both".bold(), both.len());
+    for (a, b) in both {
+        if a.public_key == b.public_key {
+            println!(
+                "  {} ({})",
+                a.name.yellow(),
+                abbreviate_key(&a.public_key).dimmed()
+            );
+        } else {
+            println!(
+                "  {} ({} vs {}) {}",
+                a.name.yellow(),
+                abbreviate_key(&a.public_key).dimmed(),
+                abbreviate_key(&b.public_key).dimmed(),
+                "public keys differ".red()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Indent every line of a (possibly multi-line) message by the given number of spaces.
+fn indent(message: &str, spaces: usize) -> String {
+    message
+        .lines()
+        .map(|line| format!("{:spaces$}{}", "", line, spaces = spaces))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Tear down an installed interface and remove its config and cached state.
+fn uninstall(interface: &str, config_path: &Path, bring_down: bool) -> Result<(), Error> {
+    if bring_down
+        && DeviceInfo::enumerate()?
+            .iter()
+            .any(|name| name == interface)
+    {
+        println!("{} bringing down the interface.", "[*]".dimmed());
+        wg::down(interface)?;
+    }
+    std::fs::remove_file(config_path).with_path(config_path)?;
+    let backup_path = InterfaceConfig::backup_path(interface);
+    if backup_path.exists() {
+        std::fs::remove_file(&backup_path).with_path(&backup_path)?;
+    }
+    DataStore::remove(interface)?;
+    println!(
+        "{} removed the existing installation of {}.",
+        "[*]".dimmed(),
+        interface.yellow()
+    );
+    Ok(())
+}
+
+/// Seed an interface's cached state and peers from the snapshot bundled into its invitation,
+/// returning how many peers were added. The server is left as configured.
+fn apply_snapshot(
+    interface: &str,
+    config: &InterfaceConfig,
+    snapshot: State,
+) -> Result<usize, Error> {
+    let peer_configs = snapshot
+        .peers
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.public_key != config.server.public_key)
+        .map(PeerConfigBuilder::from)
+        .collect::<Vec<_>>();
+    DeviceConfigBuilder::new()
+        .add_peers(&peer_configs)
+        .apply(interface)?;
+
+    let mut store = DataStore::open_or_create(interface)?;
+    store.add_peers(snapshot.peers)?;
+    store.set_cidrs(snapshot.cidrs);
+    store.set_motd(snapshot.motd);
+    store.write()?;
+    Ok(peer_configs.len())
+}
+
+fn install(invite: &Path, reinstall: bool, no_interface: bool) -> Result<(), Error> {
+    let theme = ColorfulTheme::default();
+    shared::ensure_dirs_exist(&[&client_config_path()])?;
+    let config = InterfaceConfig::from_invitation(invite)?;
+
+    let iface = Input::with_theme(&theme)
+        .with_prompt("Interface name")
+        .default(config.interface.network_name.clone())
+        .validate_with(|name: &String| shared::interface_name_validator(name))
+        .interact()?;
+    install_as(invite, config, &iface, reinstall, no_interface)
+}
+
+/// Switch the interface to a newly redeemed key, once the server has (or is about to have)
+/// transitioned to it.
+///
+/// Rather than waiting a fixed time, the key is switched right away and the server is nudged
+/// until it handshakes with the new key, which takes longer on high-latency links. If handshakes
+/// can't be observed, this falls back to waiting `REDEEM_TRANSITION_WAIT` before switching.
+fn wait_for_key_transition(iface: &str, config: &InterfaceConfig, key: Key) -> Result<(), Error> {
+    if DeviceInfo::get_by_name(iface).is_err() {
+        thread::sleep(*REDEEM_TRANSITION_WAIT);
+        DeviceConfigBuilder::new()
+            .set_private_key(key)
+            .apply(iface)?;
+        return Ok(());
+    }
+
+    let switched_at = SystemTime::now();
+    DeviceConfigBuilder::new()
+        .set_private_key(key)
+        .apply(iface)?;
+    let deadline = Instant::now() + REDEEM_HANDSHAKE_TIMEOUT;
+    while Instant::now() < deadline {
+        let handshaked = DeviceInfo::get_by_name(iface)?
+            .peers
+            .iter()
+            .find(|peer| peer.config.public_key.to_base64() == config.server.public_key)
+            .and_then(|peer| peer.stats.last_handshake_time)
+            .map(|time| time >= switched_at)
+            .unwrap_or(false);
+        if handshaked {
+            return Ok(());
+        }
+        // WireGuard only initiates a handshake when there's traffic to send, so poke the
+        // server's API. The connection attempt failing until the handshake is done is expected.
+        let _ =
+            TcpStream::connect_timeout(&config.server.internal_endpoint, Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(500));
+    }
+    println!(
+        "{} no handshake with the server after {}, trying to fetch anyway.",
+        "[!]".yellow(),
+        human_duration(REDEEM_HANDSHAKE_TIMEOUT)
+    );
+    Ok(())
+}
+
+/// Register a newly generated key with the server in place of the invitation's, switching the
+/// config to it and returning it. The interface itself still has to be switched to it.
+fn redeem(config: &mut InterfaceConfig) -> Result<Key, Error> {
+    println!("{} Generating new keypair.", "[*]".dimmed());
+    let keypair = wgctrl::KeyPair::generate();
+
+    println!(
+        "{} Registering keypair with server (at {}).",
+        "[*]".dimmed(),
+        &config.server.internal_endpoint
+    );
+    http_post(
+        &config.server.internal_endpoint,
+        "/user/redeem",
+        RedeemContents {
+            public_key: keypair.public.to_base64(),
+        },
+    )?;
+
+    config.interface.private_key = keypair.private.to_base64();
+    config.interface.redeem_pending = false;
+    Ok(keypair.private)
+}
+
+/// Install an invitation, whose config has already been read, as the given interface.
+fn install_as(
+    invite: &Path,
+    mut config: InterfaceConfig,
+    iface: &str,
+    reinstall: bool,
+    no_interface: bool,
+) -> Result<(), Error> {
+    // The name ends up in file paths, so it mustn't be able to escape the config directory.
+    shared::interface_name_validator(iface)
+        .map_err(|e| format!("invalid interface name \"{}\": {}", iface, e))?;
+    let theme = ColorfulTheme::default();
+    let target_conf = client_config_path().join(iface).with_extension("conf");
+    if target_conf.exists() {
+        if !reinstall {
+            return Err("An interface with this name already exists in innernet. \
+                        Use --reinstall to replace it."
+                .into());
+        }
+        if !Confirm::with_theme(&theme)
+            .with_prompt(format!(
+                "Tear down {} and remove its config and cached peers?",
+                iface.yellow()
+            ))
+            .default(false)
+            .interact()?
+        {
+            println!("exited without reinstalling.");
+            return Ok(());
+        }
+        uninstall(iface, &target_conf, !no_interface)?;
+    }
+
+    if no_interface {
+        // The invitation is redeemed through the tunnel, so it has to be up already, using the
+        // invitation's key.
+        if DeviceInfo::get_by_name(iface).is_err() {
+            printdoc!(
+                "
+                {star} {interface} isn't up. Bring it up with the invitation's key first, e.g. by
+                    saving the following as /etc/wireguard/{interface_name}.conf and running
+                    \"wg-quick up {interface_name}\", and then run this again.
+
+                {wg_quick}
+                ",
+                star = "[*]".dimmed(),
+                interface = iface.yellow(),
+                interface_name = iface,
+                wg_quick = config.to_wg_quick(None),
+            );
+            return Ok(());
+        }
+    } else {
+        println!("{} bringing up the interface.", "[*]".dimmed());
+        wg::up(
+            iface,
+            &config.interface.private_key,
+            config.interface.address,
+            None,
+            Some((
+                &config.server.public_key,
+                config.server.internal_endpoint.ip(),
+                config.server.external_endpoint,
+            )),
+            &config.server.extra_allowed_ips,
+        )?;
+    }
+
+    let snapshot = config.snapshot.take();
+    match (redeem(&mut config), snapshot) {
+        // The invitation's key keeps working until it's redeemed, so without the server, keep
+        // it and leave redeeming it to the first fetch that reaches the server.
+        (Err(e), Some(snapshot)) if util::is_transient(&e) => {
+            config.interface.redeem_pending = true;
+            config.write_to_path(&target_conf, false, Some(0o600))?;
+            let count = apply_snapshot(iface, &config, snapshot)?;
+            println!(
+                "{} couldn't reach the server to register a new keypair ({}), so brought up {} \
+                 peers from the invitation instead. The next fetch will register it and bring \
+                 them up to date.",
+                "[!]".yellow(),
+                e,
+                count
+            );
+        },
+        (key, snapshot) => {
+            let key = key?;
+            config.write_to_path(&target_conf, false, Some(0o600))?;
+            println!(
+                "{} New keypair registered. Copied config to {}.\n",
+                "[*]".dimmed(),
+                target_conf.to_string_lossy().yellow()
+            );
+            println!(
+                "{} Waiting for server's WireGuard interface to transition to new key.",
+                "[*]".dimmed(),
+            );
+            wait_for_key_transition(iface, &config, key)?;
+
+            match (fetch(iface, false, &FetchOpts::default()), snapshot) {
+                (Err(e), Some(snapshot)) => {
+                    let count = apply_snapshot(iface, &config, snapshot)?;
+                    println!(
+                        "{} couldn't fetch from the server ({}), so brought up {} peers from the \
+                         invitation instead. They'll be brought up to date by the next fetch.",
+                        "[!]".yellow(),
+                        e,
+                        count
+                    );
+                },
+                (result, _) => result?,
+            }
+        },
+    }
+
+    if Confirm::with_theme(&theme)
+        .with_prompt(&format!(
+            "Delete invitation file \"{}\" now? (It's no longer needed)",
+            invite.to_string_lossy().yellow()
+        ))
+        .default(true)
+        .interact()?
+    {
+        std::fs::remove_file(invite).with_path(invite)?;
+    }
+
+    let motd = DataStore::open(iface)?
+        .motd()
+        .map(|motd| format!("{}\n\n", indent(motd, 4)))
+        .unwrap_or_default();
+    if no_interface {
+        printdoc!(
+            "
+            {star} Done!
+
+                {interface} has been {installed}, with a new key.
+
+            {motd}    Replace your interface's config with the following, and keep its peers
+                up to date by running \"innernet fetch {interface_name}\" periodically.
+
+            {wg_quick}
+            ",
+            star = "[*]".dimmed(),
+            interface = iface.yellow(),
+            interface_name = iface,
+            installed = "installed".green(),
+            motd = motd,
+            wg_quick = config.to_wg_quick(None),
+        );
+        return Ok(());
+    }
+    printdoc!(
+        "
+        {star} Done!
+
+            {interface} has been {installed}.
+
+        {motd}    It's recommended to now keep the interface automatically refreshing via systemd:
+
+                {systemctl_enable}{interface}
+
+            See the documentation for more detailed instruction on managing your interface
+            and your network.
+
+    ",
+        star = "[*]".dimmed(),
+        interface = iface.yellow(),
+        installed = "installed".green(),
+        motd = motd,
+        systemctl_enable = "systemctl enable --now innernet@".yellow(),
+    );
+
+    Ok(())
+}
+
+/// Install several invitations one after another, each as an interface named after its network
+/// (with a numbered suffix if that name is already taken), carrying on past any that fail.
+fn install_all(invites: &[PathBuf]) -> Result<(), Error> {
+    shared::ensure_dirs_exist(&[&client_config_path()])?;
+    let mut taken = HashSet::new();
+    let mut results = vec![];
+    for invite in invites {
+        println!(
+            "\n{} installing {}.",
+            "[*]".dimmed(),
+            invite.to_string_lossy().yellow()
+        );
+        let result = InterfaceConfig::from_invitation(invite).and_then(|config| {
+            let network_name = &config.interface.network_name;
+            let iface = util::distinct_interface_name(network_name, |name| {
+                taken.contains(name)
+                    || client_config_path()
+                        .join(name)
+                        .with_extension("conf")
+                        .exists()
+            })
+            .ok_or_else(|| format!("no free interface name for network {}", network_name))?;
+            taken.insert(iface.clone());
+            install_as(invite, config, &iface, false, false).map(|()| iface)
+        });
+        results.push((invite, result));
+    }
+
+    println!("\n{} summary:", "[*]".dimmed());
+    let mut failures = 0;
+    for (invite, result) in &results {
+        match result {
+            Ok(iface) => println!(
+                "    {} {} as {}",
+                "installed".green(),
+                invite.to_string_lossy(),
+                iface.yellow()
+            ),
+            Err(e) => {
+                failures += 1;
+                println!("    {} {}: {}", "failed".red(), invite.to_string_lossy(), e);
+            },
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!(
+            "{} of {} invitations failed to install",
+            failures,
+            results.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// What `innernet up --daemon` does besides fetching, all of which is ignored without it.
+struct DaemonOpts<'a> {
+    health_listen: Option<SocketAddr>,
+    stats_csv: Option<&'a Path>,
+    metrics_listen: Option<SocketAddr>,
+    max_concurrent_fetches: NonZeroUsize,
+    down_on_exit: bool,
+}
+
+fn up(
+    interface: &str,
+    mut loop_interval: Option<Duration>,
+    watch_network: bool,
+    daemon_opts: &DaemonOpts,
+    fetch_opts: &FetchOpts,
+) -> Result<(), Error> {
+    let DaemonOpts {
+        health_listen,
+        stats_csv,
+        metrics_listen,
+        max_concurrent_fetches,
+        down_on_exit,
+    } = *daemon_opts;
+    // Opened only once the first fetch succeeded, so that the interface coming up isn't taken
+    // for a network change.
+    let mut network_monitor = None;
+    let health_server = match (health_listen, loop_interval) {
+        // Allow for one missed fetch before reporting unhealthy.
+        (Some(addr), Some(interval)) => Some(HealthServer::start(
+            addr,
+            interface,
+            interval.saturating_mul(2),
+        )?),
+        _ => None,
+    };
+    if let (Some(addr), Some(_)) = (metrics_listen, loop_interval) {
+        metrics::start(addr, interface)?;
+    }
+    if loop_interval.is_some() {
+        interval_signals::install()?;
+        shutdown::install()?;
+    }
+
+    let mut event_log = EventLog::open(interface)?;
+    let mut failures = 0;
+    loop {
+        if shutdown::requested() {
+            break;
+        }
+        // In daemon mode, failing to reach the server is retried, backing off exponentially
+        // while it keeps failing. Local problems (e.g. a broken config) still end the loop.
+        let result = match loop_interval {
+            Some(_) => {
+                let _slot = FetchSlot::acquire(max_concurrent_fetches)?;
+                fetch(interface, true, fetch_opts)
+            },
+            None => fetch(interface, true, fetch_opts),
+        };
+        if let Some(interval) = loop_interval {
+            let adjusted = interval_signals::adjust(interval);
+            if adjusted != interval {
+                println!(
+                    "{} fetch interval changed to {} seconds.",
+                    "[*]".dimmed(),
+                    adjusted.as_secs()
+                );
+                loop_interval = Some(adjusted);
+                if let Some(health_server) = &health_server {
+                    health_server.set_max_fetch_age(adjusted.saturating_mul(2));
+                }
+            }
+        }
+        let wait = match (result, loop_interval) {
+            (Err(e), Some(interval)) if util::is_server_error(&e) => {
+                failures += 1;
+                let backoff = interval
+                    .saturating_mul(2u32.pow(failures.min(16) - 1))
+                    .min(MAX_FETCH_BACKOFF)
+                    .max(interval);
+                println!(
+                    "{} fetch failed ({}), retrying in {} seconds.",
+                    "[!]".yellow(),
+                    e,
+                    backoff.as_secs()
+                );
+                Some(backoff)
+            },
+            (result, _) => {
+                result?;
+                failures = 0;
+                loop_interval
+            },
+        };
+        if let (0, Some(health_server)) = (failures, &health_server) {
+            health_server.fetch_succeeded();
+        }
+        if failures == 0 && watch_network && network_monitor.is_none() {
+            network_monitor = Some(NetworkMonitor::new()?);
+        }
+        if failures == 0 && loop_interval.is_some() {
+            let device_info = DeviceInfo::get_by_name(interface)?;
+            let store = DataStore::open(interface)?;
+            if let Err(e) = event_log.record(&device_info, store.peers()) {
+                println!("{} failed to record peer events: {}", "[!]".yellow(), e);
+            }
+            if let Some(path) = stats_csv {
+                if let Err(e) = StatsCsv::new(path).record(&device_info, store.peers()) {
+                    println!("{} failed to record peer stats: {}", "[!]".yellow(), e);
+                }
+            }
+        }
+        if shutdown::requested() {
+            break;
+        }
+        match (wait, &network_monitor) {
+            (Some(interval), Some(network_monitor)) => {
+                if interval_signals::wait(interval, |slice| network_monitor.wait(slice))? {
+                    println!("{} network change detected, reconnecting.", "[*]".dimmed());
+                    reassert_server_endpoint(interface)?;
+                }
+            },
+            (Some(interval), None) => interval_signals::sleep(interval),
+            (None, _) => break,
+        }
+    }
+
+    if shutdown::requested() {
+        println!("{} stopping.", "[*]".dimmed());
+        if down_on_exit {
+            wg::down(interface)?;
+            println!("{} took down {}.", "[*]".dimmed(), interface.yellow());
+        }
+    }
+    Ok(())
+}
+
+/// Set the server peer's endpoint again from the interface config, so that WireGuard starts
+/// sending to it from the new network path instead of waiting to roam on its own.
+fn reassert_server_endpoint(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    DeviceConfigBuilder::new()
+        .add_peer(
+            PeerConfigBuilder::new(&Key::from_base64(&config.server.public_key)?)
+                .set_endpoint(server_external_endpoint(&config)),
+        )
+        .apply(interface)?;
+    Ok(())
+}
+
+/// The config's extra allowed IPs that WireGuard doesn't route through the peer yet, if it's
+/// the server (e.g. when the server is a gateway to other networks).
+fn missing_extra_allowed_ips(
+    peer: &Peer,
+    config: &InterfaceConfig,
+    existing: &[AllowedIp],
+) -> Vec<IpNetwork> {
+    if peer.public_key != config.server.public_key {
+        return vec![];
+    }
+    config
+        .server
+        .extra_allowed_ips
+        .iter()
+        .filter(|net| {
+            !existing
+                .iter()
+                .any(|ip| ip.address == net.network() && ip.cidr == net.prefix())
+        })
+        .copied()
+        .collect()
+}
+
+fn add_allowed_ips(builder: PeerConfigBuilder, nets: &[IpNetwork]) -> PeerConfigBuilder {
+    nets.iter().fold(builder, |builder, net| {
+        builder.add_allowed_ip(net.network(), net.prefix())
+    })
+}
+
+/// The server's external endpoint, looked up from its SRV name if the config has one.
+fn server_external_endpoint(config: &InterfaceConfig) -> SocketAddr {
+    match &config.server.external_endpoint_srv {
+        Some(name) => srv::resolve(name).unwrap_or_else(|e| {
+            println!(
+                "{}: failed to resolve {} ({}), using {} instead.",
+                "warning".bold().yellow(),
+                name,
+                e,
+                config.server.external_endpoint
+            );
+            config.server.external_endpoint
+        }),
+        None => config.server.external_endpoint,
+    }
+}
+
+/// Take the lock for managing an interface, or report which process already holds it.
+fn lock_interface(interface: &str) -> Result<Option<InterfaceLock>, Error> {
+    let lock = InterfaceLock::try_acquire(interface)?;
+    if lock.is_none() {
+        let holder = InterfaceLock::holder(interface)
+            .map(|pid| format!(" (pid {})", pid))
+            .unwrap_or_default();
+        println!(
+            "{} interface {} is already being managed{}.",
+            "[*]".dimmed(),
+            interface.yellow(),
+            holder
+        );
+    }
+    Ok(lock)
+}
+
+fn fetch(interface: &str, bring_up_interface: bool, opts: &FetchOpts) -> Result<(), Error> {
+    let mut timer = PhaseTimer::new(opts.timing);
+    let mut config = InterfaceConfig::from_interface(interface)?;
+    // Resolved on every fetch, so that the daemon follows changes to the server's SRV record.
+    let mut server_endpoint = server_external_endpoint(&config);
+    // Enumerating can fail for reasons that have nothing to do with this interface (e.g. an
+    // unreadable socket directory of another userspace interface), so ask for it directly
+    // before concluding that it's down.
+    let interface_up = match DeviceInfo::enumerate() {
+        Ok(interfaces) => interfaces.iter().any(|name| name == interface),
+        Err(_) => DeviceInfo::get_by_name(interface).is_ok(),
+    };
+
+    if !interface_up {
+        if !bring_up_interface {
+            return Err(format!(
+                "Interface is not up. Use 'innernet up {}' instead",
+                interface
+            )
+            .into());
+        }
+
+        if config.interface.has_host_prefix() {
+            println!(
+                "{}: interface address {} has a host prefix, so other peers won't be routable.\n\
+                 Set the address to use your network's CIDR prefix in {}.",
+                "warning".bold().yellow(),
+                config.interface.address,
+                client_config_path()
+                    .join(interface)
+                    .with_extension("conf")
+                    .to_string_lossy()
+                    .yellow()
+            );
+        }
+
+        println!("{} bringing up the interface.", "[*]".dimmed());
+        up_with_free_port(interface, &mut config, server_endpoint)?;
+        timer.finish("bring up interface");
+    }
+
+    if config.interface.redeem_pending && !opts.dry_run {
+        let key = redeem(&mut config)?;
+        config.write_to_interface(interface)?;
+        println!(
+            "{} Waiting for server's WireGuard interface to transition to new key.",
+            "[*]".dimmed(),
+        );
+        wait_for_key_transition(interface, &config, key)?;
+    }
+
+    println!("{} fetching state from server.", "[*]".dimmed());
+    let mut store = DataStore::open_or_create(&interface)?;
+    let (State { peers, cidrs, motd }, min_client_version) = http_get_with_header(
+        &config.server.internal_endpoint,
+        "/user/state",
+        MIN_CLIENT_VERSION_HEADER,
+    )?;
+    timer.finish("fetch state");
+    let peers = match &opts.trusted_keys {
+        Some(path) => {
+            let own_public_key = config.interface.public_key().unwrap_or_default();
+            TrustedKeys::from_file(path)?
+                .retain(peers, &[&config.server.public_key, &own_public_key])
+        },
+        None => peers,
+    };
+    // Before anything is applied, so that a rejected peer leaves the interface as it was.
+    store.check_peers(&peers)?;
+    store.set_min_client_version(min_client_version);
+    check_min_client_version(&mut store, opts.enforce_version, opts.dry_run)?;
+
+    // The first fetch is part of installing, which shows the message itself.
+    let first_fetch = store.peers().is_empty();
+    if let Some(motd) = &motd {
+        if !first_fetch && store.motd() != Some(motd.as_str()) {
+            println!(
+                "{} the network's message of the day has changed:\n\n{}\n",
+                "[*]".dimmed(),
+                indent(motd, 4)
+            );
+        }
+    }
+    store.set_motd(motd);
+
+    // Switching endpoints saves the config, so a dry run leaves that to a real fetch.
+    let new_server_endpoint = if opts.dry_run {
+        None
+    } else {
+        new_server_endpoint(&config, &peers, opts)?
+    };
+    if let Some(endpoint) = new_server_endpoint {
+        server_endpoint = endpoint;
+    }
+
+    let device_info = DeviceInfo::get_by_name(&interface)?;
+    let interface_public_key = device_info
+        .public_key
+        .as_ref()
+        .map(|k| k.to_base64())
+        .unwrap_or_default();
+    let existing_peers = device_info
+        .peers
+        .iter()
+        .map(|peer| (peer.config.public_key.to_base64(), peer))
+        .collect::<HashMap<_, _>>();
+
+    // Peers that advertise fallback endpoints are configured with whichever of them we can
+    // reach. Servers that predate fallback endpoints never send any, leaving these untouched.
+    // The server itself is always configured with the endpoint from our config.
+    let wg_peers = peers
+        .iter()
+        .cloned()
+        .map(|mut peer| {
+            if peer.public_key == config.server.public_key {
+                peer.contents.endpoint = Some(server_endpoint);
+            } else if !peer.fallback_endpoints.is_empty() {
+                let existing_peer = existing_peers.get(&peer.public_key).copied();
+                peer.contents.endpoint = choose_endpoint(&peer, existing_peer);
+            }
+            peer
+        })
+        .collect::<Vec<_>>();
+
+    let mut device_config_builder = DeviceConfigBuilder::new();
     let mut device_config_changed = false;
 
-    if !peer_configs_diff.is_empty() {
-        device_config_builder = device_config_builder.add_peers(&peer_configs_diff);
-        device_config_changed = true;
+    if opts.repair {
+        // Ignore whatever state the device is in and rebuild the entire peer list from
+        // the server's state, replacing any existing peers.
+        let peer_configs = wg_peers
+            .iter()
+            .filter(|peer| !peer.is_disabled && peer.public_key != interface_public_key)
+            .map(|peer| {
+                add_allowed_ips(
+                    PeerConfigBuilder::from(peer),
+                    &missing_extra_allowed_ips(peer, &config, &[]),
+                )
+            })
+            .collect::<Vec<PeerConfigBuilder>>();
+
+        println!(
+            "    {} all {} peers from server state.",
+            "rebuilding".yellow(),
+            peer_configs.len()
+        );
+        device_config_builder = device_config_builder
+            .replace_peers()
+            .add_peers(&peer_configs);
+        device_config_changed = true;
+    } else {
+        let changes = peer_diff::diff(
+            &wg_peers,
+            existing_peers.values().map(|peer| &peer.config),
+            &interface_public_key,
+            &config,
+            opts.ignore_endpoint_changes,
+        );
+        if !changes.is_empty() {
+            device_config_builder = changes.removed.iter().fold(
+                device_config_builder.add_peers(&changes.configs),
+                |builder, key| builder.remove_peer_by_key(key),
+            );
+            device_config_changed = true;
+        }
+    }
+    timer.finish("compute diff");
+
+    if device_config_changed
+        && opts.interactive
+        && !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Apply these changes to {}?", interface))
+            .default(false)
+            .interact()?
+    {
+        println!("exited without changing the interface.");
+        return Ok(());
+    }
+
+    // Only saved once the changes are accepted, so that declining them keeps the old endpoint.
+    if let Some(endpoint) = new_server_endpoint {
+        config.server.external_endpoint = endpoint;
+        config.write_to_interface(interface)?;
+        println!("    saved the new endpoint to the interface's config.");
+    }
+
+    if opts.dry_run {
+        if !device_config_changed {
+            println!("{}", "    peers are already up to date.".green());
+        }
+        println!(
+            "\n{} dry run, so {} and its cached peers were left untouched.\n",
+            "[*]".dimmed(),
+            interface.yellow()
+        );
+        return Ok(());
+    }
+
+    // Saved so that later updates (e.g. `update-hosts`) keep it, and the hostnames are
+    // rewritten with it even if no peers changed.
+    let hosts_suffix_changed =
+        opts.hosts_suffix.is_some() && opts.hosts_suffix != config.interface.hosts_suffix;
+    if hosts_suffix_changed {
+        config.interface.hosts_suffix = opts.hosts_suffix.clone();
+        config.write_to_interface(interface)?;
+        println!("    saved the new hosts suffix to the interface's config.");
+    }
+
+    if device_config_changed {
+        device_config_builder.apply(&interface)?;
+        for net in &config.server.extra_allowed_ips {
+            let route = IpNetwork::new(net.network(), net.prefix())?;
+            if let Err(e) = wg::add_route(interface, route) {
+                println!(
+                    "{}: failed to route {} through the server ({}).",
+                    "warning".bold().yellow(),
+                    net,
+                    e
+                );
+            }
+        }
+        timer.finish("apply device config");
+    }
+
+    if (device_config_changed || hosts_suffix_changed)
+        && !opts.no_host_updates
+        && !config.interface.no_host_updates
+    {
+        if let Err(e) = update_hosts_file(
+            interface,
+            &hosts_template(&config),
+            config.interface.hosts_tag.as_deref(),
+            &peers,
+            &cidrs,
+        ) {
+            if opts.strict_hosts {
+                return Err(e);
+            }
+            println!(
+                "{}: failed to update /etc/hosts ({}), the peers' hostnames may be stale.",
+                "warning".bold().yellow(),
+                e
+            );
+        }
+        timer.finish("update hosts");
+    }
+
+    if device_config_changed {
+        println!(
+            "\n{} {} interface {}\n",
+            "[*]".dimmed(),
+            if opts.repair {
+                "fully rebuilt"
+            } else {
+                "updated"
+            },
+            interface.yellow()
+        );
+    } else {
+        println!("{}", "    peers are already up to date.".green());
+    }
+    store.set_cidrs(cidrs);
+    store.add_peers(peers)?;
+    store.set_fetched_at(SystemTime::now());
+    store.write()?;
+    timer.finish("write datastore");
+    timer.print();
+
+    Ok(())
+}
+
+/// Bring up the interface with its configured listen port. If another process already has the
+/// port, offer to switch to a random one instead, saving it to the interface's config.
+fn up_with_free_port(
+    interface: &str,
+    config: &mut InterfaceConfig,
+    server_endpoint: SocketAddr,
+) -> Result<(), Error> {
+    let up = |config: &InterfaceConfig, listen_port: Option<u16>| {
+        wg::up(
+            interface,
+            &config.interface.private_key,
+            config.interface.address,
+            listen_port,
+            Some((
+                &config.server.public_key,
+                config.server.internal_endpoint.ip(),
+                server_endpoint,
+            )),
+            &config.server.extra_allowed_ips,
+        )
+    };
+    // A port that's already in use is refused when the interface's link is brought up.
+    let port = match (
+        up(config, config.interface.listen_port),
+        config.interface.listen_port,
+    ) {
+        (Err(e), Some(port))
+            if e.downcast_ref::<io::Error>().map(io::Error::kind)
+                == Some(io::ErrorKind::AddrInUse) =>
+        {
+            port
+        },
+        (result, _) => return result,
+    };
+
+    println!(
+        "{}: listen port {} is already in use by another process.",
+        "warning".bold().yellow(),
+        port
+    );
+    let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    if !interactive
+        || !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Switch to a random listen port?")
+            .default(true)
+            .interact()?
+    {
+        return Err(format!(
+            "listen port {} is in use. Free it, or choose another one with \
+             \"innernet set-listen-port {}\".",
+            port, interface
+        )
+        .into());
+    }
+
+    up(config, Some(0))?;
+    config.interface.listen_port = DeviceInfo::get_by_name(interface)?.listen_port;
+    config.write_to_interface(interface)?;
+    match config.interface.listen_port {
+        Some(port) => println!(
+            "{} listening on port {} instead, and saved it to the interface's config.",
+            "[*]".dimmed(),
+            port.to_string().yellow()
+        ),
+        None => println!(
+            "{} listening on a random port instead, and unset the interface config's port.",
+            "[*]".dimmed()
+        ),
+    }
+    Ok(())
+}
+
+/// A new external endpoint advertised by the server (through the tunnel, so it can be trusted
+/// to come from the server), once the user accepts switching to it. The caller saves it to the
+/// interface's config. Configs with an SRV name leave finding the server to DNS instead.
+fn new_server_endpoint(
+    config: &InterfaceConfig,
+    peers: &[Peer],
+    opts: &FetchOpts,
+) -> Result<Option<SocketAddr>, Error> {
+    if config.server.external_endpoint_srv.is_some() {
+        return Ok(None);
+    }
+    let advertised = peers
+        .iter()
+        .find(|peer| peer.public_key == config.server.public_key)
+        .and_then(|peer| peer.endpoint);
+    let endpoint = match advertised {
+        Some(endpoint) if endpoint != config.server.external_endpoint => endpoint,
+        _ => return Ok(None),
+    };
+
+    println!(
+        "{} the server's endpoint has changed from {} to {}.",
+        "[*]".dimmed(),
+        config.server.external_endpoint,
+        endpoint.to_string().yellow()
+    );
+    let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    let accepted = opts.accept_server_endpoint
+        || interactive
+            && Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Switch to the new endpoint?")
+                .default(true)
+                .interact()?;
+    if !accepted {
+        println!(
+            "{}: keeping the old endpoint. Use --accept-server-endpoint to switch without asking.",
+            "warning".bold().yellow()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(endpoint))
+}
+
+/// Warn if this client is older than the minimum version the server last advertised, or, when
+/// enforcing it, refuse to continue (keeping the advertised version in the store regardless,
+/// unless it's a dry run).
+fn check_min_client_version(
+    store: &mut DataStore,
+    enforce: bool,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let min_version = match store.min_client_version() {
+        Some(version) => version.to_string(),
+        None => return Ok(()),
+    };
+    if compare_versions(VERSION, &min_version) != Some(Ordering::Less) {
+        return Ok(());
+    }
+
+    let message = format!(
+        "innernet {} is older than {}, the oldest version the server supports.\n\
+         Upgrade from your package manager or https://github.com/tonarino/innernet/releases.",
+        VERSION, min_version
+    );
+    if enforce {
+        if !dry_run {
+            store.write()?;
+        }
+        return Err(message.into());
+    }
+    println!("{}: {}", "warning".bold().yellow(), message);
+    Ok(())
+}
+
+fn add_cidr(
+    interface: &str,
+    name: Option<&str>,
+    cidr: Option<IpNetwork>,
+    parent: Option<&str>,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+
+    let cidr_request = match prompts::add_cidr(&cidrs, name, cidr, parent)? {
+        Some(cidr_request) => cidr_request,
+        None => {
+            println!("exited without creating CIDR.");
+            return Ok(());
+        },
+    };
+
+    println!("Creating CIDR...");
+    let cidr: Cidr = http_post(&server.internal_endpoint, "/admin/cidrs", cidr_request)?;
+
+    printdoc!(
+        "
+        CIDR \"{cidr_name}\" added.
+
+        Right now, peers within {cidr_name} can only see peers in the same CIDR
+        , and in the CIDRs flagged as infra, like the one that includes the innernet server peer.
+
+        You'll need to add more associations for peers in diffent CIDRs to communicate.
+        ",
+        cidr_name = cidr.name.bold()
+    );
+
+    Ok(())
+}
+
+fn delete_cidr(interface: &str) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+    println!("Fetching peers");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+    println!("Fetching associations");
+    let associations: Vec<Association> =
+        http_get(&server.internal_endpoint, "/admin/associations")?;
+
+    // The server refuses to delete CIDRs anything else still refers to, disabled peers
+    // included.
+    let deletable = cidrs
+        .iter()
+        .filter(|cidr| {
+            cidr.parent.is_some()
+                && !cidrs.iter().any(|c| c.parent == Some(cidr.id))
+                && !peers.iter().any(|peer| peer.cidr_id == cidr.id)
+                && !associations
+                    .iter()
+                    .any(|a| a.cidr_id_1 == cidr.id || a.cidr_id_2 == cidr.id)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if let Some(cidr) = prompts::delete_cidr(&deletable)? {
+        http_delete(
+            &server.internal_endpoint,
+            &format!("/admin/cidrs/{}", cidr.id),
+        )
+        .map_err(|e| {
+            format!(
+                "the server refused to delete {} ({}), it may have gained peers, child CIDRs, \
+                 or associations since it was fetched",
+                cidr.name, e
+            )
+        })?;
+        println!("{} CIDR {} deleted.", "[*]".dimmed(), cidr.name.yellow());
+    } else {
+        println!("exited without deleting CIDR.");
     }
 
-    for peer in existing_peers {
-        let public_key = peer.config.public_key.to_base64();
-        if peers.iter().find(|p| p.public_key == public_key).is_none() {
-            println!(
-                "    peer ({}...) was {}.",
-                &public_key[..10].yellow(),
-                "removed".red()
-            );
+    Ok(())
+}
 
-            device_config_builder =
-                device_config_builder.remove_peer_by_key(&peer.config.public_key);
-            device_config_changed = true;
+/// The state a newly added peer would get from the server, for bundling into its invitation.
+fn offline_snapshot(
+    interface: &str,
+    peer: &Peer,
+    peers: &[Peer],
+    cidrs: &[Cidr],
+    associations: &[Association],
+) -> State {
+    let visible = reachability::visible_cidrs(cidrs, associations, peer.cidr_id);
+    State {
+        peers: peers
+            .iter()
+            .filter(|p| p.is_redeemed && !p.is_disabled && visible.contains(&p.cidr_id))
+            .cloned()
+            .collect(),
+        cidrs: cidrs.to_vec(),
+        motd: DataStore::open(interface)
+            .ok()
+            .and_then(|store| store.motd().map(str::to_string)),
+    }
+}
+
+fn add_peer(interface: &str, opts: &AddPeerOpts) -> Result<(), Error> {
+    let InterfaceConfig {
+        interface: interface_info,
+        server,
+        ..
+    } = InterfaceConfig::from_interface(interface)?;
+    let local_pattern = match &opts.peer_name_regex {
+        Some(pattern) => Some(pattern.clone()),
+        None => interface_info
+            .peer_name_pattern
+            .as_deref()
+            .map(str::parse)
+            .transpose()?,
+    };
+    println!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+    println!("Fetching peers");
+    let (peers, server_pattern): (Vec<Peer>, _) = http_get_with_header(
+        &server.internal_endpoint,
+        "/admin/peers",
+        PEER_NAME_PATTERN_HEADER,
+    )?;
+    let cidr_tree = CidrTree::new(&cidrs[..]);
+
+    // The server enforces its own convention, so checking names against another one here would
+    // only let through names it refuses.
+    let name_pattern = match server_pattern {
+        Some(pattern) => {
+            let pattern = pattern.parse::<PeerNamePattern>()?;
+            if let Some(local) =
+                local_pattern.filter(|local| local.to_string() != pattern.to_string())
+            {
+                println!(
+                    "{}: the server requires peer names to match {}, so {} doesn't apply.",
+                    "warning".bold().yellow(),
+                    pattern,
+                    local
+                );
+            }
+            Some(pattern)
+        },
+        None => local_pattern,
+    };
+
+    let (peer_request, keypair) = match &opts.name {
+        Some(name) => {
+            if opts.ip.is_none() && !opts.auto_ip {
+                return Err("either --ip or --auto-ip is needed along with --name.".into());
+            }
+            let cidr = opts
+                .cidr
+                .as_deref()
+                .ok_or("--cidr is needed along with --name.")?;
+            prompts::add_peer_unattended(
+                &peers,
+                &cidr_tree,
+                name,
+                cidr,
+                opts.ip,
+                name_pattern.as_ref(),
+            )?
+        },
+        None => match prompts::add_peer(&peers, &cidr_tree, name_pattern.as_ref())? {
+            Some(selection) => selection,
+            None => {
+                println!("exited without creating peer.");
+                return Ok(());
+            },
+        },
+    };
+
+    println!("Creating peer...");
+    // Retrying with the same idempotency key is safe even if the server did create the
+    // peer, but the response was lost on the way back.
+    let idempotency_key = util::idempotency_key()?;
+    let mut attempt = 1;
+    let peer: Peer = loop {
+        match http_post_idempotent(
+            &server.internal_endpoint,
+            "/admin/peers",
+            &peer_request,
+            &idempotency_key,
+        ) {
+            Err(e) if attempt < ADD_PEER_ATTEMPTS && util::is_transient(&e) => {
+                println!("{} request failed ({}), retrying.", "[!]".yellow(), e);
+                attempt += 1;
+                thread::sleep(Duration::from_secs(attempt));
+            },
+            result => {
+                break result.map_err(|e| {
+                    explain_ip_conflict(&server.internal_endpoint, peer_request.ip, e)
+                })?
+            },
         }
+    };
+    let snapshot = if opts.offline_bundle {
+        let associations: Vec<Association> =
+            http_get(&server.internal_endpoint, "/admin/associations")?;
+        Some(offline_snapshot(
+            interface,
+            &peer,
+            &peers,
+            &cidrs,
+            &associations,
+        ))
+    } else {
+        None
+    };
+    let server_peer = peers.iter().find(|p| p.id == 1).unwrap();
+    let invitation = prompts::peer_invitation(
+        interface,
+        &peer,
+        server_peer,
+        &cidr_tree,
+        keypair,
+        &server.internal_endpoint,
+        snapshot,
+    )?;
+    let path = prompts::save_peer_invitation(&peer, &invitation, opts.save_config.as_deref())?;
+    if opts.name.is_some() {
+        // Last and on its own, for scripts to pick up.
+        println!("{}", path.to_string_lossy());
     }
 
-    if device_config_changed {
-        device_config_builder.apply(&interface)?;
+    Ok(())
+}
+
+/// If the server rejected a new peer because its IP is already taken (possibly by a peer added
+/// since the peer list was fetched), say by whom.
+fn explain_ip_conflict(server: &SocketAddr, ip: IpAddr, error: Error) -> Error {
+    if !matches!(
+        error.downcast_ref::<ureq::Error>(),
+        Some(ureq::Error::Status(400, _))
+    ) {
+        return error;
+    }
+    match http_get::<Vec<Peer>>(server, "/admin/peers") {
+        Ok(peers) => match peers.iter().find(|peer| peer.ip == ip) {
+            Some(peer) => format!("{} is already taken by peer {}", ip, peer.name).into(),
+            None => error,
+        },
+        Err(_) => error,
+    }
+}
+
+fn enable_or_disable_peer(interface: &str, enable: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!("Fetching peers.");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+
+    if let Some(peer) = prompts::enable_or_disable_peer(&peers[..], enable)? {
+        let Peer { id, mut contents } = peer;
+        contents.is_disabled = !enable;
+        http_put(
+            &server.internal_endpoint,
+            &format!("/admin/peers/{}", id),
+            contents,
+        )?;
+    } else {
+        println!("exited without disabling peer.");
+    }
 
-        update_hosts_file(interface, &peers)?;
+    Ok(())
+}
+
+fn disable_cidr(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let server = &config.server;
+    println!("Fetching CIDRs.");
+    let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+    println!("Fetching peers.");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
 
+    let cidr = prompts::choose_cidr(&cidrs, "CIDR to disable the peers of")?;
+    // Disabling the server or ourselves would cut this host off from the network.
+    let targets = peers
+        .iter()
+        .filter(|peer| peer.cidr_id == cidr.id && !peer.is_disabled)
+        .filter(|peer| peer.id != 1 && peer.ip != config.interface.address.ip())
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
         println!(
-            "\n{} updated interface {}\n",
+            "{} no enabled peers in {} to disable.",
             "[*]".dimmed(),
-            interface.yellow()
+            cidr.name.yellow()
         );
+        return Ok(());
+    }
+
+    if !Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "Disable {} peers in {} ({})?",
+            targets.len(),
+            cidr.name.yellow(),
+            cidr.cidr
+        ))
+        .default(false)
+        .interact()?
+    {
+        println!("exited without disabling peers.");
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for peer in &targets {
+        let mut contents = peer.contents.clone();
+        contents.is_disabled = true;
+        match http_put(
+            &server.internal_endpoint,
+            &format!("/admin/peers/{}", peer.id),
+            contents,
+        ) {
+            Ok(()) => println!("    {} {} ({})", "disabled".red(), peer.name, peer.ip),
+            Err(e) => {
+                println!(
+                    "    {} failed to disable {}: {}",
+                    "[!]".yellow(),
+                    peer.name,
+                    e
+                );
+                failed += 1;
+            },
+        }
+    }
+    println!(
+        "{} disabled {} of {} peers in {}.",
+        "[*]".dimmed(),
+        targets.len() - failed,
+        targets.len(),
+        cidr.name.yellow()
+    );
+
+    if failed > 0 {
+        Err(format!("failed to disable {} peers", failed).into())
     } else {
-        println!("{}", "    peers are already up to date.".green());
+        Ok(())
     }
-    store.set_cidrs(cidrs);
-    store.add_peers(peers)?;
-    store.write()?;
+}
 
+fn delete_peer(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let server = &config.server;
+    println!("Fetching peers.");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+
+    // Neither the server nor ourselves can be deleted from here.
+    let deletable = peers
+        .iter()
+        .filter(|peer| peer.id != 1 && peer.ip != config.interface.address.ip())
+        .cloned()
+        .collect::<Vec<_>>();
+    let peer = match prompts::delete_peer(&deletable)? {
+        Some(peer) => peer,
+        None => {
+            println!("exited without deleting peer.");
+            return Ok(());
+        },
+    };
+
+    http_delete(
+        &server.internal_endpoint,
+        &format!("/admin/peers/{}?permanent=true", peer.id),
+    )?;
+    // Servers that predate deleting peers ignore the query and only disable them.
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+    if peers.iter().any(|p| p.id == peer.id) {
+        return Err(format!(
+            "the server only disabled {}, since it's too old to delete peers",
+            peer.name
+        )
+        .into());
+    }
+    println!("{} peer {} deleted.", "[*]".dimmed(), peer.name.yellow());
+
+    // Deleting it here is confirmation enough, so this host doesn't keep its IP pinned.
+    if let Some(_lock) = lock_interface(interface)? {
+        let mut store = DataStore::open(interface)?;
+        store.remove_peer(&peer.public_key);
+        store.write()?;
+    }
+    print_release_ip_instructions(&[peer]);
     Ok(())
 }
 
-fn add_cidr(interface: &str) -> Result<(), Error> {
-    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+/// Tell the admin how other hosts can let new peers have the IPs of peers that are gone from
+/// the server, which they otherwise keep pinned to those peers.
+fn print_release_ip_instructions(peers: &[&Peer]) {
+    if peers.is_empty() {
+        return;
+    }
+    println!(
+        "\nUntil \"innernet release-ip <interface> <ip>\" is run on them for each of these IPs, \
+         other peers\nkeep them pinned to the removed peers and refuse any new peer at them:\n"
+    );
+    for peer in peers {
+        println!("    {} ({})", peer.ip.to_string().yellow(), peer.name);
+    }
+    println!();
+}
+
+fn reassign_peer_ip(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let server = &config.server;
     println!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+    println!("Fetching peers");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
 
-    let cidr_request = prompts::add_cidr(&cidrs)?;
+    // Moving the server would cut everyone off from it, and moving ourselves would cut us off.
+    let movable = peers
+        .iter()
+        .filter(|peer| peer.id != 1 && peer.ip != config.interface.address.ip())
+        .cloned()
+        .collect::<Vec<_>>();
+    let (peer, ip) = match prompts::reassign_peer_ip(&movable, &peers, &cidrs)? {
+        Some(selection) => selection,
+        None => {
+            println!("exited without moving peer.");
+            return Ok(());
+        },
+    };
 
-    println!("Creating CIDR...");
-    let cidr: Cidr = http_post(&server.internal_endpoint, "/admin/cidrs", cidr_request)?;
+    let mut contents = peer.contents.clone();
+    contents.ip = ip;
+    http_put(
+        &server.internal_endpoint,
+        &format!("/admin/peers/{}", peer.id),
+        contents,
+    )
+    .map_err(|e| explain_ip_conflict(&server.internal_endpoint, ip, e))?;
+    // Servers that predate moving peers silently keep their IPs.
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+    if !peers.iter().any(|p| p.id == peer.id && p.ip == ip) {
+        return Err(format!(
+            "the server kept {} at {}, since it's too old to move peers",
+            peer.name, peer.ip
+        )
+        .into());
+    }
+    println!(
+        "{} peer {} moved from {} to {}.",
+        "[*]".dimmed(),
+        peer.name.yellow(),
+        peer.ip,
+        ip
+    );
+
+    // Moving it here is confirmation enough, so this host doesn't keep its old IP pinned.
+    if let Some(_lock) = lock_interface(interface)? {
+        let mut store = DataStore::open(interface)?;
+        store.add_peers(peers.into_iter().filter(|p| p.id == peer.id).collect())?;
+        store.release_ip(peer.ip);
+        store.write()?;
+    }
 
+    let prefix = CidrTree::new(&cidrs[..]).interface_prefix(ip);
     printdoc!(
         "
-        CIDR \"{cidr_name}\" added.
 
-        Right now, peers within {cidr_name} can only see peers in the same CIDR
-        , and in the special \"infra\" CIDR that includes the innernet server peer.
+        Other peers pick up the new IP on their next fetch, but {name} itself can't reach the
+        server until it uses it. On {name}, set its address in its interface config
+        ({config_dir}/<interface>.conf) to
 
-        You'll need to add more associations for peers in diffent CIDRs to communicate.
+            address = \"{ip}/{prefix}\"
+
+        and restart it with \"innernet down <interface>\" and \"innernet up <interface>\".
+
+        Until \"innernet release-ip <interface> {old_ip}\" is run on them, other peers keep
+        {old_ip} pinned to {name} and refuse any other peer at it.
         ",
-        cidr_name = cidr.name.bold()
+        name = peer.name.yellow(),
+        config_dir = client_config_path().display(),
+        ip = ip,
+        prefix = prefix,
+        old_ip = peer.ip,
     );
+    Ok(())
+}
+
+fn rename_peer(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let server = &config.server;
+    println!("Fetching peers.");
+    let (peers, server_pattern): (Vec<Peer>, _) = http_get_with_header(
+        &server.internal_endpoint,
+        "/admin/peers",
+        PEER_NAME_PATTERN_HEADER,
+    )?;
+    let name_pattern = server_pattern
+        .as_deref()
+        .or(config.interface.peer_name_pattern.as_deref())
+        .map(str::parse::<PeerNamePattern>)
+        .transpose()?;
+
+    if let Some((peer, name)) = prompts::rename_peer(&peers, name_pattern.as_ref())? {
+        let Peer { id, mut contents } = peer;
+        contents.name = name.clone();
+        http_put(
+            &server.internal_endpoint,
+            &format!("/admin/peers/{}", id),
+            contents,
+        )?;
+        println!("{} peer renamed to {}.", "[*]".dimmed(), name.yellow());
+
+        // The cached peers are only updated on the next fetch, so rename it in the hosts
+        // file from a copy of them.
+        if let (false, Ok(store)) = (config.interface.no_host_updates, DataStore::open(interface)) {
+            let mut cached_peers = store.peers().to_vec();
+            if let Some(peer) = cached_peers.iter_mut().find(|peer| peer.id == id) {
+                peer.contents.name = name;
+                cached_peers.retain(|peer| !peer.is_disabled);
+                update_hosts_file(
+                    interface,
+                    &hosts_template(&config),
+                    config.interface.hosts_tag.as_deref(),
+                    &cached_peers,
+                    store.cidrs(),
+                )?;
+            }
+        }
+    } else {
+        println!("exited without renaming peer.");
+    }
 
     Ok(())
 }
 
-fn add_peer(interface: &str) -> Result<(), Error> {
+fn reap_expired(interface: &str) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!("Fetching peers.");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+
+    // Expired peers that were already disabled (e.g. by an older reap-expired) are deleted
+    // too, freeing up their IPs.
+    let now = SystemTime::now();
+    let expired = peers
+        .iter()
+        .filter(|peer| matches!(peer.expires_at, Some(expires_at) if expires_at <= now))
+        .collect::<Vec<_>>();
+    if expired.is_empty() {
+        println!("{} no expired peers.", "[*]".dimmed());
+        return Ok(());
+    }
+
+    let mut deleted = vec![];
+    for peer in &expired {
+        match http_delete(
+            &server.internal_endpoint,
+            &format!("/admin/peers/{}?permanent=true", peer.id),
+        ) {
+            Ok(()) => {
+                println!("    {} {} ({})", "deleted".red(), peer.name, peer.ip);
+                deleted.push(*peer);
+            },
+            Err(e) => {
+                println!(
+                    "    {} failed to delete {}: {}",
+                    "[!]".yellow(),
+                    peer.name,
+                    e
+                );
+            },
+        }
+    }
+    println!(
+        "{} deleted {} of {} expired peers.",
+        "[*]".dimmed(),
+        deleted.len(),
+        expired.len()
+    );
+
+    // Deleting them here is confirmation enough, so this host doesn't keep their IPs pinned.
+    if let Some(_lock) = lock_interface(interface)? {
+        let mut store = DataStore::open(interface)?;
+        for peer in &deleted {
+            store.remove_peer(&peer.public_key);
+        }
+        store.write()?;
+    }
+    if !deleted.is_empty() {
+        println!(
+            "\nOther peers keep the deleted peers' IPs pinned to them until \
+             \"innernet release-ip <interface> --all-deleted\"\nis run on them, refusing any new \
+             peer at those IPs.\n"
+        );
+    }
+
+    let failed = expired.len() - deleted.len();
+    if failed > 0 {
+        Err(format!("failed to delete {} expired peers", failed).into())
+    } else {
+        Ok(())
+    }
+}
+
+fn set_infra_cidr(interface: &str, infra: bool) -> Result<(), Error> {
     let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
     println!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
     println!("Fetching peers");
     let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
-    let cidr_tree = CidrTree::new(&cidrs[..]);
+    let server_cidr_id = peers
+        .iter()
+        .find(|peer| peer.id == 1)
+        .map(|peer| peer.cidr_id);
 
-    if let Some((peer_request, keypair)) = prompts::add_peer(&peers, &cidr_tree)? {
-        println!("Creating peer...");
-        let peer: Peer = http_post(&server.internal_endpoint, "/admin/peers", peer_request)?;
-        let server_peer = peers.iter().find(|p| p.id == 1).unwrap();
-        prompts::save_peer_invitation(
-            interface,
-            &peer,
-            server_peer,
-            &cidr_tree,
-            keypair,
+    if let Some(cidr) = prompts::set_infra_cidr(&cidrs, infra, server_cidr_id)? {
+        let Cidr { id, mut contents } = cidr;
+        contents.is_infra = infra;
+        http_put(
             &server.internal_endpoint,
+            &format!("/admin/cidrs/{}", id),
+            contents,
         )?;
     } else {
-        println!("exited without creating peer.");
+        println!("exited without changing the CIDR.");
     }
 
     Ok(())
 }
 
-fn enable_or_disable_peer(interface: &str, enable: bool) -> Result<(), Error> {
+fn set_endpoint_override(interface: &str, allowed: bool) -> Result<(), Error> {
     let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
     println!("Fetching peers.");
     let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
 
-    if let Some(peer) = prompts::enable_or_disable_peer(&peers[..], enable)? {
+    if let Some(peer) = prompts::set_endpoint_override(&peers, allowed)? {
         let Peer { id, mut contents } = peer;
-        contents.is_disabled = !enable;
+        contents.endpoint_override_allowed = allowed;
         http_put(
             &server.internal_endpoint,
             &format!("/admin/peers/{}", id),
             contents,
         )?;
     } else {
-        println!("exited without disabling peer.");
+        println!("exited without changing the peer.");
+    }
+
+    Ok(())
+}
+
+fn audit_redemptions(interface: &str, stale_after: Duration) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!("Fetching peers.");
+    let peers: Vec<Peer> = http_get(&server.internal_endpoint, "/admin/peers")?;
+
+    let (redeemed, pending): (Vec<_>, Vec<_>) = peers
+        .iter()
+        .filter(|peer| !peer.is_disabled)
+        .partition(|peer| peer.is_redeemed);
+
+    let age = |peer: &Peer| {
+        peer.created_at
+            .and_then(|created_at| created_at.elapsed().ok())
+    };
+
+    println!("{} ({}):", "redeemed".green().bold(), redeemed.len());
+    for peer in &redeemed {
+        println!(
+            "  {} ({}) created {}",
+            peer.name.yellow(),
+            peer.ip,
+            age(peer).map_or_else(|| "at an unknown time".to_string(), human_duration)
+        );
+    }
+
+    println!("{} ({}):", "pending".yellow().bold(), pending.len());
+    for peer in &pending {
+        let age = age(peer);
+        println!(
+            "  {} ({}) created {}{}",
+            peer.name.yellow(),
+            peer.ip,
+            age.map_or_else(|| "at an unknown time".to_string(), human_duration),
+            if matches!(age, Some(age) if age > stale_after) {
+                format!(" [{}]", "stale invitation".red())
+            } else {
+                String::new()
+            }
+        );
     }
 
     Ok(())
 }
 
-fn add_association(interface: &str) -> Result<(), Error> {
+/// Print which peers would gain or lose reachability to each other by changing the network's
+/// associations from `before` to `after`.
+fn print_association_impact(
+    server: &SocketAddr,
+    cidrs: &[Cidr],
+    before: &[Association],
+    after: &[Association],
+) -> Result<(), Error> {
+    println!("Fetching peers");
+    let peers: Vec<Peer> = http_get(server, "/admin/peers")?;
+    let impact = reachability::impact(cidrs, before, after);
+
+    let print_pairs = |pairs: &[(i64, i64)], text: ColoredString| {
+        let mut count = 0;
+        for (cidr_id_1, cidr_id_2) in pairs {
+            // The impact is already worked out per CIDR, children included, so only the peers
+            // directly in each CIDR belong to its pairs.
+            let members = |id: i64| {
+                peers
+                    .iter()
+                    .filter(|p| !p.is_disabled && p.cidr_id == id)
+                    .collect::<Vec<_>>()
+            };
+            for peer_1 in members(*cidr_id_1) {
+                for peer_2 in members(*cidr_id_2) {
+                    println!(
+                        "    {} <=> {} {}",
+                        peer_1.name.yellow(),
+                        peer_2.name.yellow(),
+                        text
+                    );
+                    count += 1;
+                }
+            }
+        }
+        count
+    };
+    println!();
+    let gained = print_pairs(&impact.gained, "would gain reachability".green());
+    let lost = print_pairs(&impact.lost, "would lose reachability".red());
+    println!(
+        "\n{} {} pairs of peers would gain reachability, and {} would lose it.\n",
+        "[*]".dimmed(),
+        gained,
+        lost
+    );
+    Ok(())
+}
+
+fn add_association(interface: &str, preview: bool) -> Result<(), Error> {
     let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
 
     println!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
 
-    if let Some((cidr1, cidr2)) = prompts::add_association(&cidrs[..])? {
-        http_post(
-            &server.internal_endpoint,
-            "/admin/associations",
-            AssociationContents {
+    if preview {
+        println!("Fetching associations");
+        let associations: Vec<Association> =
+            http_get(&server.internal_endpoint, "/admin/associations")?;
+        let cidr1 = prompts::choose_cidr(&cidrs, "First CIDR")?;
+        let cidr2 = prompts::choose_cidr(&cidrs, "Second CIDR")?;
+        let mut proposed = associations.clone();
+        proposed.push(Association {
+            id: 0,
+            contents: AssociationContents {
                 cidr_id_1: cidr1.id,
                 cidr_id_2: cidr2.id,
+                label: None,
             },
+        });
+        print_association_impact(&server.internal_endpoint, &cidrs, &associations, &proposed)?;
+        println!("exited without adding association (preview only).");
+        return Ok(());
+    }
+
+    if let Some(association_request) = prompts::add_association(&cidrs[..])? {
+        http_post(
+            &server.internal_endpoint,
+            "/admin/associations",
+            association_request,
         )?;
     } else {
         println!("exited without adding association.");
@@ -460,7 +3042,7 @@ fn add_association(interface: &str) -> Result<(), Error> {
     Ok(())
 }
 
-fn delete_association(interface: &str) -> Result<(), Error> {
+fn delete_association(interface: &str, preview: bool) -> Result<(), Error> {
     let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
 
     println!("Fetching CIDRs");
@@ -469,6 +3051,18 @@ fn delete_association(interface: &str) -> Result<(), Error> {
     let associations: Vec<Association> =
         http_get(&server.internal_endpoint, "/admin/associations")?;
 
+    if preview {
+        let association = prompts::choose_association(&associations, &cidrs)?;
+        let proposed = associations
+            .iter()
+            .filter(|a| a.id != association.id)
+            .cloned()
+            .collect::<Vec<_>>();
+        print_association_impact(&server.internal_endpoint, &cidrs, &associations, &proposed)?;
+        println!("exited without deleting association (preview only).");
+        return Ok(());
+    }
+
     if let Some(association) = prompts::delete_association(&associations[..], &cidrs[..])? {
         http_delete(
             &server.internal_endpoint,
@@ -481,6 +3075,36 @@ fn delete_association(interface: &str) -> Result<(), Error> {
     Ok(())
 }
 
+fn list_cidrs(interface: &str, tree: bool, json: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    if json {
+        let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+        println!("{}", OutputFormat::Json.render(&cidrs)?);
+        return Ok(());
+    }
+    println!("Fetching CIDRs");
+    let mut cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+
+    if tree {
+        print_tree(&CidrTree::new(&cidrs), &[], 0, false);
+        return Ok(());
+    }
+    cidrs.sort_by_key(|cidr| (cidr.cidr.network(), cidr.cidr.prefix()));
+    for cidr in &cidrs {
+        println!(
+            "{} {}{}",
+            cidr.cidr.to_string().bold().blue(),
+            cidr.name.blue(),
+            if cidr.is_infra {
+                " (infra)".dimmed().to_string()
+            } else {
+                String::new()
+            }
+        );
+    }
+    Ok(())
+}
+
 fn list_associations(interface: &str) -> Result<(), Error> {
     let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
     println!("Fetching CIDRs");
@@ -491,7 +3115,7 @@ fn list_associations(interface: &str) -> Result<(), Error> {
 
     for association in associations {
         println!(
-            "{}: {} <=> {}",
+            "{}: {} <=> {}{}",
             association.id,
             &cidrs
                 .iter()
@@ -504,17 +3128,362 @@ fn list_associations(interface: &str) -> Result<(), Error> {
                 .find(|c| c.id == association.cidr_id_2)
                 .unwrap()
                 .name
-                .yellow()
+                .yellow(),
+            association
+                .label
+                .as_ref()
+                .map(|label| format!(" ({})", label).dimmed().to_string())
+                .unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+fn reachability_matrix(interface: &str) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+    println!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = http_get(&server.internal_endpoint, "/admin/cidrs")?;
+    println!("Fetching associations");
+    let associations: Vec<Association> =
+        http_get(&server.internal_endpoint, "/admin/associations")?;
+
+    let matrix = reachability::matrix(&cidrs, &associations);
+    let name_width = cidrs.iter().map(|cidr| cidr.name.len()).max().unwrap_or(0);
+    let index_width = cidrs.len().to_string().len();
+
+    println!(
+        "\n{} which CIDRs' peers (columns) the peers in each CIDR (rows) can reach:\n",
+        "[*]".dimmed()
+    );
+    print!("{:width$} ", "", width = index_width + name_width + 1);
+    for i in 1..=cidrs.len() {
+        print!(" {:>width$}", i, width = index_width);
+    }
+    println!();
+    for (i, (cidr, row)) in cidrs.iter().zip(matrix).enumerate() {
+        print!(
+            "{:>index_width$} {:name_width$} ",
+            i + 1,
+            cidr.name.yellow(),
+            index_width = index_width,
+            name_width = name_width
+        );
+        for reachability in row {
+            let cell = match reachability {
+                Reachability::Mutual => "✓".green(),
+                Reachability::OneWay => "~".yellow(),
+                Reachability::Unreachable => "·".dimmed(),
+            };
+            print!(" {:>width$}", cell, width = index_width);
+        }
+        println!();
+    }
+    printdoc!(
+        "
+
+        {mutual} reachable, {one_way} the row's peers are told about the column's, but not the
+        other way around, so they can't connect, {unreachable} not reachable.
+
+        The innernet server can reach every peer. Associations aren't transitive.
+        ",
+        mutual = "✓".green(),
+        one_way = "~".yellow(),
+        unreachable = "·".dimmed(),
+    );
+
+    Ok(())
+}
+
+fn show_routes(interface: &str) -> Result<(), Error> {
+    let routes = routes::get(interface)?;
+    let device_info = DeviceInfo::get_by_name(interface)?;
+    let cidrs = DataStore::open(interface)
+        .map(|store| store.cidrs().to_vec())
+        .unwrap_or_default();
+
+    println!("{} routes through {}:", "[*]".dimmed(), interface.yellow());
+    if routes.is_empty() {
+        println!("    {}", "none".red());
+    }
+    for (i, route) in routes.iter().enumerate() {
+        // The most specific CIDR the route falls within.
+        let cidr = cidrs
+            .iter()
+            .filter(|cidr| cidr.is_ipv4() == route.is_ipv4())
+            .filter(|cidr| cidr.contains(route.network()) && cidr.prefix() <= route.prefix())
+            .max_by_key(|cidr| cidr.prefix());
+        let description = match cidr {
+            Some(cidr) if cidr.cidr == *route => cidr.name.clone(),
+            Some(cidr) => format!("within {} ({})", cidr.name, cidr.cidr),
+            None => "not an innernet CIDR".dimmed().to_string(),
+        };
+        let duplicate = if routes[..i].contains(route) {
+            format!(" {}", "(duplicate)".yellow())
+        } else {
+            String::new()
+        };
+        println!(
+            "    {} {}{}",
+            route.to_string().bold(),
+            description,
+            duplicate
+        );
+    }
+
+    let unrouted = device_info
+        .peers
+        .iter()
+        .flat_map(|info| &info.config.allowed_ips)
+        .filter(|ip| {
+            !routes.iter().any(|route| {
+                route.is_ipv4() == ip.address.is_ipv4()
+                    && route.contains(ip.address)
+                    && route.prefix() <= ip.cidr
+            })
+        })
+        .collect::<Vec<_>>();
+    for ip in &unrouted {
+        println!(
+            "{}: {}/{} is a peer's allowed IP, but isn't routed through {}.",
+            "warning".bold().yellow(),
+            ip.address,
+            ip.cidr,
+            interface
+        );
+    }
+    Ok(())
+}
+
+fn expect_offline(interface: &str, name: &str, unset: bool) -> Result<(), Error> {
+    let mut store = DataStore::open(interface).map_err(|_| {
+        format!(
+            "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+            interface, interface
+        )
+    })?;
+    let public_key = store
+        .peers()
+        .iter()
+        .find(|peer| peer.name == name)
+        .map(|peer| peer.public_key.clone())
+        .ok_or_else(|| format!("no peer named \"{}\" in {}.", name, interface))?;
+    store.set_expected_offline(&public_key, !unset);
+    store.write()?;
+
+    println!(
+        "{} {} is {} expected to be offline.",
+        "[*]".dimmed(),
+        name.yellow(),
+        if unset { "no longer" } else { "now" }
+    );
+    Ok(())
+}
+
+fn release_ip(interface: &str, ip: IpAddr) -> Result<(), Error> {
+    let mut store = DataStore::open(interface).map_err(|_| {
+        format!(
+            "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+            interface, interface
+        )
+    })?;
+    // Releasing a peer that's still on the server would only have it pinned again by the next
+    // fetch, so it's likely the wrong IP.
+    if let Some(peer) = store.peer_at(ip) {
+        let config = InterfaceConfig::from_interface(interface)?;
+        let State { peers, .. } = http_get(&config.server.internal_endpoint, "/user/state")?;
+        if peers
+            .iter()
+            .any(|p| p.ip == ip && p.public_key == peer.public_key)
+        {
+            return Err(format!(
+                "{} is still at {} on the server; delete it first with \"innernet delete-peer\".",
+                peer.name, ip
+            )
+            .into());
+        }
+    }
+    if !store.release_ip(ip) {
+        let pinned = store
+            .moved_from()
+            .iter()
+            .map(|pin| pin.ip.to_string())
+            .collect::<Vec<_>>();
+        return Err(match &pinned[..] {
+            [] => format!("{} isn't pinned to any peer in {}.", ip, interface),
+            _ => format!(
+                "{} isn't pinned to any peer in {}. The IPs peers moved away from are: {}.",
+                ip,
+                interface,
+                pinned.join(", ")
+            ),
+        }
+        .into());
+    }
+    store.write()?;
+
+    println!(
+        "{} {} can now be given to another peer.",
+        "[*]".dimmed(),
+        ip.to_string().yellow()
+    );
+    Ok(())
+}
+
+/// Release the IPs of every cached peer that the server no longer lists.
+fn release_deleted_ips(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let mut store = DataStore::open(interface).map_err(|_| {
+        format!(
+            "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+            interface, interface
+        )
+    })?;
+    let State { peers, .. } = http_get(&config.server.internal_endpoint, "/user/state")?;
+    let deleted = store
+        .peers()
+        .iter()
+        .filter(|cached| !peers.iter().any(|p| p.public_key == cached.public_key))
+        .cloned()
+        .collect::<Vec<_>>();
+    if deleted.is_empty() {
+        println!(
+            "{} the server still lists every cached peer.",
+            "[*]".dimmed()
         );
+        return Ok(());
+    }
+    for peer in &deleted {
+        store.remove_peer(&peer.public_key);
+        println!("    {} {} ({})", "released".green(), peer.ip, peer.name);
     }
+    store.write()?;
 
+    println!(
+        "{} {} IPs can now be given to other peers.",
+        "[*]".dimmed(),
+        deleted.len()
+    );
     Ok(())
 }
 
-fn set_listen_port(interface: &str, unset: bool) -> Result<(), Error> {
+fn verify(interface: &str) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(interface)?;
+    let store = DataStore::open(interface).map_err(|_| {
+        format!(
+            "no cached peers for {} yet; run \"innernet fetch {}\" first.",
+            interface, interface
+        )
+    })?;
+    let device_info = DeviceInfo::get_by_name(interface)?;
+    let public_key = device_info
+        .public_key
+        .as_ref()
+        .ok_or("interface has no private key set.")?
+        .to_base64();
+    let me = store
+        .peers()
+        .iter()
+        .find(|peer| peer.public_key == public_key)
+        .ok_or("missing peer info")?;
+    let peers = store
+        .peers()
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.id != me.id)
+        .collect::<Vec<_>>();
+
+    // Which CIDRs' peers are told about which others is only known to admins.
+    let associations = if me.is_admin {
+        println!("Fetching associations");
+        Some(http_get::<Vec<Association>>(
+            &config.server.internal_endpoint,
+            "/admin/associations",
+        )?)
+    } else {
+        None
+    };
+
+    let mut problems = vec![];
+    for peer in &peers {
+        let prefix = if peer.ip.is_ipv4() { 32 } else { 128 };
+        match device_info
+            .peers
+            .iter()
+            .find(|info| info.config.public_key.to_base64() == peer.public_key)
+        {
+            None => problems.push(format!("{} is missing from the interface.", peer.name)),
+            Some(info)
+                if !info
+                    .config
+                    .allowed_ips
+                    .iter()
+                    .any(|ip| ip.address == peer.ip && ip.cidr == prefix) =>
+            {
+                problems.push(format!(
+                    "{}'s IP {} isn't in its allowed IPs on the interface.",
+                    peer.name, peer.ip
+                ))
+            },
+            Some(_) => {},
+        }
+
+        // The server is configured with every peer, regardless of associations.
+        if let Some(associations) = &associations {
+            if peer.public_key != config.server.public_key
+                && !reachability::visible_cidrs(store.cidrs(), associations, peer.cidr_id)
+                    .contains(&me.cidr_id)
+            {
+                problems.push(format!(
+                    "{} isn't told about you, so it can't connect back to you.",
+                    peer.name
+                ));
+            }
+        }
+    }
+    for info in &device_info.peers {
+        let public_key = info.config.public_key.to_base64();
+        if !store
+            .peers()
+            .iter()
+            .any(|peer| peer.public_key == public_key)
+        {
+            problems.push(format!(
+                "peer ({}) on the interface isn't in the last fetch.",
+                abbreviate_key(&public_key)
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        println!(
+            "{} all {} peers of {} are configured both ways.",
+            "[*]".dimmed(),
+            peers.len(),
+            interface.yellow()
+        );
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("    {} {}", "[!]".yellow(), problem);
+    }
+    if associations.is_none() {
+        println!(
+            "{} only admins can check whether peers are told about you.",
+            "[*]".dimmed()
+        );
+    }
+    Err(format!(
+        "found {} problems, which \"innernet fetch --repair {}\" may fix",
+        problems.len(),
+        interface
+    )
+    .into())
+}
+
+fn set_listen_port(interface: &str, unset: bool, range: Option<PortRange>) -> Result<(), Error> {
     let mut config = InterfaceConfig::from_interface(interface)?;
 
-    if let Some(listen_port) = prompts::set_listen_port(&config.interface, unset)? {
+    if let Some(listen_port) = prompts::set_listen_port(&config.interface, unset, range)? {
         wg::set_listen_port(interface, listen_port)?;
         println!("{} the interface is updated", "[*]".dimmed(),);
 
@@ -528,23 +3497,63 @@ fn set_listen_port(interface: &str, unset: bool) -> Result<(), Error> {
     Ok(())
 }
 
-fn override_endpoint(interface: &str, unset: bool) -> Result<(), Error> {
+fn override_endpoint(
+    interface: &str,
+    unset: bool,
+    endpoint: Option<SocketAddr>,
+    fallbacks: &[SocketAddr],
+) -> Result<(), Error> {
     let config = InterfaceConfig::from_interface(interface)?;
+    // Refuse early if the server has said we aren't allowed to, rather than having it reject the
+    // request after prompting. Without a cached peer list, leave it for the server to decide.
+    let me = DataStore::open(interface).ok().and_then(|store| {
+        store
+            .peers()
+            .iter()
+            .find(|peer| peer.ip == config.interface.address.ip())
+            .cloned()
+    });
+    if let Some(me) = me {
+        if !me.endpoint_override_allowed {
+            return Err(format!(
+                "peer {} isn't allowed to override its endpoint; ask an admin to allow it with \"innernet allow-endpoint-override\".",
+                me.name
+            )
+            .into());
+        }
+    }
+
     if !unset && config.interface.listen_port.is_none() {
+        if endpoint.is_some() {
+            return Err(format!(
+                "you need to set a listen port for your interface first, with \
+                 \"innernet set-listen-port {}\".",
+                interface
+            )
+            .into());
+        }
         println!(
             "{}: you need to set a listen port for your interface first.",
             "note".bold().yellow()
         );
-        set_listen_port(interface, unset)?;
+        set_listen_port(interface, unset, None)?;
     }
 
-    if let Some(endpoint) = prompts::override_endpoint(unset)? {
+    let endpoint = match endpoint {
+        Some(endpoint) => Some(Some(endpoint)),
+        None => prompts::override_endpoint(unset, fallbacks)?,
+    };
+    if let Some(endpoint) = endpoint {
+        let contents = match endpoint {
+            Some(endpoint) if !fallbacks.is_empty() => EndpointContents::SetMultiple(
+                std::iter::once(endpoint)
+                    .chain(fallbacks.iter().copied())
+                    .collect(),
+            ),
+            endpoint => EndpointContents::from(endpoint),
+        };
         println!("Updating endpoint.");
-        http_put(
-            &config.server.internal_endpoint,
-            "/user/endpoint",
-            EndpointContents::from(endpoint),
-        )?;
+        http_put(&config.server.internal_endpoint, "/user/endpoint", contents)?;
     } else {
         println!("exited without overriding endpoint.");
     }
@@ -552,58 +3561,218 @@ fn override_endpoint(interface: &str, unset: bool) -> Result<(), Error> {
     Ok(())
 }
 
-fn show(short: bool, tree: bool, interface: Option<Interface>) -> Result<(), Error> {
+fn show(opts: &ShowOpts, interface: Option<Interface>) -> Result<(), Error> {
+    let ShowOpts {
+        short,
+        tree,
+        infra_only,
+        format,
+        json,
+        limit,
+        sort,
+        reverse,
+        probe,
+        raw_bytes,
+    } = *opts;
+    let format = if json {
+        Some(OutputFormat::Json)
+    } else {
+        format
+    };
+    if format.is_some() {
+        // Keep the output parseable, whatever --color says.
+        colored::control::set_override(false);
+    }
+
     let interfaces = interface.map_or_else(
         || DeviceInfo::enumerate(),
         |interface| Ok(vec![interface.to_string()]),
     )?;
 
-    let devices = interfaces.into_iter().filter_map(|name| {
-        DataStore::open(&name)
-            .and_then(|store| Ok((DeviceInfo::get_by_name(&name)?, store)))
-            .ok()
-    });
+    // Each interface's device query can take a while, so look them all up at once and only
+    // then print them, in order.
+    let lookups = interfaces
+        .into_iter()
+        .map(|name| {
+            thread::spawn(move || {
+                DataStore::open(&name)
+                    .and_then(|store| Ok((DeviceInfo::get_by_name(&name)?, store)))
+                    .ok()
+            })
+        })
+        .collect::<Vec<_>>();
+    let devices = lookups
+        .into_iter()
+        .filter_map(|lookup| lookup.join().ok().flatten());
+    let mut output = ShowOutput::default();
+    let (mut rx_total, mut tx_total, mut online, mut listed_any) = (0, 0, 0, false);
     for (mut device_info, store) in devices {
-        let peers = store.peers();
+        let mut peers = store.peers().to_vec();
         let cidrs = store.cidrs();
         let me = peers
             .iter()
             .find(|p| p.public_key == device_info.public_key.as_ref().unwrap().to_base64())
-            .ok_or("missing peer info")?;
+            .ok_or("missing peer info")?
+            .clone();
 
-        print_interface(&device_info, me, short)?;
-        // Sort the peers by last handshake time (descending),
-        // then by IP address (ascending)
-        device_info.peers.sort_by_key(|peer| {
-            let our_peer = peers
-                .iter()
-                .find(|p| p.public_key == peer.config.public_key.to_base64())
-                .ok_or("missing peer info")
-                .unwrap();
+        if infra_only {
+            let infra_cidr_ids: HashSet<i64> = if cidrs.iter().any(|c| c.is_infra) {
+                cidrs
+                    .iter()
+                    .filter(|c| c.is_infra)
+                    .flat_map(|c| reachability::subtree(cidrs, c.id))
+                    .collect()
+            } else {
+                // Servers from before CIDRs could be flagged as infra don't say which ones are,
+                // but the innernet server is always in one.
+                peers
+                    .iter()
+                    .find(|p| p.id == 1)
+                    .map(|server_peer| std::iter::once(server_peer.cidr_id).collect())
+                    .ok_or("unable to find the innernet server peer in the local peer cache")?
+            };
+            peers.retain(|p| infra_cidr_ids.contains(&p.cidr_id));
+            device_info.peers.retain(|peer| {
+                let public_key = peer.config.public_key.to_base64();
+                peers.iter().any(|p| p.public_key == public_key)
+            });
+        }
 
-            (
-                std::cmp::Reverse(peer.stats.last_handshake_time),
-                our_peer.ip,
-            )
+        if format.is_some() {
+            let mut state = InterfaceState::new(&device_info, &me, &peers, cidrs);
+            for peer in &mut state.peers {
+                peer.expected_offline = store.is_expected_offline(&peer.peer.public_key);
+            }
+            output.interfaces.push(state);
+            continue;
+        }
+
+        print_interface(&device_info, &me, short)?;
+        if !short {
+            // Everything but the live stats comes from the cache, which goes stale while the
+            // server is unreachable.
+            let fetched = store
+                .fetched_at()
+                .and_then(|time| time.elapsed().ok())
+                .map_or_else(|| "never".to_string(), human_duration);
+            println!("  {}: {}", "data last fetched".bold(), fetched);
+        }
+        listed_any = true;
+        for peer in &device_info.peers {
+            rx_total += peer.stats.rx_bytes;
+            tx_total += peer.stats.tx_bytes;
+            let recent = peer
+                .stats
+                .last_handshake_time
+                .and_then(|time| time.elapsed().ok())
+                .map(|elapsed| elapsed < health::MAX_HANDSHAKE_AGE)
+                .unwrap_or(false);
+            if recent {
+                online += 1;
+            }
+        }
+        device_info.peers.sort_by(|a, b| {
+            let our_peer = |peer: &PeerInfo| {
+                peers
+                    .iter()
+                    .find(|p| p.public_key == peer.config.public_key.to_base64())
+                    .ok_or("missing peer info")
+                    .unwrap()
+            };
+            let ordering = sort.compare((our_peer(a), a), (our_peer(b), b));
+            if reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
         });
 
         if tree {
             let cidr_tree = CidrTree::new(&cidrs[..]);
-            print_tree(&cidr_tree, &peers, 1);
+            print_tree(&cidr_tree, &peers, 1, short);
         } else {
-            for peer in device_info.peers {
-                let our_peer = peers
+            // Peers marked as intentionally offline are left out of the main listing (and
+            // --limit), so that they don't drown out the ones that shouldn't be.
+            let (expected_offline, active): (Vec<_>, Vec<_>) = device_info
+                .peers
+                .iter()
+                .partition(|peer| store.is_expected_offline(&peer.config.public_key.to_base64()));
+            let our_peer = |peer: &PeerInfo| {
+                peers
                     .iter()
                     .find(|p| p.public_key == peer.config.public_key.to_base64())
-                    .ok_or("missing peer info")?;
-                print_peer(our_peer, &peer, short)?;
+                    .ok_or("missing peer info")
+            };
+            let total = active.len();
+            let shown = if limit == 0 { total } else { limit.min(total) };
+            let listed = active
+                .into_iter()
+                .take(shown)
+                .map(|peer| Ok((our_peer(peer)?, peer)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let reachable = probe.map(|probe| {
+                let ips = listed.iter().map(|(p, _)| p.ip).collect::<Vec<_>>();
+                probe.probe_all(&ips)
+            });
+            for (i, (our_peer, peer)) in listed.into_iter().enumerate() {
+                let reachable = reachable.as_ref().map(|reachable| reachable[i]);
+                print_peer(our_peer, peer, short, raw_bytes, reachable)?;
+            }
+            if shown < total {
+                println!(
+                    "{}",
+                    format!("... and {} more (use --limit 0 for all)", total - shown).dimmed()
+                );
+            }
+            if !expected_offline.is_empty() {
+                println!(
+                    "{}",
+                    format!("expected offline ({}):", expected_offline.len()).dimmed()
+                );
+                for peer in expected_offline {
+                    let our_peer = our_peer(peer)?;
+                    println!(
+                        "  {}",
+                        format!("{}: {}", our_peer.ip, our_peer.name).dimmed()
+                    );
+                }
             }
         }
     }
+
+    if let Some(format) = format {
+        println!("{}", output.render(format)?);
+    } else if listed_any && !short {
+        let size = |bytes| {
+            if raw_bytes {
+                format!("{} {}", bytes, "B".cyan())
+            } else {
+                human_size(bytes)
+            }
+        };
+        println!(
+            "\n{}: {} received, {} sent, {} {}",
+            "total".bold(),
+            size(rx_total),
+            size(tx_total),
+            online,
+            "online".green()
+        );
+    }
     Ok(())
 }
 
-fn print_tree(cidr: &CidrTree, peers: &[Peer], level: usize) {
+fn tree_has_peers(cidr: &CidrTree, peers: &[Peer]) -> bool {
+    peers.iter().any(|p| p.cidr_id == cidr.id)
+        || cidr.children().any(|child| tree_has_peers(&child, peers))
+}
+
+/// Print the CIDR hierarchy with each CIDR's peers. The short version leaves out CIDRs
+/// without any peers beneath them, and lists peers like "show --short" does.
+fn print_tree(cidr: &CidrTree, peers: &[Peer], level: usize, short: bool) {
+    if short && !tree_has_peers(cidr, peers) {
+        return;
+    }
     println!(
         "{:pad$}{} {}",
         "",
@@ -613,16 +3782,26 @@ fn print_tree(cidr: &CidrTree, peers: &[Peer], level: usize) {
     );
 
     cidr.children()
-        .for_each(|child| print_tree(&child, peers, level + 1));
+        .for_each(|child| print_tree(&child, peers, level + 1, short));
 
     for peer in peers.iter().filter(|p| p.cidr_id == cidr.id) {
-        println!(
-            "{:pad$}| {} {}",
-            "",
-            peer.ip.to_string().yellow().bold(),
-            peer.name.yellow(),
-            pad = level * 2
-        );
+        if short {
+            println!(
+                "{:pad$}  {}: {}",
+                "",
+                peer.ip.to_string().yellow().bold(),
+                peer.name.yellow(),
+                pad = level * 2
+            );
+        } else {
+            println!(
+                "{:pad$}| {} {}",
+                "",
+                peer.ip.to_string().yellow().bold(),
+                peer.name.yellow(),
+                pad = level * 2
+            );
+        }
     }
 }
 
@@ -636,116 +3815,348 @@ fn print_interface(device_info: &DeviceInfo, me: &Peer, short: bool) -> Result<(
     if short {
         println!("{}", device_info.name.green().bold());
         println!(
-            "  {} {}: {} ({}...)",
+            "  {} {}: {} ({})",
             "(you)".bold(),
             me.ip.to_string().yellow().bold(),
             me.name.yellow(),
-            public_key[..10].dimmed()
+            abbreviate_key(&public_key).dimmed()
         );
     } else {
         println!(
-            "{}: {} ({}...)",
+            "{}: {} ({})",
             "interface".green().bold(),
             device_info.name.green(),
-            public_key[..10].yellow()
+            abbreviate_key(&public_key).yellow()
         );
         if !short {
             if let Some(listen_port) = device_info.listen_port {
                 println!("  {}: {}", "listening_port".bold(), listen_port);
             }
-            println!("  {}: {}", "ip".bold(), me.ip);
+            // Fall back to our own IP if the interface's addresses can't be listed.
+            let addresses = wg::get_addrs(&device_info.name)
+                .ok()
+                .filter(|addrs| !addrs.is_empty())
+                .map(|addrs| {
+                    addrs
+                        .iter()
+                        .map(|addr| addr.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                })
+                .unwrap_or_else(|| me.ip.to_string());
+            println!("  {}: {}", "addresses".bold(), addresses);
         }
     }
     Ok(())
 }
 
-fn print_peer(our_peer: &Peer, peer: &PeerInfo, short: bool) -> Result<(), Error> {
+fn reachability(reachable: bool) -> ColoredString {
+    if reachable {
+        "reachable".green()
+    } else {
+        "unreachable".red()
+    }
+}
+
+/// The "last handshake" label, colored by how recent the handshake was: green while the
+/// session is live, yellow for a while after, and red once it's been gone for long.
+fn handshake_label(age: Option<Duration>) -> ColoredString {
+    let label = "last handshake".bold();
+    match age {
+        Some(age) if age < health::MAX_HANDSHAKE_AGE => label.green(),
+        Some(age) if age < STALE_HANDSHAKE_AGE => label.yellow(),
+        _ => label.red(),
+    }
+}
+
+fn print_peer(
+    our_peer: &Peer,
+    peer: &PeerInfo,
+    short: bool,
+    raw_bytes: bool,
+    reachable: Option<bool>,
+) -> Result<(), Error> {
+    let size = |bytes| {
+        if raw_bytes {
+            format!("{} {}", bytes, "B".cyan())
+        } else {
+            human_size(bytes)
+        }
+    };
+
     if short {
         println!(
-            "  {}: {} ({}...)",
+            "  {}: {} ({}){}",
             peer.config.allowed_ips[0]
                 .address
                 .to_string()
                 .yellow()
                 .bold(),
             our_peer.name.yellow(),
-            &our_peer.public_key[..10].dimmed()
+            abbreviate_key(&our_peer.public_key).dimmed(),
+            reachable
+                .map(|reachable| format!(" {}", reachability(reachable)))
+                .unwrap_or_default()
         );
     } else {
         println!(
-            "{}: {} ({}...)",
+            "{}: {} ({})",
             "peer".yellow().bold(),
             our_peer.name.yellow(),
-            &our_peer.public_key[..10].yellow()
+            abbreviate_key(&our_peer.public_key).yellow()
         );
         println!("  {}: {}", "ip".bold(), our_peer.ip);
         if let Some(endpoint) = our_peer.endpoint {
             println!("  {}: {}", "endpoint".bold(), endpoint);
         }
-        if let Some(last_handshake) = peer.stats.last_handshake_time {
-            let duration = last_handshake.elapsed()?;
-            println!(
-                "  {}: {}",
-                "last handshake".bold(),
-                human_duration(duration),
-            );
+        match peer.stats.last_handshake_time {
+            Some(last_handshake) => {
+                let duration = last_handshake.elapsed()?;
+                println!(
+                    "  {}: {}",
+                    handshake_label(Some(duration)),
+                    if raw_bytes {
+                        format!("{} {} ago", duration.as_secs(), "seconds".cyan())
+                    } else {
+                        human_duration(duration)
+                    },
+                );
+            },
+            None => println!("  {}: {}", handshake_label(None), "never".red()),
         }
         if peer.stats.tx_bytes > 0 || peer.stats.rx_bytes > 0 {
             println!(
                 "  {}: {} received, {} sent",
                 "transfer".bold(),
-                human_size(peer.stats.rx_bytes),
-                human_size(peer.stats.tx_bytes),
+                size(peer.stats.rx_bytes),
+                size(peer.stats.tx_bytes),
             );
         }
+        if let Some(reachable) = reachable {
+            println!("  {}: {}", "probe".bold(), reachability(reachable));
+        }
     }
 
     Ok(())
 }
 
 fn main() {
-    let opt = Opt::from_args();
+    let matches = Opt::clap().get_matches();
+    let opt = Opt::from_clap(&matches);
+    opt.color.apply();
 
-    if let Err(e) = run(opt) {
+    if let Err(e) = run(opt, matches.subcommand_name().unwrap_or("show")) {
         eprintln!("\n{} {}\n", "[ERROR]".red(), e);
         std::process::exit(1);
     }
 }
 
-fn run(opt: Opt) -> Result<(), Error> {
-    if unsafe { libc::getuid() } != 0 {
+fn run(opt: Opt, command_name: &str) -> Result<(), Error> {
+    if let Some(Command::Man) = opt.command {
+        print!("{}", man::render(VERSION));
+        return Ok(());
+    }
+    if let Some(Command::Completions { shell }) = opt.command {
+        Opt::clap().gen_completions_to("innernet", shell, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    if opt.config_dir.is_none() && unsafe { libc::getuid() } != 0 {
         return Err("innernet must run as root.".into());
     }
+    shared::set_client_dir(opt.config_dir);
 
     let command = opt.command.unwrap_or(Command::Show {
-        short: false,
-        tree: false,
+        opts: ShowOpts::default(),
         interface: None,
     });
 
+    util::set_http_bind(match (opt.bind_interface, opt.bind_address) {
+        (Some(interface), _) => Some(HttpBind::Interface(interface)),
+        (None, Some(address)) => Some(HttpBind::Address(address)),
+        (None, None) => None,
+    });
+
+    if opt.explain {
+        explain::print_explanation(command_name, &command);
+        if !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Proceed?")
+            .default(false)
+            .interact()?
+        {
+            println!("exited without running the command.");
+            return Ok(());
+        }
+    }
+
+    let auto_fetch_interface = if opt.auto_fetch {
+        command.admin_mutation_interface().cloned()
+    } else {
+        None
+    };
+
     match command {
-        Command::Install { config } => install(&config)?,
-        Command::Show {
-            short,
-            tree,
+        Command::Install {
+            config,
+            reinstall,
+            no_interface,
+        } => install(&config, reinstall, no_interface)?,
+        Command::InstallAll { configs } => install_all(&configs)?,
+        Command::Validate { config } => validate::validate(&config)?,
+        Command::Show { opts, interface } => show(&opts, interface)?,
+        Command::Fetch {
             interface,
-        } => show(short, tree, interface)?,
-        Command::Fetch { interface } => fetch(&interface, false)?,
+            repair,
+            interactive,
+            dry_run,
+            fetch_opts,
+        } => {
+            if let Some(_lock) = lock_interface(&interface)? {
+                let fetch_opts = FetchOpts {
+                    repair,
+                    interactive,
+                    dry_run,
+                    ..fetch_opts
+                };
+                fetch(&interface, false, &fetch_opts)?
+            }
+        },
         Command::Up {
             interface,
             daemon,
             interval,
-        } => up(&interface, daemon.then(|| Duration::from_secs(interval)))?,
+            watch_network,
+            health_listen,
+            stats_csv,
+            metrics_listen,
+            metrics_port,
+            max_concurrent_fetches,
+            nice,
+            ionice,
+            down_on_exit,
+            fetch_opts,
+        } => {
+            util::set_process_priority(nice, ionice)?;
+            if let Some(_lock) = lock_interface(&interface)? {
+                up(
+                    &interface,
+                    daemon.then_some(interval),
+                    watch_network,
+                    &DaemonOpts {
+                        health_listen,
+                        stats_csv: stats_csv.as_deref(),
+                        metrics_listen: metrics_listen
+                            .or_else(|| metrics_port.map(|port| ([0, 0, 0, 0], port).into())),
+                        max_concurrent_fetches,
+                        down_on_exit,
+                    },
+                    &fetch_opts,
+                )?
+            }
+        },
+        Command::UpdateHosts { interface, tag } => update_hosts(&interface, tag)?,
+        Command::Diff {
+            interface_a,
+            interface_b,
+        } => diff_interfaces(&interface_a, &interface_b)?,
+        Command::Events { interface } => show_events(&interface)?,
+        Command::Inventory { interface, format } => print_inventory(&interface, format)?,
+        Command::Routes { interface } => show_routes(&interface)?,
+        Command::ExpectOffline {
+            interface,
+            peer,
+            unset,
+        } => {
+            if let Some(_lock) = lock_interface(&interface)? {
+                expect_offline(&interface, &peer, unset)?
+            }
+        },
+        Command::ReleaseIp {
+            interface,
+            ip,
+            all_deleted,
+        } => {
+            if let Some(_lock) = lock_interface(&interface)? {
+                match ip {
+                    Some(ip) if !all_deleted => release_ip(&interface, ip)?,
+                    _ => release_deleted_ips(&interface)?,
+                }
+            }
+        },
+        Command::PublicKey { interface } => print_public_key(&interface)?,
+        Command::Status { interface } => print_status(&interface)?,
+        Command::ExportWgQuick {
+            interface,
+            all_peers,
+            output,
+        } => export_wg_quick(&interface, all_peers, output.as_deref())?,
+        Command::Ping { interface, count } => ping_server(&interface, count)?,
         Command::Down { interface } => wg::down(&interface)?,
-        Command::AddPeer { interface } => add_peer(&interface)?,
-        Command::AddCidr { interface } => add_cidr(&interface)?,
+        Command::AddPeer { interface, opts } => add_peer(&interface, &opts)?,
+        Command::AddCidr {
+            interface,
+            name,
+            cidr,
+            parent,
+        } => add_cidr(&interface, name.as_deref(), cidr, parent.as_deref())?,
+        Command::DeleteCidr { interface } => delete_cidr(&interface)?,
         Command::DisablePeer { interface } => enable_or_disable_peer(&interface, false)?,
         Command::EnablePeer { interface } => enable_or_disable_peer(&interface, true)?,
-        Command::AddAssociation { interface } => add_association(&interface)?,
-        Command::DeleteAssociation { interface } => delete_association(&interface)?,
+        Command::DisableCidr { interface } => disable_cidr(&interface)?,
+        Command::DeletePeer { interface } => delete_peer(&interface)?,
+        Command::ReassignPeerIp { interface } => reassign_peer_ip(&interface)?,
+        Command::RenamePeer { interface } => rename_peer(&interface)?,
+        Command::ReapExpired { interface } => reap_expired(&interface)?,
+        Command::AuditRedemptions {
+            interface,
+            stale_after_days,
+        } => audit_redemptions(
+            &interface,
+            Duration::from_secs(stale_after_days * 60 * 60 * 24),
+        )?,
+        Command::SetInfraCidr { interface, unset } => set_infra_cidr(&interface, !unset)?,
+        Command::AllowEndpointOverride {
+            interface,
+            disallow,
+        } => set_endpoint_override(&interface, !disallow)?,
+        Command::AddAssociation { interface, preview } => add_association(&interface, preview)?,
+        Command::DeleteAssociation { interface, preview } => {
+            delete_association(&interface, preview)?
+        },
+        Command::ListCidrs {
+            interface,
+            tree,
+            json,
+        } => list_cidrs(&interface, tree, json)?,
         Command::ListAssociations { interface } => list_associations(&interface)?,
-        Command::SetListenPort { interface, unset } => set_listen_port(&interface, unset)?,
-        Command::OverrideEndpoint { interface, unset } => override_endpoint(&interface, unset)?,
+        Command::MigratePeers {
+            from_interface,
+            to_interface,
+        } => migrate::migrate_peers(&from_interface, &to_interface)?,
+        Command::ReachabilityMatrix { interface } => reachability_matrix(&interface)?,
+        Command::Verify { interface } => verify(&interface)?,
+        Command::SetListenPort {
+            interface,
+            unset,
+            range,
+        } => set_listen_port(&interface, unset, range)?,
+        Command::OverrideEndpoint {
+            interface,
+            unset,
+            endpoint,
+            fallbacks,
+        } => override_endpoint(&interface, unset, endpoint, &fallbacks)?,
+        Command::Man => unreachable!("the man page is printed before checking for root"),
+        Command::Completions { .. } => {
+            unreachable!("completions are printed before checking for root")
+        },
+    }
+
+    // Nothing to pick up if the command was declined before changing anything.
+    if let (Some(interface), true) = (auto_fetch_interface, util::made_changes()) {
+        if let Some(_lock) = lock_interface(&interface)? {
+            fetch(&interface, false, &FetchOpts::default())?;
+        }
     }
 
     Ok(())
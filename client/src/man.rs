@@ -0,0 +1,214 @@
+//! Generates a roff man page from the CLI's help output, so that it stays in sync with the actual
+//! commands and options (and their doc comments) instead of being maintained by hand.
+
+use crate::help;
+
+/// Escape text for roff, so that hyphens, backslashes, and lines starting with control
+/// characters aren't interpreted as formatting.
+fn escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A flag, option, argument, or subcommand listed in a help section, e.g. "-d, --daemon".
+#[derive(Debug, Default, PartialEq)]
+struct Entry {
+    names: String,
+    help: String,
+}
+
+/// A parsed `--help` output: the description, usage line, and the entries of each section by
+/// its heading (e.g. "FLAGS").
+#[derive(Debug, Default)]
+struct Help {
+    about: String,
+    usage: String,
+    sections: Vec<(String, Vec<Entry>)>,
+}
+
+impl Help {
+    /// Lines with a heading's indentation start an entry, while more deeply indented ones
+    /// continue its help, which clap either aligns after the names or wraps onto the next lines.
+    fn parse(text: &str) -> Self {
+        let mut help = Help::default();
+        let mut lines = text.lines().skip(1);
+        help.about = lines
+            .by_ref()
+            .take_while(|line| *line != "USAGE:")
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim()
+            .to_string();
+        for line in lines {
+            let indent = line.len() - line.trim_start().len();
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if indent == 0 {
+                help.sections
+                    .push((trimmed.trim_end_matches(':').to_string(), vec![]));
+                continue;
+            }
+            let (heading, entries) = match help.sections.last_mut() {
+                Some(section) => section,
+                None => {
+                    help.usage = trimmed.to_string();
+                    continue;
+                },
+            };
+            let starts_entry =
+                indent <= 8 && (heading == "SUBCOMMANDS" || trimmed.starts_with(&['-', '<'][..]));
+            match entries.last_mut() {
+                Some(entry) if !starts_entry => {
+                    if !entry.help.is_empty() {
+                        entry.help.push(' ');
+                    }
+                    entry.help.push_str(trimmed);
+                },
+                _ => {
+                    let mut parts = trimmed.splitn(2, "  ");
+                    entries.push(Entry {
+                        names: parts.next().unwrap_or_default().to_string(),
+                        help: parts.next().unwrap_or_default().trim().to_string(),
+                    });
+                },
+            }
+        }
+        help
+    }
+
+    fn entries<'a>(&'a self, heading: &'a str) -> impl Iterator<Item = &'a Entry> {
+        self.sections
+            .iter()
+            .filter(move |(name, _)| name == heading)
+            .flat_map(|(_, entries)| entries)
+    }
+}
+
+/// Write entries as roff tagged paragraphs.
+fn write_entries<'a>(entries: impl Iterator<Item = &'a Entry>, out: &mut String) {
+    for entry in entries {
+        out.push_str(&format!(
+            ".TP\n\\fB{}\\fR\n{}\n",
+            escape(&entry.names),
+            escape(&entry.help)
+        ));
+    }
+}
+
+/// Render the man page for innernet and all of its (non-hidden) subcommands.
+pub fn render(version: &str) -> String {
+    let top = Help::parse(&help::help(None));
+    let mut out = String::new();
+
+    out.push_str(&format!(
+        ".TH INNERNET 8 \"\" \"innernet {}\"\n",
+        escape(version)
+    ));
+    out.push_str(&format!(".SH NAME\ninnernet \\- {}\n", escape(&top.about)));
+    out.push_str(".SH SYNOPSIS\n\\fBinnernet\\fR [\\fIOPTIONS\\fR] [\\fICOMMAND\\fR]\n");
+
+    out.push_str(".SH OPTIONS\n");
+    let globals = top
+        .entries("FLAGS")
+        .chain(top.entries("OPTIONS"))
+        .collect::<Vec<_>>();
+    write_entries(globals.iter().copied(), &mut out);
+
+    out.push_str(".SH COMMANDS\n");
+    for subcommand in top
+        .entries("SUBCOMMANDS")
+        .filter(|entry| entry.names != "help")
+    {
+        let help = Help::parse(&help::help(Some(&subcommand.names)));
+        out.push_str(&format!(
+            ".SS \"innernet {}\"\n{}\n",
+            escape(&subcommand.names),
+            escape(&help.usage)
+        ));
+        if !help.about.is_empty() {
+            out.push_str(&format!(".PP\n{}\n", escape(&help.about)));
+        }
+        // The global options are only described once, above.
+        let is_global = |entry: &&Entry| globals.iter().any(|global| global.names == entry.names);
+        write_entries(
+            help.entries("FLAGS")
+                .chain(help.entries("OPTIONS"))
+                .chain(help.entries("ARGS"))
+                .filter(|entry| !is_global(entry)),
+            &mut out,
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_help() {
+        let help = Help::parse(
+            "innernet-up 1.0.0\n\
+             Bring up your local interface\n\
+             \n\
+             USAGE:\n    innernet up [FLAGS] <interface>\n\
+             \n\
+             FLAGS:\n\
+             \x20   -d, --daemon     Enable daemon mode, keeping the process running,\n\
+             \x20                    while fetching\n\
+             \x20       --timing     Print timings\n\
+             \n\
+             OPTIONS:\n\
+             \x20       --interval <interval>\n\
+             \x20           Fetch at this interval [default: 60]\n\
+             \n\
+             ARGS:\n    <interface>    \n",
+        );
+        assert_eq!(help.about, "Bring up your local interface");
+        assert_eq!(help.usage, "innernet up [FLAGS] <interface>");
+        assert_eq!(
+            help.entries("FLAGS").collect::<Vec<_>>(),
+            vec![
+                &Entry {
+                    names: "-d, --daemon".to_string(),
+                    help: "Enable daemon mode, keeping the process running, while fetching"
+                        .to_string(),
+                },
+                &Entry {
+                    names: "--timing".to_string(),
+                    help: "Print timings".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            help.entries("OPTIONS").next().unwrap(),
+            &Entry {
+                names: "--interval <interval>".to_string(),
+                help: "Fetch at this interval [default: 60]".to_string(),
+            }
+        );
+        assert_eq!(help.entries("ARGS").next().unwrap().names, "<interface>");
+    }
+
+    #[test]
+    fn test_render() {
+        let page = render("1.0.0");
+        assert!(page.contains(".SS \"innernet up\"\n"));
+        assert!(page.contains("\\fB\\-d, \\-\\-daemon\\fR"));
+        // Global options are only listed once.
+        assert_eq!(page.matches("\\-\\-explain").count(), 1);
+    }
+}
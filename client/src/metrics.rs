@@ -0,0 +1,152 @@
+//! Peer statistics in the Prometheus text format for `innernet up --daemon --metrics-listen`,
+//! so that a mesh can be monitored (and alerted on peers that stop handshaking) by scraping
+//! `/metrics`.
+//!
+//! The stats are read from the WireGuard interface on every scrape, and peers are named after
+//! the last fetched peer list.
+
+use crate::{data_store::DataStore, http_server, Error};
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use wgctrl::DeviceInfo;
+
+struct PeerSample {
+    name: String,
+    public_key: String,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    handshake_age: Option<Duration>,
+}
+
+/// Start serving in the background.
+pub fn start(addr: SocketAddr, interface: &str) -> Result<(), Error> {
+    let interface = interface.to_string();
+    http_server::serve(
+        addr,
+        "metrics scrapes",
+        "text/plain; version=0.0.4",
+        move |request| {
+            if !request.starts_with("GET /metrics") {
+                return ("404 Not Found", "only /metrics is served\n".to_string());
+            }
+            match samples(&interface) {
+                Ok(samples) => ("200 OK", render(&interface, &samples)),
+                Err(e) => ("503 Service Unavailable", format!("{}\n", e)),
+            }
+        },
+    )
+}
+
+fn samples(interface: &str) -> Result<Vec<PeerSample>, Error> {
+    let device_info = DeviceInfo::get_by_name(interface)?;
+    let peers = DataStore::open(interface)
+        .map(|store| store.peers().to_vec())
+        .unwrap_or_default();
+    let now = SystemTime::now();
+    Ok(device_info
+        .peers
+        .into_iter()
+        .map(|info| {
+            let public_key = info.config.public_key.to_base64();
+            PeerSample {
+                name: peers
+                    .iter()
+                    .find(|peer| peer.public_key == public_key)
+                    .map_or_else(String::new, |peer| peer.name.clone()),
+                public_key,
+                rx_bytes: info.stats.rx_bytes,
+                tx_bytes: info.stats.tx_bytes,
+                handshake_age: info
+                    .stats
+                    .last_handshake_time
+                    .and_then(|time| now.duration_since(time).ok()),
+            }
+        })
+        .collect())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A gauge's name (after "innernet_peer_"), its help text, and its value for a peer, if any.
+type Gauge = (&'static str, &'static str, fn(&PeerSample) -> Option<u64>);
+
+const GAUGES: [Gauge; 3] = [
+    ("rx_bytes", "Bytes received from the peer.", |s| {
+        Some(s.rx_bytes)
+    }),
+    ("tx_bytes", "Bytes sent to the peer.", |s| Some(s.tx_bytes)),
+    (
+        "last_handshake_seconds",
+        "Seconds since the last handshake with the peer.",
+        |s| s.handshake_age.map(|age| age.as_secs()),
+    ),
+];
+
+/// Render the samples as Prometheus gauges. Peers that have never handshaked have no
+/// `last_handshake_seconds` sample.
+fn render(interface: &str, samples: &[PeerSample]) -> String {
+    let mut metrics = String::new();
+    for (name, help, value) in &GAUGES {
+        let _ = writeln!(metrics, "# HELP innernet_peer_{} {}", name, help);
+        let _ = writeln!(metrics, "# TYPE innernet_peer_{} gauge", name);
+        for sample in samples {
+            if let Some(value) = value(sample) {
+                let _ = writeln!(
+                    metrics,
+                    "innernet_peer_{}{{interface=\"{}\",peer=\"{}\",public_key=\"{}\"}} {}",
+                    name,
+                    escape_label(interface),
+                    escape_label(&sample.name),
+                    escape_label(&sample.public_key),
+                    value
+                );
+            }
+        }
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let samples = vec![
+            PeerSample {
+                name: "laptop".to_string(),
+                public_key: "bGFwdG9w".to_string(),
+                rx_bytes: 1024,
+                tx_bytes: 2048,
+                handshake_age: Some(Duration::from_secs(42)),
+            },
+            PeerSample {
+                name: "a \"quoted\" name".to_string(),
+                public_key: "cGhvbmU=".to_string(),
+                rx_bytes: 0,
+                tx_bytes: 0,
+                handshake_age: None,
+            },
+        ];
+        let metrics = render("evilcorp", &samples);
+        assert!(metrics.contains(
+            "innernet_peer_rx_bytes{interface=\"evilcorp\",peer=\"laptop\",\
+             public_key=\"bGFwdG9w\"} 1024\n"
+        ));
+        assert!(metrics.contains(
+            "innernet_peer_last_handshake_seconds{interface=\"evilcorp\",peer=\"laptop\",\
+             public_key=\"bGFwdG9w\"} 42\n"
+        ));
+        assert!(metrics.contains("peer=\"a \\\"quoted\\\" name\""));
+        assert_eq!(metrics.matches("last_handshake_seconds{").count(), 1);
+        assert_eq!(metrics.matches("# TYPE").count(), 3);
+    }
+}
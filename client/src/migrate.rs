@@ -0,0 +1,260 @@
+//! Recreating one network's CIDRs, peers, and associations on another server, for migrating
+//! to a new innernet server.
+//!
+//! Everything is read from the source server's admin endpoints and created through the
+//! destination server's, matching up CIDRs by name (the root CIDRs are always matched with each
+//! other). Peers keep their IPs and public keys, so that once they're re-pointed at the new
+//! server they don't need to be invited again.
+
+use crate::{
+    util::{http_get, http_post},
+    Error,
+};
+use colored::*;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use shared::{
+    interface_config::InterfaceConfig, Association, AssociationContents, Cidr, CidrContents, Peer,
+    PeerContents,
+};
+use std::{collections::HashMap, net::SocketAddr};
+
+struct Network {
+    server: SocketAddr,
+    cidrs: Vec<Cidr>,
+    peers: Vec<Peer>,
+    associations: Vec<Association>,
+}
+
+impl Network {
+    fn fetch(interface: &str) -> Result<Self, Error> {
+        let InterfaceConfig { server, .. } = InterfaceConfig::from_interface(interface)?;
+        let server = server.internal_endpoint;
+        println!("Fetching CIDRs, peers, and associations of {}", interface);
+        Ok(Self {
+            server,
+            cidrs: http_get(&server, "/admin/cidrs")?,
+            peers: http_get(&server, "/admin/peers")?,
+            associations: http_get(&server, "/admin/associations")?,
+        })
+    }
+}
+
+/// Order CIDRs so that every CIDR comes after its parent.
+fn parents_first(cidrs: &[Cidr]) -> Vec<&Cidr> {
+    let mut ordered: Vec<&Cidr> = cidrs.iter().filter(|c| c.parent.is_none()).collect();
+    let mut i = 0;
+    while i < ordered.len() {
+        let id = ordered[i].id;
+        ordered.extend(cidrs.iter().filter(|c| c.parent == Some(id)));
+        i += 1;
+    }
+    ordered
+}
+
+fn conflict(message: String) {
+    println!("    {} {}", "conflict:".red(), message);
+}
+
+pub fn migrate_peers(from_interface: &str, to_interface: &str) -> Result<(), Error> {
+    let source = Network::fetch(from_interface)?;
+    let mut dest = Network::fetch(to_interface)?;
+
+    // Unredeemed invitations point at the old server and would need to be reissued anyway,
+    // and the servers themselves (always peer 1) are each network's own.
+    let peers = source
+        .peers
+        .iter()
+        .filter(|p| p.id != 1 && p.is_redeemed && !p.is_disabled)
+        .collect::<Vec<_>>();
+    println!(
+        "\n{} will recreate {} CIDRs, {} peers, and {} associations from {} on {}, skipping any \
+         that already exist.",
+        "[*]".dimmed(),
+        source.cidrs.len(),
+        peers.len(),
+        source.associations.len(),
+        from_interface.yellow(),
+        to_interface.yellow()
+    );
+    if !Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Migrate?")
+        .default(false)
+        .interact()?
+    {
+        println!("exited without migrating.");
+        return Ok(());
+    }
+
+    // Source CIDR IDs to their destination counterparts.
+    let mut cidr_ids = HashMap::new();
+    let mut created = (0, 0, 0);
+
+    println!("{} CIDRs:", "[*]".dimmed());
+    for cidr in parents_first(&source.cidrs) {
+        let parent = match cidr.parent {
+            None => {
+                match dest.cidrs.iter().find(|c| c.parent.is_none()) {
+                    Some(root) => {
+                        cidr_ids.insert(cidr.id, root.id);
+                    },
+                    None => conflict("the destination has no root CIDR.".to_string()),
+                }
+                continue;
+            },
+            Some(parent) => match cidr_ids.get(&parent) {
+                Some(parent) => *parent,
+                None => {
+                    conflict(format!("{} skipped, since its parent was.", cidr.name));
+                    continue;
+                },
+            },
+        };
+
+        if let Some(existing) = dest.cidrs.iter().find(|c| c.name == cidr.name) {
+            if existing.cidr != cidr.cidr {
+                conflict(format!(
+                    "{} is {} on the destination, instead of {}.",
+                    cidr.name, existing.cidr, cidr.cidr
+                ));
+            }
+            cidr_ids.insert(cidr.id, existing.id);
+            continue;
+        }
+
+        let contents = CidrContents {
+            parent: Some(parent),
+            ..cidr.contents.clone()
+        };
+        match http_post::<_, Cidr>(&dest.server, "/admin/cidrs", contents) {
+            Ok(new_cidr) => {
+                println!("    {} {} ({})", "created".green(), cidr.name, cidr.cidr);
+                cidr_ids.insert(cidr.id, new_cidr.id);
+                dest.cidrs.push(new_cidr);
+                created.0 += 1;
+            },
+            Err(e) => conflict(format!("failed to create {}: {}", cidr.name, e)),
+        }
+    }
+
+    println!("{} peers:", "[*]".dimmed());
+    for peer in peers {
+        if dest.peers.iter().any(|p| p.name == peer.name) {
+            conflict(format!("{} already exists.", peer.name));
+            continue;
+        }
+        if let Some(existing) = dest.peers.iter().find(|p| p.ip == peer.ip) {
+            conflict(format!(
+                "{}'s IP {} is already taken by {}.",
+                peer.name, peer.ip, existing.name
+            ));
+            continue;
+        }
+        let cidr_id = match cidr_ids.get(&peer.cidr_id) {
+            Some(cidr_id) => *cidr_id,
+            None => {
+                conflict(format!("{} skipped, since its CIDR was.", peer.name));
+                continue;
+            },
+        };
+
+        let contents = PeerContents {
+            cidr_id,
+            created_at: None,
+            ..peer.contents.clone()
+        };
+        match http_post::<_, Peer>(&dest.server, "/admin/peers", contents) {
+            Ok(new_peer) => {
+                println!("    {} {} ({})", "created".green(), peer.name, peer.ip);
+                dest.peers.push(new_peer);
+                created.1 += 1;
+            },
+            Err(e) => conflict(format!("failed to create {}: {}", peer.name, e)),
+        }
+    }
+
+    println!("{} associations:", "[*]".dimmed());
+    for association in &source.associations {
+        let name = |id| {
+            source
+                .cidrs
+                .iter()
+                .find(|c| c.id == id)
+                .map_or("?", |c| &c.name[..])
+        };
+        let pair = format!(
+            "{} <=> {}",
+            name(association.cidr_id_1),
+            name(association.cidr_id_2)
+        );
+        let (cidr_id_1, cidr_id_2) = match (
+            cidr_ids.get(&association.cidr_id_1),
+            cidr_ids.get(&association.cidr_id_2),
+        ) {
+            (Some(id1), Some(id2)) => (*id1, *id2),
+            _ => {
+                conflict(format!("{} skipped, since one of its CIDRs was.", pair));
+                continue;
+            },
+        };
+        if dest.associations.iter().any(|a| {
+            (a.cidr_id_1, a.cidr_id_2) == (cidr_id_1, cidr_id_2)
+                || (a.cidr_id_1, a.cidr_id_2) == (cidr_id_2, cidr_id_1)
+        }) {
+            continue;
+        }
+
+        let contents = AssociationContents {
+            cidr_id_1,
+            cidr_id_2,
+            label: association.label.clone(),
+        };
+        match http_post::<_, ()>(&dest.server, "/admin/associations", contents) {
+            Ok(()) => {
+                println!("    {} {}", "created".green(), pair);
+                created.2 += 1;
+            },
+            Err(e) => conflict(format!("failed to create {}: {}", pair, e)),
+        }
+    }
+
+    println!(
+        "\n{} created {} CIDRs, {} peers, and {} associations on {}. Peers still need their \
+         interface configs re-pointed at the new server.\n",
+        "[*]".dimmed(),
+        created.0,
+        created.1,
+        created.2,
+        to_interface.yellow()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parents_first() {
+        let cidr = |id, parent| Cidr {
+            id,
+            contents: CidrContents {
+                name: format!("cidr{}", id),
+                cidr: "10.0.0.0/8".parse().unwrap(),
+                parent,
+                is_infra: false,
+            },
+        };
+        let cidrs = vec![
+            cidr(4, Some(3)),
+            cidr(3, Some(1)),
+            cidr(2, Some(1)),
+            cidr(1, None),
+        ];
+        let ids = parents_first(&cidrs)
+            .into_iter()
+            .map(|c| c.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 3, 2, 4]);
+    }
+}
@@ -0,0 +1,141 @@
+//! Watches the system's links, addresses, and routes for changes via a netlink socket, so that
+//! daemon mode can react right away when the underlying network changes (e.g. when roaming
+//! from wifi to ethernet) instead of waiting for the next fetch interval.
+
+#[cfg(target_os = "linux")]
+use std::time::Instant;
+use std::{io, time::Duration};
+
+/// The longest a burst of changes is waited out for before reacting to it anyway.
+#[cfg(target_os = "linux")]
+const MAX_SETTLE: Duration = Duration::from_secs(5);
+
+#[cfg(target_os = "linux")]
+pub struct NetworkMonitor {
+    fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkMonitor {
+    pub fn new() -> Result<Self, io::Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let monitor = Self { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_LINK
+            | libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_ROUTE) as u32;
+
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(monitor)
+    }
+
+    /// Block until either a network change is seen or the timeout elapses, returning
+    /// whether a change was seen.
+    ///
+    /// Changes tend to come in bursts (a new link, then its addresses, then its routes), so
+    /// after the first event this waits a moment for things to settle and discards the rest,
+    /// but never longer than `MAX_SETTLE` if the events don't stop.
+    pub fn wait(&self, timeout: Duration) -> Result<bool, io::Error> {
+        if !self.poll(timeout)? {
+            return Ok(false);
+        }
+
+        let deadline = Instant::now() + MAX_SETTLE;
+        loop {
+            self.drain()?;
+            if Instant::now() >= deadline || !self.poll(Duration::from_secs(1))? {
+                self.drain()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, io::Error> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            n if n < 0 => {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(error)
+                }
+            },
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn drain(&self) -> Result<(), io::Error> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let read = unsafe {
+                libc::recv(
+                    self.fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                return match error.kind() {
+                    io::ErrorKind::WouldBlock => Ok(()),
+                    _ => Err(error),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for NetworkMonitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct NetworkMonitor;
+
+#[cfg(not(target_os = "linux"))]
+impl NetworkMonitor {
+    pub fn new() -> Result<Self, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "watching for network changes is only supported on Linux",
+        ))
+    }
+
+    pub fn wait(&self, _timeout: Duration) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+}
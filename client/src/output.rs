@@ -0,0 +1,201 @@
+//! Machine-readable output of `innernet show`, for consumption by config-management tooling.
+
+use serde::Serialize;
+use shared::{Cidr, Error, Peer};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+use wgctrl::{DeviceInfo, PeerInfo};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "json" => Ok(Self::Json),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            _ => Err(format!(
+                "unknown format \"{}\" (expected json, yaml, or toml)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Yaml => write!(f, "yaml"),
+            Self::Toml => write!(f, "toml"),
+        }
+    }
+}
+
+/// The state of all shown interfaces. TOML documents must be a table at the top level,
+/// so the list of interfaces is wrapped in a struct for every format alike.
+#[derive(Debug, Default, Serialize)]
+pub struct ShowOutput {
+    pub interfaces: Vec<InterfaceState>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterfaceState {
+    pub name: String,
+    pub public_key: Option<String>,
+    pub listen_port: Option<u16>,
+    pub ip: IpAddr,
+    pub peers: Vec<PeerState>,
+    pub cidrs: Vec<Cidr>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerState {
+    #[serde(flatten)]
+    pub peer: Peer,
+
+    /// The endpoint WireGuard is currently using, which may differ from the advertised one.
+    pub current_endpoint: Option<SocketAddr>,
+
+    /// Seconds since the UNIX epoch.
+    pub last_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+
+    /// Whether the peer was marked as intentionally offline on this host.
+    pub expected_offline: bool,
+}
+
+impl InterfaceState {
+    pub fn new(device_info: &DeviceInfo, me: &Peer, peers: &[Peer], cidrs: &[Cidr]) -> Self {
+        let peers = device_info
+            .peers
+            .iter()
+            .filter_map(|info| {
+                let public_key = info.config.public_key.to_base64();
+                peers
+                    .iter()
+                    .find(|p| p.public_key == public_key)
+                    .map(|peer| PeerState::new(peer, info))
+            })
+            .collect();
+
+        Self {
+            name: device_info.name.clone(),
+            public_key: device_info.public_key.as_ref().map(|key| key.to_base64()),
+            listen_port: device_info.listen_port,
+            ip: me.ip,
+            peers,
+            cidrs: cidrs.to_vec(),
+        }
+    }
+}
+
+impl PeerState {
+    fn new(peer: &Peer, info: &PeerInfo) -> Self {
+        Self {
+            peer: peer.clone(),
+            current_endpoint: info.config.endpoint,
+            last_handshake: info
+                .stats
+                .last_handshake_time
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+            rx_bytes: info.stats.rx_bytes,
+            tx_bytes: info.stats.tx_bytes,
+            expected_offline: false,
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Render any output in this format. TOML documents must be a table at the top level, so
+    /// `value` has to be a struct.
+    pub fn render<T: Serialize>(self, value: &T) -> Result<String, Error> {
+        Ok(match self {
+            Self::Json => serde_json::to_string_pretty(value)?,
+            Self::Yaml => serde_yaml::to_string(value)?,
+            // Going through toml::Value lets the serializer reorder plain values before
+            // tables, which TOML requires but the struct field order doesn't guarantee.
+            Self::Toml => toml::to_string_pretty(&toml::Value::try_from(value)?)?,
+        })
+    }
+}
+
+impl ShowOutput {
+    pub fn render(&self, format: OutputFormat) -> Result<String, Error> {
+        format.render(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::CidrContents;
+    use std::time::{Duration, SystemTime};
+
+    fn sample_output() -> ShowOutput {
+        let mut peer = Peer::for_tests(2, "blah", "10.0.0.2", "abc");
+        peer.contents.endpoint = Some("1.2.3.4:51820".parse().unwrap());
+        peer.contents.created_at =
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        ShowOutput {
+            interfaces: vec![InterfaceState {
+                name: "test".to_string(),
+                public_key: Some("def".to_string()),
+                listen_port: None,
+                ip: "10.0.0.1".parse().unwrap(),
+                peers: vec![PeerState {
+                    peer,
+                    current_endpoint: None,
+                    last_handshake: Some(1_600_000_100),
+                    rx_bytes: 1,
+                    tx_bytes: 2,
+                    expected_offline: false,
+                }],
+                cidrs: vec![Cidr {
+                    id: 1,
+                    contents: CidrContents {
+                        name: "cidr".to_string(),
+                        cidr: "10.0.0.0/24".parse().unwrap(),
+                        parent: None,
+                        is_infra: false,
+                    },
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_render_all_formats() {
+        let output = sample_output();
+        for format in &[OutputFormat::Json, OutputFormat::Yaml, OutputFormat::Toml] {
+            let rendered = output.render(*format).unwrap();
+            assert!(rendered.contains("blah"), "{} output: {}", format, rendered);
+            assert!(
+                rendered.contains("10.0.0.0/24"),
+                "{} output: {}",
+                format,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("YAML".parse::<OutputFormat>(), Ok(OutputFormat::Yaml));
+        assert_eq!("yml".parse::<OutputFormat>(), Ok(OutputFormat::Yaml));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
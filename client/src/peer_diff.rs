@@ -0,0 +1,181 @@
+//! Working out which peers `innernet fetch` has to add, modify, and remove on the interface to
+//! match the server's peer list.
+//!
+//! Meshes can have hundreds of peers, and fetch often runs on low-power routers, so the
+//! interface's peers are indexed by public key once rather than searched for every peer.
+
+use crate::{add_allowed_ips, missing_extra_allowed_ips, util::abbreviate_key};
+use colored::*;
+use shared::{interface_config::InterfaceConfig, Peer};
+use std::collections::{HashMap, HashSet};
+use wgctrl::{Key, PeerConfig, PeerConfigBuilder};
+
+#[derive(Debug, Default)]
+pub struct PeerChanges {
+    /// The configs of the peers that were added or modified.
+    pub configs: Vec<PeerConfigBuilder>,
+
+    /// The public keys of the peers the server no longer has.
+    pub removed: Vec<Key>,
+}
+
+impl PeerChanges {
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Diff the server's peers against the interface's, logging each change. Disabled peers and
+/// our own peer are never configured, and endpoint-only changes aren't logged if
+/// `ignore_endpoint_changes` is set.
+pub fn diff<'a>(
+    peers: &[Peer],
+    existing: impl IntoIterator<Item = &'a PeerConfig>,
+    own_public_key: &str,
+    config: &InterfaceConfig,
+    ignore_endpoint_changes: bool,
+) -> PeerChanges {
+    let existing = existing
+        .into_iter()
+        .map(|peer| (peer.public_key.to_base64(), peer))
+        .collect::<HashMap<_, _>>();
+
+    let configs = peers
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.public_key != own_public_key)
+        .filter_map(|peer| {
+            let (builder, text, quiet) = match existing.get(&peer.public_key) {
+                Some(existing_peer) => {
+                    let missing =
+                        missing_extra_allowed_ips(peer, config, &existing_peer.allowed_ips);
+                    let (builder, quiet) = match peer.diff(existing_peer) {
+                        Some(diff) => (
+                            PeerConfigBuilder::from(&diff),
+                            ignore_endpoint_changes && diff.is_endpoint_only(),
+                        ),
+                        None if !missing.is_empty() => {
+                            (PeerConfigBuilder::new(&existing_peer.public_key), false)
+                        },
+                        None => return None,
+                    };
+                    (
+                        add_allowed_ips(builder, &missing),
+                        "modified".normal(),
+                        quiet,
+                    )
+                },
+                None => (
+                    add_allowed_ips(
+                        PeerConfigBuilder::from(peer),
+                        &missing_extra_allowed_ips(peer, config, &[]),
+                    ),
+                    "added".green(),
+                    false,
+                ),
+            };
+            if !quiet {
+                println!(
+                    "    peer {} ({}) was {}.",
+                    peer.name.yellow(),
+                    abbreviate_key(&peer.public_key).dimmed(),
+                    text
+                );
+            }
+            Some(builder)
+        })
+        .collect();
+
+    let current = peers
+        .iter()
+        .map(|peer| &peer.public_key[..])
+        .collect::<HashSet<_>>();
+    let removed = existing
+        .into_iter()
+        .filter(|(public_key, _)| !current.contains(&public_key[..]))
+        .map(|(public_key, peer)| {
+            println!(
+                "    peer ({}) was {}.",
+                abbreviate_key(&public_key).yellow(),
+                "removed".red()
+            );
+            peer.public_key.clone()
+        })
+        .collect();
+
+    PeerChanges { configs, removed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CONFIG: &str = "[interface]\nnetwork-name = \"evilcorp\"\n\
+                          address = \"10.42.0.2/16\"\n\
+                          private-key = \"iBkG25cCz6Ud7YlXlqVOEGvDUy8UR0eZAV1kSn6yJHI=\"\n\n\
+                          [server]\n\
+                          public-key = \"8Z3tBa9dpM+VaDjkqXKsB0uxJfRu8mrJHJcR2pXL+Cs=\"\n\
+                          external-endpoint = \"203.0.113.1:51820\"\n\
+                          internal-endpoint = \"10.42.0.1:51820\"\n";
+
+    fn key(i: u32) -> Key {
+        Key::from_hex(&format!("{:08x}{:056x}", i, 0)).unwrap()
+    }
+
+    fn peer(i: u32) -> Peer {
+        let ip = Ipv4Addr::from(0x0a2a_0100 + i).to_string();
+        let mut peer = Peer::for_tests(i as i64, &format!("peer-{}", i), &ip, &key(i).to_base64());
+        peer.contents.endpoint = Some(([198, 51, 100, 1], 40000 + i as u16).into());
+        peer.contents.persistent_keepalive_interval = Some(25);
+        peer
+    }
+
+    #[test]
+    fn test_diff_large_mesh() {
+        let config: InterfaceConfig = toml::from_str(CONFIG).unwrap();
+
+        // Peers 0..350 are on the interface, 50..500 on the server: 0..50 were removed and
+        // 350..500 added. Of the rest, every third has moved to a new endpoint.
+        let mut peers = (50..500).map(peer).collect::<Vec<_>>();
+        for peer in peers
+            .iter_mut()
+            .filter(|peer| peer.id < 350 && peer.id % 3 == 0)
+        {
+            peer.contents.endpoint = Some(([198, 51, 100, 2], 50000).into());
+        }
+        // Disabled peers are left alone rather than added.
+        peers[449].contents.is_disabled = true;
+        let existing = (0..350)
+            .map(|i| PeerConfigBuilder::from(&peer(i)).into_peer_config())
+            .collect::<Vec<_>>();
+
+        let changes = diff(&peers, &existing, "", &config, false);
+        let mut changed = changes
+            .configs
+            .into_iter()
+            .map(|builder| builder.into_peer_config().public_key)
+            .collect::<Vec<_>>();
+        changed.sort_by_key(|key| key.to_base64());
+        let mut expected = (50..350)
+            .filter(|i| i % 3 == 0)
+            .chain(350..499)
+            .map(key)
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|key| key.to_base64());
+        assert_eq!(changed, expected);
+
+        let mut removed = changes.removed;
+        removed.sort_by_key(|key| key.to_base64());
+        let mut expected = (0..50).map(key).collect::<Vec<_>>();
+        expected.sort_by_key(|key| key.to_base64());
+        assert_eq!(removed, expected);
+
+        // Nothing changes once the interface matches the server.
+        let existing = peers
+            .iter()
+            .filter(|peer| !peer.is_disabled)
+            .map(|peer| PeerConfigBuilder::from(peer).into_peer_config())
+            .collect::<Vec<_>>();
+        assert!(diff(&peers, &existing, "", &config, false).is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Reachability probes for `innernet show --probe`, which check whether peers can actually be
+//! routed to through the tunnel right now, rather than only whether they handshaked recently.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long to wait for a peer to answer a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The most probes to have in flight at once, so that probing a large network stays fast
+/// without opening hundreds of sockets (or spawning hundreds of processes) at the same time.
+const MAX_CONCURRENT_PROBES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Probe {
+    /// Send an ICMP echo request, using the system's `ping`.
+    Icmp,
+
+    /// Open a TCP connection to the given port.
+    Tcp(u16),
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("icmp"), None) => Ok(Self::Icmp),
+            (Some("tcp"), Some(port)) => port
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|_| format!("invalid port \"{}\"", port)),
+            _ => Err("probe must be \"icmp\" or \"tcp:<port>\"".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Icmp => write!(f, "icmp"),
+            Self::Tcp(port) => write!(f, "tcp:{}", port),
+        }
+    }
+}
+
+impl Probe {
+    pub fn is_reachable(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::Icmp => Command::new("ping")
+                .args(["-n", "-c", "1", "-W"])
+                .arg(PROBE_TIMEOUT.as_secs().to_string())
+                .arg(ip.to_string())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false),
+            Self::Tcp(port) => {
+                match TcpStream::connect_timeout(&SocketAddr::new(ip, *port), PROBE_TIMEOUT) {
+                    Ok(_) => true,
+                    // The peer answered, it just isn't listening on that port.
+                    Err(e) => e.kind() == io::ErrorKind::ConnectionRefused,
+                }
+            },
+        }
+    }
+
+    /// Probe all of the given IPs concurrently, returning whether each one is reachable, in
+    /// the same order.
+    pub fn probe_all(&self, ips: &[IpAddr]) -> Vec<bool> {
+        let ips = Arc::new(ips.to_vec());
+        let next = Arc::new(AtomicUsize::new(0));
+        let workers = (0..MAX_CONCURRENT_PROBES.min(ips.len()))
+            .map(|_| {
+                let (probe, ips, next) = (*self, ips.clone(), next.clone());
+                thread::spawn(move || {
+                    let mut results = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        match ips.get(i) {
+                            Some(ip) => results.push((i, probe.is_reachable(*ip))),
+                            None => break results,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut reachable = vec![false; ips.len()];
+        for worker in workers {
+            for (i, result) in worker.join().expect("probe thread panicked") {
+                reachable[i] = result;
+            }
+        }
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_probe() {
+        assert_eq!("icmp".parse(), Ok(Probe::Icmp));
+        assert_eq!("tcp:22".parse(), Ok(Probe::Tcp(22)));
+        assert!("tcp".parse::<Probe>().is_err());
+        assert!("tcp:ssh".parse::<Probe>().is_err());
+        assert!("udp:53".parse::<Probe>().is_err());
+    }
+
+    #[test]
+    fn test_tcp_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let probe = Probe::Tcp(listener.local_addr().unwrap().port());
+        let ips = vec!["127.0.0.1".parse().unwrap(); 40];
+        assert!(probe.probe_all(&ips).into_iter().all(|reachable| reachable));
+    }
+}
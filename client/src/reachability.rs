@@ -0,0 +1,245 @@
+//! The effective reachability between CIDRs, as the server decides which peers each peer gets
+//! to know about in its `/user/state` response.
+//!
+//! A peer in a CIDR is told about the peers in:
+//!
+//! * its own CIDR, and all of its child CIDRs,
+//! * the CIDRs flagged as infra (and their children), which include the innernet server,
+//! * any CIDR associated with its CIDR or one of its CIDR's ancestors, and their children.
+//!
+//! Associations are direct, not transitive: associating A with B and B with C doesn't let A
+//! reach C. And since a WireGuard tunnel needs both peers to know about each other, two
+//! CIDRs' peers can only reach each other if each is told about the other. The innernet server
+//! itself is the exception, since it's configured with every peer.
+
+use shared::{Association, Cidr};
+use std::collections::HashSet;
+
+/// The "infra" CIDR servers from before CIDRs could be flagged as infra forcibly associate
+/// every CIDR with (1 being the root).
+const LEGACY_INFRA_CIDR_ID: i64 = 2;
+
+/// Whether the peers of one CIDR can reach the peers of another.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reachability {
+    /// Each CIDR's peers are told about the other's.
+    Mutual,
+
+    /// The first CIDR's peers are told about the second's, but not the other way around, so
+    /// no tunnel can be established.
+    OneWay,
+
+    Unreachable,
+}
+
+/// The CIDR and all of its descendants.
+pub fn subtree(cidrs: &[Cidr], id: i64) -> HashSet<i64> {
+    let mut ids = HashSet::new();
+    let mut pending = vec![id];
+    while let Some(id) = pending.pop() {
+        if ids.insert(id) {
+            pending.extend(cidrs.iter().filter(|c| c.parent == Some(id)).map(|c| c.id));
+        }
+    }
+    ids
+}
+
+/// The CIDR and all of its ancestors.
+fn ancestors(cidrs: &[Cidr], id: i64) -> HashSet<i64> {
+    let mut ids = HashSet::new();
+    let mut next = Some(id);
+    while let Some(id) = next {
+        if !ids.insert(id) {
+            break;
+        }
+        next = cidrs.iter().find(|c| c.id == id).and_then(|c| c.parent);
+    }
+    ids
+}
+
+/// The CIDRs every CIDR is forcibly associated with.
+///
+/// Servers that know about infra flags always have at least the innernet server's CIDR flagged,
+/// since they flag it when initializing or migrating and refuse to unflag it. So no CIDR being
+/// flagged means the server predates the flags, and still uses the legacy infra CIDR.
+pub fn infra_cidrs(cidrs: &[Cidr]) -> Vec<i64> {
+    let infra = cidrs
+        .iter()
+        .filter(|c| c.is_infra)
+        .map(|c| c.id)
+        .collect::<Vec<_>>();
+    if infra.is_empty() {
+        vec![LEGACY_INFRA_CIDR_ID]
+    } else {
+        infra
+    }
+}
+
+/// The CIDRs whose peers a peer in the given CIDR is told about.
+pub fn visible_cidrs(cidrs: &[Cidr], associations: &[Association], id: i64) -> HashSet<i64> {
+    let ancestors = ancestors(cidrs, id);
+    let associated = associations.iter().filter_map(|association| {
+        if ancestors.contains(&association.cidr_id_1) {
+            Some(association.cidr_id_2)
+        } else if ancestors.contains(&association.cidr_id_2) {
+            Some(association.cidr_id_1)
+        } else {
+            None
+        }
+    });
+
+    std::iter::once(id)
+        .chain(infra_cidrs(cidrs))
+        .chain(associated)
+        .flat_map(|id| subtree(cidrs, id))
+        .collect()
+}
+
+/// The reachability between every pair of CIDRs, indexed in the same order as `cidrs`.
+pub fn matrix(cidrs: &[Cidr], associations: &[Association]) -> Vec<Vec<Reachability>> {
+    let visible = cidrs
+        .iter()
+        .map(|cidr| visible_cidrs(cidrs, associations, cidr.id))
+        .collect::<Vec<_>>();
+
+    cidrs
+        .iter()
+        .enumerate()
+        .map(|(i, from)| {
+            cidrs
+                .iter()
+                .enumerate()
+                .map(
+                    |(j, to)| match (visible[i].contains(&to.id), visible[j].contains(&from.id)) {
+                        (true, true) => Reachability::Mutual,
+                        (true, false) => Reachability::OneWay,
+                        _ => Reachability::Unreachable,
+                    },
+                )
+                .collect()
+        })
+        .collect()
+}
+
+/// The pairs of CIDRs (by ID, in the same order as `cidrs`) whose peers would gain or lose
+/// mutual reachability by changing from one set of associations to another.
+#[derive(Debug, Default, PartialEq)]
+pub struct Impact {
+    pub gained: Vec<(i64, i64)>,
+    pub lost: Vec<(i64, i64)>,
+}
+
+pub fn impact(cidrs: &[Cidr], before: &[Association], after: &[Association]) -> Impact {
+    let (before, after) = (matrix(cidrs, before), matrix(cidrs, after));
+    let mut impact = Impact::default();
+    for i in 0..cidrs.len() {
+        for j in (i + 1)..cidrs.len() {
+            let pair = (cidrs[i].id, cidrs[j].id);
+            match (
+                before[i][j] == Reachability::Mutual,
+                after[i][j] == Reachability::Mutual,
+            ) {
+                (false, true) => impact.gained.push(pair),
+                (true, false) => impact.lost.push(pair),
+                _ => {},
+            }
+        }
+    }
+    impact
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{AssociationContents, CidrContents};
+
+    fn cidr(id: i64, parent: Option<i64>) -> Cidr {
+        Cidr {
+            id,
+            contents: CidrContents {
+                name: format!("cidr{}", id),
+                cidr: format!("10.{}.0.0/16", id).parse().unwrap(),
+                parent,
+                is_infra: false,
+            },
+        }
+    }
+
+    fn association(id: i64, cidr_id_1: i64, cidr_id_2: i64) -> Association {
+        Association {
+            id,
+            contents: AssociationContents {
+                cidr_id_1,
+                cidr_id_2,
+                label: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_infra_cidrs() {
+        let mut cidrs = vec![cidr(1, None), cidr(2, Some(1)), cidr(3, Some(1))];
+        assert_eq!(infra_cidrs(&cidrs), vec![2]);
+        cidrs[2].contents.is_infra = true;
+        assert_eq!(infra_cidrs(&cidrs), vec![3]);
+    }
+
+    #[test]
+    fn test_impact() {
+        // root(1) -> infra(2), dev(3) -> dev-sub(4), prod(5)
+        let cidrs = vec![
+            cidr(1, None),
+            cidr(2, Some(1)),
+            cidr(3, Some(1)),
+            cidr(4, Some(3)),
+            cidr(5, Some(1)),
+        ];
+        let before = vec![association(1, 3, 5)];
+        let after = vec![association(2, 4, 5)];
+
+        assert_eq!(impact(&cidrs, &before, &before), Impact::default());
+        assert_eq!(
+            impact(&cidrs, &[], &before),
+            Impact {
+                gained: vec![(3, 5), (4, 5)],
+                lost: vec![],
+            }
+        );
+        // dev-sub stays reachable from prod, but the rest of dev doesn't.
+        assert_eq!(
+            impact(&cidrs, &before, &after),
+            Impact {
+                gained: vec![],
+                lost: vec![(3, 5)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_matrix() {
+        use Reachability::{Mutual, OneWay, Unreachable as No};
+
+        // root(1) -> infra(2), dev(3) -> dev-sub(4), prod(5), monitoring(6)
+        let cidrs = vec![
+            cidr(1, None),
+            cidr(2, Some(1)),
+            cidr(3, Some(1)),
+            cidr(4, Some(3)),
+            cidr(5, Some(1)),
+            cidr(6, Some(1)),
+        ];
+        let associations = vec![association(1, 3, 6), association(2, 6, 5)];
+
+        assert_eq!(
+            matrix(&cidrs, &associations),
+            vec![
+                vec![Mutual, OneWay, OneWay, OneWay, OneWay, OneWay],
+                vec![No, Mutual, No, No, No, No],
+                vec![No, OneWay, Mutual, OneWay, No, Mutual],
+                vec![No, OneWay, No, Mutual, No, Mutual],
+                vec![No, OneWay, No, No, Mutual, Mutual],
+                vec![No, OneWay, Mutual, Mutual, Mutual, Mutual],
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Reading the routes the kernel has through an interface by dumping its routing table over a
+//! netlink socket (like `network_monitor` watches it), rather than parsing `ip route` output.
+
+use crate::Error;
+use ipnetwork::IpNetwork;
+#[cfg(target_os = "linux")]
+use std::{
+    convert::{TryFrom, TryInto},
+    ffi::CString,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+#[cfg(target_os = "linux")]
+const NLMSG_HEADER_LEN: usize = 16;
+#[cfg(target_os = "linux")]
+const RTMSG_LEN: usize = 12;
+
+/// The destinations the kernel currently routes through the interface in its main table, where
+/// "default" is the whole address family.
+#[cfg(target_os = "linux")]
+pub fn get(interface: &str) -> Result<Vec<IpNetwork>, Error> {
+    let name = CString::new(interface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(format!("no interface named {}", interface).into());
+    }
+
+    let socket = Socket::open()?;
+    let mut routes = vec![];
+    for &family in &[libc::AF_INET, libc::AF_INET6] {
+        socket.send(&dump_request(family as u8))?;
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let len = socket.recv(&mut buf)?;
+            if parse_routes(&buf[..len], ifindex, &mut routes)? {
+                break;
+            }
+        }
+    }
+    Ok(routes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_interface: &str) -> Result<Vec<IpNetwork>, Error> {
+    Err("listing routes is currently only supported on Linux".into())
+}
+
+#[cfg(target_os = "linux")]
+struct Socket(libc::c_int);
+
+#[cfg(target_os = "linux")]
+impl Socket {
+    fn open() -> Result<Self, io::Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+
+    fn send(&self, message: &[u8]) -> Result<(), io::Error> {
+        let sent = unsafe {
+            libc::send(
+                self.0,
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let read =
+            unsafe { libc::recv(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(read as usize)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Netlink messages and their attributes are padded to four bytes.
+#[cfg(target_os = "linux")]
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(target_os = "linux")]
+fn dump_request(family: u8) -> Vec<u8> {
+    let mut message = Vec::with_capacity(NLMSG_HEADER_LEN + RTMSG_LEN);
+    message.extend_from_slice(&((NLMSG_HEADER_LEN + RTMSG_LEN) as u32).to_ne_bytes());
+    message.extend_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    message.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    // The sequence number and port ID, which the kernel fills in.
+    message.extend_from_slice(&[0; 8]);
+    message.push(family);
+    message.extend_from_slice(&[0; RTMSG_LEN - 1]);
+    message
+}
+
+/// Add the routes through the interface from a batch of a dump's messages, returning whether
+/// the dump is done.
+#[cfg(target_os = "linux")]
+fn parse_routes(mut buf: &[u8], ifindex: u32, routes: &mut Vec<IpNetwork>) -> Result<bool, Error> {
+    while buf.len() >= NLMSG_HEADER_LEN {
+        let len = u32::from_ne_bytes(buf[..4].try_into()?) as usize;
+        let kind = u16::from_ne_bytes(buf[4..6].try_into()?);
+        if len < NLMSG_HEADER_LEN || len > buf.len() {
+            return Err("truncated netlink message".into());
+        }
+        let payload = &buf[NLMSG_HEADER_LEN..len];
+        match kind as libc::c_int {
+            libc::NLMSG_DONE => return Ok(true),
+            libc::NLMSG_ERROR => {
+                let errno = i32::from_ne_bytes(
+                    payload
+                        .get(..4)
+                        .ok_or("truncated netlink error")?
+                        .try_into()?,
+                );
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno).into());
+                }
+            },
+            _ if kind == libc::RTM_NEWROUTE => routes.extend(parse_route(payload, ifindex)),
+            _ => {},
+        }
+        buf = buf.get(align(len)..).unwrap_or_default();
+    }
+    Ok(false)
+}
+
+/// A route's destination, if it's in the main table and through the interface.
+#[cfg(target_os = "linux")]
+fn parse_route(payload: &[u8], ifindex: u32) -> Option<IpNetwork> {
+    let header = payload.get(..RTMSG_LEN)?;
+    let (family, prefix) = (header[0], header[1]);
+    let mut table = header[4] as u32;
+    let (mut oif, mut destination) = (None, None);
+
+    let mut attributes = &payload[RTMSG_LEN..];
+    while attributes.len() >= 4 {
+        let len = u16::from_ne_bytes(attributes[..2].try_into().ok()?) as usize;
+        let kind = u16::from_ne_bytes(attributes[2..4].try_into().ok()?);
+        let data = attributes.get(4..len)?;
+        match kind {
+            libc::RTA_OIF => oif = Some(u32::from_ne_bytes(data.try_into().ok()?)),
+            libc::RTA_TABLE => table = u32::from_ne_bytes(data.try_into().ok()?),
+            libc::RTA_DST => destination = Some(data),
+            _ => {},
+        }
+        attributes = attributes.get(align(len)..).unwrap_or_default();
+    }
+    if oif != Some(ifindex) || table != libc::RT_TABLE_MAIN as u32 {
+        return None;
+    }
+
+    // Default routes have no destination.
+    let ip = match (family as libc::c_int, destination) {
+        (libc::AF_INET, None) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (libc::AF_INET, Some(data)) => IpAddr::V4(<[u8; 4]>::try_from(data).ok()?.into()),
+        (libc::AF_INET6, None) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        (libc::AF_INET6, Some(data)) => IpAddr::V6(<[u8; 16]>::try_from(data).ok()?.into()),
+        _ => return None,
+    };
+    IpNetwork::new(ip, prefix).ok()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// A route message as the kernel sends it in a dump.
+    fn route_message(family: u8, destination: &[u8], prefix: u8, table: u8, oif: u32) -> Vec<u8> {
+        let mut attributes = vec![];
+        if !destination.is_empty() {
+            attributes.extend_from_slice(&(4 + destination.len() as u16).to_ne_bytes());
+            attributes.extend_from_slice(&libc::RTA_DST.to_ne_bytes());
+            attributes.extend_from_slice(destination);
+        }
+        attributes.extend_from_slice(&8u16.to_ne_bytes());
+        attributes.extend_from_slice(&libc::RTA_OIF.to_ne_bytes());
+        attributes.extend_from_slice(&oif.to_ne_bytes());
+
+        let len = NLMSG_HEADER_LEN + RTMSG_LEN + attributes.len();
+        let mut message = vec![];
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&libc::RTM_NEWROUTE.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(&[family, prefix, 0, 0, table, 0, 0, 1, 0, 0, 0, 0]);
+        message.extend_from_slice(&attributes);
+        message
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let (inet, inet6, main) = (
+            libc::AF_INET as u8,
+            libc::AF_INET6 as u8,
+            libc::RT_TABLE_MAIN,
+        );
+        let mut buf = vec![];
+        buf.extend(route_message(inet, &[10, 42, 0, 0], 16, main, 5));
+        buf.extend(route_message(inet, &[10, 43, 1, 5], 32, main, 5));
+        buf.extend(route_message(inet, &[], 0, main, 5));
+        // Another interface's, and one in the local table.
+        buf.extend(route_message(inet, &[192, 168, 1, 0], 24, main, 2));
+        buf.extend(route_message(
+            inet,
+            &[10, 42, 0, 2],
+            32,
+            libc::RT_TABLE_LOCAL,
+            5,
+        ));
+        let mut fd00 = [0; 16];
+        fd00[..4].copy_from_slice(&[0xfd, 0, 0, 0x42]);
+        buf.extend(route_message(inet6, &fd00, 64, main, 5));
+
+        let mut routes = vec![];
+        assert!(!parse_routes(&buf, 5, &mut routes).unwrap());
+        assert_eq!(
+            routes,
+            vec![
+                "10.42.0.0/16".parse::<IpNetwork>().unwrap(),
+                "10.43.1.5/32".parse().unwrap(),
+                "0.0.0.0/0".parse().unwrap(),
+                "fd00:42::/64".parse().unwrap(),
+            ]
+        );
+
+        let mut done = (NLMSG_HEADER_LEN as u32).to_ne_bytes().to_vec();
+        done.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        done.extend_from_slice(&[0; 10]);
+        assert!(parse_routes(&done, 5, &mut routes).unwrap());
+        assert!(parse_routes(&buf[..buf.len() - 1], 5, &mut routes).is_err());
+    }
+}
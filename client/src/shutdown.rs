@@ -0,0 +1,24 @@
+//! Stopping `innernet up --daemon` cleanly on SIGTERM (e.g. from systemd) or SIGINT: the daemon
+//! finishes the fetch it's in the middle of, if any, and then leaves its loop instead of being
+//! killed halfway through updating the interface.
+
+use crate::util;
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn install() -> Result<(), io::Error> {
+    util::handle_signals(&[libc::SIGTERM, libc::SIGINT], handle)
+}
+
+/// Whether a signal asked the daemon to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,57 @@
+//! Resolving the server's external endpoint from a DNS SRV record (e.g.
+//! `_innernet._udp.example.com`), so that a network's server can be moved or failed over by
+//! updating DNS instead of every client's config.
+//!
+//! The standard library can only resolve hostnames to addresses, so the SRV query goes through a
+//! resolver configured like the system's (the nameservers, timeouts, and attempts of
+//! /etc/resolv.conf), which also retries truncated answers over TCP.
+
+use crate::Error;
+use std::{cmp::Reverse, net::SocketAddr};
+use trust_dns_resolver::{proto::rr::rdata::SRV, Resolver};
+
+/// Resolve an SRV name to the endpoint of its most preferred target.
+pub fn resolve(name: &str) -> Result<SocketAddr, Error> {
+    let resolver = Resolver::from_system_conf()?;
+    let lookup = resolver.srv_lookup(name)?;
+    let record =
+        most_preferred(lookup.iter()).ok_or_else(|| format!("no SRV records for {}", name))?;
+    if record.target().is_root() {
+        return Err(format!("{} says the service isn't available", name).into());
+    }
+    let target = record.target().to_utf8();
+    resolver
+        .lookup_ip(&target[..])?
+        .iter()
+        .next()
+        .map(|ip| SocketAddr::new(ip, record.port()))
+        .ok_or_else(|| format!("{} (from {}) has no addresses", target, name).into())
+}
+
+/// The record with the lowest priority, and of those the highest weight.
+fn most_preferred<'a>(records: impl Iterator<Item = &'a SRV>) -> Option<&'a SRV> {
+    records.min_by_key(|record| (record.priority(), Reverse(record.weight())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_resolver::Name;
+
+    #[test]
+    fn test_most_preferred() {
+        let record = |priority, weight, target| {
+            SRV::new(priority, weight, 51820, Name::from_ascii(target).unwrap())
+        };
+        let records = [
+            record(20, 0, "backup.example.com."),
+            record(10, 1, "light.example.com."),
+            record(10, 5, "main.example.com."),
+        ];
+        assert_eq!(
+            most_preferred(records.iter()).unwrap().target().to_utf8(),
+            "main.example.com."
+        );
+        assert!(most_preferred([].iter()).is_none());
+    }
+}
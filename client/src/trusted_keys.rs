@@ -0,0 +1,93 @@
+//! Client-side authorization of peers for `innernet fetch --trusted-keys`, so that a compromised
+//! server can't slip rogue peers into a network whose authorized public keys are distributed
+//! out-of-band.
+//!
+//! The list has one base64-encoded public key per line, optionally followed by a description
+//! (e.g. the peer's name). Blank lines and lines starting with "#" are ignored. It's read on
+//! every fetch, so that the daemon picks up changes to it.
+
+use crate::{util::abbreviate_key, Error};
+use colored::*;
+use shared::{IoErrorContext, Peer};
+use std::{collections::HashSet, fs, path::Path};
+use wgctrl::Key;
+
+#[derive(Debug)]
+pub struct TrustedKeys(HashSet<String>);
+
+impl TrustedKeys {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).with_path(path)?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut keys = HashSet::new();
+        for (i, line) in contents.lines().enumerate() {
+            let key = match line.split_whitespace().next() {
+                Some(key) if !key.starts_with('#') => key,
+                _ => continue,
+            };
+            if Key::from_base64(key).is_err() {
+                return Err(format!(
+                    "line {}: \"{}\" isn't a valid public key",
+                    i + 1,
+                    key
+                ));
+            }
+            keys.insert(key.to_string());
+        }
+        Ok(Self(keys))
+    }
+
+    pub fn contains(&self, public_key: &str) -> bool {
+        self.0.contains(public_key)
+    }
+
+    /// Drop the peers whose public keys aren't trusted, logging each one. The keys in `always`
+    /// (i.e. the server's, which comes from the invitation) are kept regardless.
+    pub fn retain(&self, peers: Vec<Peer>, always: &[&str]) -> Vec<Peer> {
+        peers
+            .into_iter()
+            .filter(|peer| {
+                let trusted =
+                    self.contains(&peer.public_key) || always.contains(&&peer.public_key[..]);
+                if !trusted {
+                    println!(
+                        "    peer {} ({}) was {}, since its public key isn't trusted.",
+                        peer.name.yellow(),
+                        abbreviate_key(&peer.public_key).dimmed(),
+                        "rejected".red()
+                    );
+                }
+                trusted
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAPTOP: &str = "8Z3tBa9dpM+VaDjkqXKsB0uxJfRu8mrJHJcR2pXL+Cs=";
+    const PHONE: &str = "iBkG25cCz6Ud7YlXlqVOEGvDUy8UR0eZAV1kSn6yJHI=";
+
+    #[test]
+    fn test_parse() {
+        let keys = TrustedKeys::parse(&format!(
+            "# authorized peers\n\n{}  laptop\n  {}\n",
+            LAPTOP, PHONE
+        ))
+        .unwrap();
+        assert!(keys.contains(LAPTOP));
+        assert!(keys.contains(PHONE));
+        assert!(!keys.contains("laptop"));
+
+        assert_eq!(
+            TrustedKeys::parse(&format!("{}\nlaptop\n", LAPTOP)).unwrap_err(),
+            "line 2: \"laptop\" isn't a valid public key"
+        );
+        assert!(TrustedKeys::parse("").unwrap().0.is_empty());
+    }
+}
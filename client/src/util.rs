@@ -1,7 +1,55 @@
-use crate::{ClientError, Error};
+use crate::{
+    bound_http::{self, HttpBind},
+    ClientError, Error,
+};
 use colored::*;
+use lazy_static::lazy_static;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{net::SocketAddr, time::Duration};
+use shared::{prompts, IoErrorContext, Peer, IDEMPOTENCY_KEY_HEADER};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+use wgctrl::PeerInfo;
+
+/// How long a peer can go without a handshake on its current endpoint before the next of its
+/// advertised endpoints is tried. Peers are configured with a persistent keepalive, so a
+/// working endpoint will have handshaken well within this time.
+const ENDPOINT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Parse the daemon's fetch interval, e.g. "30s", "5m", or a bare number of seconds. Zero is
+/// rejected, since the daemon would fetch in a busy loop.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let interval = shared::parse_duration(s)?;
+    if interval.as_secs() == 0 {
+        return Err("the interval must be at least 1 second".to_string());
+    }
+    Ok(interval)
+}
+
+/// The network's name if it's not taken, or else the first free one of "<name>-2", "<name>-3",
+/// and so on.
+pub fn distinct_interface_name(network_name: &str, taken: impl Fn(&str) -> bool) -> Option<String> {
+    std::iter::once(network_name.to_string())
+        .chain((2..100).map(|n| format!("{}-{}", network_name, n)))
+        .find(|name| !taken(name))
+}
+
+/// A public key's first few characters, enough to tell peers apart in output. Keys that are
+/// shorter than expected (i.e. malformed ones) are shown whole instead of panicking.
+pub fn abbreviate_key(key: &str) -> String {
+    format!("{}...", key.chars().take(10).collect::<String>())
+}
 
 pub fn human_duration(duration: Duration) -> String {
     match duration.as_secs() {
@@ -46,13 +94,330 @@ pub fn human_size(bytes: u64) -> String {
     }
 }
 
+/// Whether this host has a route to an address. Connecting a UDP socket consults the routing
+/// table without sending any packets, so this catches unreachable networks, but not endpoints
+/// that are routable yet down.
+pub fn is_routable(addr: SocketAddr) -> bool {
+    let bind_addr: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    UdpSocket::bind(bind_addr)
+        .and_then(|socket| socket.connect(addr))
+        .is_ok()
+}
+
+/// Call `handler` on each of the signals. Interrupted system calls are restarted, so that a
+/// signal in the middle of a fetch doesn't fail it; waits that must notice the signal are
+/// expected to check for it in slices.
+pub fn handle_signals(
+    signals: &[libc::c_int],
+    handler: extern "C" fn(libc::c_int),
+) -> Result<(), io::Error> {
+    for &signal in signals {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The file that `append_rotating` moves a log's previous contents to.
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(".1");
+    path.into()
+}
+
+/// Append to a log file, first moving it aside (replacing the previously rotated one) once it
+/// has grown past `max_bytes`, so that at most about twice that is kept around. A new file
+/// starts with the header, if there is one.
+pub fn append_rotating(
+    path: &Path,
+    max_bytes: u64,
+    header: Option<&str>,
+    contents: &str,
+) -> Result<(), Error> {
+    if matches!(fs::metadata(path), Ok(m) if m.len() >= max_bytes) {
+        fs::rename(path, rotated_path(path)).with_path(path)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_path(path)?;
+    let mut lines = String::new();
+    if let (Some(header), 0) = (header, file.metadata()?.len()) {
+        lines.push_str(header);
+        lines.push('\n');
+    }
+    lines.push_str(contents);
+    file.write_all(lines.as_bytes()).with_path(path)?;
+    Ok(())
+}
+
+/// The peers whose hostnames collide, grouped by hostname. A hostname can be shared by an IPv4
+/// and an IPv6 peer, so only peers in the same address family collide.
+pub fn hostname_conflicts<'a>(hostnames: &[(String, &'a Peer)]) -> Vec<(String, Vec<&'a Peer>)> {
+    let mut by_hostname: BTreeMap<(&str, bool), Vec<&Peer>> = BTreeMap::new();
+    for (hostname, peer) in hostnames {
+        by_hostname
+            .entry((hostname, peer.ip.is_ipv4()))
+            .or_default()
+            .push(peer);
+    }
+    by_hostname
+        .into_iter()
+        .filter(|(_, peers)| peers.len() > 1)
+        .map(|((hostname, _), peers)| (hostname.to_string(), peers))
+        .collect()
+}
+
+/// Parse the domain that peers' default hostnames end in, e.g. "internal" or "wg.example.com".
+/// A leading dot is ignored.
+pub fn parse_hosts_suffix(s: &str) -> Result<String, String> {
+    let suffix = s.strip_prefix('.').unwrap_or(s);
+    if suffix.is_empty() || !suffix.split('.').all(prompts::is_valid_hostname) {
+        return Err(format!("\"{}\" isn't a valid domain", s));
+    }
+    Ok(suffix.to_string())
+}
+
+/// Choose which of a peer's advertised endpoints to configure, probing them in order.
+///
+/// An endpoint that's in use and recently handshook is kept. One that's gone quiet is
+/// failed over to the next routable endpoint in the peer's list, wrapping around.
+pub fn choose_endpoint(peer: &Peer, existing: Option<&PeerInfo>) -> Option<SocketAddr> {
+    let candidates = peer
+        .endpoints()
+        .filter(|endpoint| is_routable(*endpoint))
+        .collect::<Vec<_>>();
+
+    if let Some(info) = existing {
+        let current = info
+            .config
+            .endpoint
+            .and_then(|endpoint| candidates.iter().position(|c| *c == endpoint));
+        if let Some(position) = current {
+            let healthy = info
+                .stats
+                .last_handshake_time
+                .and_then(|time| time.elapsed().ok())
+                .map(|elapsed| elapsed < ENDPOINT_FAILOVER_TIMEOUT)
+                .unwrap_or(false);
+            if healthy {
+                return Some(candidates[position]);
+            }
+            return candidates
+                .get(position + 1)
+                .or_else(|| candidates.first())
+                .copied();
+        }
+    }
+
+    candidates.first().copied().or(peer.endpoint)
+}
+
+/// Lower the scheduling priority of the whole process, so that background fetches don't
+/// contend with other workloads. `nice` is the usual -20 (highest) to 19 (lowest) niceness,
+/// and `io_idle` puts the process in the idle I/O scheduling class (Linux only).
+pub fn set_process_priority(nice: Option<i32>, io_idle: bool) -> Result<(), Error> {
+    if let Some(nice) = nice {
+        if !(-20..=19).contains(&nice) {
+            return Err(format!("niceness must be between -20 and 19, not {}", nice).into());
+        }
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(format!(
+                "failed to set niceness to {}: {}",
+                nice,
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+    }
+
+    if io_idle {
+        set_io_idle()?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_idle() -> Result<(), Error> {
+    // From linux/ioprio.h, which libc doesn't have bindings for.
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if ret != 0 {
+        return Err(format!(
+            "failed to set the I/O scheduling class: {}",
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_idle() -> Result<(), Error> {
+    Err("--ionice is only supported on Linux.".into())
+}
+
+/// Measures how long each phase of a command takes, for `--timing`.
+pub struct PhaseTimer {
+    enabled: bool,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimer {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            last: Instant::now(),
+            phases: vec![],
+        }
+    }
+
+    /// Record that a phase just finished, having started when the previous one finished.
+    pub fn finish(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+
+    /// Print how long each recorded phase took, if timing is enabled.
+    pub fn print(&self) {
+        if !self.enabled {
+            return;
+        }
+        println!("{} timing:", "[*]".dimmed());
+        for (phase, duration) in &self.phases {
+            println!(
+                "    {:<24} {:>10.1}ms",
+                phase,
+                duration.as_secs_f64() * 1000.0
+            );
+        }
+        let total = self
+            .phases
+            .iter()
+            .map(|(_, duration)| *duration)
+            .sum::<Duration>();
+        println!(
+            "    {:<24} {:>10.1}ms",
+            "total".bold(),
+            total.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+/// How long to wait for the server to accept a connection, and then for each read or write of a
+/// request, so that a stalled server fails the request rather than hanging the daemon.
+pub const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const HTTP_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    /// Where requests to the server are sent from, if not left up to the OS.
+    static ref HTTP_BIND: RwLock<Option<HttpBind>> = RwLock::new(None);
+
+    static ref HTTP_AGENT: ureq::Agent = ureq::AgentBuilder::new()
+        .timeout_connect(HTTP_CONNECT_TIMEOUT)
+        .timeout_read(HTTP_IO_TIMEOUT)
+        .timeout_write(HTTP_IO_TIMEOUT)
+        .build();
+}
+
+/// Whether a request that changes the server's state (anything but a GET) has succeeded.
+static MADE_CHANGES: AtomicBool = AtomicBool::new(false);
+
+/// Whether this process has changed anything on the server, which an admin command that was
+/// declined at its prompt hasn't.
+pub fn made_changes() -> bool {
+    MADE_CHANGES.load(Ordering::SeqCst)
+}
+
+/// Send all subsequent requests to the server from the given interface or address.
+pub fn set_http_bind(bind: Option<HttpBind>) {
+    *HTTP_BIND.write().unwrap() = bind;
+}
+
+/// Send a request to the server with an optional extra request header, returning the response
+/// body and the value of the `header` response header, if the server sent it.
+fn send(
+    method: &str,
+    server: &SocketAddr,
+    endpoint: &str,
+    body: Option<serde_json::Value>,
+    request_header: Option<(&str, &str)>,
+    header: Option<&str>,
+) -> Result<(String, Option<String>), Error> {
+    let path = format!("/v1{}", endpoint);
+    if let Some(bind) = &*HTTP_BIND.read().unwrap() {
+        let body = body.map(|body| body.to_string());
+        let response = bound_http::request(
+            bind,
+            method,
+            server,
+            &path,
+            body.as_deref(),
+            request_header,
+            header,
+        )?;
+        if method != "GET" {
+            MADE_CHANGES.store(true, Ordering::SeqCst);
+        }
+        return Ok(response);
+    }
+
+    let mut request = HTTP_AGENT.request(method, &format!("http://{}{}", server, path));
+    if let Some((name, value)) = request_header {
+        request = request.set(name, value);
+    }
+    let response = match body {
+        Some(body) => request.send_json(body)?,
+        None => request.call()?,
+    };
+    if method != "GET" {
+        MADE_CHANGES.store(true, Ordering::SeqCst);
+    }
+    let value = header
+        .and_then(|header| response.header(header))
+        .map(String::from);
+    Ok((response.into_string()?, value))
+}
+
 pub fn http_get<T: DeserializeOwned>(server: &SocketAddr, endpoint: &str) -> Result<T, Error> {
-    let response = ureq::get(&format!("http://{}/v1{}", server, endpoint)).call()?;
+    let (response, _) = send("GET", server, endpoint, None, None, None)?;
     process_response(response)
 }
 
+/// Like `http_get`, but also returns the value of a response header, if the server sent it.
+pub fn http_get_with_header<T: DeserializeOwned>(
+    server: &SocketAddr,
+    endpoint: &str,
+    header: &str,
+) -> Result<(T, Option<String>), Error> {
+    let (response, value) = send("GET", server, endpoint, None, None, Some(header))?;
+    Ok((process_response(response)?, value))
+}
+
 pub fn http_delete(server: &SocketAddr, endpoint: &str) -> Result<(), Error> {
-    ureq::get(&format!("http://{}/v1{}", server, endpoint)).call()?;
+    send("DELETE", server, endpoint, None, None, None)?;
     Ok(())
 }
 
@@ -61,19 +426,85 @@ pub fn http_post<S: Serialize, D: DeserializeOwned>(
     endpoint: &str,
     form: S,
 ) -> Result<D, Error> {
-    let response = ureq::post(&format!("http://{}/v1{}", server, endpoint))
-        .send_json(serde_json::to_value(form)?)?;
+    let (response, _) = send(
+        "POST",
+        server,
+        endpoint,
+        Some(serde_json::to_value(form)?),
+        None,
+        None,
+    )?;
+    process_response(response)
+}
+
+/// Like `http_post`, but sends an idempotency key so that the request can be safely retried,
+/// with the same key, if its response is lost.
+pub fn http_post_idempotent<S: Serialize, D: DeserializeOwned>(
+    server: &SocketAddr,
+    endpoint: &str,
+    form: S,
+    idempotency_key: &str,
+) -> Result<D, Error> {
+    let (response, _) = send(
+        "POST",
+        server,
+        endpoint,
+        Some(serde_json::to_value(form)?),
+        Some((IDEMPOTENCY_KEY_HEADER, idempotency_key)),
+        None,
+    )?;
     process_response(response)
 }
 
+/// Generate a random (version 4) UUID to use as an idempotency key.
+pub fn idempotency_key() -> Result<String, Error> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// Whether a request failed before getting a response from the server (e.g. the connection
+/// dropped), rather than being rejected by it, making it worth retrying.
+pub fn is_transient(error: &Error) -> bool {
+    match error.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Transport(_)) => true,
+        Some(ureq::Error::Status(..)) => false,
+        None => error.is::<std::io::Error>(),
+    }
+}
+
+/// Whether an error came from talking to the server at all (e.g. it's unreachable or
+/// responded with an error), as opposed to a local problem like a broken config file.
+pub fn is_server_error(error: &Error) -> bool {
+    is_transient(error) || error.is::<ureq::Error>() || error.is::<ClientError>()
+}
+
 pub fn http_put<S: Serialize>(server: &SocketAddr, endpoint: &str, form: S) -> Result<(), Error> {
-    ureq::put(&format!("http://{}/v1{}", server, endpoint))
-        .send_json(serde_json::to_value(form)?)?;
+    send(
+        "PUT",
+        server,
+        endpoint,
+        Some(serde_json::to_value(form)?),
+        None,
+        None,
+    )?;
     Ok(())
 }
 
-fn process_response<T: DeserializeOwned>(response: ureq::Response) -> Result<T, Error> {
-    let mut response = response.into_string()?;
+fn process_response<T: DeserializeOwned>(mut response: String) -> Result<T, Error> {
     if response.is_empty() {
         response = "null".into();
     }
@@ -84,3 +515,111 @@ fn process_response<T: DeserializeOwned>(response: ureq::Response) -> Result<T,
         ))
     })?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_abbreviate_key() {
+        assert_eq!(
+            abbreviate_key("8Z3tBa9dpM+VaDjkqXKsB0uxJfRu8mrJHJcR2pXL+Cs="),
+            "8Z3tBa9dpM..."
+        );
+        assert_eq!(abbreviate_key("abcd"), "abcd...");
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("60"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_interval("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_interval("5m"), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("-1").is_err());
+        assert!(parse_interval("5x").is_err());
+    }
+
+    #[test]
+    fn test_parse_hosts_suffix() {
+        assert_eq!(parse_hosts_suffix("internal"), Ok("internal".to_string()));
+        assert_eq!(
+            parse_hosts_suffix(".wg.example.com"),
+            Ok("wg.example.com".to_string())
+        );
+        assert!(parse_hosts_suffix("").is_err());
+        assert!(parse_hosts_suffix(".").is_err());
+        assert!(parse_hosts_suffix("wg..example").is_err());
+        assert!(parse_hosts_suffix("under_score").is_err());
+    }
+
+    #[test]
+    fn test_distinct_interface_name() {
+        let taken = ["evilcorp", "evilcorp-2", "other"];
+        let is_taken = |name: &str| taken.contains(&name);
+        assert_eq!(
+            distinct_interface_name("evilcorp", is_taken),
+            Some("evilcorp-3".to_string())
+        );
+        assert_eq!(
+            distinct_interface_name("fresh", is_taken),
+            Some("fresh".to_string())
+        );
+        assert_eq!(distinct_interface_name("evilcorp", |_| true), None);
+    }
+
+    fn peer(name: &str, ip: &str) -> Peer {
+        Peer::for_tests(0, name, ip, "")
+    }
+
+    #[test]
+    fn test_append_rotating() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.csv");
+        assert_eq!(rotated_path(&path), dir.path().join("stats.csv.1"));
+
+        append_rotating(&path, 16, Some("header"), "1\n").unwrap();
+        append_rotating(&path, 16, Some("header"), "2\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "header\n1\n2\n");
+
+        // Past the limit, the next append starts a new file.
+        append_rotating(&path, 16, Some("header"), "3456789\n").unwrap();
+        append_rotating(&path, 16, Some("header"), "4\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "header\n4\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path)).unwrap(),
+            "header\n1\n2\n3456789\n"
+        );
+    }
+
+    #[test]
+    fn test_hostname_conflicts() {
+        let peers = [
+            peer("laptop", "10.0.0.2"),
+            peer("phone", "10.0.0.3"),
+            peer("laptop", "10.0.0.4"),
+            peer("laptop", "fd00::2"),
+        ];
+        let hostnames = peers
+            .iter()
+            .map(|peer| (format!("{}.evilcorp.wg", peer.name), peer))
+            .collect::<Vec<_>>();
+        let conflicts = hostname_conflicts(&hostnames);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, "laptop.evilcorp.wg");
+        assert_eq!(
+            conflicts[0]
+                .1
+                .iter()
+                .map(|peer| peer.ip)
+                .collect::<Vec<_>>(),
+            vec![
+                "10.0.0.2".parse::<IpAddr>().unwrap(),
+                "10.0.0.4".parse().unwrap()
+            ]
+        );
+        assert!(hostname_conflicts(&hostnames[..2]).is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+//! Offline checks of an interface config or invitation file, for `innernet validate`, so that
+//! automation can vet a config before trusting it with an interface.
+//!
+//! Nothing is changed and the server isn't contacted: only the file itself is read.
+
+use crate::Error;
+use colored::*;
+use ipnetwork::IpNetwork;
+use shared::interface_config::InterfaceConfig;
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+use wgctrl::Key;
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+
+    /// Usable, but likely not what was intended.
+    Warn(String),
+
+    Fail(String),
+}
+
+fn check_key(key: &str) -> Outcome {
+    match Key::from_base64(key) {
+        Ok(_) => Outcome::Pass,
+        Err(_) => Outcome::Fail("isn't a valid base64-encoded WireGuard key".to_string()),
+    }
+}
+
+fn check_address(address: IpNetwork) -> Outcome {
+    if address.prefix() == address.max_prefix() {
+        Outcome::Warn(format!(
+            "{} has a host prefix, so other peers won't be routable",
+            address
+        ))
+    } else if address.ip() == address.network() {
+        Outcome::Fail(format!("{} is its network's own address", address.ip()))
+    } else {
+        Outcome::Pass
+    }
+}
+
+fn check_srv_name(name: &str) -> Outcome {
+    let labels = name.trim_end_matches('.').split('.').collect::<Vec<_>>();
+    if labels.len() < 3 || labels.iter().any(|l| l.is_empty() || l.len() > 63) {
+        Outcome::Fail(format!("\"{}\" isn't a valid SRV name", name))
+    } else if !labels[0].starts_with('_') || !labels[1].starts_with('_') {
+        Outcome::Warn(format!(
+            "\"{}\" doesn't look like \"_service._proto.domain\"",
+            name
+        ))
+    } else {
+        Outcome::Pass
+    }
+}
+
+/// Every check that only depends on the config's contents.
+fn check_config(config: &InterfaceConfig) -> Vec<(&'static str, Outcome)> {
+    let InterfaceConfig {
+        interface, server, ..
+    } = config;
+    let mut checks = vec![
+        ("private key", check_key(&interface.private_key)),
+        ("server public key", check_key(&server.public_key)),
+    ];
+    if let (Outcome::Pass, Ok(public_key)) = (&checks[1].1, interface.public_key()) {
+        if public_key == server.public_key {
+            checks[1].1 = Outcome::Fail("is this peer's own public key".to_string());
+        }
+    }
+
+    checks.push(("address", check_address(interface.address)));
+
+    let external = server.external_endpoint;
+    checks.push((
+        "server external endpoint",
+        if external.ip().is_unspecified() || external.port() == 0 {
+            Outcome::Fail(format!("{} can't be connected to", external))
+        } else {
+            Outcome::Pass
+        },
+    ));
+    if let Some(name) = &server.external_endpoint_srv {
+        checks.push(("server SRV name", check_srv_name(name)));
+    }
+
+    let internal = server.internal_endpoint;
+    checks.push((
+        "server internal endpoint",
+        if internal.port() == 0 {
+            Outcome::Fail(format!("{} has no port", internal))
+        } else if !interface.address.contains(internal.ip()) {
+            Outcome::Fail(format!(
+                "{} is outside of the interface's network {}",
+                internal.ip(),
+                interface.address
+            ))
+        } else {
+            Outcome::Pass
+        },
+    ));
+    checks
+}
+
+fn check_permissions(path: &Path) -> Outcome {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 == 0 {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!(
+                    "mode {:o} lets other users read the private key (should be 600)",
+                    mode
+                ))
+            }
+        },
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
+
+pub fn validate(path: &Path) -> Result<(), Error> {
+    println!(
+        "{} validating {}.",
+        "[*]".dimmed(),
+        path.to_string_lossy().yellow()
+    );
+    let mut checks = vec![("file permissions", check_permissions(path))];
+    match InterfaceConfig::from_file(path) {
+        Ok(config) => {
+            checks.push(("parse", Outcome::Pass));
+            checks.extend(check_config(&config));
+        },
+        Err(e) => checks.push(("parse", Outcome::Fail(e.to_string()))),
+    }
+
+    let (mut warnings, mut failures) = (0, 0);
+    for (name, outcome) in &checks {
+        match outcome {
+            Outcome::Pass => println!("    {} {}", "pass".green(), name),
+            Outcome::Warn(reason) => {
+                warnings += 1;
+                println!("    {} {}: {}", "warn".yellow(), name, reason);
+            },
+            Outcome::Fail(reason) => {
+                failures += 1;
+                println!("    {} {}: {}", "fail".red(), name, reason);
+            },
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} of {} checks failed", failures, checks.len()).into());
+    }
+    println!(
+        "\n{} all {} checks passed, with {} warnings.",
+        "[*]".dimmed(),
+        checks.len(),
+        warnings
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVITATION: &str = "[interface]\nnetwork-name = \"evilcorp\"\n\
+                              address = \"10.42.5.2/16\"\n\
+                              private-key = \"iBkG25cCz6Ud7YlXlqVOEGvDUy8UR0eZAV1kSn6yJHI=\"\n\n\
+                              [server]\n\
+                              public-key = \"8Z3tBa9dpM+VaDjkqXKsB0uxJfRu8mrJHJcR2pXL+Cs=\"\n\
+                              external-endpoint = \"203.0.113.1:51820\"\n\
+                              internal-endpoint = \"10.42.0.1:51820\"\n";
+
+    fn failures(config: &InterfaceConfig) -> Vec<&'static str> {
+        check_config(config)
+            .into_iter()
+            .filter(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn test_check_config() {
+        let mut config: InterfaceConfig = toml::from_str(INVITATION).unwrap();
+        assert_eq!(failures(&config), Vec::<&str>::new());
+
+        config.interface.private_key = "abcd".to_string();
+        config.server.internal_endpoint = "10.43.0.1:51820".parse().unwrap();
+        config.server.external_endpoint = "0.0.0.0:51820".parse().unwrap();
+        assert_eq!(
+            failures(&config),
+            vec![
+                "private key",
+                "server external endpoint",
+                "server internal endpoint"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_address() {
+        assert_eq!(
+            check_address("10.42.5.2/16".parse().unwrap()),
+            Outcome::Pass
+        );
+        assert!(matches!(
+            check_address("10.42.5.2/32".parse().unwrap()),
+            Outcome::Warn(_)
+        ));
+        assert!(matches!(
+            check_address("10.42.0.0/16".parse().unwrap()),
+            Outcome::Fail(_)
+        ));
+    }
+}
@@ -9,7 +9,9 @@ fi
 
 cargo build
 
-for binary in "innernet" "innernet-server"; do
-    help2man --no-discard-stderr -s8 "target/debug/$binary" -N > "doc/$binary.8"
-    gzip -f "doc/$binary.8"
-done
+# The client generates its own man page from its CLI definition.
+target/debug/innernet man > doc/innernet.8
+gzip -f doc/innernet.8
+
+help2man --no-discard-stderr -s8 target/debug/innernet-server -N > doc/innernet-server.8
+gzip -f doc/innernet-server.8
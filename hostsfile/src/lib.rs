@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     fmt,
     fs::{self, File},
     io::{BufRead, BufReader, Write},
@@ -72,7 +72,9 @@ impl std::error::Error for Error {
 /// ```
 pub struct HostsBuilder {
     tag: String,
-    hostname_map: HashMap<IpAddr, Vec<String>>,
+    /// Ordered, so that the section is only rewritten differently when its mappings change,
+    /// with all of the IPv4 addresses before the IPv6 ones.
+    hostname_map: BTreeMap<IpAddr, Vec<String>>,
 }
 
 impl HostsBuilder {
@@ -81,7 +83,7 @@ impl HostsBuilder {
     pub fn new<S: Into<String>>(tag: S) -> Self {
         Self {
             tag: tag.into(),
-            hostname_map: HashMap::new(),
+            hostname_map: BTreeMap::new(),
         }
     }
 
@@ -92,6 +94,20 @@ impl HostsBuilder {
         hostnames_dest.push(hostname.to_string());
     }
 
+    /// Adds a mapping of `ip` to `hostname`, unless the hostname is already mapped to an address
+    /// of the same family, so that it resolves to at most one IPv4 and one IPv6 address. Returns
+    /// whether the mapping was added.
+    pub fn add_hostname_per_family<S: ToString>(&mut self, ip: IpAddr, hostname: S) -> bool {
+        let hostname = hostname.to_string();
+        let taken = self.hostname_map.iter().any(|(mapped_ip, hostnames)| {
+            mapped_ip.is_ipv4() == ip.is_ipv4() && hostnames.contains(&hostname)
+        });
+        if !taken {
+            self.add_hostname(ip, hostname);
+        }
+        !taken
+    }
+
     /// Adds a mapping of `ip` to a list of `hostname`s. If there hostnames associated with the IP
     /// already, the new hostnames will be appended to the list.
     pub fn add_hostnames<I: IntoIterator<Item = impl ToString>>(
@@ -140,15 +156,29 @@ impl HostsBuilder {
             _ => Vec::new(),
         };
 
-        let begin = lines.iter().position(|line| line.trim() == begin_marker);
-        let end = lines.iter().position(|line| line.trim() == end_marker);
-
-        let insert = match (begin, end) {
-            (Some(begin), Some(end)) => {
-                lines.drain(begin..end + 1);
-                begin
-            },
-            (None, None) => {
+        // Every section with the tag is removed (in case one was ever duplicated), and the new
+        // one takes the place of the first.
+        let mut insert = None;
+        loop {
+            let begin = lines.iter().position(|line| line.trim() == begin_marker);
+            let end = lines.iter().position(|line| line.trim() == end_marker);
+            match (begin, end) {
+                (Some(begin), Some(end)) if begin < end => {
+                    lines.drain(begin..end + 1);
+                    insert.get_or_insert(begin);
+                },
+                (None, None) => break,
+                _ => {
+                    return Err(Box::new(Error(format!(
+                        "start or end marker missing in {:?}",
+                        &hosts_file
+                    ))));
+                },
+            }
+        }
+        let insert = match insert {
+            Some(insert) => insert,
+            None => {
                 // Insert a blank line before a new section.
                 if let Some(last_line) = lines.iter().last() {
                     if last_line != "" {
@@ -157,12 +187,6 @@ impl HostsBuilder {
                 }
                 lines.len()
             },
-            _ => {
-                return Err(Box::new(Error(format!(
-                    "start or end marker missing in {:?}",
-                    &hosts_file
-                ))));
-            },
         };
 
         // The tempfile should be in the same filesystem as the hosts file.
@@ -197,3 +221,66 @@ impl HostsBuilder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_hosts(builder: &HostsBuilder, contents: &str) -> Result<String> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("hosts");
+        fs::write(&path, contents)?;
+        builder.write_to(&path)?;
+        Ok(fs::read_to_string(&path)?)
+    }
+
+    #[test]
+    fn test_write_replaces_duplicate_sections() {
+        let mut builder = HostsBuilder::new("test");
+        builder.add_hostname("10.0.0.2".parse().unwrap(), "laptop.wg");
+        let hosts = write_hosts(
+            &builder,
+            "127.0.0.1 localhost\n\
+             # DO NOT EDIT test BEGIN\n10.0.0.3 phone.wg\n# DO NOT EDIT test END\n\
+             192.168.1.1 router\n\
+             # DO NOT EDIT test BEGIN\n10.0.0.4 phone.wg\n# DO NOT EDIT test END\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hosts,
+            "127.0.0.1 localhost\n\
+             # DO NOT EDIT test BEGIN\n10.0.0.2 laptop.wg\n# DO NOT EDIT test END\n\
+             192.168.1.1 router\n"
+        );
+    }
+
+    #[test]
+    fn test_write_without_end_marker() {
+        let mut builder = HostsBuilder::new("test");
+        builder.add_hostname("10.0.0.2".parse().unwrap(), "laptop.wg");
+        let contents = "127.0.0.1 localhost\n# DO NOT EDIT test BEGIN\n10.0.0.3 phone.wg\n";
+        assert!(write_hosts(&builder, contents).is_err());
+
+        // Another tag's markers don't count.
+        let contents = "# DO NOT EDIT test BEGIN\n10.0.0.3 phone.wg\n# DO NOT EDIT other END\n";
+        assert!(write_hosts(&builder, contents).is_err());
+    }
+
+    #[test]
+    fn test_add_hostname_per_family() {
+        let mut builder = HostsBuilder::new("test");
+        assert!(builder.add_hostname_per_family("fd00::2".parse().unwrap(), "laptop.wg"));
+        assert!(builder.add_hostname_per_family("10.0.0.2".parse().unwrap(), "laptop.wg"));
+        assert!(!builder.add_hostname_per_family("10.0.0.4".parse().unwrap(), "laptop.wg"));
+        assert!(!builder.add_hostname_per_family("fd00::4".parse().unwrap(), "laptop.wg"));
+        assert!(builder.add_hostname_per_family("10.0.0.4".parse().unwrap(), "phone.wg"));
+
+        // IPv4 addresses come first, whatever order they were added in.
+        assert_eq!(
+            write_hosts(&builder, "127.0.0.1 localhost\n").unwrap(),
+            "127.0.0.1 localhost\n\n\
+             # DO NOT EDIT test BEGIN\n10.0.0.2 laptop.wg\n10.0.0.4 phone.wg\n\
+             fd00::2 laptop.wg\n# DO NOT EDIT test END\n"
+        );
+    }
+}
@@ -14,6 +14,7 @@ pub mod routes {
         warp::path("cidrs").and(
             list(context.clone())
                 .or(create(context.clone()))
+                .or(update(context.clone()))
                 .or(delete(context)),
         )
     }
@@ -37,6 +38,17 @@ pub mod routes {
             .and_then(handlers::create)
     }
 
+    pub fn update(
+        context: Context,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path::param()
+            .and(warp::path::end())
+            .and(warp::put())
+            .and(form_body())
+            .and(with_admin_session(context))
+            .and_then(handlers::update)
+    }
+
     pub fn delete(
         context: Context,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -73,6 +85,17 @@ mod handlers {
         Ok(warp::reply::json(&cidrs))
     }
 
+    pub async fn update(
+        id: i64,
+        contents: CidrContents,
+        session: AdminSession,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let conn = session.context.db.lock();
+        DatabaseCidr::update(&conn, id, contents)?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
     pub async fn delete(
         id: i64,
         session: AdminSession,
@@ -101,6 +124,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            is_infra: false,
         };
 
         let filter = crate::routes(server.context());
@@ -121,6 +145,66 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_cidr_set_infra() -> Result<()> {
+        let server = test::Server::new()?;
+        let user1 = DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?;
+        let visible_to_user1 = |server: &test::Server| -> Result<Vec<String>> {
+            Ok(user1
+                .get_all_allowed_peers(&server.db().lock())?
+                .into_iter()
+                .map(|peer| peer.inner.contents.name)
+                .collect())
+        };
+        assert!(!visible_to_user1(&server)?.contains(&"developer1".to_string()));
+
+        let contents = CidrContents {
+            is_infra: true,
+            ..DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?.contents
+        };
+        let filter = crate::routes(server.context());
+        let res = test::put_request_from_ip(test::ADMIN_PEER_IP)
+            .path(&format!("/v1/admin/cidrs/{}", test::DEVELOPER_CIDR_ID))
+            .body(serde_json::to_string(&contents)?)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        assert!(DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?.is_infra);
+        assert!(visible_to_user1(&server)?.contains(&"developer1".to_string()));
+
+        // Only the infra flag can be changed.
+        let contents = CidrContents {
+            name: "renamed".to_string(),
+            ..contents
+        };
+        let res = test::put_request_from_ip(test::ADMIN_PEER_IP)
+            .path(&format!("/v1/admin/cidrs/{}", test::DEVELOPER_CIDR_ID))
+            .body(serde_json::to_string(&contents)?)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?.name,
+            "developer"
+        );
+
+        // The innernet server's CIDR has to stay infra.
+        let contents = CidrContents {
+            is_infra: false,
+            ..DatabaseCidr::get(&server.db().lock(), test::INFRA_CIDR_ID)?.contents
+        };
+        let res = test::put_request_from_ip(test::ADMIN_PEER_IP)
+            .path(&format!("/v1/admin/cidrs/{}", test::INFRA_CIDR_ID))
+            .body(serde_json::to_string(&contents)?)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(DatabaseCidr::get(&server.db().lock(), test::INFRA_CIDR_ID)?.is_infra);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_cidr_name_uniqueness() -> Result<()> {
         let server = test::Server::new()?;
@@ -129,6 +213,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            is_infra: false,
         };
 
         let filter = crate::routes(server.context());
@@ -144,6 +229,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
             parent: Some(cidr_res.id),
+            is_infra: false,
         };
         let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
             .path("/v1/admin/cidrs")
@@ -163,6 +249,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            is_infra: false,
         };
 
         let filter = crate::routes(server.context());
@@ -184,6 +271,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            is_infra: false,
         };
         let filter = crate::routes(server.context());
         let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
@@ -197,6 +285,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            is_infra: false,
         };
 
         let filter = crate::routes(server.context());
@@ -218,6 +307,7 @@ mod tests {
             name: "experimental".to_string(),
             cidr: "10.80.1.0/21".parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            is_infra: false,
         };
         let filter = crate::routes(server.context());
         let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
@@ -240,6 +330,7 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                is_infra: false,
             },
         )?;
         let experimental_subcidr = DatabaseCidr::create(
@@ -248,6 +339,7 @@ mod tests {
                 name: "experimental subcidr".to_string(),
                 cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
                 parent: Some(experimental_cidr.id),
+                is_infra: false,
             },
         )?;
 
@@ -289,6 +381,7 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                is_infra: false,
             },
         )?;
 
@@ -1,12 +1,25 @@
 use crate::{
     api::inject_endpoints, db::DatabasePeer, with_admin_session, AdminSession, Context, ServerError,
 };
-use shared::PeerContents;
+use serde::Deserialize;
+use shared::{PeerContents, IDEMPOTENCY_KEY_HEADER, PEER_NAME_PATTERN_HEADER};
+use std::time::{Duration, Instant};
 use warp::{
     http::{response::Response, StatusCode},
     Filter,
 };
-use wgctrl::DeviceConfigBuilder;
+use wgctrl::{DeviceConfigBuilder, Key, PeerConfigBuilder};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteQuery {
+    /// Delete the peer altogether instead of only disabling it.
+    #[serde(default)]
+    permanent: bool,
+}
+
+/// How long the peer created for an idempotency key is remembered, after which the key may be
+/// reused. Keys are only kept in memory, so they're also forgotten when the server restarts.
+const IDEMPOTENCY_KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub mod routes {
     use crate::form_body;
@@ -31,6 +44,7 @@ pub mod routes {
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path::end()
             .and(warp::post())
+            .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
             .and(form_body())
             .and(with_admin_session(context))
             .and_then(handlers::create)
@@ -58,13 +72,14 @@ pub mod routes {
             .and_then(handlers::list)
     }
 
-    // DELETE /v1/admin/peers/:id
+    // DELETE /v1/admin/peers/:id[?permanent=true]
     pub fn delete(
         context: Context,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path::param()
             .and(warp::path::end())
             .and(warp::delete())
+            .and(warp::query::<DeleteQuery>())
             .and(with_admin_session(context))
             .and_then(handlers::delete)
     }
@@ -73,14 +88,51 @@ pub mod routes {
 mod handlers {
     use super::*;
 
+    /// Create a new peer.
+    ///
+    /// If the request has an idempotency key that was already used to create a peer, that peer is
+    /// returned again instead of creating a duplicate, so that clients can safely retry requests
+    /// whose response was lost. Reusing a key for a different peer is rejected.
     pub async fn create(
+        idempotency_key: Option<String>,
         form: PeerContents,
         session: AdminSession,
     ) -> Result<impl warp::Reply, warp::Rejection> {
+        if let Some(pattern) = &session.context.peer_name_pattern {
+            if !pattern.is_match(&form.name) {
+                log::warn!(
+                    "refusing to add peer {}, whose name doesn't match {}.",
+                    form.name,
+                    pattern
+                );
+                return Err(ServerError::InvalidQuery.into());
+            }
+        }
+
+        // Held until the peer is created, so that concurrent retries can't both create one.
+        let mut idempotency_keys = session.context.idempotency_keys.lock();
+        idempotency_keys.retain(|_, (created, ..)| created.elapsed() < IDEMPOTENCY_KEY_LIFETIME);
+        if let Some((_, request, peer)) = idempotency_key
+            .as_ref()
+            .and_then(|key| idempotency_keys.get(key))
+        {
+            if *request != form {
+                log::warn!("idempotency key was reused for a different peer.");
+                return Err(ServerError::InvalidQuery.into());
+            }
+            log::info!("peer {} was already created for this request.", peer);
+            return Ok(Response::builder()
+                .status(StatusCode::CREATED)
+                .body(serde_json::to_string(peer).unwrap()));
+        }
+
         let conn = session.context.db.lock();
 
-        let peer = DatabasePeer::create(&conn, form)?;
+        let peer = DatabasePeer::create(&conn, form.clone())?;
         log::info!("adding peer {}", &*peer);
+        if let Some(key) = idempotency_key {
+            idempotency_keys.insert(key, (Instant::now(), form, peer.inner.clone()));
+        }
 
         if cfg!(not(test)) {
             // Update the current WireGuard interface with the new peers.
@@ -104,12 +156,39 @@ mod handlers {
     ) -> Result<impl warp::Reply, warp::Rejection> {
         let conn = session.context.db.lock();
         let mut peer = DatabasePeer::get(&conn, id)?;
+        // Peers named before the convention was set keep their names until they're renamed.
+        if let Some(pattern) = &session.context.peer_name_pattern {
+            if form.name != peer.name && !pattern.is_match(&form.name) {
+                log::warn!(
+                    "refusing to rename peer {} to {}, which doesn't match {}.",
+                    peer.name,
+                    form.name,
+                    pattern
+                );
+                return Err(ServerError::InvalidQuery.into());
+            }
+        }
+        let ip_changed = form.ip != peer.ip;
+        if ip_changed {
+            peer.set_ip(&conn, form.ip)?;
+        }
         peer.update(&conn, form)?;
 
+        if ip_changed && cfg!(not(test)) {
+            DeviceConfigBuilder::new()
+                .add_peer(PeerConfigBuilder::from(&*peer))
+                .apply(&session.context.interface)
+                .map_err(|_| ServerError::WireGuard)?;
+            log::info!("updated WireGuard interface, moving {}", &*peer);
+        }
+
         Ok(StatusCode::NO_CONTENT)
     }
 
     /// List all peers, including disabled ones. This is an admin-only endpoint.
+    ///
+    /// The network's naming convention for new peers, if it has one, is sent along in a
+    /// header, so that clients can check names before trying to add a peer.
     pub async fn list(session: AdminSession) -> Result<impl warp::Reply, warp::Rejection> {
         let conn = session.context.db.lock();
         let mut peers = DatabasePeer::list(&conn)?
@@ -117,15 +196,45 @@ mod handlers {
             .map(|peer| peer.inner)
             .collect::<Vec<_>>();
         inject_endpoints(&session, &mut peers);
-        Ok(warp::reply::json(&peers))
+        let reply = warp::reply::json(&peers);
+        Ok(match &session.context.peer_name_pattern {
+            Some(pattern) => Box::new(warp::reply::with_header(
+                reply,
+                PEER_NAME_PATTERN_HEADER,
+                pattern.to_string(),
+            )) as Box<dyn warp::Reply>,
+            None => Box::new(reply),
+        })
     }
 
+    /// Disable a peer, or with `permanent`, delete it altogether so that its IP can be reused.
     pub async fn delete(
         id: i64,
+        query: DeleteQuery,
         session: AdminSession,
     ) -> Result<impl warp::Reply, warp::Rejection> {
         let conn = session.context.db.lock();
-        DatabasePeer::disable(&conn, id)?;
+        if !query.permanent {
+            DatabasePeer::disable(&conn, id)?;
+            return Ok(StatusCode::NO_CONTENT);
+        }
+
+        if id == 1 {
+            log::warn!("refusing to delete the innernet server's own peer.");
+            return Err(ServerError::InvalidQuery.into());
+        }
+        let peer = DatabasePeer::delete(&conn, id)?;
+        log::info!("deleted peer {}", &*peer);
+
+        if cfg!(not(test)) {
+            let public_key =
+                Key::from_base64(&peer.public_key).map_err(|_| ServerError::WireGuard)?;
+            DeviceConfigBuilder::new()
+                .remove_peer_by_key(&public_key)
+                .apply(&session.context.interface)
+                .map_err(|_| ServerError::WireGuard)?;
+            log::info!("updated WireGuard interface, removing {}", &*peer);
+        }
 
         Ok(StatusCode::NO_CONTENT)
     }
@@ -156,7 +265,15 @@ mod tests {
         assert_eq!(res.status(), StatusCode::CREATED);
         // The response contains the new peer information.
         let peer_res: Peer = serde_json::from_slice(&res.body())?;
-        assert_eq!(peer, peer_res.contents);
+        // The server is responsible for setting the creation time.
+        assert!(peer_res.created_at.is_some());
+        assert_eq!(
+            peer,
+            PeerContents {
+                created_at: None,
+                ..peer_res.contents
+            }
+        );
 
         // The number of peer entries in the database increased by 1.
         let new_peers = DatabasePeer::list(&server.db().lock())?;
@@ -165,6 +282,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_idempotent() -> Result<()> {
+        let server = test::Server::new()?;
+
+        let old_peers = DatabasePeer::list(&server.db().lock())?;
+
+        let peer = test::developer_peer_contents("developer3", "10.80.64.4")?;
+
+        let filter = crate::routes(server.context());
+        let mut created = vec![];
+        for _ in 0..2 {
+            let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
+                .path("/v1/admin/peers")
+                .header(IDEMPOTENCY_KEY_HEADER, "retried-request")
+                .body(serde_json::to_string(&peer)?)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            created.push(serde_json::from_slice::<Peer>(res.body())?);
+        }
+        // The retry returns the same peer instead of creating another.
+        assert_eq!(created[0], created[1]);
+        let new_peers = DatabasePeer::list(&server.db().lock())?;
+        assert_eq!(old_peers.len() + 1, new_peers.len());
+
+        // Reusing the key for a different peer is an error.
+        let other_peer = test::developer_peer_contents("developer4", "10.80.64.5")?;
+        let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
+            .path("/v1/admin/peers")
+            .header(IDEMPOTENCY_KEY_HEADER, "retried-request")
+            .body(serde_json::to_string(&other_peer)?)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_invalid_name() -> Result<()> {
         let server = test::Server::new()?;
@@ -182,6 +337,37 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_with_name_pattern() -> Result<()> {
+        let server = test::Server::new()?;
+        let mut context = server.context();
+        context.peer_name_pattern = Some("dev-[a-z0-9]+".parse().unwrap());
+        let filter = crate::routes(context);
+
+        let res = test::request_from_ip(test::ADMIN_PEER_IP)
+            .path("/v1/admin/peers")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.headers()[PEER_NAME_PATTERN_HEADER], "dev-[a-z0-9]+");
+
+        let peer = test::developer_peer_contents("developer3", "10.80.64.4")?;
+        let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
+            .path("/v1/admin/peers")
+            .body(serde_json::to_string(&peer)?)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let peer = test::developer_peer_contents("dev-3", "10.80.64.4")?;
+        let res = test::post_request_from_ip(test::ADMIN_PEER_IP)
+            .path("/v1/admin/peers")
+            .body(serde_json::to_string(&peer)?)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_duplicate_name() -> Result<()> {
         let server = test::Server::new()?;
@@ -317,6 +503,77 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_update_peer_with_name_pattern() -> Result<()> {
+        let server = test::Server::new()?;
+        let old_peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        let mut context = server.context();
+        context.peer_name_pattern = Some("dev-[a-z0-9]+".parse().unwrap());
+        let filter = crate::routes(context);
+        let put = |name: &str| {
+            let change = PeerContents {
+                name: name.to_string(),
+                ..old_peer.contents.clone()
+            };
+            test::put_request_from_ip(test::ADMIN_PEER_IP)
+                .path(&format!("/v1/admin/peers/{}", test::DEVELOPER1_PEER_ID))
+                .body(serde_json::to_string(&change).unwrap())
+        };
+
+        // The peer's existing name predates the convention, but can be kept.
+        assert_eq!(
+            put(&old_peer.name).reply(&filter).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            put("new-peer-name").reply(&filter).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            put("dev-laptop").reply(&filter).await.status(),
+            StatusCode::NO_CONTENT
+        );
+
+        let new_peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(new_peer.name, "dev-laptop");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reassign_peer_ip() -> Result<()> {
+        let server = test::Server::new()?;
+        let old_peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        let filter = crate::routes(server.context());
+        let put = |ip: &str| {
+            let change = PeerContents {
+                ip: ip.parse().unwrap(),
+                ..old_peer.contents.clone()
+            };
+            test::put_request_from_ip(test::ADMIN_PEER_IP)
+                .path(&format!("/v1/admin/peers/{}", test::DEVELOPER1_PEER_ID))
+                .body(serde_json::to_string(&change).unwrap())
+        };
+
+        // Outside of the peer's CIDR, and taken by another peer.
+        assert_eq!(
+            put(test::USER1_PEER_IP).reply(&filter).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            put(test::DEVELOPER2_PEER_IP).reply(&filter).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        assert_eq!(
+            put("10.80.64.100").reply(&filter).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let new_peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(new_peer.ip, "10.80.64.100".parse::<std::net::IpAddr>()?);
+        assert_eq!(new_peer.public_key, old_peer.public_key);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_update_peer_from_non_admin() -> Result<()> {
         let server = test::Server::new()?;
@@ -402,6 +659,40 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_delete_permanently() -> Result<()> {
+        let server = test::Server::new()?;
+        let filter = crate::routes(server.context());
+
+        let old_peers = DatabasePeer::list(&server.db().lock())?;
+
+        let res = test::request_from_ip(test::ADMIN_PEER_IP)
+            .method("DELETE")
+            .path(&format!(
+                "/v1/admin/peers/{}?permanent=true",
+                test::USER1_PEER_ID
+            ))
+            .reply(&filter)
+            .await;
+
+        assert!(res.status().is_success());
+
+        // The peer is gone altogether, rather than disabled.
+        let new_peers = DatabasePeer::list(&server.db().lock())?;
+        assert_eq!(old_peers.len() - 1, new_peers.len());
+        assert!(new_peers.iter().all(|p| p.id != test::USER1_PEER_ID));
+
+        // The server's own peer can't be deleted.
+        let res = test::request_from_ip(test::ADMIN_PEER_IP)
+            .method("DELETE")
+            .path("/v1/admin/peers/1?permanent=true")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_delete_from_non_admin() -> Result<()> {
         let server = test::Server::new()?;
@@ -5,7 +5,10 @@ use crate::{
     UnredeemedSession,
 };
 use hyper::StatusCode;
-use shared::{EndpointContents, PeerContents, RedeemContents, State, REDEEM_TRANSITION_WAIT};
+use shared::{
+    EndpointContents, PeerContents, RedeemContents, State, MIN_CLIENT_VERSION_HEADER,
+    REDEEM_TRANSITION_WAIT,
+};
 use warp::Filter;
 use wgctrl::DeviceConfigBuilder;
 
@@ -75,7 +78,19 @@ mod handlers {
             .collect();
         inject_endpoints(&session, &mut peers);
 
-        Ok(warp::reply::json(&State { cidrs, peers }))
+        let reply = warp::reply::json(&State {
+            cidrs,
+            peers,
+            motd: session.context.motd.clone(),
+        });
+        Ok(match &session.context.min_client_version {
+            Some(version) => Box::new(warp::reply::with_header(
+                reply,
+                MIN_CLIENT_VERSION_HEADER,
+                version.as_str(),
+            )) as Box<dyn warp::Reply>,
+            None => Box::new(reply),
+        })
     }
 
     /// Redeems an invitation. An invitation includes a WireGuard keypair generated by either the server
@@ -145,12 +160,31 @@ mod handlers {
         contents: EndpointContents,
         session: Session,
     ) -> Result<impl warp::Reply, warp::Rejection> {
+        let (endpoint, fallback_endpoints) = match contents {
+            EndpointContents::SetMultiple(endpoints) if endpoints.is_empty() => {
+                return Err(ServerError::InvalidQuery.into());
+            },
+            EndpointContents::SetMultiple(mut endpoints) => {
+                let primary = endpoints.remove(0);
+                (Some(primary), endpoints)
+            },
+            contents => (contents.into(), vec![]),
+        };
+
         let conn = session.context.db.lock();
         let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
+        if !selected_peer.endpoint_override_allowed {
+            log::warn!(
+                "peer {} tried to override its endpoint, which it isn't allowed to.",
+                selected_peer.name
+            );
+            return Err(ServerError::Unauthorized.into());
+        }
         selected_peer.update(
             &conn,
             PeerContents {
-                endpoint: contents.into(),
+                endpoint,
+                fallback_endpoints,
                 ..selected_peer.contents.clone()
             },
         )?;
@@ -190,6 +224,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_state_advertises_min_client_version() -> Result<()> {
+        let server = test::Server::new()?;
+        let res = test::request_from_ip(test::DEVELOPER1_PEER_IP)
+            .path("/v1/user/state")
+            .reply(&crate::routes(server.context()))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(MIN_CLIENT_VERSION_HEADER).is_none());
+
+        let mut context = server.context();
+        context.min_client_version = Some("1.2.3".to_string());
+        let res = test::request_from_ip(test::DEVELOPER1_PEER_IP)
+            .path("/v1/user/state")
+            .reply(&crate::routes(context))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[MIN_CLIENT_VERSION_HEADER], "1.2.3");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_state_includes_motd() -> Result<()> {
+        let server = test::Server::new()?;
+        let mut context = server.context();
+        context.motd = Some("This network is monitored.".to_string());
+        let res = test::request_from_ip(test::DEVELOPER1_PEER_IP)
+            .path("/v1/user/state")
+            .reply(&crate::routes(context))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let State { motd, .. } = serde_json::from_slice(res.body())?;
+        assert_eq!(motd.as_deref(), Some("This network is monitored."));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_override_endpoint() -> Result<()> {
         let server = test::Server::new()?;
@@ -217,6 +289,33 @@ mod tests {
             StatusCode::NO_CONTENT
         );
 
+        assert_eq!(
+            test::put_request_from_ip(test::DEVELOPER1_PEER_IP)
+                .path("/v1/user/endpoint")
+                .body(serde_json::to_string(&EndpointContents::SetMultiple(
+                    vec!["1.1.1.1:51820".parse()?, "10.99.0.1:51820".parse()?,]
+                ))?)
+                .reply(&filter)
+                .await
+                .status(),
+            StatusCode::NO_CONTENT
+        );
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.endpoint, Some("1.1.1.1:51820".parse()?));
+        assert_eq!(peer.fallback_endpoints, vec!["10.99.0.1:51820".parse()?]);
+
+        assert_eq!(
+            test::put_request_from_ip(test::DEVELOPER1_PEER_IP)
+                .path("/v1/user/endpoint")
+                .body(serde_json::to_string(&EndpointContents::SetMultiple(
+                    vec![]
+                ))?)
+                .reply(&filter)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+
         assert_eq!(
             test::put_request_from_ip(test::DEVELOPER1_PEER_IP)
                 .path("/v1/user/endpoint")
@@ -230,6 +329,38 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_override_endpoint_not_allowed() -> Result<()> {
+        let server = test::Server::new()?;
+        {
+            let db = server.db();
+            let conn = db.lock();
+            let mut peer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+            let contents = PeerContents {
+                endpoint_override_allowed: false,
+                ..peer.contents.clone()
+            };
+            peer.update(&conn, contents)?;
+        }
+
+        let filter = crate::routes(server.context());
+        assert_eq!(
+            test::put_request_from_ip(test::DEVELOPER1_PEER_IP)
+                .path("/v1/user/endpoint")
+                .body(serde_json::to_string(&EndpointContents::Set(
+                    "1.1.1.1:51820".parse()?
+                ))?)
+                .reply(&filter)
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.endpoint, None);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_peers_from_unknown_ip() -> Result<()> {
         let server = test::Server::new()?;
@@ -258,6 +389,7 @@ mod tests {
                     name: "experiment cidr".to_string(),
                     cidr: test::EXPERIMENTAL_CIDR.parse()?,
                     parent: Some(test::ROOT_CIDR_ID),
+                    is_infra: false,
                 },
             )?;
             let subcidr = DatabaseCidr::create(
@@ -266,6 +398,7 @@ mod tests {
                     name: "experiment subcidr".to_string(),
                     cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
                     parent: Some(cidr.id),
+                    is_infra: false,
                 },
             )?;
             DatabasePeer::create(
@@ -284,6 +417,7 @@ mod tests {
                 AssociationContents {
                     cidr_id_1: test::DEVELOPER_CIDR_ID,
                     cidr_id_2: cidr.id,
+                    label: None,
                 },
             )?;
             DatabaseAssociation::create(
@@ -291,6 +425,7 @@ mod tests {
                 AssociationContents {
                     cidr_id_1: test::INFRA_CIDR_ID,
                     cidr_id_2: cidr.id,
+                    label: None,
                 },
             )?;
         }
@@ -329,6 +464,7 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                is_infra: false,
             },
         )?;
 
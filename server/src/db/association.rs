@@ -11,6 +11,7 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE associations (
       id         INTEGER PRIMARY KEY,
       cidr_id_1  INTEGER NOT NULL,
       cidr_id_2  INTEGER NOT NULL,
+      label      TEXT,             /* An optional note on why the association exists. */
       UNIQUE(cidr_id_1, cidr_id_2),
       FOREIGN KEY (cidr_id_1)
          REFERENCES cidrs (id) 
@@ -52,15 +53,21 @@ impl DatabaseAssociation {
         conn: &Connection,
         contents: AssociationContents,
     ) -> Result<Association, ServerError> {
+        // Empty labels are the same as none at all.
+        let contents = AssociationContents {
+            label: contents.label.filter(|label| !label.trim().is_empty()),
+            ..contents
+        };
         let AssociationContents {
             cidr_id_1,
             cidr_id_2,
+            label,
         } = &contents;
 
         conn.execute(
-            "INSERT INTO associations (cidr_id_1, cidr_id_2)
-              VALUES (?1, ?2)",
-            params![cidr_id_1, cidr_id_2],
+            "INSERT INTO associations (cidr_id_1, cidr_id_2, label)
+              VALUES (?1, ?2, ?3)",
+            params![cidr_id_1, cidr_id_2, label],
         )?;
         let id = conn.last_insert_rowid();
         Ok(Association { id, contents })
@@ -72,16 +79,19 @@ impl DatabaseAssociation {
     }
 
     pub fn list(conn: &Connection) -> Result<Vec<Association>, ServerError> {
-        let mut stmt = conn.prepare_cached("SELECT id, cidr_id_1, cidr_id_2 FROM associations")?;
+        let mut stmt =
+            conn.prepare_cached("SELECT id, cidr_id_1, cidr_id_2, label FROM associations")?;
         let auth_iter = stmt.query_map(params![], |row| {
             let id = row.get(0)?;
             let cidr_id_1 = row.get(1)?;
             let cidr_id_2 = row.get(2)?;
+            let label = row.get(3)?;
             Ok(Association {
                 id,
                 contents: AssociationContents {
                     cidr_id_1,
                     cidr_id_2,
+                    label,
                 },
             })
         })?;
@@ -1,6 +1,6 @@
 use crate::ServerError;
 use ipnetwork::IpNetwork;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
 use shared::{Cidr, CidrContents};
 use std::ops::Deref;
 
@@ -10,6 +10,7 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE cidrs (
       ip               TEXT NOT NULL,
       prefix           INTEGER NOT NULL,
       parent           INTEGER REFERENCES cidrs,
+      is_infra         INTEGER DEFAULT 0 NOT NULL,
       UNIQUE(ip, prefix),
       FOREIGN KEY (parent)
          REFERENCES cidrs (id)
@@ -37,7 +38,12 @@ impl Deref for DatabaseCidr {
 
 impl DatabaseCidr {
     pub fn create(conn: &Connection, contents: CidrContents) -> Result<Cidr, ServerError> {
-        let CidrContents { name, cidr, parent } = &contents;
+        let CidrContents {
+            name,
+            cidr,
+            parent,
+            is_infra,
+        } = &contents;
 
         log::debug!("creating {:?}", contents);
 
@@ -93,14 +99,52 @@ impl DatabaseCidr {
         }
 
         conn.execute(
-            "INSERT INTO cidrs (name, ip, prefix, parent)
-              VALUES (?1, ?2, ?3, ?4)",
-            params![name, cidr.ip().to_string(), cidr.prefix() as i32, parent],
+            "INSERT INTO cidrs (name, ip, prefix, parent, is_infra)
+              VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                name,
+                cidr.ip().to_string(),
+                cidr.prefix() as i32,
+                parent,
+                is_infra
+            ],
         )?;
         let id = conn.last_insert_rowid();
         Ok(Cidr { id, contents })
     }
 
+    /// Update a CIDR. Only whether it's an infra CIDR can be changed, since changing its
+    /// network or place in the tree would need all of its peers and children to move along.
+    ///
+    /// The innernet server's CIDR can't be unflagged, since every peer would then lose the
+    /// server, and so the whole network.
+    pub fn update(conn: &Connection, id: i64, contents: CidrContents) -> Result<Cidr, ServerError> {
+        let current = Self::get(conn, id)?.contents;
+        if contents.name != current.name
+            || contents.cidr != current.cidr
+            || contents.parent != current.parent
+        {
+            log::warn!("tried to change a CIDR's name, network, or parent.");
+            return Err(ServerError::InvalidQuery);
+        }
+        let server_cidr_id: Option<i64> = conn
+            .query_row("SELECT cidr_id FROM peers WHERE id = 1", params![], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if !contents.is_infra && server_cidr_id == Some(id) {
+            log::warn!("tried to unflag the innernet server's infra CIDR.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let cidr = Cidr { id, contents };
+        conn.execute(
+            "UPDATE cidrs SET is_infra = ?1 WHERE id = ?2",
+            params![cidr.is_infra, id],
+        )?;
+        Ok(cidr)
+    }
+
     pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
         conn.execute("DELETE FROM cidrs WHERE id = ?1", params![id])?;
         Ok(())
@@ -118,22 +162,29 @@ impl DatabaseCidr {
         )
         .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?;
         let parent = row.get(4)?;
+        let is_infra = row.get(5)?;
         Ok(Cidr {
             id,
-            contents: CidrContents { name, cidr, parent },
+            contents: CidrContents {
+                name,
+                cidr,
+                parent,
+                is_infra,
+            },
         })
     }
 
     pub fn get(conn: &Connection, id: i64) -> Result<Cidr, ServerError> {
         Ok(conn.query_row(
-            "SELECT id, name, ip, prefix, parent FROM cidrs WHERE id = ?1",
+            "SELECT id, name, ip, prefix, parent, is_infra FROM cidrs WHERE id = ?1",
             params![id],
             Self::from_row,
         )?)
     }
 
     pub fn list(conn: &Connection) -> Result<Vec<Cidr>, ServerError> {
-        let mut stmt = conn.prepare_cached("SELECT id, name, ip, prefix, parent FROM cidrs")?;
+        let mut stmt =
+            conn.prepare_cached("SELECT id, name, ip, prefix, parent, is_infra FROM cidrs")?;
         let cidr_iter = stmt.query_map(params![], Self::from_row)?;
 
         Ok(cidr_iter.collect::<Result<Vec<_>, rusqlite::Error>>()?)
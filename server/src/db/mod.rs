@@ -5,3 +5,145 @@ pub mod peer;
 pub use association::DatabaseAssociation;
 pub use cidr::DatabaseCidr;
 pub use peer::DatabasePeer;
+
+use rusqlite::{params, Connection};
+
+/// The current version of the database schema, stored in SQLite's `user_version` pragma.
+///
+/// Bump this and add a step to `auto_migrate` whenever a table's schema changes.
+pub const CURRENT_VERSION: i64 = 6;
+
+/// Bring a database created by an older version of innernet-server up to date.
+///
+/// The steps and the version bump run in one transaction, so that a failed step leaves the
+/// database as it was instead of re-running the earlier steps (and failing on their columns
+/// already existing) on the next start.
+pub fn auto_migrate(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let conn = conn.unchecked_transaction()?;
+    let old_version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if old_version < 1 {
+        conn.execute("ALTER TABLE peers ADD COLUMN created_at INTEGER", params![])?;
+    }
+
+    if old_version < 2 {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN fallback_endpoints TEXT",
+            params![],
+        )?;
+    }
+
+    if old_version < 3 {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN endpoint_override_allowed INTEGER DEFAULT 1 NOT NULL",
+            params![],
+        )?;
+    }
+
+    if old_version < 4 {
+        conn.execute("ALTER TABLE associations ADD COLUMN label TEXT", params![])?;
+    }
+
+    if old_version < 5 {
+        conn.execute(
+            "ALTER TABLE cidrs ADD COLUMN is_infra INTEGER DEFAULT 0 NOT NULL",
+            params![],
+        )?;
+        // The infra CIDR used to always be the one with id 2, created by the init wizard.
+        conn.execute("UPDATE cidrs SET is_infra = 1 WHERE id = 2", params![])?;
+    }
+
+    if old_version < 6 {
+        conn.execute("ALTER TABLE peers ADD COLUMN expires_at INTEGER", params![])?;
+    }
+
+    if old_version != CURRENT_VERSION {
+        conn.pragma_update(None, "user_version", &CURRENT_VERSION)?;
+        conn.commit()?;
+        log::info!(
+            "migrated database from version {} to {}.",
+            old_version,
+            CURRENT_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_auto_migrate_from_unversioned() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute(
+            "CREATE TABLE peers (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE)",
+            params![],
+        )?;
+        conn.execute(
+            "CREATE TABLE associations (id INTEGER PRIMARY KEY, cidr_id_1 INTEGER NOT NULL, cidr_id_2 INTEGER NOT NULL)",
+            params![],
+        )?;
+        conn.execute(
+            "CREATE TABLE cidrs (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE)",
+            params![],
+        )?;
+        conn.execute(
+            "INSERT INTO cidrs (name) VALUES ('root'), ('infra')",
+            params![],
+        )?;
+
+        auto_migrate(&conn)?;
+        conn.execute(
+            "INSERT INTO peers (name, created_at, fallback_endpoints, endpoint_override_allowed, expires_at) VALUES ('peer', 0, '', 1, NULL)",
+            params![],
+        )?;
+        conn.execute(
+            "INSERT INTO associations (cidr_id_1, cidr_id_2, label) VALUES (1, 2, 'label')",
+            params![],
+        )?;
+        let infra: String = conn.query_row(
+            "SELECT name FROM cidrs WHERE is_infra = 1",
+            params![],
+            |row| row.get(0),
+        )?;
+        assert_eq!(infra, "infra");
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        assert_eq!(CURRENT_VERSION, version);
+
+        // Migrating an up-to-date database is a no-op.
+        auto_migrate(&conn)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_migrate_failed_step() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute(
+            "CREATE TABLE peers (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE)",
+            params![],
+        )?;
+        conn.execute(
+            "CREATE TABLE cidrs (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE)",
+            params![],
+        )?;
+
+        // The associations step fails without its table, which undoes the steps before it.
+        assert!(auto_migrate(&conn).is_err());
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        assert_eq!(version, 0);
+
+        conn.execute(
+            "CREATE TABLE associations (id INTEGER PRIMARY KEY, cidr_id_1 INTEGER NOT NULL, cidr_id_2 INTEGER NOT NULL)",
+            params![],
+        )?;
+        auto_migrate(&conn)?;
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        assert_eq!(CURRENT_VERSION, version);
+
+        Ok(())
+    }
+}
@@ -5,8 +5,9 @@ use regex::Regex;
 use rusqlite::{params, Connection};
 use shared::{Peer, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
 use std::{
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     ops::{Deref, DerefMut},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use structopt::lazy_static;
 
@@ -20,6 +21,10 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peers (
       is_admin        INTEGER DEFAULT 0 NOT NULL,   /* Admin capabilities are per-peer, not per-CIDR.                   */
       is_disabled     INTEGER DEFAULT 0 NOT NULL,   /* Is the peer disabled? (peers cannot be deleted)                  */
       is_redeemed     INTEGER DEFAULT 0 NOT NULL,   /* Has the peer redeemed their invite yet?                          */
+      created_at      INTEGER,                      /* When the peer was created, in seconds since the UNIX epoch.      */
+      fallback_endpoints TEXT,                      /* Comma-separated fallback endpoints, in order of preference.      */
+      endpoint_override_allowed INTEGER DEFAULT 1 NOT NULL, /* May the peer override its own endpoint?         */
+      expires_at      INTEGER,                      /* When the peer expires, in seconds since the UNIX epoch.          */
       FOREIGN KEY (cidr_id)
          REFERENCES cidrs (id)
             ON UPDATE RESTRICT
@@ -68,6 +73,9 @@ impl DatabasePeer {
             is_admin,
             is_disabled,
             is_redeemed,
+            fallback_endpoints,
+            endpoint_override_allowed,
+            expires_at,
             ..
         } = &contents;
         log::info!("creating peer {:?}", contents);
@@ -88,8 +96,12 @@ impl DatabasePeer {
             return Err(ServerError::InvalidQuery);
         }
 
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the UNIX epoch")
+            .as_secs();
         conn.execute(
-            "INSERT INTO peers (name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            "INSERT INTO peers (name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed, created_at, fallback_endpoints, endpoint_override_allowed, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
             params![
                 name,
                 ip.to_string(),
@@ -99,9 +111,21 @@ impl DatabasePeer {
                 is_admin,
                 is_disabled,
                 is_redeemed,
+                created_at as i64,
+                Self::join_endpoints(fallback_endpoints),
+                endpoint_override_allowed,
+                expires_at.map(|time| {
+                    time.duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs() as i64
+                }),
             ],
         )?;
         let id = conn.last_insert_rowid();
+        let contents = PeerContents {
+            created_at: Some(UNIX_EPOCH + Duration::from_secs(created_at)),
+            ..contents
+        };
         Ok(Peer { id, contents }.into())
     }
 
@@ -109,6 +133,20 @@ impl DatabasePeer {
         name.len() < 64 && PEER_NAME_REGEX.is_match(name)
     }
 
+    fn join_endpoints(endpoints: &[SocketAddr]) -> Option<String> {
+        if endpoints.is_empty() {
+            None
+        } else {
+            Some(
+                endpoints
+                    .iter()
+                    .map(SocketAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+        }
+    }
+
     /// Update self with new contents, validating them and updating the backend in the process.
     pub fn update(&mut self, conn: &Connection, contents: PeerContents) -> Result<(), ServerError> {
         if !Self::is_valid_name(&contents.name) {
@@ -124,8 +162,10 @@ impl DatabasePeer {
         let new_contents = PeerContents {
             name: contents.name,
             endpoint: contents.endpoint,
+            fallback_endpoints: contents.fallback_endpoints,
             is_admin: contents.is_admin,
             is_disabled: contents.is_disabled,
+            endpoint_override_allowed: contents.endpoint_override_allowed,
             ..self.contents.clone()
         };
 
@@ -134,13 +174,17 @@ impl DatabasePeer {
                 name = ?1,
                 endpoint = ?2,
                 is_admin = ?3,
-                is_disabled = ?4
-            WHERE id = ?5",
+                is_disabled = ?4,
+                fallback_endpoints = ?5,
+                endpoint_override_allowed = ?6
+            WHERE id = ?7",
             params![
                 new_contents.name,
                 new_contents.endpoint.map(|endpoint| endpoint.to_string()),
                 new_contents.is_admin,
                 new_contents.is_disabled,
+                Self::join_endpoints(&new_contents.fallback_endpoints),
+                new_contents.endpoint_override_allowed,
                 self.id,
             ],
         )?;
@@ -149,6 +193,36 @@ impl DatabasePeer {
         Ok(())
     }
 
+    /// Move the peer to another IP in its CIDR, e.g. when its current one clashes with other
+    /// infrastructure. Only admins may do this, so it's kept out of `update`.
+    pub fn set_ip(&mut self, conn: &Connection, ip: IpAddr) -> Result<(), ServerError> {
+        if self.id == 1 {
+            log::warn!("refusing to change the innernet server's own IP.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let cidr = DatabaseCidr::get(conn, self.cidr_id)?;
+        if !cidr.cidr.contains(ip) || !cidr.cidr.is_assignable(ip) {
+            log::warn!("tried to move peer to an IP that isn't assignable in its CIDR.");
+            return Err(ServerError::InvalidQuery);
+        }
+
+        conn.execute(
+            "UPDATE peers SET ip = ?1 WHERE id = ?2",
+            params![ip.to_string(), self.id],
+        )?;
+        log::info!("moved peer {} from {} to {}", self.name, self.ip, ip);
+        self.contents.ip = ip;
+        Ok(())
+    }
+
+    /// Delete the peer for good, freeing up its name and IP for reuse, and return it.
+    pub fn delete(conn: &Connection, id: i64) -> Result<Self, ServerError> {
+        let peer = Self::get(conn, id)?;
+        conn.execute("DELETE FROM peers WHERE id = ?1", params![id])?;
+        Ok(peer)
+    }
+
     pub fn disable(conn: &Connection, id: i64) -> Result<(), ServerError> {
         match conn.execute(
             "UPDATE peers SET is_disabled = 1 WHERE id = ?1",
@@ -188,6 +262,22 @@ impl DatabasePeer {
         let is_admin = row.get(6)?;
         let is_disabled = row.get(7)?;
         let is_redeemed = row.get(8)?;
+        let created_at = row
+            .get::<_, Option<i64>>(9)?
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64));
+        let fallback_endpoints = row
+            .get::<_, Option<String>>(10)?
+            .map(|endpoints| {
+                endpoints
+                    .split(',')
+                    .filter_map(|endpoint| endpoint.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let endpoint_override_allowed = row.get(11)?;
+        let expires_at = row
+            .get::<_, Option<i64>>(12)?
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64));
         let persistent_keepalive_interval = Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS);
 
         Ok(Peer {
@@ -202,6 +292,10 @@ impl DatabasePeer {
                 is_disabled,
                 persistent_keepalive_interval,
                 is_redeemed,
+                created_at,
+                fallback_endpoints,
+                endpoint_override_allowed,
+                expires_at,
             },
         }
         .into())
@@ -210,7 +304,7 @@ impl DatabasePeer {
     pub fn get(conn: &Connection, id: i64) -> Result<Self, ServerError> {
         let result = conn.query_row(
             "SELECT
-            id, name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed
+            id, name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed, created_at, fallback_endpoints, endpoint_override_allowed, expires_at
             FROM peers
             WHERE id = ?1",
             params![id],
@@ -223,7 +317,7 @@ impl DatabasePeer {
     pub fn get_from_ip(conn: &Connection, ip: IpAddr) -> Result<Self, ServerError> {
         let result = conn.query_row(
             "SELECT
-            id, name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed
+            id, name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed, created_at, fallback_endpoints, endpoint_override_allowed, expires_at
             FROM peers
             WHERE ip = ?1",
             params![ip.to_string()],
@@ -241,7 +335,8 @@ impl DatabasePeer {
         // 3. associated_subcidrs: For each association, list all peers by enumerating down each
         //    associated CIDR's children and listing any peers belonging to them.
         //
-        // NOTE that a forced association is created with the special "infra" CIDR with id 2 (1 being the root).
+        // NOTE that a forced association is created with the CIDRs flagged as infra (by default the
+        // innernet server's own CIDR, with id 2).
         let mut stmt = conn.prepare_cached(
             "WITH
                 parent_of(id, parent) AS (
@@ -255,13 +350,15 @@ impl DatabasePeer {
                     SELECT associations.cidr_id_1 FROM associations, parent_of WHERE associations.cidr_id_2 = parent_of.id
                 ),
                 associated_subcidrs(cidr_id) AS (
-                    VALUES(?1), (2)
+                    VALUES(?1)
+                    UNION
+                    SELECT id FROM cidrs WHERE is_infra = 1
                     UNION
                     SELECT cidr_id FROM associated
                     UNION
                     SELECT id FROM cidrs, associated_subcidrs WHERE cidrs.parent=associated_subcidrs.cidr_id
                 )
-                SELECT DISTINCT peers.id, peers.name, peers.ip, peers.cidr_id, peers.public_key, peers.endpoint, peers.is_admin, peers.is_disabled, peers.is_redeemed
+                SELECT DISTINCT peers.id, peers.name, peers.ip, peers.cidr_id, peers.public_key, peers.endpoint, peers.is_admin, peers.is_disabled, peers.is_redeemed, peers.created_at, peers.fallback_endpoints, peers.endpoint_override_allowed, peers.expires_at
                 FROM peers
                 JOIN associated_subcidrs ON peers.cidr_id=associated_subcidrs.cidr_id
                 WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1;",
@@ -274,7 +371,7 @@ impl DatabasePeer {
 
     pub fn list(conn: &Connection) -> Result<Vec<Self>, ServerError> {
         let mut stmt = conn.prepare_cached(
-            "SELECT id, name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed FROM peers",
+            "SELECT id, name, ip, cidr_id, public_key, endpoint, is_admin, is_disabled, is_redeemed, created_at, fallback_endpoints, endpoint_override_allowed, expires_at FROM peers",
         )?;
         let peer_iter = stmt.query_map(params![], Self::from_row)?;
 
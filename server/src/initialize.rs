@@ -17,6 +17,7 @@ fn create_database<P: AsRef<Path>>(
     conn.execute(db::peer::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::association::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::cidr::CREATE_TABLE_SQL, params![])?;
+    conn.pragma_update(None, "user_version", &db::CURRENT_VERSION)?;
     Ok(conn)
 }
 
@@ -38,6 +39,7 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             name: db_init_data.root_cidr_name.clone(),
             cidr: db_init_data.root_cidr,
             parent: None,
+            is_infra: false,
         },
     )
     .map_err(|_| "failed to create root CIDR".to_string())?;
@@ -48,6 +50,7 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             name: SERVER_NAME.into(),
             cidr: db_init_data.server_cidr,
             parent: Some(root_cidr.id),
+            is_infra: true,
         },
     )
     .map_err(|_| "failed to create innernet-server CIDR".to_string())?;
@@ -64,6 +67,10 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             is_disabled: false,
             is_redeemed: true,
             persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+            created_at: None,
+            fallback_endpoints: vec![],
+            endpoint_override_allowed: true,
+            expires_at: None,
         },
     )
     .map_err(|_| "failed to create innernet peer.".to_string())?;
@@ -127,6 +134,9 @@ pub fn init_wizard(conf: &ServerConfig) -> Result<(), Error> {
         listen_port,
         address: our_ip,
         network_cidr_prefix: root_cidr.prefix(),
+        min_client_version: None,
+        motd: None,
+        peer_name_pattern: None,
     };
     config.write_to_path(&config_path)?;
 
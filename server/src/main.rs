@@ -5,8 +5,9 @@ use ipnetwork::IpNetwork;
 use parking_lot::Mutex;
 use rusqlite::Connection;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use shared::IoErrorContext;
+use shared::{IoErrorContext, Peer, PeerContents, PeerNamePattern, State};
 use std::{
+    collections::HashMap,
     env,
     fs::File,
     io::prelude::*,
@@ -14,6 +15,7 @@ use std::{
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Instant,
 };
 use structopt::StructOpt;
 use warp::Filter;
@@ -53,19 +55,43 @@ enum Command {
     Serve { interface: Interface },
 
     /// Add a peer to an existing network.
-    AddPeer { interface: Interface },
+    AddPeer {
+        interface: Interface,
+
+        /// Bundle the peers the new peer can reach into its invitation, so that it has
+        /// connectivity even if this server can't be reached right after installing.
+        #[structopt(long)]
+        offline_bundle: bool,
+    },
 
     /// Add a new CIDR to an existing network.
     AddCidr { interface: Interface },
+
+    /// Change the server's external endpoint (e.g. after moving it to a new IP), which clients
+    /// switch to and save in their configs on their next fetch.
+    SetEndpoint {
+        interface: Interface,
+
+        /// The new endpoint. Asked for if not given.
+        endpoint: Option<SocketAddr>,
+    },
 }
 
 pub type Db = Arc<Mutex<Connection>>;
 
+/// Peers recently created with an idempotency key, along with when and from which request, so
+/// that retries of the same request return the original peer instead of creating another.
+pub type IdempotencyKeys = Arc<Mutex<HashMap<String, (Instant, PeerContents, Peer)>>>;
+
 #[derive(Clone)]
 pub struct Context {
     pub db: Db,
     pub endpoints: Arc<Endpoints>,
     pub interface: String,
+    pub min_client_version: Option<String>,
+    pub motd: Option<String>,
+    pub peer_name_pattern: Option<PeerNamePattern>,
+    pub idempotency_keys: IdempotencyKeys,
 }
 
 pub struct Session {
@@ -105,6 +131,22 @@ pub struct ConfigFile {
 
     /// The CIDR prefix of the WireGuard network
     pub network_cidr_prefix: u8,
+
+    /// The oldest client version this server supports, advertised to clients when they
+    /// fetch the network's state so that outdated ones can warn or refuse to run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_client_version: Option<String>,
+
+    /// A message of the day for the network's members, e.g. onboarding instructions or support
+    /// contacts, shown by clients when they install the network and whenever it changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+
+    /// A regex that the whole name of every peer added (with "innernet-server add-peer", or by
+    /// an admin's client) has to match, to keep the network's peer names following a
+    /// convention. Advertised to admins' clients, which check names against it before asking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_name_pattern: Option<String>,
 }
 
 impl ConfigFile {
@@ -182,8 +224,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         },
         Command::Serve { interface } => serve(&interface, &conf).await?,
-        Command::AddPeer { interface } => add_peer(&interface, &conf)?,
+        Command::AddPeer {
+            interface,
+            offline_bundle,
+        } => add_peer(&interface, &conf, offline_bundle)?,
         Command::AddCidr { interface } => add_cidr(&interface, &conf)?,
+        Command::SetEndpoint {
+            interface,
+            endpoint,
+        } => set_endpoint(&interface, &conf, endpoint)?,
     }
 
     Ok(())
@@ -202,10 +251,12 @@ fn open_database_connection(
         .into());
     }
 
-    Ok(Connection::open(&database_path)?)
+    let conn = Connection::open(&database_path)?;
+    db::auto_migrate(&conn)?;
+    Ok(conn)
 }
 
-fn add_peer(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
+fn add_peer(interface: &str, conf: &ServerConfig, offline_bundle: bool) -> Result<(), Error> {
     let config = ConfigFile::from_file(conf.config_path(&interface))?;
     let conn = open_database_connection(interface, conf)?;
     let peers = DatabasePeer::list(&conn)?
@@ -214,8 +265,15 @@ fn add_peer(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
         .collect::<Vec<_>>();
     let cidrs = DatabaseCidr::list(&conn)?;
     let cidr_tree = CidrTree::new(&cidrs[..]);
-
-    if let Some((peer_request, keypair)) = shared::prompts::add_peer(&peers, &cidr_tree)? {
+    let name_pattern = config
+        .peer_name_pattern
+        .as_deref()
+        .map(str::parse::<PeerNamePattern>)
+        .transpose()?;
+
+    if let Some((peer_request, keypair)) =
+        shared::prompts::add_peer(&peers, &cidr_tree, name_pattern.as_ref())?
+    {
         let peer = DatabasePeer::create(&conn, peer_request)?;
         if cfg!(not(test)) && DeviceInfo::get_by_name(interface).is_ok() {
             // Update the current WireGuard interface with the new peers.
@@ -227,15 +285,30 @@ fn add_peer(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
             println!("adding to WireGuard interface: {}", &*peer);
         }
 
+        let snapshot = if offline_bundle {
+            Some(State {
+                peers: peer
+                    .get_all_allowed_peers(&conn)?
+                    .into_iter()
+                    .map(|p| p.inner)
+                    .collect(),
+                cidrs: cidrs.clone(),
+                motd: config.motd.clone(),
+            })
+        } else {
+            None
+        };
         let server_peer = DatabasePeer::get(&conn, 1)?;
-        prompts::save_peer_invitation(
+        let invitation = prompts::peer_invitation(
             interface,
             &peer,
             &*server_peer,
             &cidr_tree,
             keypair,
             &SocketAddr::new(config.address, config.listen_port),
+            snapshot,
         )?;
+        prompts::save_peer_invitation(&peer, &invitation, None)?;
     } else {
         println!("exited without creating peer.");
     }
@@ -246,7 +319,7 @@ fn add_peer(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
 fn add_cidr(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
     let conn = open_database_connection(interface, conf)?;
     let cidrs = DatabaseCidr::list(&conn)?;
-    if let Some(cidr_request) = shared::prompts::add_cidr(&cidrs)? {
+    if let Some(cidr_request) = shared::prompts::add_cidr(&cidrs, None, None, None)? {
         let cidr = DatabaseCidr::create(&conn, cidr_request)?;
         printdoc!(
             "
@@ -266,8 +339,48 @@ fn add_cidr(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
     Ok(())
 }
 
+fn set_endpoint(
+    interface: &str,
+    conf: &ServerConfig,
+    endpoint: Option<SocketAddr>,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let mut server_peer = DatabasePeer::get(&conn, 1)?;
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint,
+        None => prompts::ask_endpoint()?,
+    };
+    let contents = PeerContents {
+        endpoint: Some(endpoint),
+        ..server_peer.contents.clone()
+    };
+    server_peer.update(&conn, contents)?;
+
+    printdoc!(
+        "
+        The server's endpoint is now {endpoint}.
+
+        Clients switch to it on their next fetch, so keep the server reachable at its old
+        endpoint until they all have.
+        ",
+        endpoint = endpoint.to_string().bold()
+    );
+
+    Ok(())
+}
+
 async fn serve(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
     let config = ConfigFile::from_file(conf.config_path(&interface))?;
+    if let Some(version) = &config.min_client_version {
+        if shared::compare_versions(version, version).is_none() {
+            return Err(format!("invalid min-client-version \"{}\" in config", version).into());
+        }
+    }
+    let peer_name_pattern = config
+        .peer_name_pattern
+        .as_deref()
+        .map(str::parse::<PeerNamePattern>)
+        .transpose()?;
     let conn = open_database_connection(&interface, conf)?;
     // Foreign key constraints aren't on in SQLite by default. Enable.
     conn.pragma_update(None, "foreign_keys", &1)?;
@@ -285,6 +398,7 @@ async fn serve(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
         IpNetwork::new(config.address, config.network_cidr_prefix)?,
         Some(config.listen_port),
         None,
+        &[],
     )?;
 
     DeviceConfigBuilder::new()
@@ -300,6 +414,10 @@ async fn serve(interface: &str, conf: &ServerConfig) -> Result<(), Error> {
         db,
         interface: interface.to_string(),
         endpoints,
+        min_client_version: config.min_client_version,
+        motd: config.motd,
+        peer_name_pattern,
+        idempotency_keys: Default::default(),
     };
 
     log::info!("innernet-server {} starting.", VERSION);
@@ -3,7 +3,7 @@ use crate::{
     db::{DatabaseCidr, DatabasePeer},
     endpoints::Endpoints,
     initialize::init_wizard,
-    Context, ServerConfig,
+    Context, IdempotencyKeys, ServerConfig,
 };
 use anyhow::{anyhow, Result};
 use parking_lot::Mutex;
@@ -45,6 +45,7 @@ pub const USER2_PEER_ID: i64 = 6;
 pub struct Server {
     pub db: Arc<Mutex<Connection>>,
     endpoints: Arc<Endpoints>,
+    idempotency_keys: IdempotencyKeys,
     interface: String,
     conf: ServerConfig,
     // The directory will be removed during destruction.
@@ -114,6 +115,7 @@ impl Server {
             conf,
             db,
             endpoints,
+            idempotency_keys: Default::default(),
             interface,
             _test_dir: test_dir,
         })
@@ -128,6 +130,10 @@ impl Server {
             db: self.db.clone(),
             interface: self.interface.clone(),
             endpoints: self.endpoints.clone(),
+            min_client_version: None,
+            motd: None,
+            peer_name_pattern: None,
+            idempotency_keys: self.idempotency_keys.clone(),
         }
     }
 
@@ -143,6 +149,7 @@ pub fn create_cidr(db: &Connection, name: &str, cidr_str: &str) -> Result<Cidr>
             name: name.to_string(),
             cidr: cidr_str.parse()?,
             parent: Some(ROOT_CIDR_ID),
+            is_infra: false,
         },
     )?;
 
@@ -188,6 +195,10 @@ pub fn peer_contents(
         persistent_keepalive_interval: None,
         is_disabled: false,
         is_redeemed: true,
+        created_at: None,
+        fallback_endpoints: vec![],
+        endpoint_override_allowed: true,
+        expires_at: None,
     })
 }
 
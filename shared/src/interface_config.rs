@@ -1,9 +1,13 @@
-use crate::{ensure_dirs_exist, Error, IoErrorContext, CLIENT_CONFIG_PATH};
+use crate::{
+    client_config_path, ensure_dirs_exist, Error, IoErrorContext, State,
+    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+};
 use indoc::writedoc;
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{File, OpenOptions},
+    fmt,
+    fs::{self, File, OpenOptions, Permissions},
     io::Write,
     net::SocketAddr,
     os::unix::fs::PermissionsExt,
@@ -13,6 +17,16 @@ use std::{
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct InterfaceConfig {
+    /// The invitee's view of the network when the invitation was created, so that `install`
+    /// can bring up its peers even if the server can't be reached right away. Only ever set
+    /// in invitation files, and first since TOML values can't follow tables.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "json_snapshot"
+    )]
+    pub snapshot: Option<State>,
+
     /// The information to bring up the interface.
     pub interface: InterfaceInfo,
 
@@ -20,6 +34,35 @@ pub struct InterfaceConfig {
     pub server: ServerInfo,
 }
 
+/// TOML can't represent peers (their tables and values are interleaved), so snapshots are
+/// embedded as a JSON string instead.
+mod json_snapshot {
+    use crate::State;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        state: &Option<State>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match state {
+            Some(state) => serializer
+                .serialize_str(&serde_json::to_string(state).map_err(serde::ser::Error::custom)?),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<State>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(json) => serde_json::from_str(&json)
+                .map(Some)
+                .map_err(de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct InterfaceInfo {
@@ -28,6 +71,11 @@ pub struct InterfaceInfo {
 
     /// The invited peer's internal IP address that's been allocated to it, inside
     /// the entire network's CIDR prefix.
+    ///
+    /// The prefix length should be that of the network's root CIDR (e.g. "10.42.5.2/16"),
+    /// which lets the interface route traffic for every peer in the network. A host prefix
+    /// ("/32" or "/128") only covers the address itself, leaving other peers unreachable
+    /// unless routes are added manually.
     pub address: IpNetwork,
 
     /// WireGuard private key (base64)
@@ -35,6 +83,42 @@ pub struct InterfaceInfo {
 
     /// The local listen port. A random port will be used if `None`.
     pub listen_port: Option<u16>,
+
+    /// The hostname to give each peer in /etc/hosts, with "{name}", "{interface}", "{cidr}",
+    /// and "{ip}" placeholders. Defaults to "{name}.{interface}.<hosts-suffix>" if `None`. On
+    /// dual-stack networks, an IPv4 and an IPv6 peer rendering the same hostname both get it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_template: Option<String>,
+
+    /// The domain the default hostnames end in, e.g. when "wg" collides with an existing
+    /// internal TLD. Defaults to "wg" if `None`, and is unused if `hosts_template` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_suffix: Option<String>,
+
+    /// The tag marking innernet's section of /etc/hosts (in "# DO NOT EDIT <tag> BEGIN"),
+    /// which has to be unique per interface. Defaults to "innernet <interface>" if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts_tag: Option<String>,
+
+    /// Never write to /etc/hosts when fetching, e.g. because it's managed by another tool.
+    /// Same as always passing `--no-host-updates`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub no_host_updates: bool,
+
+    /// A regex that the whole name of every peer added from here with "innernet add-peer" has
+    /// to match, to keep the network's peer names following a convention. Servers that
+    /// advertise a convention of their own enforce it instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_name_pattern: Option<String>,
+
+    /// The private key is still the invitation's, because the server couldn't be reached to
+    /// redeem it while installing. The next fetch redeems it before anything else.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub redeem_pending: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -46,8 +130,20 @@ pub struct ServerInfo {
     /// The external internet endpoint to reach the server.
     pub external_endpoint: SocketAddr,
 
+    /// A DNS SRV name (e.g. "_innernet._udp.example.com") to look up the external endpoint
+    /// from instead, so that the server can be moved through DNS. `external_endpoint` is used
+    /// whenever it can't be resolved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_endpoint_srv: Option<String>,
+
     /// An internal endpoint in the WireGuard network that hosts the coordination API.
     pub internal_endpoint: SocketAddr,
+
+    /// Networks beyond the innernet network (e.g. "192.168.10.0/24") to route through the
+    /// server, for setups where it's a gateway to them. Removing one only takes effect on
+    /// "innernet fetch --repair".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_allowed_ips: Vec<IpNetwork>,
 }
 
 impl InterfaceConfig {
@@ -91,9 +187,16 @@ impl InterfaceConfig {
         Ok(())
     }
 
-    /// Overwrites the config file if it already exists.
+    /// Overwrites the config file if it already exists, keeping a copy of the previous one
+    /// (see `backup_path`) to recover from a botched change.
     pub fn write_to_interface(&self, interface: &str) -> Result<PathBuf, Error> {
         let path = Self::build_config_file_path(interface)?;
+        if path.exists() {
+            let backup_path = Self::backup_path(interface);
+            fs::copy(&path, &backup_path).with_path(&backup_path)?;
+            fs::set_permissions(&backup_path, Permissions::from_mode(0o600))
+                .with_path(&backup_path)?;
+        }
         File::create(&path)
             .with_path(&path)?
             .write_all(toml::to_string(self).unwrap().as_bytes())?;
@@ -104,20 +207,242 @@ impl InterfaceConfig {
         Ok(toml::from_slice(&std::fs::read(&path).with_path(path)?)?)
     }
 
+    /// Like `from_file`, but for invitation files passed in by users, explaining what's likely
+    /// wrong if the file can't be parsed.
+    pub fn from_invitation<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read(&path).with_path(&path)?;
+        Ok(Self::parse_invitation(path.as_ref(), &contents)?)
+    }
+
+    fn parse_invitation(path: &Path, contents: &[u8]) -> Result<Self, InvalidInvitation> {
+        toml::from_slice(contents).map_err(|source| InvalidInvitation {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
     pub fn from_interface(interface: &str) -> Result<Self, Error> {
         Self::from_file(Self::build_config_file_path(interface)?)
     }
 
+    /// The equivalent wg-quick config for bringing the interface up with other tools, with
+    /// `peers` if given, or else with the server as the only peer (the rest of the peers are
+    /// added by `innernet fetch`).
+    pub fn to_wg_quick(&self, peers: Option<&[WgQuickPeer]>) -> String {
+        let mut wg_quick = format!(
+            "[Interface]\nPrivateKey = {}\nAddress = {}\n",
+            self.interface.private_key, self.interface.address
+        );
+        if let Some(listen_port) = self.interface.listen_port {
+            wg_quick.push_str(&format!("ListenPort = {}\n", listen_port));
+        }
+        let server = [self.server_peer()];
+        for peer in peers.unwrap_or(&server) {
+            wg_quick.push('\n');
+            if let Some(name) = &peer.name {
+                wg_quick.push_str(&format!("# {}\n", name));
+            }
+            wg_quick.push_str(&format!("[Peer]\nPublicKey = {}\n", peer.public_key));
+            if let Some(preshared_key) = &peer.preshared_key {
+                wg_quick.push_str(&format!("PresharedKey = {}\n", preshared_key));
+            }
+            if let Some(endpoint) = peer.endpoint {
+                wg_quick.push_str(&format!("Endpoint = {}\n", endpoint));
+            }
+            let allowed_ips = peer
+                .allowed_ips
+                .iter()
+                .map(|net| net.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            wg_quick.push_str(&format!("AllowedIPs = {}\n", allowed_ips));
+            if let Some(keepalive) = peer.persistent_keepalive {
+                wg_quick.push_str(&format!("PersistentKeepalive = {}\n", keepalive));
+            }
+        }
+        wg_quick
+    }
+
+    fn server_peer(&self) -> WgQuickPeer {
+        let server_ip = self.server.internal_endpoint.ip();
+        WgQuickPeer {
+            name: None,
+            public_key: self.server.public_key.clone(),
+            preshared_key: None,
+            endpoint: Some(self.server.external_endpoint),
+            allowed_ips: std::iter::once(IpNetwork::from(server_ip))
+                .chain(self.server.extra_allowed_ips.iter().copied())
+                .collect(),
+            persistent_keepalive: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        }
+    }
+
+    /// Where `write_to_interface` keeps the interface's previous config. Only the most recent
+    /// one is kept.
+    pub fn backup_path(interface: &str) -> PathBuf {
+        client_config_path()
+            .join(interface)
+            .with_extension("conf.bak")
+    }
+
     fn build_config_file_path(interface: &str) -> Result<PathBuf, Error> {
-        ensure_dirs_exist(&[*CLIENT_CONFIG_PATH])?;
-        Ok(CLIENT_CONFIG_PATH.join(interface).with_extension("conf"))
+        ensure_dirs_exist(&[&client_config_path()])?;
+        Ok(client_config_path().join(interface).with_extension("conf"))
+    }
+}
+
+/// A peer of a config rendered by `InterfaceConfig::to_wg_quick`.
+#[derive(Clone, Debug)]
+pub struct WgQuickPeer {
+    /// Written as a comment above the peer, if it's known.
+    pub name: Option<String>,
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNetwork>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// An invitation file that couldn't be parsed, e.g. because it's truncated or isn't an
+/// invitation at all.
+#[derive(Debug)]
+pub struct InvalidInvitation {
+    path: PathBuf,
+    source: toml::de::Error,
+}
+
+impl fmt::Display for InvalidInvitation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} doesn't look like a valid innernet invitation; ensure you passed the correct \
+             .toml file, and if it was downloaded or copied over, that it isn't truncated. \
+             ({})",
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for InvalidInvitation {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
     }
 }
 
 impl InterfaceInfo {
+    /// Whether the interface address has a host prefix (/32 or /128), meaning that no
+    /// route to the rest of the network would be installed when bringing it up.
+    pub fn has_host_prefix(&self) -> bool {
+        self.address.prefix() == self.address.max_prefix()
+    }
+
     pub fn public_key(&self) -> Result<String, Error> {
         Ok(wgctrl::Key::from_base64(&self.private_key)?
             .generate_public()
             .to_base64())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Peer;
+    use std::{error::Error as _, time::SystemTime};
+
+    #[test]
+    fn test_invalid_invitation() {
+        let path = Path::new("/tmp/invite.toml");
+        let truncated = b"[interface]\nnetwork-name = \"evilcorp\"\naddr";
+        let error = InterfaceConfig::parse_invitation(path, truncated).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("/tmp/invite.toml doesn't look like"));
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let invitation = "[interface]\nnetwork-name = \"evilcorp\"\n\
+                          address = \"10.42.5.2/16\"\nprivate-key = \"cHJpdmF0ZQ==\"\n\n\
+                          [server]\npublic-key = \"c2VydmVy\"\n\
+                          external-endpoint = \"203.0.113.1:51820\"\n\
+                          internal-endpoint = \"10.42.0.1:51820\"\n";
+        let mut config: InterfaceConfig = toml::from_str(invitation).unwrap();
+        assert!(config.snapshot.is_none());
+
+        let mut peer = Peer::for_tests(2, "laptop", "10.42.5.3", "bGFwdG9w");
+        peer.contents.cidr_id = 2;
+        peer.contents.created_at = Some(SystemTime::UNIX_EPOCH);
+        config.snapshot = Some(State {
+            peers: vec![peer.clone()],
+            cidrs: vec![],
+            motd: None,
+        });
+        let config: InterfaceConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(config.snapshot.unwrap().peers, vec![peer]);
+    }
+
+    #[test]
+    fn test_to_wg_quick() {
+        let mut config = InterfaceConfig {
+            interface: InterfaceInfo {
+                network_name: "evilcorp".to_string(),
+                address: "10.42.5.2/16".parse().unwrap(),
+                private_key: "cHJpdmF0ZQ==".to_string(),
+                listen_port: None,
+                hosts_template: None,
+                hosts_suffix: None,
+                hosts_tag: None,
+                no_host_updates: false,
+                peer_name_pattern: None,
+                redeem_pending: false,
+            },
+            server: ServerInfo {
+                public_key: "c2VydmVy".to_string(),
+                external_endpoint: "203.0.113.1:51820".parse().unwrap(),
+                external_endpoint_srv: None,
+                internal_endpoint: "10.42.0.1:51820".parse().unwrap(),
+                extra_allowed_ips: vec![],
+            },
+            snapshot: None,
+        };
+        assert_eq!(
+            config.to_wg_quick(None),
+            "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nAddress = 10.42.5.2/16\n\n\
+             [Peer]\nPublicKey = c2VydmVy\nEndpoint = 203.0.113.1:51820\n\
+             AllowedIPs = 10.42.0.1/32\nPersistentKeepalive = 25\n"
+        );
+
+        config.server.extra_allowed_ips = vec!["192.168.10.0/24".parse().unwrap()];
+        assert!(config
+            .to_wg_quick(None)
+            .contains("AllowedIPs = 10.42.0.1/32, 192.168.10.0/24\n"));
+
+        config.interface.listen_port = Some(51820);
+        let peers = [
+            WgQuickPeer {
+                name: Some("innernet-server".to_string()),
+                ..config.server_peer()
+            },
+            WgQuickPeer {
+                name: None,
+                public_key: "bGFwdG9w".to_string(),
+                preshared_key: None,
+                endpoint: None,
+                allowed_ips: vec![
+                    "10.42.6.3/32".parse().unwrap(),
+                    "10.42.7.0/24".parse().unwrap(),
+                ],
+                persistent_keepalive: None,
+            },
+        ];
+        assert_eq!(
+            config.to_wg_quick(Some(&peers)),
+            "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nAddress = 10.42.5.2/16\nListenPort = 51820\n\n\
+             # innernet-server\n[Peer]\nPublicKey = c2VydmVy\nEndpoint = 203.0.113.1:51820\n\
+             AllowedIPs = 10.42.0.1/32, 192.168.10.0/24\nPersistentKeepalive = 25\n\n\
+             [Peer]\nPublicKey = bGFwdG9w\nAllowedIPs = 10.42.6.3/32, 10.42.7.0/24\n"
+        );
+    }
+}
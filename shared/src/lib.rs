@@ -1,17 +1,19 @@
 use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
-use prompts::hostname_validator;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Ordering,
     fmt::{Display, Formatter},
     fs::{self, File},
     io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
     ops::Deref,
     os::unix::fs::PermissionsExt,
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
-    time::Duration,
+    sync::RwLock,
+    time::{Duration, SystemTime},
 };
 use wgctrl::{Key, PeerConfig, PeerConfigBuilder};
 
@@ -25,10 +27,48 @@ lazy_static! {
     pub static ref SERVER_CONFIG_DIR: &'static Path = Path::new("/etc/innernet-server");
     pub static ref SERVER_DATABASE_DIR: &'static Path = Path::new("/var/lib/innernet-server");
     pub static ref REDEEM_TRANSITION_WAIT: Duration = Duration::from_secs(5);
+
+    /// Where the client keeps everything instead, if overridden with `set_client_dir`.
+    static ref CLIENT_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Keep the client's configs, as well as its cached peer lists and other state, in `dir`
+/// rather than `CLIENT_CONFIG_PATH` and `CLIENT_DATA_PATH`, e.g. to run a separate profile as
+/// non-root, or in tests.
+pub fn set_client_dir(dir: Option<PathBuf>) {
+    *CLIENT_DIR.write().unwrap() = dir;
+}
+
+/// Where the client's interface configs are read from and written to.
+pub fn client_config_path() -> PathBuf {
+    CLIENT_DIR
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| CLIENT_CONFIG_PATH.to_path_buf())
+}
+
+/// Where the client's cached peer lists, locks, and event logs are kept.
+pub fn client_data_path() -> PathBuf {
+    CLIENT_DIR
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| CLIENT_DATA_PATH.to_path_buf())
 }
 
 pub static PERSISTENT_KEEPALIVE_INTERVAL_SECS: u16 = 25;
 
+/// The response header the server uses to advertise the oldest client version it supports.
+pub static MIN_CLIENT_VERSION_HEADER: &str = "X-Innernet-Min-Client-Version";
+/// The naming convention new peers have to follow, advertised to admins listing the peers.
+pub static PEER_NAME_PATTERN_HEADER: &str = "X-Innernet-Peer-Name-Pattern";
+
+/// The request header clients use to make `POST /v1/admin/peers` safe to retry. The server
+/// remembers the peer created for each key for a day, returning it again for any retried
+/// request with the same key and contents instead of creating a duplicate.
+pub static IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
 pub type Error = Box<dyn std::error::Error>;
 
 pub trait IoErrorContext<T> {
@@ -63,6 +103,30 @@ impl std::fmt::Display for WrappedIoError {
 
 impl std::error::Error for WrappedIoError {}
 
+/// The longest interface name Linux allows (IFNAMSIZ, less the terminating NUL).
+pub const MAX_INTERFACE_NAME_LEN: usize = 15;
+
+/// Whether a name is safe to use for an interface, and for the config and data files named
+/// after it: letters, digits, "-" and "_" only, so that it can't escape their directories.
+pub fn is_valid_interface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_INTERFACE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn interface_name_validator(name: &str) -> Result<(), &'static str> {
+    if is_valid_interface_name(name) {
+        Ok(())
+    } else {
+        Err(
+            "interface names can only have letters, digits, \"-\", and \"_\", and be at most \
+             15 characters long",
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Interface {
     name: String,
@@ -72,8 +136,7 @@ impl FromStr for Interface {
     type Err = &'static str;
 
     fn from_str(name: &str) -> Result<Self, Self::Err> {
-        let s = name.to_string();
-        hostname_validator(&s)?;
+        interface_name_validator(name)?;
         Ok(Self {
             name: name.to_string(),
         })
@@ -88,10 +151,87 @@ impl Deref for Interface {
     }
 }
 
+/// An inclusive range of ports, written as "LO-HI" (e.g. "51820-51830").
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    /// Find the first port in the range that's not already bound by another UDP socket.
+    pub fn find_free_udp_port(&self) -> Option<u16> {
+        (self.start..=self.end).find(|port| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, *port)).is_ok())
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+        let (start, end) = match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) => (start.trim().parse(), end.trim().parse()),
+            _ => return Err("port range must be in the form LO-HI"),
+        };
+        match (start, end) {
+            (Ok(start), Ok(end)) if start <= end => Ok(Self { start, end }),
+            (Ok(_), Ok(_)) => Err("port range start must not be greater than its end"),
+            _ => Err("port range bounds must be valid port numbers"),
+        }
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// A naming convention for new peers, as a regex that the whole name has to match (e.g.
+/// "[a-z]+-[a-z]+-[a-z0-9-]+" for "team-role-hostname").
+#[derive(Debug, Clone)]
+pub struct PeerNamePattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl FromStr for PeerNamePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let regex = Regex::new(&format!("^(?:{})$", s))
+            .map_err(|e| format!("invalid peer name pattern \"{}\": {}", s, e))?;
+        Ok(Self {
+            pattern: s.to_string(),
+            regex,
+        })
+    }
+}
+
+impl Display for PeerNamePattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl PeerNamePattern {
+    pub fn is_match(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "option", content = "content")]
 pub enum EndpointContents {
     Set(SocketAddr),
+    /// A prioritized list of endpoints, the first being the primary endpoint and the rest
+    /// fallbacks for clients that can't reach it.
+    SetMultiple(Vec<SocketAddr>),
     Unset,
 }
 
@@ -99,6 +239,7 @@ impl Into<Option<SocketAddr>> for EndpointContents {
     fn into(self) -> Option<SocketAddr> {
         match self {
             Self::Set(addr) => Some(addr),
+            Self::SetMultiple(addrs) => addrs.first().copied(),
             Self::Unset => None,
         }
     }
@@ -113,13 +254,17 @@ impl From<Option<SocketAddr>> for EndpointContents {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct AssociationContents {
     pub cidr_id_1: i64,
     pub cidr_id_2: i64,
+
+    /// An optional note on why the association exists, e.g. "monitoring scrapes prod metrics".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Association {
     pub id: i64,
 
@@ -140,6 +285,11 @@ pub struct CidrContents {
     pub name: String,
     pub cidr: IpNetwork,
     pub parent: Option<i64>,
+
+    /// Whether this is an "infra" CIDR, whose peers (e.g. the innernet server) every other
+    /// peer is told about, regardless of associations.
+    #[serde(default)]
+    pub is_infra: bool,
 }
 
 impl Deref for CidrContents {
@@ -201,6 +351,18 @@ impl<'a> CidrTree<'a> {
             })
     }
 
+    /// The prefix length to give an interface with the given address, i.e. that of the
+    /// broadest CIDR in the tree that contains it. On a dual-stack network this is the
+    /// top-level CIDR of the address's own family, rather than the root's.
+    pub fn interface_prefix(&self, ip: IpAddr) -> u8 {
+        self.cidrs
+            .iter()
+            .filter(|c| c.cidr.contains(ip))
+            .map(|c| c.cidr.prefix())
+            .min()
+            .unwrap_or_else(|| self.cidr.prefix())
+    }
+
     pub fn leaves(&self) -> Vec<Cidr> {
         let mut leaves = vec![];
         for cidr in self.cidrs {
@@ -228,6 +390,40 @@ pub struct PeerContents {
     pub is_admin: bool,
     pub is_disabled: bool,
     pub is_redeemed: bool,
+
+    /// When the peer was created on the server. This is set by the server, and will be
+    /// `None` for peers created before it was tracked.
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
+
+    /// Additional endpoints the peer can be reached at, in order of preference, for clients
+    /// that can't reach `endpoint` (e.g. a VPN-internal IP for a dual-homed peer).
+    #[serde(default)]
+    pub fallback_endpoints: Vec<SocketAddr>,
+
+    /// Whether the peer may override its own endpoint. Admins can turn this off for ordinary
+    /// peers, leaving only gateways and other infra peers to publish their endpoints.
+    #[serde(default = "default_endpoint_override_allowed")]
+    pub endpoint_override_allowed: bool,
+
+    /// When an ephemeral peer (e.g. a guest or a CI runner) should be deleted by
+    /// `innernet reap-expired`. `None` for peers that don't expire.
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+}
+
+fn default_endpoint_override_allowed() -> bool {
+    true
+}
+
+impl PeerContents {
+    /// All of the peer's advertised endpoints, in order of preference.
+    pub fn endpoints(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.endpoint
+            .iter()
+            .chain(self.fallback_endpoints.iter())
+            .copied()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -258,9 +454,44 @@ pub struct PeerDiff {
     pub endpoint: Option<SocketAddr>,
     pub persistent_keepalive_interval: Option<u16>,
     pub is_disabled: bool,
+
+    /// The peer's new IP, when an admin moved it and its allowed IPs have to follow.
+    pub ip: Option<IpAddr>,
+}
+
+impl PeerDiff {
+    /// Whether the only change is the endpoint, which happens routinely as peers roam
+    /// between networks or NAT mappings change.
+    pub fn is_endpoint_only(&self) -> bool {
+        self.endpoint.is_some() && self.persistent_keepalive_interval.is_none() && self.ip.is_none()
+    }
 }
 
 impl Peer {
+    /// A redeemed, enabled, non-admin peer in CIDR 1 with no endpoint, for other crates' tests
+    /// to adjust to what they need instead of spelling out every field.
+    #[doc(hidden)]
+    pub fn for_tests(id: i64, name: &str, ip: &str, public_key: &str) -> Self {
+        Self {
+            id,
+            contents: PeerContents {
+                name: name.to_string(),
+                ip: ip.parse().unwrap(),
+                cidr_id: 1,
+                public_key: public_key.to_string(),
+                endpoint: None,
+                persistent_keepalive_interval: None,
+                is_admin: false,
+                is_disabled: false,
+                is_redeemed: true,
+                created_at: None,
+                fallback_endpoints: vec![],
+                endpoint_override_allowed: true,
+                expires_at: None,
+            },
+        }
+    }
+
     pub fn diff(&self, peer: &PeerConfig) -> Option<PeerDiff> {
         assert_eq!(self.public_key, peer.public_key.to_base64());
 
@@ -277,7 +508,18 @@ impl Peer {
                 None
             };
 
-        if endpoint_diff.is_none() && keepalive_diff.is_none() {
+        let prefix = if self.ip.is_ipv4() { 32 } else { 128 };
+        let ip_diff = if peer
+            .allowed_ips
+            .iter()
+            .any(|allowed_ip| allowed_ip.address == self.ip && allowed_ip.cidr == prefix)
+        {
+            None
+        } else {
+            Some(self.ip)
+        };
+
+        if endpoint_diff.is_none() && keepalive_diff.is_none() && ip_diff.is_none() {
             None
         } else {
             Some(PeerDiff {
@@ -285,6 +527,7 @@ impl Peer {
                 endpoint: endpoint_diff,
                 persistent_keepalive_interval: keepalive_diff,
                 is_disabled: self.is_disabled,
+                ip: ip_diff,
             })
         }
     }
@@ -326,6 +569,14 @@ impl<'a> From<&'a PeerDiff> for PeerConfigBuilder {
             builder
         };
 
+        let builder = if let Some(ip) = peer.ip {
+            builder
+                .replace_allowed_ips()
+                .add_allowed_ip(ip, if ip.is_ipv4() { 32 } else { 128 })
+        } else {
+            builder
+        };
+
         let builder = if let Some(interval) = peer.persistent_keepalive_interval {
             builder.set_persistent_keepalive_interval(interval)
         } else {
@@ -352,6 +603,11 @@ pub struct State {
     /// At the moment, this is all CIDRs, regardless of whether the peer is
     /// eligible to communicate with them or not.
     pub cidrs: Vec<Cidr>,
+
+    /// A message from the network's admins to its members, e.g. onboarding instructions or
+    /// support contacts, shown when installing and whenever it changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
 }
 
 pub static WG_MANAGE_DIR: &str = "/etc/innernet";
@@ -375,6 +631,109 @@ pub fn ensure_dirs_exist(dirs: &[&Path]) -> Result<(), Error> {
     Ok(())
 }
 
+/// The first and last addresses of a network, as integers, along with its total bit length.
+fn network_range(network: &IpNetwork) -> (u128, u128, u8) {
+    match network {
+        IpNetwork::V4(network) => (
+            u32::from(network.network()) as u128,
+            u32::from(network.broadcast()) as u128,
+            32,
+        ),
+        IpNetwork::V6(network) => {
+            let start = u128::from(network.network());
+            let host_bits = 128 - network.prefix() as u32;
+            let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+            (start, start | host_mask, 128)
+        },
+    }
+}
+
+/// Find the first subnet of a parent network with the given prefix length that doesn't
+/// overlap any of the `taken` networks (e.g. the parent's existing child CIDRs).
+pub fn first_free_subnet(parent: &IpNetwork, taken: &[IpNetwork], prefix: u8) -> Option<IpNetwork> {
+    let (parent_start, parent_end, bits) = network_range(parent);
+    if prefix <= parent.prefix() || prefix > bits {
+        return None;
+    }
+    let size = 1u128 << (bits - prefix);
+
+    let mut taken = taken
+        .iter()
+        .filter(|network| network.is_ipv4() == parent.is_ipv4())
+        .map(network_range)
+        .collect::<Vec<_>>();
+    taken.sort_unstable();
+
+    // Walk the gaps between taken ranges in order, trying the first aligned subnet in each.
+    let mut candidate = parent_start;
+    for (start, end, _) in taken {
+        if candidate.checked_add(size - 1)? < start {
+            break;
+        }
+        if end >= candidate {
+            let next = end.checked_add(1)?;
+            candidate = next.checked_add(size - 1)? / size * size;
+        }
+    }
+    if candidate.checked_add(size - 1)? > parent_end {
+        return None;
+    }
+
+    let ip = if bits == 32 {
+        IpAddr::V4((candidate as u32).into())
+    } else {
+        IpAddr::V6(candidate.into())
+    };
+    IpNetwork::new(ip, prefix).ok()
+}
+
+/// Compare two dotted version numbers (e.g. "1.2.0") numerically, ignoring any pre-release or
+/// build suffix. Returns `None` if either isn't a valid version number.
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    fn parse(version: &str) -> Option<Vec<u64>> {
+        version
+            .trim()
+            .split(&['-', '+'][..])
+            .next()?
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect()
+    }
+
+    let (mut a, mut b) = (parse(a)?, parse(b)?);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    Some(a.cmp(&b))
+}
+
+/// Parse a duration like "90s", "30m", "8h", "3d", or "2w". A bare number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration \"{}\"", s))?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit \"{}\" (expected s, m, h, d, or w)",
+                unit
+            ))
+        },
+    };
+    amount
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration \"{}\" is too long", s))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -383,20 +742,7 @@ mod tests {
     fn test_peer_no_diff() {
         const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let peer = Peer {
-            id: 1,
-            contents: PeerContents {
-                name: "peer1".to_owned(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: None,
-                persistent_keepalive_interval: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-            },
-        };
+        let peer = Peer::for_tests(1, "peer1", "10.0.0.1", PUBKEY);
         let builder =
             PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
 
@@ -409,20 +755,8 @@ mod tests {
     fn test_peer_diff() {
         const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let peer = Peer {
-            id: 1,
-            contents: PeerContents {
-                name: "peer1".to_owned(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: None,
-                persistent_keepalive_interval: Some(15),
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-            },
-        };
+        let mut peer = Peer::for_tests(1, "peer1", "10.0.0.1", PUBKEY);
+        peer.contents.persistent_keepalive_interval = Some(15);
         let builder =
             PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
 
@@ -432,4 +766,132 @@ mod tests {
         println!("{:?}", config);
         assert!(matches!(peer.diff(&config), Some(_)));
     }
+
+    #[test]
+    fn test_peer_ip_diff() {
+        const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
+        let old_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer = Peer::for_tests(1, "peer1", "10.0.0.9", PUBKEY);
+        let config = PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap())
+            .add_allowed_ip(old_ip, 32)
+            .into_peer_config();
+
+        let diff = peer.diff(&config).unwrap();
+        assert_eq!(diff.ip, Some(peer.ip));
+        assert!(!diff.is_endpoint_only());
+    }
+
+    #[test]
+    fn test_port_range_parse() {
+        assert_eq!(
+            "51820-51830".parse(),
+            Ok(PortRange {
+                start: 51820,
+                end: 51830
+            })
+        );
+        assert!("51830-51820".parse::<PortRange>().is_err());
+        assert!("51820".parse::<PortRange>().is_err());
+        assert!("51820-99999".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_peer_name_pattern() {
+        let pattern: PeerNamePattern = "[a-z]+-[a-z]+-[a-z0-9-]+".parse().unwrap();
+        assert!(pattern.is_match("infra-db-pg1"));
+        assert!(!pattern.is_match("laptop"));
+        // The whole name has to match, not just part of it.
+        assert!(!pattern.is_match("infra-db-pg1.example"));
+        assert_eq!(pattern.to_string(), "[a-z]+-[a-z]+-[a-z0-9-]+");
+        assert!("[a-z".parse::<PeerNamePattern>().is_err());
+    }
+
+    #[test]
+    fn test_first_free_subnet() {
+        let parent: IpNetwork = "10.0.0.0/16".parse().unwrap();
+        let net = |s: &str| s.parse::<IpNetwork>().unwrap();
+
+        assert_eq!(
+            first_free_subnet(&parent, &[], 24),
+            Some(net("10.0.0.0/24"))
+        );
+        assert_eq!(
+            first_free_subnet(&parent, &[net("10.0.0.0/24"), net("10.0.2.0/23")], 24),
+            Some(net("10.0.1.0/24"))
+        );
+        assert_eq!(
+            first_free_subnet(&parent, &[net("10.0.0.0/24"), net("10.0.1.0/24")], 23),
+            Some(net("10.0.2.0/23"))
+        );
+        assert_eq!(
+            first_free_subnet(&parent, &[net("10.0.0.0/17"), net("10.0.128.0/17")], 24),
+            None
+        );
+        assert_eq!(first_free_subnet(&parent, &[], 16), None);
+        assert_eq!(
+            first_free_subnet(&net("fd00::/48"), &[net("fd00::/64")], 64),
+            Some(net("fd00:0:0:1::/64"))
+        );
+    }
+
+    #[test]
+    fn test_interface_name() {
+        assert!("evilcorp".parse::<Interface>().is_ok());
+        assert!("wg_Office-2".parse::<Interface>().is_ok());
+        assert!("fifteen-chars-x".parse::<Interface>().is_ok());
+        assert!("sixteen-chars-xx".parse::<Interface>().is_err());
+        assert!("".parse::<Interface>().is_err());
+        assert!("../etc".parse::<Interface>().is_err());
+        assert!("a/b".parse::<Interface>().is_err());
+        assert!("evil corp".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn test_interface_prefix() {
+        let cidr = |id, cidr: &str, parent| Cidr {
+            id,
+            contents: CidrContents {
+                name: format!("cidr{}", id),
+                cidr: cidr.parse().unwrap(),
+                parent,
+                is_infra: false,
+            },
+        };
+        let cidrs = vec![
+            cidr(1, "10.80.0.0/15", None),
+            cidr(2, "10.80.1.0/24", Some(1)),
+            cidr(3, "fd00:80::/48", None),
+            cidr(4, "fd00:80::/64", Some(3)),
+        ];
+        let tree = CidrTree::new(&cidrs);
+        assert_eq!(tree.interface_prefix("10.80.1.2".parse().unwrap()), 15);
+        assert_eq!(tree.interface_prefix("fd00:80::2".parse().unwrap()), 48);
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("1.0.0", "1.0.1"), Some(Ordering::Less));
+        assert_eq!(compare_versions("1.10.0", "1.9.0"), Some(Ordering::Greater));
+        assert_eq!(compare_versions("1.2", "1.2.0"), Some(Ordering::Equal));
+        assert_eq!(
+            compare_versions("1.2.0-beta.1", "1.2.0"),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(compare_versions("1.x", "1.0"), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("8h"), Ok(Duration::from_secs(8 * 60 * 60)));
+        assert_eq!(
+            parse_duration(" 3d "),
+            Ok(Duration::from_secs(3 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("100000000000000w").is_err());
+    }
 }
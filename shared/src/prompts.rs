@@ -1,14 +1,19 @@
 use crate::{
+    first_free_subnet,
     interface_config::{InterfaceConfig, InterfaceInfo, ServerInfo},
-    Association, Cidr, CidrContents, CidrTree, Error, Peer, PeerContents,
-    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+    parse_duration, Association, AssociationContents, Cidr, CidrContents, CidrTree, Error, Peer,
+    PeerContents, PeerNamePattern, PortRange, State, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
 use colored::*;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 use wgctrl::KeyPair;
 
 lazy_static! {
@@ -31,16 +36,67 @@ pub fn hostname_validator(name: &String) -> Result<(), &'static str> {
     }
 }
 
+/// Check a new peer's name, which has to be a valid hostname and follow the network's naming
+/// convention, if it has one.
+fn check_peer_name(name: &str, pattern: Option<&PeerNamePattern>) -> Result<(), String> {
+    if !is_valid_hostname(name) {
+        return Err(format!("\"{}\" is not a valid hostname", name));
+    }
+    match pattern {
+        Some(pattern) if !pattern.is_match(name) => Err(format!(
+            "\"{}\" doesn't follow the network's naming convention, which requires names to \
+             match {}",
+            name, pattern
+        )),
+        _ => Ok(()),
+    }
+}
+
 /// Bring up a prompt to create a new CIDR. Returns the peer request.
-pub fn add_cidr(cidrs: &[Cidr]) -> Result<Option<CidrContents>, Error> {
-    let parent_cidr = choose_cidr(cidrs, "Parent CIDR")?;
-    let name: String = Input::with_theme(&*THEME).with_prompt("Name").interact()?;
-    let cidr: IpNetwork = Input::with_theme(&*THEME).with_prompt("CIDR").interact()?;
+///
+/// The name, CIDR, and parent (by name) that are given are used as the prompts' defaults, or
+/// without prompting at all when all three are given.
+pub fn add_cidr(
+    cidrs: &[Cidr],
+    name: Option<&str>,
+    cidr: Option<IpNetwork>,
+    parent: Option<&str>,
+) -> Result<Option<CidrContents>, Error> {
+    let parent_cidr = match parent {
+        Some(parent) => cidrs
+            .iter()
+            .find(|cidr| cidr.name == parent)
+            .ok_or_else(|| format!("no CIDR named \"{}\"", parent))?,
+        None => choose_cidr(cidrs, "Parent CIDR")?,
+    };
+    if let (Some(name), Some(cidr), Some(_)) = (name, cidr, parent) {
+        return Ok(Some(CidrContents {
+            name: name.to_string(),
+            cidr,
+            parent: Some(parent_cidr.id),
+            is_infra: false,
+        }));
+    }
+
+    let mut name_input = Input::with_theme(&*THEME);
+    if let Some(name) = name {
+        name_input.default(name.to_string());
+    }
+    let name: String = name_input.with_prompt("Name").interact()?;
+
+    let cidr = match cidr {
+        Some(cidr) => Input::with_theme(&*THEME)
+            .with_prompt("CIDR")
+            .default(cidr)
+            .interact()?,
+        None => prompt_free_subnet(cidrs, parent_cidr)?,
+    };
 
     let cidr_request = CidrContents {
         name,
         cidr,
         parent: Some(parent_cidr.id),
+        is_infra: false,
     };
 
     Ok(
@@ -56,15 +112,90 @@ pub fn add_cidr(cidrs: &[Cidr]) -> Result<Option<CidrContents>, Error> {
     )
 }
 
+/// Ask for a subnet of the parent CIDR.
+fn prompt_free_subnet(cidrs: &[Cidr], parent_cidr: &Cidr) -> Result<IpNetwork, Error> {
+    // Suggest the first free subnet of the desired size, so that users don't have to work
+    // out a non-overlapping range by hand.
+    let max_prefix = if parent_cidr.is_ipv4() { 32 } else { 128 };
+    let prefix: u8 = Input::with_theme(&*THEME)
+        .with_prompt("Prefix length")
+        .default((parent_cidr.prefix() + 8).min(max_prefix))
+        .interact()?;
+    let siblings = cidrs
+        .iter()
+        .filter(|cidr| cidr.parent == Some(parent_cidr.id))
+        .map(|cidr| cidr.cidr)
+        .collect::<Vec<_>>();
+    let mut cidr_input = Input::with_theme(&*THEME);
+    match first_free_subnet(&parent_cidr.cidr, &siblings, prefix) {
+        Some(suggestion) => {
+            cidr_input.default(suggestion);
+        },
+        None => println!(
+            "{} no free /{} subnet found in {}.",
+            "[!]".yellow(),
+            prefix,
+            parent_cidr.cidr
+        ),
+    }
+    Ok(cidr_input.with_prompt("CIDR").interact()?)
+}
+
+/// Lists longer than this are first narrowed down with a search before choosing from them.
+const SEARCH_THRESHOLD: usize = 15;
+
+/// Whether all of the query's characters appear in the item in the same order, ignoring case,
+/// e.g. "dvl3" matches "developer3".
+fn fuzzy_match(item: &str, query: &str) -> bool {
+    let mut chars = item.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|q| chars.any(|c| c == q))
+}
+
+/// Choose one of the items, returning its index. Long lists are narrowed down by fuzzy
+/// searching them first, so that large networks stay manageable.
+fn fuzzy_select(prompt: &str, items: &[String]) -> Result<usize, Error> {
+    let mut matches = (0..items.len()).collect::<Vec<_>>();
+    while matches.len() > SEARCH_THRESHOLD {
+        let query: String = Input::with_theme(&*THEME)
+            .with_prompt(format!(
+                "{} (search {} entries, or leave empty to list all)",
+                prompt,
+                matches.len()
+            ))
+            .allow_empty(true)
+            .interact()?;
+        if query.is_empty() {
+            break;
+        }
+        let found = matches
+            .iter()
+            .copied()
+            .filter(|&i| fuzzy_match(&items[i], &query))
+            .collect::<Vec<_>>();
+        if found.is_empty() {
+            println!("No entries match \"{}\".", query);
+        } else {
+            matches = found;
+        }
+    }
+
+    let names = matches.iter().map(|&i| &items[i]).collect::<Vec<_>>();
+    let index = Select::with_theme(&*THEME)
+        .with_prompt(prompt)
+        .items(&names)
+        .interact()?;
+    Ok(matches[index])
+}
+
 pub fn choose_cidr<'a>(cidrs: &'a [Cidr], text: &'static str) -> Result<&'a Cidr, Error> {
     let cidr_names: Vec<_> = cidrs
         .iter()
         .map(|cidr| format!("{} ({})", &cidr.name, &cidr.cidr))
         .collect();
-    let cidr_index = Select::with_theme(&*THEME)
-        .with_prompt(text)
-        .items(&cidr_names)
-        .interact()?;
+    let cidr_index = fuzzy_select(text, &cidr_names)?;
     Ok(&cidrs[cidr_index])
 }
 
@@ -76,7 +207,7 @@ pub fn choose_association<'a>(
         .iter()
         .map(|association| {
             format!(
-                "{}: {} <=> {}",
+                "{}: {} <=> {}{}",
                 association.id,
                 &cidrs
                     .iter()
@@ -87,21 +218,29 @@ pub fn choose_association<'a>(
                     .iter()
                     .find(|c| c.id == association.cidr_id_2)
                     .unwrap()
-                    .name
+                    .name,
+                association
+                    .label
+                    .as_ref()
+                    .map(|label| format!(" ({})", label))
+                    .unwrap_or_default()
             )
         })
         .collect();
-    let index = Select::with_theme(&*THEME)
-        .with_prompt("Association")
-        .items(&names)
-        .interact()?;
+    let index = fuzzy_select("Association", &names)?;
 
     Ok(&associations[index])
 }
 
-pub fn add_association(cidrs: &[Cidr]) -> Result<Option<(&Cidr, &Cidr)>, Error> {
+/// Bring up a prompt to associate two CIDRs. Returns the association request.
+pub fn add_association(cidrs: &[Cidr]) -> Result<Option<AssociationContents>, Error> {
     let cidr1 = choose_cidr(&cidrs[..], "First CIDR")?;
     let cidr2 = choose_cidr(&cidrs[..], "Second CIDR")?;
+    let label: String = Input::with_theme(&*THEME)
+        .with_prompt("Label (optional, e.g. why it's needed)")
+        .allow_empty(true)
+        .interact()?;
+    let label = Some(label.trim().to_string()).filter(|label| !label.is_empty());
 
     Ok(
         if Confirm::with_theme(&*THEME)
@@ -113,7 +252,11 @@ pub fn add_association(cidrs: &[Cidr]) -> Result<Option<(&Cidr, &Cidr)>, Error>
             .default(false)
             .interact()?
         {
-            Some((cidr1, cidr2))
+            Some(AssociationContents {
+                cidr_id_1: cidr1.id,
+                cidr_id_2: cidr2.id,
+                label,
+            })
         } else {
             None
         },
@@ -139,51 +282,121 @@ pub fn delete_association<'a>(
     )
 }
 
+/// Presents a selection and confirmation of CIDRs to delete, which should only include the
+/// ones that are empty.
+pub fn delete_cidr(cidrs: &[Cidr]) -> Result<Option<&Cidr>, Error> {
+    if cidrs.is_empty() {
+        return Err(
+            "no CIDRs can be deleted, since they all have peers, child CIDRs, or associations"
+                .into(),
+        );
+    }
+    let cidr = choose_cidr(cidrs, "CIDR to delete")?;
+
+    Ok(
+        if Confirm::with_theme(&*THEME)
+            .with_prompt(format!(
+                "Delete CIDR {} ({})?",
+                cidr.name.yellow(),
+                cidr.cidr
+            ))
+            .default(false)
+            .interact()?
+        {
+            Some(cidr)
+        } else {
+            None
+        },
+    )
+}
+
+/// When a peer added now expires, given a duration like "8h" or "3d".
+fn expiry_after(s: &str) -> Result<SystemTime, String> {
+    SystemTime::now()
+        .checked_add(parse_duration(s)?)
+        .ok_or_else(|| format!("expiry \"{}\" is too far in the future", s.trim()))
+}
+
 /// Bring up a prompt to create a new peer. Returns the peer request.
 pub fn add_peer(
     peers: &[Peer],
     cidr_tree: &CidrTree,
+    name_pattern: Option<&PeerNamePattern>,
 ) -> Result<Option<(PeerContents, KeyPair)>, Error> {
-    let leaves = cidr_tree.leaves();
+    let mut leaves = cidr_tree.leaves();
+
+    // On dual-stack networks, pick the address family first so that the CIDR list (and the
+    // suggested IP) only covers the family the peer will actually be configured with.
+    let has_ipv4 = leaves.iter().any(|cidr| cidr.is_ipv4());
+    let has_ipv6 = leaves.iter().any(|cidr| cidr.is_ipv6());
+    if has_ipv4 && has_ipv6 {
+        let ipv6 = Select::with_theme(&*THEME)
+            .with_prompt("Address family")
+            .items(&["IPv4", "IPv6"])
+            .default(0)
+            .interact()?
+            == 1;
+        leaves.retain(|cidr| cidr.is_ipv6() == ipv6);
+    }
 
     let cidr = choose_cidr(&leaves[..], "Eligible CIDRs for peer")?;
 
-    let mut available_ip = None;
-    let candidate_ips = cidr.iter().filter(|ip| cidr.is_assignable(*ip));
-    for ip in candidate_ips {
-        if peers.iter().find(|peer| peer.ip == ip).is_none() {
-            available_ip = Some(ip);
-            break;
-        }
-    }
-
-    let available_ip = available_ip.expect("No IPs in this CIDR are avavilable");
+    let available_ip = first_free_ip(cidr, peers)
+        .ok_or_else(|| format!("no IPs in {} are available", cidr.cidr))?;
 
-    let ip = Input::with_theme(&*THEME)
-        .with_prompt("IP")
-        .default(available_ip)
+    // Infra peers often want a stable, well-known address rather than the next free one.
+    let manual_ip = Confirm::with_theme(&*THEME)
+        .with_prompt(format!(
+            "Specify IP manually (instead of {})?",
+            available_ip
+        ))
+        .default(false)
         .interact()?;
 
+    let ip = if manual_ip {
+        Input::with_theme(&*THEME)
+            .with_prompt("IP")
+            .validate_with(|ip: &IpAddr| check_free_ip(cidr, peers, *ip))
+            .interact()?
+    } else {
+        available_ip
+    };
+
+    if let Some(pattern) = name_pattern {
+        println!("Names have to match {}.", pattern.to_string().yellow());
+    }
     let name: String = Input::with_theme(&*THEME)
         .with_prompt("Name")
-        .validate_with(hostname_validator)
+        .validate_with(|name: &String| check_peer_name(name, name_pattern))
         .interact()?;
 
     let is_admin = Confirm::with_theme(&*THEME)
         .with_prompt(&format!("Make {} an admin?", name))
         .default(false)
         .interact()?;
+
+    let expires_in: String = Input::with_theme(&*THEME)
+        .with_prompt("Expire after (e.g. \"8h\" or \"3d\", empty to never expire)")
+        .allow_empty(true)
+        .validate_with(|s: &String| {
+            if s.trim().is_empty() {
+                Ok(())
+            } else {
+                expiry_after(s).map(|_| ())
+            }
+        })
+        .interact()?;
+    let expires_at = if expires_in.trim().is_empty() {
+        None
+    } else {
+        Some(expiry_after(&expires_in)?)
+    };
+
     let default_keypair = KeyPair::generate();
     let peer_request = PeerContents {
-        name,
-        ip,
-        cidr_id: cidr.id,
-        public_key: default_keypair.public.to_base64(),
-        endpoint: None,
         is_admin,
-        is_disabled: false,
-        is_redeemed: false,
-        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        expires_at,
+        ..new_peer_contents(name, ip, cidr.id, &default_keypair)
     };
 
     Ok(
@@ -199,6 +412,76 @@ pub fn add_peer(
     )
 }
 
+/// A new, unredeemed, non-admin peer that never expires.
+fn new_peer_contents(name: String, ip: IpAddr, cidr_id: i64, keypair: &KeyPair) -> PeerContents {
+    PeerContents {
+        name,
+        ip,
+        cidr_id,
+        public_key: keypair.public.to_base64(),
+        endpoint: None,
+        is_admin: false,
+        is_disabled: false,
+        is_redeemed: false,
+        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        created_at: None,
+        fallback_endpoints: vec![],
+        endpoint_override_allowed: true,
+        expires_at: None,
+    }
+}
+
+/// Like `add_peer`, but without any prompts, e.g. for provisioning scripts: the peer is
+/// created in the CIDR with the given name, at the given IP or else the first free one.
+pub fn add_peer_unattended(
+    peers: &[Peer],
+    cidr_tree: &CidrTree,
+    name: &str,
+    cidr_name: &str,
+    ip: Option<IpAddr>,
+    name_pattern: Option<&PeerNamePattern>,
+) -> Result<(PeerContents, KeyPair), Error> {
+    check_peer_name(name, name_pattern)?;
+    let leaves = cidr_tree.leaves();
+    let cidr = leaves
+        .iter()
+        .find(|cidr| cidr.name == cidr_name)
+        .ok_or_else(|| format!("no CIDR without child CIDRs named \"{}\"", cidr_name))?;
+    let ip = match ip {
+        Some(ip) => {
+            check_free_ip(cidr, peers, ip)?;
+            ip
+        },
+        None => first_free_ip(cidr, peers)
+            .ok_or_else(|| format!("no IPs in {} are available", cidr.cidr))?,
+    };
+
+    let keypair = KeyPair::generate();
+    Ok((
+        new_peer_contents(name.to_string(), ip, cidr.id, &keypair),
+        keypair,
+    ))
+}
+
+/// The first IP in the CIDR that can be assigned to a peer and isn't taken yet.
+fn first_free_ip(cidr: &Cidr, peers: &[Peer]) -> Option<IpAddr> {
+    cidr.iter()
+        .filter(|ip| cidr.is_assignable(*ip))
+        .find(|ip| peers.iter().all(|peer| peer.ip != *ip))
+}
+
+fn check_free_ip(cidr: &Cidr, peers: &[Peer], ip: IpAddr) -> Result<(), String> {
+    if !cidr.contains(ip) {
+        Err(format!("IP must be within {}", cidr.cidr))
+    } else if !cidr.is_assignable(ip) {
+        Err(format!("{} can't be assigned to a peer", ip))
+    } else if let Some(peer) = peers.iter().find(|peer| peer.ip == ip) {
+        Err(format!("{} is already taken by peer {}", ip, peer.name))
+    } else {
+        Ok(())
+    }
+}
+
 /// Presents a selection and confirmation of eligible peers for either disabling or enabling,
 /// and returns back the ID of the selected peer.
 pub fn enable_or_disable_peer(peers: &[Peer], enable: bool) -> Result<Option<Peer>, Error> {
@@ -211,13 +494,10 @@ pub fn enable_or_disable_peer(peers: &[Peer], enable: bool) -> Result<Option<Pee
         .iter()
         .map(|peer| format!("{} ({})", &peer.name, &peer.ip))
         .collect();
-    let index = Select::with_theme(&*THEME)
-        .with_prompt(&format!(
-            "Peer to {}able",
-            if enable { "en" } else { "dis" }
-        ))
-        .items(&peer_selection)
-        .interact()?;
+    let index = fuzzy_select(
+        &format!("Peer to {}able", if enable { "en" } else { "dis" }),
+        &peer_selection,
+    )?;
     let peer = enabled_peers[index];
 
     Ok(
@@ -237,35 +517,293 @@ pub fn enable_or_disable_peer(peers: &[Peer], enable: bool) -> Result<Option<Pee
     )
 }
 
-/// Confirm and write a innernet invitation file after a peer has been created.
-pub fn save_peer_invitation(
+/// Presents a selection of peers to rename and asks for a new, unused name, returning the
+/// selected peer along with its new name once confirmed.
+pub fn rename_peer(
+    peers: &[Peer],
+    name_pattern: Option<&PeerNamePattern>,
+) -> Result<Option<(Peer, String)>, Error> {
+    let peer_selection: Vec<_> = peers
+        .iter()
+        .map(|peer| format!("{} ({})", &peer.name, &peer.ip))
+        .collect();
+    let index = fuzzy_select("Peer to rename", &peer_selection)?;
+    let peer = &peers[index];
+
+    if let Some(pattern) = name_pattern {
+        println!("Names have to match {}.", pattern.to_string().yellow());
+    }
+    let name: String = Input::with_theme(&*THEME)
+        .with_prompt("New name")
+        .validate_with(|name: &String| {
+            check_peer_name(name, name_pattern)?;
+            if peers.iter().any(|peer| &peer.name == name) {
+                Err("a peer with that name already exists".to_string())
+            } else {
+                Ok(())
+            }
+        })
+        .interact()?;
+
+    Ok(
+        if Confirm::with_theme(&*THEME)
+            .with_prompt(format!(
+                "Rename {} to {}?",
+                peer.name.yellow(),
+                name.yellow()
+            ))
+            .default(false)
+            .interact()?
+        {
+            Some((peer.clone(), name))
+        } else {
+            None
+        },
+    )
+}
+
+/// Presents a selection of peers to delete, warning that it can't be undone, and returns back
+/// the selected peer once confirmed.
+pub fn delete_peer(peers: &[Peer]) -> Result<Option<&Peer>, Error> {
+    if peers.is_empty() {
+        return Err("no peers can be deleted".into());
+    }
+    let peer_selection: Vec<_> = peers
+        .iter()
+        .map(|peer| format!("{} ({})", &peer.name, &peer.ip))
+        .collect();
+    let index = fuzzy_select("Peer to delete", &peer_selection)?;
+    let peer = &peers[index];
+
+    Ok(
+        if Confirm::with_theme(&*THEME)
+            .with_prompt(format!(
+                "Permanently delete peer {}? This can't be undone, and frees up {} for new peers.",
+                peer.name.yellow(),
+                peer.ip
+            ))
+            .default(false)
+            .interact()?
+        {
+            Some(peer)
+        } else {
+            None
+        },
+    )
+}
+
+/// Presents a selection of peers to move to another IP in their CIDR, suggesting the first free
+/// one, and returns back the selected peer along with its new IP once confirmed. `peers` are
+/// all of the network's peers, whose IPs are taken.
+pub fn reassign_peer_ip<'a>(
+    movable: &'a [Peer],
+    peers: &[Peer],
+    cidrs: &[Cidr],
+) -> Result<Option<(&'a Peer, IpAddr)>, Error> {
+    if movable.is_empty() {
+        return Err("no peers can be moved".into());
+    }
+    let peer_selection: Vec<_> = movable
+        .iter()
+        .map(|peer| format!("{} ({})", &peer.name, &peer.ip))
+        .collect();
+    let index = fuzzy_select("Peer to move", &peer_selection)?;
+    let peer = &movable[index];
+    let cidr = cidrs
+        .iter()
+        .find(|cidr| cidr.id == peer.cidr_id)
+        .ok_or_else(|| format!("{}'s CIDR not found", peer.name))?;
+
+    let mut ip_input = Input::with_theme(&*THEME);
+    if let Some(ip) = first_free_ip(cidr, peers) {
+        ip_input.default(ip);
+    }
+    let ip: IpAddr = ip_input
+        .with_prompt(format!("New IP (in {})", cidr.cidr))
+        .validate_with(|ip: &IpAddr| check_free_ip(cidr, peers, *ip))
+        .interact()?;
+
+    Ok(
+        if Confirm::with_theme(&*THEME)
+            .with_prompt(format!(
+                "Move peer {} from {} to {}?",
+                peer.name.yellow(),
+                peer.ip,
+                ip
+            ))
+            .default(false)
+            .interact()?
+        {
+            Some((peer, ip))
+        } else {
+            None
+        },
+    )
+}
+
+/// Presents a selection of peers whose permission to override their own endpoint differs from
+/// `allowed`, and returns back the selected peer once confirmed.
+pub fn set_endpoint_override(peers: &[Peer], allowed: bool) -> Result<Option<Peer>, Error> {
+    let eligible_peers: Vec<_> = peers
+        .iter()
+        .filter(|peer| peer.endpoint_override_allowed != allowed)
+        .collect();
+    if eligible_peers.is_empty() {
+        return Err(format!(
+            "no peers to {} endpoint overrides for",
+            if allowed { "allow" } else { "disallow" }
+        )
+        .into());
+    }
+
+    let peer_selection: Vec<_> = eligible_peers
+        .iter()
+        .map(|peer| format!("{} ({})", &peer.name, &peer.ip))
+        .collect();
+    let index = fuzzy_select(
+        if allowed {
+            "Peer to allow overriding its endpoint"
+        } else {
+            "Peer to stop overriding its endpoint"
+        },
+        &peer_selection,
+    )?;
+    let peer = eligible_peers[index];
+
+    let prompt = if allowed {
+        format!(
+            "Allow {} to override the endpoint other peers reach it at?",
+            peer.name.yellow()
+        )
+    } else {
+        format!(
+            "Stop {} from overriding its endpoint, leaving it to the one the server sees?",
+            peer.name.yellow()
+        )
+    };
+    Ok(
+        if Confirm::with_theme(&*THEME)
+            .with_prompt(prompt)
+            .default(false)
+            .interact()?
+        {
+            Some(peer.clone())
+        } else {
+            None
+        },
+    )
+}
+
+/// Presents a selection and confirmation of CIDRs to either flag as infra or unflag, and
+/// returns back the selected CIDR.
+pub fn set_infra_cidr(
+    cidrs: &[Cidr],
+    infra: bool,
+    server_cidr_id: Option<i64>,
+) -> Result<Option<Cidr>, Error> {
+    // The server refuses to unflag its own CIDR, which would cut every peer off from it.
+    let eligible_cidrs: Vec<_> = cidrs
+        .iter()
+        .filter(|cidr| cidr.is_infra != infra && Some(cidr.id) != server_cidr_id)
+        .collect();
+    if eligible_cidrs.is_empty() {
+        return Err(format!(
+            "no CIDRs to {}",
+            if infra { "flag as infra" } else { "unflag" }
+        )
+        .into());
+    }
+
+    let cidr_selection: Vec<_> = eligible_cidrs
+        .iter()
+        .map(|cidr| format!("{} ({})", &cidr.name, &cidr.cidr))
+        .collect();
+    let index = fuzzy_select(
+        if infra {
+            "CIDR to flag as infra"
+        } else {
+            "Infra CIDR to unflag"
+        },
+        &cidr_selection,
+    )?;
+    let cidr = eligible_cidrs[index];
+
+    let prompt = if infra {
+        format!(
+            "Flag {} as infra, making its peers visible to every other peer?",
+            cidr.name.yellow()
+        )
+    } else {
+        format!(
+            "Unflag {}, leaving its peers visible only through associations?",
+            cidr.name.yellow()
+        )
+    };
+    Ok(
+        if Confirm::with_theme(&*THEME)
+            .with_prompt(prompt)
+            .default(false)
+            .interact()?
+        {
+            Some(cidr.clone())
+        } else {
+            None
+        },
+    )
+}
+
+/// The innernet invitation for a peer that has just been created, optionally bundling a
+/// snapshot of the network's state as the new peer will see it.
+pub fn peer_invitation(
     network_name: &str,
     peer: &Peer,
     server_peer: &Peer,
-    root_cidr: &Cidr,
+    cidr_tree: &CidrTree,
     keypair: KeyPair,
     server_api_addr: &SocketAddr,
-) -> Result<(), Error> {
-    let peer_invitation = InterfaceConfig {
+    snapshot: Option<State>,
+) -> Result<InterfaceConfig, Error> {
+    Ok(InterfaceConfig {
+        snapshot,
         interface: InterfaceInfo {
             network_name: network_name.to_string(),
             private_key: keypair.private.to_base64(),
-            address: IpNetwork::new(peer.ip, root_cidr.prefix())?,
+            address: IpNetwork::new(peer.ip, cidr_tree.interface_prefix(peer.ip))?,
             listen_port: None,
+            hosts_template: None,
+            hosts_suffix: None,
+            hosts_tag: None,
+            no_host_updates: false,
+            peer_name_pattern: None,
+            redeem_pending: false,
         },
         server: ServerInfo {
             external_endpoint: server_peer
                 .endpoint
                 .expect("The innernet server should have a WireGuard endpoint"),
+            external_endpoint_srv: None,
             internal_endpoint: *server_api_addr,
             public_key: server_peer.public_key.clone(),
+            extra_allowed_ips: vec![],
         },
-    };
+    })
+}
 
-    let invitation_save_path = Input::with_theme(&*THEME)
-        .with_prompt("Save peer invitation file as")
-        .default(format!("{}.toml", peer.name))
-        .interact()?;
+/// Write a peer's invitation file to the given path, or else to one that's asked for,
+/// returning where it was written.
+pub fn save_peer_invitation(
+    peer: &Peer,
+    peer_invitation: &InterfaceConfig,
+    save_path: Option<&Path>,
+) -> Result<PathBuf, Error> {
+    let invitation_save_path = match save_path {
+        Some(path) => path.to_path_buf(),
+        None => Input::<String>::with_theme(&*THEME)
+            .with_prompt("Save peer invitation file as")
+            .default(format!("{}.toml", peer.name))
+            .interact()?
+            .into(),
+    };
 
     peer_invitation.write_to_path(&invitation_save_path, true, None)?;
 
@@ -275,24 +813,35 @@ pub fn save_peer_invitation(
          Please send it to them securely (eg. via magic-wormhole) \
          to bootstrap them onto the network.",
         peer.name.bold(),
-        invitation_save_path.bold()
+        invitation_save_path.to_string_lossy().bold()
     );
 
-    Ok(())
+    Ok(invitation_save_path)
 }
 
 pub fn set_listen_port(
     interface: &InterfaceInfo,
     unset: bool,
+    range: Option<PortRange>,
 ) -> Result<Option<Option<u16>>, Error> {
-    let listen_port = (!unset)
-        .then(|| {
+    let listen_port = match (unset, range) {
+        (true, _) => None,
+        // Keep the current port if it already satisfies the range, otherwise pick a free one.
+        (false, Some(range)) => match interface.listen_port {
+            Some(port) if range.contains(port) => Some(port),
+            _ => Some(
+                range
+                    .find_free_udp_port()
+                    .ok_or_else(|| format!("no free UDP ports available in range {}", range))?,
+            ),
+        },
+        (false, None) => Some(
             Input::with_theme(&*THEME)
                 .with_prompt("Listen port")
                 .default(interface.listen_port.unwrap_or(51820))
-                .interact()
-        })
-        .transpose()?;
+                .interact()?,
+        ),
+    };
 
     let mut confirmation = Confirm::with_theme(&*THEME);
     confirmation
@@ -338,14 +887,29 @@ pub fn ask_endpoint() -> Result<SocketAddr, Error> {
         .map_err(|e| Error::from(e))
 }
 
-pub fn override_endpoint(unset: bool) -> Result<Option<Option<SocketAddr>>, Error> {
+pub fn override_endpoint(
+    unset: bool,
+    fallbacks: &[SocketAddr],
+) -> Result<Option<Option<SocketAddr>>, Error> {
     let endpoint = if !unset { Some(ask_endpoint()?) } else { None };
 
     Ok(
         if Confirm::with_theme(&*THEME)
             .with_prompt(
                 &(if let Some(endpoint) = &endpoint {
-                    format!("Set external endpoint to {}?", endpoint)
+                    if fallbacks.is_empty() {
+                        format!("Set external endpoint to {}?", endpoint)
+                    } else {
+                        format!(
+                            "Set external endpoint to {}, falling back to {}?",
+                            endpoint,
+                            fallbacks
+                                .iter()
+                                .map(SocketAddr::to_string)
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    }
                 } else {
                     "Unset external endpoint to enable automatic endpoint discovery?".to_string()
                 }),
@@ -359,3 +923,81 @@ pub fn override_endpoint(unset: bool) -> Result<Option<Option<SocketAddr>>, Erro
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzzy_match() {
+        assert!(fuzzy_match("developer3 (10.80.64.3)", "dvl3"));
+        assert!(fuzzy_match("developer3 (10.80.64.3)", "DEV"));
+        assert!(fuzzy_match("developer3 (10.80.64.3)", ""));
+        assert!(!fuzzy_match("developer3 (10.80.64.3)", "3dev"));
+        assert!(!fuzzy_match("infra", "infrastructure"));
+    }
+
+    #[test]
+    fn test_add_peer_unattended() {
+        let cidr = |id, parent, name: &str, cidr: &str| Cidr {
+            id,
+            contents: CidrContents {
+                name: name.to_string(),
+                cidr: cidr.parse().unwrap(),
+                parent,
+                is_infra: false,
+            },
+        };
+        let cidrs = vec![
+            cidr(1, None, "root", "10.80.0.0/15"),
+            cidr(2, Some(1), "infra", "10.80.0.0/24"),
+        ];
+        let cidr_tree = CidrTree::new(&cidrs);
+        let keypair = KeyPair::generate();
+        let peers = vec![Peer {
+            id: 1,
+            contents: new_peer_contents(
+                "innernet-server".to_string(),
+                "10.80.0.1".parse().unwrap(),
+                2,
+                &keypair,
+            ),
+        }];
+
+        let (contents, keypair) =
+            add_peer_unattended(&peers, &cidr_tree, "gateway", "infra", None, None).unwrap();
+        assert_eq!(contents.ip, "10.80.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(contents.cidr_id, 2);
+        assert_eq!(contents.public_key, keypair.public.to_base64());
+
+        let ip = Some("10.80.0.10".parse().unwrap());
+        let (contents, _) =
+            add_peer_unattended(&peers, &cidr_tree, "gateway", "infra", ip, None).unwrap();
+        assert_eq!(Some(contents.ip), ip);
+
+        // Taken, outside of the CIDR, not a leaf CIDR, and an invalid name.
+        let taken = Some("10.80.0.1".parse().unwrap());
+        assert!(add_peer_unattended(&peers, &cidr_tree, "gateway", "infra", taken, None).is_err());
+        let outside = Some("10.80.1.1".parse().unwrap());
+        assert!(
+            add_peer_unattended(&peers, &cidr_tree, "gateway", "infra", outside, None).is_err()
+        );
+        assert!(add_peer_unattended(&peers, &cidr_tree, "gateway", "root", None, None).is_err());
+        assert!(add_peer_unattended(&peers, &cidr_tree, "-gateway", "infra", None, None).is_err());
+
+        let pattern = "[a-z]+-[a-z]+".parse().unwrap();
+        assert!(
+            add_peer_unattended(&peers, &cidr_tree, "gateway", "infra", None, Some(&pattern))
+                .is_err()
+        );
+        assert!(add_peer_unattended(
+            &peers,
+            &cidr_tree,
+            "infra-gw",
+            "infra",
+            None,
+            Some(&pattern)
+        )
+        .is_ok());
+    }
+}
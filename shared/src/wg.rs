@@ -52,26 +52,96 @@ pub fn set_addr(interface: &str, addr: IpNetwork) -> Result<(), Error> {
         "ip",
         &["address", "replace", &addr.to_string(), "dev", interface],
     )?;
-    let _ = cmd(
+    // The kernel only binds the listen port once the link is up, so this is where a port that's
+    // already in use is refused. Any other failure is ignored, as it always has been.
+    match cmd(
         "ip",
         &["link", "set", "mtu", "1420", "up", "dev", interface],
-    );
-    Ok(())
+    ) {
+        Err(e) if e.to_string().contains("Address already in use") => {
+            Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, e.to_string()).into())
+        },
+        _ => Ok(()),
+    }
+}
+
+/// The addresses currently configured on the interface, which may be more than innernet itself
+/// set (e.g. on a dual-stack interface).
+#[cfg(target_os = "macos")]
+pub fn get_addrs(interface: &str) -> Result<Vec<IpNetwork>, Error> {
+    let real_interface = wgctrl::backends::userspace::resolve_tun(interface).with_str(interface)?;
+    let output = cmd("ifconfig", &[&real_interface])?;
+    Ok(parse_ifconfig_addrs(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// The addresses currently configured on the interface, which may be more than innernet itself
+/// set (e.g. on a dual-stack interface).
+#[cfg(target_os = "linux")]
+pub fn get_addrs(interface: &str) -> Result<Vec<IpNetwork>, Error> {
+    let output = cmd("ip", &["-o", "address", "show", "dev", interface])?;
+    Ok(parse_ip_addrs(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse "ifconfig" output, e.g. "inet 10.0.0.2 --> 10.0.0.2 netmask 0xffff0000" and
+/// "inet6 fd00::2 prefixlen 64" lines.
+#[cfg(target_os = "macos")]
+fn parse_ifconfig_addrs(output: &str) -> Vec<IpNetwork> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let ip: IpAddr = fields.get(1)?.split('%').next()?.parse().ok()?;
+            let value_of = |key| {
+                let i = fields.iter().position(|field| *field == key)?;
+                fields.get(i + 1).copied()
+            };
+            let prefix = match *fields.first()? {
+                "inet" => {
+                    let netmask = value_of("netmask")?.trim_start_matches("0x");
+                    u32::from_str_radix(netmask, 16).ok()?.count_ones() as u8
+                },
+                "inet6" => value_of("prefixlen")?.parse().ok()?,
+                _ => return None,
+            };
+            IpNetwork::new(ip, prefix).ok()
+        })
+        .collect()
+}
+
+/// Parse "ip -o address" output, e.g. "5: wg0    inet 10.0.0.2/16 scope global wg0".
+#[cfg(target_os = "linux")]
+fn parse_ip_addrs(output: &str) -> Vec<IpNetwork> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            fields.find(|field| *field == "inet" || *field == "inet6")?;
+            fields.next()?.parse().ok()
+        })
+        .collect()
 }
 
+/// Bring up the interface, optionally with a first peer. The peer's `extra_allowed_ips` are
+/// routed through it in addition to its own address, e.g. to reach networks behind it.
 pub fn up(
     interface: &str,
     private_key: &str,
     address: IpNetwork,
     listen_port: Option<u16>,
     peer: Option<(&str, IpAddr, SocketAddr)>,
+    extra_allowed_ips: &[IpNetwork],
 ) -> Result<(), Error> {
     let mut device = DeviceConfigBuilder::new();
     if let Some((public_key, address, endpoint)) = peer {
         let prefix = if address.is_ipv4() { 32 } else { 128 };
-        let peer_config = PeerConfigBuilder::new(&wgctrl::Key::from_base64(&public_key)?)
-            .add_allowed_ip(address, prefix)
-            .set_endpoint(endpoint);
+        let peer_config = extra_allowed_ips.iter().fold(
+            PeerConfigBuilder::new(&wgctrl::Key::from_base64(&public_key)?)
+                .add_allowed_ip(address, prefix)
+                .set_endpoint(endpoint),
+            |peer_config, net| peer_config.add_allowed_ip(net.network(), net.prefix()),
+        );
         device = device.add_peer(peer_config);
     }
     if let Some(listen_port) = listen_port {
@@ -81,7 +151,16 @@ pub fn up(
         .set_private_key(wgctrl::Key::from_base64(&private_key).unwrap())
         .apply(interface)?;
     set_addr(interface, address)?;
-    add_route(interface, address)?;
+    // Route the entire network through the interface rather than only our own address.
+    add_route(
+        interface,
+        IpNetwork::new(address.network(), address.prefix())?,
+    )?;
+    if peer.is_some() {
+        for net in extra_allowed_ips {
+            add_route(interface, IpNetwork::new(net.network(), net.prefix())?)?;
+        }
+    }
     Ok(())
 }
 
@@ -146,3 +225,37 @@ pub fn add_route(interface: &str, cidr: IpNetwork) -> Result<bool, Error> {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_ip_addrs() {
+        let output = "5: wg0    inet 10.42.0.2/16 scope global wg0\\       valid_lft forever preferred_lft forever
+5: wg0    inet6 fd00:42::2/64 scope global \\       valid_lft forever preferred_lft forever
+";
+        assert_eq!(
+            super::parse_ip_addrs(output),
+            vec![
+                "10.42.0.2/16".parse().unwrap(),
+                "fd00:42::2/64".parse().unwrap()
+            ]
+        );
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_parse_ifconfig_addrs() {
+        let output = "utun4: flags=8051<UP,POINTOPOINT,RUNNING,MULTICAST> mtu 1420
+\tinet 10.42.0.2 --> 10.42.0.2 netmask 0xffff0000
+\tinet6 fd00:42::2 prefixlen 64
+";
+        assert_eq!(
+            super::parse_ifconfig_addrs(output),
+            vec![
+                "10.42.0.2/16".parse().unwrap(),
+                "fd00:42::2/64".parse().unwrap()
+            ]
+        );
+    }
+}